@@ -0,0 +1,277 @@
+use eyre::{eyre, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Tracks the member processes of a cgroup, so that processes joining the
+/// cgroup after the collector started can be registered as new targets.
+pub struct Cgroup {
+    path: PathBuf,
+    known_pids: HashSet<usize>,
+}
+
+impl Cgroup {
+    /// Opens the cgroup at `path`, either within the `cgroupfs` mount point or
+    /// relative to it.
+    pub fn new(cgroupfs: &str, path: &str) -> Result<Self> {
+        let path = if Path::new(path).starts_with(cgroupfs) {
+            PathBuf::from(path)
+        } else {
+            Path::new(cgroupfs).join(path.trim_start_matches('/'))
+        };
+
+        if !path.join("cgroup.procs").exists() {
+            return Err(eyre!("No cgroup.procs file in {:?}", path));
+        }
+
+        Ok(Self {
+            path,
+            known_pids: HashSet::new(),
+        })
+    }
+
+    /// Resolves a container id (or id prefix) or name to the cgroup its
+    /// processes belong to, as listed in the `procfs` mount point. Names are
+    /// resolved to ids through `docker inspect`.
+    pub fn from_container(procfs: &str, cgroupfs: &str, container: &str) -> Result<Self> {
+        if let Some(path) = Self::search_container_cgroup(procfs, container)? {
+            return Self::new(cgroupfs, &path);
+        }
+
+        let output = Command::new("docker")
+            .args(["inspect", "--format", "{{.Id}}", container])
+            .output()?;
+        if !output.status.success() {
+            return Err(eyre!("Could not resolve container {}", container));
+        }
+        let id = String::from_utf8(output.stdout)?;
+        let path = Self::search_container_cgroup(procfs, id.trim())?.ok_or(eyre!(
+            "No running process found for container {}",
+            container
+        ))?;
+        Self::new(cgroupfs, &path)
+    }
+
+    /// Returns the cgroup of the container whose id starts with `id`, i.e. the
+    /// shortest cgroup path of the processes ending with its component.
+    fn search_container_cgroup(procfs: &str, id: &str) -> Result<Option<String>> {
+        if id.len() < 12 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+
+        let mut container: Option<String> = None;
+        for task in fs::read_dir(procfs)? {
+            let file_path = task?.path();
+            let is_pid = file_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.parse::<usize>().is_ok());
+            if !is_pid {
+                continue;
+            }
+
+            let content = match fs::read_to_string(file_path.join("cgroup")) {
+                Ok(content) => content,
+                Err(_) => continue,
+            };
+            let paths = content
+                .lines()
+                .filter_map(Self::parse_proc_cgroup_line)
+                .filter_map(|path| Self::container_path(&path, id));
+            container = container
+                .into_iter()
+                .chain(paths)
+                .min_by_key(|path| path.len());
+        }
+
+        Ok(container)
+    }
+
+    /// Truncates a cgroup path after the component of the container whose id
+    /// starts with `id`, i.e. `docker-<id>.scope` with the systemd driver, or
+    /// `docker/<id>` with the cgroupfs driver.
+    fn container_path(path: &str, id: &str) -> Option<String> {
+        let components: Vec<&str> = path.split('/').collect();
+        let position = components.iter().enumerate().position(|(i, component)| {
+            let container = match component.strip_prefix("docker-") {
+                Some(scope) => scope.strip_suffix(".scope"),
+                None if i > 0 && components[i - 1] == "docker" => Some(*component),
+                None => None,
+            };
+            container.is_some_and(|container| container.starts_with(id))
+        })?;
+        Some(components[..=position].join("/"))
+    }
+
+    /// Converts a `/proc/<pid>/cgroup` entry into a path relative to the
+    /// cgroup filesystem root. cgroup v2 entries have an empty controller list
+    /// (`0::/system.slice/docker-<id>.scope`), whereas cgroup v1 entries are
+    /// mounted under a directory named after their controllers.
+    fn parse_proc_cgroup_line(line: &str) -> Option<String> {
+        let mut elements = line.splitn(3, ':');
+        let (_hierarchy, controllers, path) =
+            (elements.next()?, elements.next()?, elements.next()?);
+        if controllers.is_empty() {
+            Some(path.to_string())
+        } else {
+            Some(format!(
+                "{}{}",
+                controllers.trim_start_matches("name="),
+                path
+            ))
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns all the processes in the cgroup and its descendants.
+    pub fn pids(&self) -> Result<Vec<usize>> {
+        let mut pids = Vec::new();
+        let mut directories = vec![self.path.clone()];
+        while let Some(directory) = directories.pop() {
+            let procs = match fs::read_to_string(directory.join("cgroup.procs")) {
+                Ok(procs) => procs,
+                Err(_) => continue,
+            };
+            for line in procs.lines() {
+                if let Ok(pid) = line.trim().parse() {
+                    pids.push(pid);
+                }
+            }
+
+            for entry in fs::read_dir(&directory)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    directories.push(entry.path());
+                }
+            }
+        }
+
+        Ok(pids)
+    }
+
+    /// Returns the processes which joined the cgroup since the last call.
+    pub fn take_new_pids(&mut self) -> Result<Vec<usize>> {
+        let pids = self.pids()?;
+        Ok(pids
+            .into_iter()
+            .filter(|pid| self.known_pids.insert(*pid))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cgroup;
+    use eyre::Result;
+    use std::{collections::HashSet, ffi::OsStr, fs, os::unix::ffi::OsStrExt};
+    use tempdir::TempDir;
+
+    #[test]
+    fn parse_cgroup_v2_line() {
+        let line = "0::/system.slice/docker-4f1c2a9e8b7d.scope";
+        assert_eq!(
+            Cgroup::parse_proc_cgroup_line(line),
+            Some("/system.slice/docker-4f1c2a9e8b7d.scope".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_cgroup_v1_line() {
+        let line = "4:cpu,cpuacct:/docker/4f1c2a9e8b7d";
+        assert_eq!(
+            Cgroup::parse_proc_cgroup_line(line),
+            Some("cpu,cpuacct/docker/4f1c2a9e8b7d".to_string())
+        );
+    }
+
+    #[test]
+    fn search_container_cgroup() -> Result<()> {
+        let procfs = TempDir::new("")?;
+        for (pid, cgroup) in [
+            // A process in a leaf cgroup of the container, and one whose
+            // cgroup holds the id in another component
+            (
+                "8877",
+                "0::/system.slice/docker-4f1c2a9e8b7d0c5e.scope/init\n",
+            ),
+            ("8878", "0::/system.slice/app-4f1c2a9e8b7d.slice\n"),
+            ("8955", "0::/system.slice/docker-4f1c2a9e8b7d0c5e.scope\n"),
+            ("9001", "4:cpu,cpuacct:/docker/8b7d4f1c2a9e0c5e/nested\n"),
+        ] {
+            fs::create_dir(procfs.path().join(pid))?;
+            fs::write(procfs.path().join(pid).join("cgroup"), cgroup)?;
+        }
+        fs::create_dir(procfs.path().join("self"))?;
+        fs::create_dir(procfs.path().join(OsStr::from_bytes(b"\xff")))?;
+        let procfs = procfs.path().to_str().unwrap();
+
+        assert_eq!(
+            Cgroup::search_container_cgroup(procfs, "4f1c2a9e8b7d")?,
+            Some("/system.slice/docker-4f1c2a9e8b7d0c5e.scope".to_string())
+        );
+        assert_eq!(
+            Cgroup::search_container_cgroup(procfs, "8b7d4f1c2a9e")?,
+            Some("cpu,cpuacct/docker/8b7d4f1c2a9e0c5e".to_string())
+        );
+        assert_eq!(
+            Cgroup::search_container_cgroup(procfs, "0c5e4f1c2a9e")?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_in_cgroupfs() -> Result<()> {
+        let cgroupfs = TempDir::new("")?;
+        let procfs = TempDir::new("")?;
+        let scope = "system.slice/docker-4f1c2a9e8b7d0c5e.scope";
+        fs::create_dir_all(cgroupfs.path().join(scope))?;
+        fs::write(cgroupfs.path().join(scope).join("cgroup.procs"), "8877\n")?;
+        fs::create_dir(procfs.path().join("8877"))?;
+        fs::write(
+            procfs.path().join("8877/cgroup"),
+            format!("0::/{}\n", scope),
+        )?;
+        let cgroupfs_path = cgroupfs.path().to_str().unwrap();
+
+        let cgroup = Cgroup::new(cgroupfs_path, &format!("/{}", scope))?;
+        assert_eq!(cgroup.path(), cgroupfs.path().join(scope));
+        let absolute = cgroupfs.path().join(scope);
+        let cgroup = Cgroup::new(cgroupfs_path, absolute.to_str().unwrap())?;
+        assert_eq!(cgroup.pids()?, vec![8877]);
+        assert!(Cgroup::new(cgroupfs_path, "system.slice").is_err());
+
+        let procfs = procfs.path().to_str().unwrap();
+        let cgroup = Cgroup::from_container(procfs, cgroupfs_path, "4f1c2a9e8b7d")?;
+        assert_eq!(cgroup.path(), cgroupfs.path().join(scope));
+        Ok(())
+    }
+
+    #[test]
+    fn take_new_pids() -> Result<()> {
+        let root = TempDir::new("")?;
+        fs::write(root.path().join("cgroup.procs"), "10\n11\n")?;
+        fs::create_dir(root.path().join("child"))?;
+        fs::write(root.path().join("child/cgroup.procs"), "12\n")?;
+
+        let mut cgroup = Cgroup {
+            path: root.path().to_path_buf(),
+            known_pids: HashSet::new(),
+        };
+        let mut pids = cgroup.take_new_pids()?;
+        pids.sort();
+        assert_eq!(pids, vec![10, 11, 12]);
+
+        fs::write(root.path().join("cgroup.procs"), "10\n11\n13\n")?;
+        assert_eq!(cgroup.take_new_pids()?, vec![13]);
+        assert_eq!(cgroup.take_new_pids()?, Vec::<usize>::new());
+
+        Ok(())
+    }
+}
@@ -1,8 +1,22 @@
 use clap::{command, value_parser, Arg, ArgAction, Command};
 
+use crate::configure::{Compression, Format, OnLimit};
+
+pub const SUBSYSTEMS: [&str; 4] = ["sched", "futex", "ipc", "iowait"];
+
 pub fn register_args() -> Command {
     command!() // requires `cargo` feature
         .next_line_help(true)
+        .arg(
+            Arg::new("config")
+                .required(false)
+                .long("config")
+                .global(true)
+                .action(ArgAction::Set)
+                .help(
+                    "TOML configuration file. Arguments passed on the command line take precedence",
+                ),
+        )
         .arg(
             Arg::new("pids")
                 .required(false)
@@ -17,18 +31,178 @@ pub fn register_args() -> Command {
                 .required(false)
                 .default_value("1000")
                 .long("period")
+                .global(true)
                 .action(ArgAction::Set)
                 .value_parser(value_parser!(u64))
                 .help("Sleep time between two consecutive samples"),
         )
+        .arg(
+            Arg::new("procfs")
+                .required(false)
+                .default_value("/proc")
+                .long("procfs")
+                .global(true)
+                .action(ArgAction::Set)
+                .help("Mount point of procfs, e.g. the host's when running in a container"),
+        )
+        .arg(
+            Arg::new("cgroupfs")
+                .required(false)
+                .default_value("/sys/fs/cgroup")
+                .long("cgroupfs")
+                .global(true)
+                .action(ArgAction::Set)
+                .help("Mount point of the cgroup filesystem, which --cgroup is relative to"),
+        )
         .arg(
             Arg::new("data-directory")
                 .required(false)
                 .default_value("./data")
                 .long("data-directory")
+                .global(true)
                 .action(ArgAction::Set)
                 .help("Root directory where data should be stored"),
         )
+        .arg(
+            Arg::new("format")
+                .required(false)
+                .default_value("csv")
+                .long("format")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(Format))
+                .help("Format of the data files"),
+        )
+        .arg(
+            Arg::new("compression")
+                .required(false)
+                .long("compression")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(Compression))
+                .help("Compress the CSV files of a minute or day once it is over"),
+        )
+        .arg(
+            Arg::new("record-raw")
+                .long("record-raw")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Record the output of the bpftrace programs, to be replayed"),
+        )
+        .arg(
+            Arg::new("max-size-mb")
+                .required(false)
+                .long("max-size-mb")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64))
+                .help("Size in MiB of the session directory beyond which --on-limit applies"),
+        )
+        .arg(
+            Arg::new("max-age-s")
+                .required(false)
+                .long("max-age-s")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64))
+                .help("Seconds after its last sample a partition is dropped"),
+        )
+        .arg(
+            Arg::new("on-limit")
+                .required(false)
+                .long("on-limit")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(OnLimit))
+                .help("What to do once the session directory reached --max-size-mb"),
+        )
+        .arg(
+            Arg::new("flight-recorder")
+                .required(false)
+                .long("flight-recorder")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64))
+                .value_name("SECONDS")
+                .help("Keep the latest samples in memory, and only write them when a dump is triggered"),
+        )
+        .arg(
+            Arg::new("slo-source")
+                .required(false)
+                .long("slo-source")
+                .global(true)
+                .action(ArgAction::Set)
+                .help("File of end_ts,duration_ms response times to tail, or - for stdin. Traces futex, ipc and iowait only while the SLO is breached"),
+        )
+        .arg(
+            Arg::new("slo-threshold-ms")
+                .required(false)
+                .long("slo-threshold-ms")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(f64))
+                .help("Response time in milliseconds the percentile must stay under"),
+        )
+        .arg(
+            Arg::new("slo-percentile")
+                .required(false)
+                .long("slo-percentile")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(f64))
+                .help("Percentile of the response times compared to the threshold"),
+        )
+        .arg(
+            Arg::new("metrics-listen")
+                .required(false)
+                .long("metrics-listen")
+                .global(true)
+                .action(ArgAction::Set)
+                .help("Address to serve the latest samples on as OpenMetrics, e.g. 127.0.0.1:9464"),
+        )
+        .arg(
+            Arg::new("collectors")
+                .required(false)
+                .long("collectors")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(SUBSYSTEMS)
+                .value_delimiter(',')
+                .help("Subsystems to collect metrics for. Defaults to all of them"),
+        )
+        .arg(
+            Arg::new("bpf-dir")
+                .required(false)
+                .long("bpf-dir")
+                .global(true)
+                .action(ArgAction::Set)
+                .help("Directory to load the bpftrace scripts from instead of the bundled ones"),
+        )
+        .arg(
+            Arg::new("bpftrace")
+                .required(false)
+                .long("bpftrace")
+                .global(true)
+                .action(ArgAction::Set)
+                .help("bpftrace binary to launch the programs with, instead of the one in the PATH"),
+        )
+        .arg(
+            Arg::new("plugin-dir")
+                .required(false)
+                .long("plugin-dir")
+                .global(true)
+                .action(ArgAction::Set)
+                .help(
+                    "Directory of additional bpftrace scripts, each with a TOML schema of its map",
+                ),
+        )
+        .arg(
+            Arg::new("skip-doctor")
+                .long("skip-doctor")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Start recording even if the preflight checks of the doctor subcommand fail"),
+        )
         .arg(
             Arg::new("process-name")
                 .required(false)
@@ -36,4 +210,54 @@ pub fn register_args() -> Command {
                 .action(ArgAction::Set)
                 .help("Name of the target process"),
         )
+        .arg(
+            Arg::new("cgroup")
+                .required(false)
+                .long("cgroup")
+                .action(ArgAction::Set)
+                .help("Path of the cgroup whose processes should be monitored"),
+        )
+        .arg(
+            Arg::new("container")
+                .required(false)
+                .long("container")
+                .action(ArgAction::Set)
+                .help("Id or name of the container whose processes should be monitored"),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Launch a command and monitor it from its first instruction")
+                .arg(
+                    Arg::new("command")
+                        .required(true)
+                        .num_args(1..)
+                        .last(true)
+                        .action(ArgAction::Append)
+                        .help("Command to launch, passed after --"),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check that the host meets the requirements for recording, and exit"),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("Have the flight recorder of a running session write its samples")
+                .arg(
+                    Arg::new("session")
+                        .required(true)
+                        .action(ArgAction::Set)
+                        .help("system-metrics directory of the session"),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Rebuild the data files of a session recorded with --record-raw in a new session")
+                .arg(
+                    Arg::new("session")
+                        .required(true)
+                        .action(ArgAction::Set)
+                        .help("system-metrics directory of the session"),
+                ),
+        )
 }
@@ -1,50 +1,782 @@
 use chrono::prelude::*;
-use clap::ArgMatches;
+use clap::{parser::ValueSource, ArgMatches, ValueEnum};
 use eyre::eyre;
-use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use std::{fs, num::NonZeroUsize, rc::Rc, time::Duration};
 
 pub struct Config {
     pub pids: Option<Vec<u64>>,
     pub period: u64,
     pub data_directory: Rc<str>,
+    /// Mount point of procfs, which the threads of the targets and their
+    /// scheduler statistics are read from.
+    pub procfs: String,
+    /// Mount point of the cgroup filesystem, which the cgroup of the targets
+    /// is resolved in.
+    pub cgroupfs: String,
     pub process_name: Option<String>,
+    pub cgroup: Option<String>,
+    pub container: Option<String>,
+    pub command: Option<Vec<String>>,
+    /// Only run the preflight checks of the `doctor` subcommand.
+    pub doctor: bool,
+    /// Session directory whose flight recorder is asked to dump by the
+    /// `dump` subcommand.
+    pub dump: Option<String>,
+    /// Session directory whose raw streams are replayed by the `replay`
+    /// subcommand.
+    pub replay: Option<String>,
+    pub skip_doctor: bool,
+    pub subsystems: Subsystems,
+    pub kernel_threads: Vec<String>,
+    pub bpf: Bpf,
+    pub buffers: Buffers,
+    pub format: Format,
+    pub compression: Option<Compression>,
+    pub record_raw: bool,
+    pub exporter: Exporter,
+    pub retention: Retention,
+    pub flight_recorder: FlightRecorder,
+    pub slo: Slo,
+    /// Configuration after merging the command line arguments into the
+    /// configuration file, as stored in the session directory.
+    pub resolved: ConfigFile,
+}
+
+/// Layout of the file passed with `--config`. Every field is optional, and
+/// command line arguments take precedence over the values of the file.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub period: Option<u64>,
+    pub procfs: Option<String>,
+    pub cgroupfs: Option<String>,
+    pub targets: Targets,
+    pub subsystems: Subsystems,
+    pub kernel_threads: KernelThreads,
+    pub bpf: Bpf,
+    pub buffers: Buffers,
+    pub output: Output,
+    pub exporter: Exporter,
+    pub retention: Retention,
+    pub flight_recorder: FlightRecorder,
+    pub slo: Slo,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Targets {
+    pub pids: Option<Vec<u64>>,
+    pub process_name: Option<String>,
+    pub cgroup: Option<String>,
+    pub container: Option<String>,
+    pub command: Option<Vec<String>>,
+}
+
+impl Targets {
+    fn count(&self) -> usize {
+        [
+            self.command.is_some(),
+            self.pids.is_some(),
+            self.process_name.is_some(),
+            self.cgroup.is_some(),
+            self.container.is_some(),
+        ]
+        .iter()
+        .filter(|&&target| target)
+        .count()
+    }
+}
+
+/// Subsystems whose metrics are collected for each target.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Subsystems {
+    pub sched: bool,
+    pub futex: bool,
+    pub ipc: bool,
+    pub iowait: bool,
+}
+
+impl Default for Subsystems {
+    fn default() -> Self {
+        Self {
+            sched: true,
+            futex: true,
+            ipc: true,
+            iowait: true,
+        }
+    }
+}
+
+impl Subsystems {
+    /// Enables only the subsystems listed in `names`, as passed to
+    /// `--collectors`.
+    fn from_names<'a, I: Iterator<Item = &'a str>>(names: I) -> Self {
+        let mut subsystems = Self {
+            sched: false,
+            futex: false,
+            ipc: false,
+            iowait: false,
+        };
+        for name in names {
+            match name {
+                "sched" => subsystems.sched = true,
+                "futex" => subsystems.futex = true,
+                "ipc" => subsystems.ipc = true,
+                "iowait" => subsystems.iowait = true,
+                _ => unreachable!("Validated by the argument parser"),
+            }
+        }
+        subsystems
+    }
+
+    fn any(&self) -> bool {
+        self.sched || self.futex || self.ipc || self.iowait
+    }
+}
+
+/// Kernel threads traced alongside the target, matched by their command name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KernelThreads {
+    pub patterns: Vec<String>,
+}
+
+impl Default for KernelThreads {
+    fn default() -> Self {
+        Self {
+            patterns: vec!["jbd2".to_string()],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bpf {
+    /// Directory containing the clone, futex_wait, io_wait and ipc scripts.
+    /// The scripts bundled into the binary are used if unset.
+    pub script_directory: Option<String>,
+    /// Directory containing user-supplied `<plugin>.bt` scripts, each with a
+    /// `<plugin>.toml` descriptor of the map it prints.
+    pub plugin_directory: Option<String>,
+    /// bpftrace binary the programs are launched with, found in the `PATH` if
+    /// unset.
+    pub bpftrace: Option<String>,
+}
+
+impl Bpf {
+    pub fn bpftrace(&self) -> &str {
+        self.bpftrace.as_deref().unwrap_or("bpftrace")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Buffers {
+    /// Size in bytes of the pipe between a bpftrace program and the collector.
+    pub futex_pipe_size: u32,
+    pub ipc_pipe_size: u32,
+    pub iowait_pipe_size: u32,
+    pub plugin_pipe_size: u32,
+    /// Number of futex data files kept open per thread.
+    pub futex_open_files: usize,
+    /// Seconds an unused data file is kept open.
+    pub ipc_open_files_expiry_s: u64,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            futex_pipe_size: 1 << 20,
+            ipc_pipe_size: 1 << 21,
+            iowait_pipe_size: 1 << 20,
+            plugin_pipe_size: 1 << 20,
+            futex_open_files: 4,
+            ipc_open_files_expiry_s: 120,
+        }
+    }
+}
+
+impl Buffers {
+    pub fn futex_open_files(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.futex_open_files).expect("Validated on configuration")
+    }
+
+    pub fn ipc_open_files_expiry(&self) -> Duration {
+        Duration::from_secs(self.ipc_open_files_expiry_s)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    /// Root directory where session directories are created.
+    pub data_directory: Option<String>,
+    pub format: Option<Format>,
+    /// Compression of the CSV files of a partition once it is closed.
+    pub compression: Option<Compression>,
+    /// Records the output of the bpftrace programs in the `raw` directory of
+    /// the session, to be replayed.
+    pub record_raw: bool,
+}
+
+/// Layout of the data files of a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A CSV file per series and minute, or day for the scheduler statistics.
+    #[default]
+    Csv,
+    /// A Parquet file per subsystem and minute, the series being columns.
+    Parquet,
+    /// A SQLite database with a table per subsystem, and tables of the
+    /// threads, processes and resources the samples refer to.
+    Sqlite,
+}
+
+/// HTTP endpoint serving the latest samples as OpenMetrics, while the session
+/// is recorded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Exporter {
+    /// Address to serve `/metrics` on, e.g. `127.0.0.1:9464`. The exporter
+    /// is disabled if unset.
+    pub listen: Option<String>,
+    /// Number of series kept per subsystem, beyond which the samples of new
+    /// series are dropped.
+    pub max_series: usize,
+    /// Seconds after which a series without new samples is no longer served.
+    pub series_expiry_s: u64,
+}
+
+impl Default for Exporter {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            max_series: 1000,
+            series_expiry_s: 300,
+        }
+    }
+}
+
+impl Exporter {
+    pub fn series_expiry(&self) -> Duration {
+        Duration::from_secs(self.series_expiry_s)
+    }
+}
+
+/// Bounds of the disk space used by the data files of a session.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Size in MiB of the session directory beyond which `on_limit` applies.
+    /// Unbounded if unset.
+    pub max_size_mb: Option<u64>,
+    /// Seconds after its last sample a partition is dropped. Partitions are
+    /// kept for the whole session if unset.
+    pub max_age_s: Option<u64>,
+    pub on_limit: OnLimit,
+}
+
+impl Retention {
+    pub fn max_size_bytes(&self) -> Option<u64> {
+        self.max_size_mb.map(|size| size << 20)
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age_s.map(Duration::from_secs)
+    }
+}
+
+/// What the collector does once the session directory reached its maximum
+/// size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnLimit {
+    /// Ends the session.
+    #[default]
+    Stop,
+    /// Drops the partitions with the oldest samples.
+    DropOldest,
+    /// Disables the ipc, futex, plugin and iowait subsystems in turn,
+    /// dropping their partitions, and keeps recording the scheduler
+    /// statistics.
+    Degrade,
+}
+
+/// Keeps the latest samples in memory rather than writing them, until a dump
+/// is triggered.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlightRecorder {
+    /// Seconds of samples kept in memory. The samples are written as they
+    /// are collected if unset.
+    pub window_s: Option<u64>,
+    /// Number of samples kept in memory, beyond which the oldest are dropped.
+    pub max_samples: usize,
+}
+
+impl Default for FlightRecorder {
+    fn default() -> Self {
+        Self {
+            window_s: None,
+            max_samples: 1_000_000,
+        }
+    }
+}
+
+impl FlightRecorder {
+    pub fn window(&self) -> Option<Duration> {
+        self.window_s.map(Duration::from_secs)
+    }
+}
+
+/// Records the futex, ipc, iowait and plugin subsystems only while the
+/// response times of the application breach an SLO, and the scheduler
+/// statistics otherwise.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Slo {
+    /// File of `end_ts,duration_ms` rows appended by the application, e.g.
+    /// `application-metrics/response_time.csv`, or `-` for stdin. Every
+    /// subsystem is recorded for the whole session if unset.
+    pub source: Option<String>,
+    /// Percentile of the response times compared to the threshold.
+    pub percentile: f64,
+    /// Response time in milliseconds the percentile must stay under.
+    pub threshold_ms: f64,
+    /// Seconds of responses, by their end time, the percentile is computed
+    /// over.
+    pub rolling_window_s: u64,
+    /// Seconds every subsystem is recorded for after the last breach.
+    pub escalation_s: u64,
+}
+
+impl Default for Slo {
+    fn default() -> Self {
+        Self {
+            source: None,
+            percentile: 99.0,
+            threshold_ms: 100.0,
+            rolling_window_s: 10,
+            escalation_s: 60,
+        }
+    }
+}
+
+impl Slo {
+    pub fn escalation(&self) -> Duration {
+        Duration::from_secs(self.escalation_s)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl ConfigFile {
+    pub fn read(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content =
+            fs::read_to_string(path).map_err(|e| eyre!("Could not read config {}. {}", path, e))?;
+        toml::from_str(&content).map_err(|e| eyre!("Invalid config {}. {}", path, e).into())
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.subsystems.any() {
+            return Err(eyre!("At least one collector must be enabled").into());
+        }
+        let buffers = &self.buffers;
+        if buffers.futex_pipe_size == 0
+            || buffers.ipc_pipe_size == 0
+            || buffers.iowait_pipe_size == 0
+            || buffers.plugin_pipe_size == 0
+        {
+            return Err(eyre!("Pipe sizes must be greater than zero").into());
+        }
+        // Pipe sizes are passed to fcntl as an int
+        let max_pipe_size = i32::MAX as u32;
+        if buffers.futex_pipe_size > max_pipe_size
+            || buffers.ipc_pipe_size > max_pipe_size
+            || buffers.iowait_pipe_size > max_pipe_size
+            || buffers.plugin_pipe_size > max_pipe_size
+        {
+            return Err(eyre!("Pipe sizes must be at most {}", max_pipe_size).into());
+        }
+        if buffers.futex_open_files == 0 {
+            return Err(eyre!("futex_open_files must be greater than zero").into());
+        }
+        if self.exporter.max_series == 0 {
+            return Err(eyre!("max_series must be greater than zero").into());
+        }
+        if self.flight_recorder.max_samples == 0 {
+            return Err(eyre!("max_samples must be greater than zero").into());
+        }
+        if !(self.slo.percentile > 0.0 && self.slo.percentile <= 100.0) {
+            return Err(eyre!("percentile must be within (0, 100]").into());
+        }
+        if self.retention.max_size_mb == Some(0) {
+            return Err(eyre!("max_size_mb must be greater than zero").into());
+        }
+        Ok(())
+    }
+}
+
+/// Directory of a session started at `start`, within the root directory of
+/// the sessions.
+pub fn session_directory(root_directory: &str, start: DateTime<Utc>) -> String {
+    format!("{}/{}/system-metrics", root_directory, start.to_rfc3339())
+}
+
+/// Picks the command line value of an argument if it was passed explicitly,
+/// otherwise the value of the configuration file, and the argument's default
+/// as a fallback.
+fn merge_arg<T: Clone + Send + Sync + 'static>(
+    matches: &mut ArgMatches,
+    id: &str,
+    file_value: Option<T>,
+) -> Option<T> {
+    let from_cli = matches.value_source(id) == Some(ValueSource::CommandLine);
+    let cli_value = matches.remove_one::<T>(id);
+    match file_value {
+        Some(value) if !from_cli => Some(value),
+        _ => cli_value,
+    }
 }
 
 impl TryFrom<ArgMatches> for Config {
     type Error = Box<dyn std::error::Error>;
     fn try_from(mut matches: ArgMatches) -> Result<Self, Self::Error> {
-        let pids: Option<Vec<u64>> = matches
-            .remove_many::<u64>("pids")
-            .map(|pids| pids.collect());
-
-        let process_name = matches.remove_one::<String>("process-name");
-        match (&pids, &process_name) {
-            (None, None) => return Err(eyre!("Pass --process-name or --pid arg required").into()),
-            (Some(_), Some(_)) => {
-                return Err(
-                    eyre!("Arguments --process-name and --pid are mutually exclusive").into(),
+        let mut file = match matches.remove_one::<String>("config") {
+            Some(path) => ConfigFile::read(&path)?,
+            None => ConfigFile::default(),
+        };
+
+        let mut doctor = false;
+        let mut dump = None;
+        let mut replay = None;
+        let command: Option<Vec<String>> = match matches.remove_subcommand() {
+            Some((name, mut submatches)) if name == "run" => submatches
+                .remove_many::<String>("command")
+                .map(|command| command.collect()),
+            Some((name, _)) if name == "doctor" => {
+                doctor = true;
+                None
+            }
+            Some((name, mut submatches)) if name == "dump" => {
+                dump = submatches.remove_one::<String>("session");
+                None
+            }
+            Some((name, mut submatches)) if name == "replay" => {
+                replay = submatches.remove_one::<String>("session");
+                None
+            }
+            _ => None,
+        };
+        let cli_targets = Targets {
+            pids: matches
+                .remove_many::<u64>("pids")
+                .map(|pids| pids.collect()),
+            process_name: matches.remove_one::<String>("process-name"),
+            cgroup: matches.remove_one::<String>("cgroup"),
+            container: matches.remove_one::<String>("container"),
+            command,
+        };
+        // Targets passed on the command line replace those of the file
+        // rather than being merged with them.
+        if cli_targets.count() > 0 {
+            file.targets = cli_targets;
+        }
+        match file.targets.count() {
+            // The preflight checks, dumps and replays do not depend on the
+            // target
+            0 if doctor || dump.is_some() || replay.is_some() => {}
+            0 => {
+                return Err(eyre!(
+                    "Pass --process-name, --pids, --cgroup or --container arg, or the run subcommand"
                 )
+                .into())
             }
-            _ => {}
+            1 => {}
+            _ => return Err(eyre!(
+                "Arguments --process-name, --pids, --cgroup, --container and the run subcommand are mutually exclusive"
+            )
+            .into()),
+        }
+        if let Some(collectors) = matches.remove_many::<String>("collectors") {
+            let collectors: Vec<String> = collectors.collect();
+            file.subsystems = Subsystems::from_names(collectors.iter().map(|name| name.as_str()));
+        }
+        if let Some(on_limit) = matches.remove_one::<OnLimit>("on-limit") {
+            file.retention.on_limit = on_limit;
+        }
+        file.retention.max_size_mb =
+            merge_arg(&mut matches, "max-size-mb", file.retention.max_size_mb);
+        file.retention.max_age_s = merge_arg(&mut matches, "max-age-s", file.retention.max_age_s);
+        file.slo.source = merge_arg(&mut matches, "slo-source", file.slo.source.take());
+        if let Some(threshold_ms) = matches.remove_one::<f64>("slo-threshold-ms") {
+            file.slo.threshold_ms = threshold_ms;
+        }
+        if let Some(percentile) = matches.remove_one::<f64>("slo-percentile") {
+            file.slo.percentile = percentile;
         }
+        file.validate()?;
 
-        let period: u64 = matches
-            .remove_one::<u64>("period")
-            .expect("Missing period")
-            .try_into()
-            .expect("Convert usize to u64");
+        let period: u64 = merge_arg(&mut matches, "period", file.period).expect("Missing period");
+        file.period = Some(period);
+        let procfs: String =
+            merge_arg(&mut matches, "procfs", file.procfs.take()).expect("Missing procfs");
+        file.procfs = Some(procfs.clone());
+        let cgroupfs: String =
+            merge_arg(&mut matches, "cgroupfs", file.cgroupfs.take()).expect("Missing cgroupfs");
+        file.cgroupfs = Some(cgroupfs.clone());
 
-        let utc: DateTime<Utc> = Utc::now();
-        let mut data_directory = matches
-            .remove_one::<String>("data-directory")
-            .expect("Required field");
-        data_directory += &format!("/{}/system-metrics", utc.to_rfc3339());
+        let root_directory: String = merge_arg(
+            &mut matches,
+            "data-directory",
+            file.output.data_directory.take(),
+        )
+        .expect("Required field");
+        file.output.data_directory = Some(root_directory.clone());
+        let format: Format =
+            merge_arg(&mut matches, "format", file.output.format).expect("Missing format");
+        file.output.format = Some(format);
+        file.output.compression = merge_arg(&mut matches, "compression", file.output.compression);
+        file.output.record_raw |= matches.get_flag("record-raw");
 
+        file.bpf.script_directory =
+            merge_arg(&mut matches, "bpf-dir", file.bpf.script_directory.take());
+        file.bpf.plugin_directory =
+            merge_arg(&mut matches, "plugin-dir", file.bpf.plugin_directory.take());
+        file.bpf.bpftrace = merge_arg(&mut matches, "bpftrace", file.bpf.bpftrace.take());
+        file.exporter.listen =
+            merge_arg(&mut matches, "metrics-listen", file.exporter.listen.take());
+        file.flight_recorder.window_s = merge_arg(
+            &mut matches,
+            "flight-recorder",
+            file.flight_recorder.window_s,
+        );
+
+        let data_directory = session_directory(&root_directory, Utc::now());
+
+        let targets = file.targets.clone();
         Ok(Self {
-            pids,
+            pids: targets.pids,
             period,
             data_directory: Rc::from(data_directory),
-            process_name,
+            procfs,
+            cgroupfs,
+            process_name: targets.process_name,
+            cgroup: targets.cgroup,
+            container: targets.container,
+            command: targets.command,
+            doctor,
+            dump,
+            replay,
+            skip_doctor: matches.get_flag("skip-doctor"),
+            subsystems: file.subsystems.clone(),
+            kernel_threads: file.kernel_threads.patterns.clone(),
+            bpf: file.bpf.clone(),
+            buffers: file.buffers,
+            format,
+            compression: file.output.compression,
+            record_raw: file.output.record_raw,
+            exporter: file.exporter.clone(),
+            retention: file.retention.clone(),
+            flight_recorder: file.flight_recorder.clone(),
+            slo: file.slo.clone(),
+            resolved: file,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, Config, Format, OnLimit};
+    use crate::cmdline;
+    use eyre::Result;
+    use indoc::indoc;
+    use std::fs;
+    use tempdir::TempDir;
+
+    const CONFIG: &str = indoc! {r#"
+        period = 500
+
+        [targets]
+        process_name = "redis-server"
+
+        [subsystems]
+        ipc = false
+
+        [kernel_threads]
+        patterns = ["jbd2", "kworker"]
+
+        [buffers]
+        futex_open_files = 8
+
+        [output]
+        data_directory = "/tmp/prism"
+
+        [retention]
+        max_size_mb = 2048
+        on_limit = "drop-oldest"
+    "#};
+
+    fn config_from(args: &[&str]) -> Config {
+        let matches = cmdline::register_args().get_matches_from(args);
+        Config::try_from(matches).unwrap()
+    }
+
+    #[test]
+    fn file_values() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("prism.toml");
+        fs::write(&path, CONFIG)?;
+
+        let config = config_from(&["metric-collector", "--config", path.to_str().unwrap()]);
+        assert_eq!(config.period, 500);
+        assert_eq!(config.process_name.as_deref(), Some("redis-server"));
+        assert!(config.data_directory.starts_with("/tmp/prism/"));
+        assert!(!config.subsystems.ipc);
+        assert!(config.subsystems.futex);
+        assert_eq!(config.kernel_threads, vec!["jbd2", "kworker"]);
+        assert_eq!(config.buffers.futex_open_files, 8);
+        assert_eq!(config.buffers.ipc_pipe_size, 1 << 21);
+        assert_eq!(config.format, Format::Csv);
+        assert_eq!(config.compression, None);
+        assert!(!config.record_raw);
+        assert_eq!(config.retention.max_size_bytes(), Some(2048 << 20));
+        assert_eq!(config.retention.max_age_s, None);
+        assert_eq!(config.retention.on_limit, OnLimit::DropOldest);
+        Ok(())
+    }
+
+    #[test]
+    fn cli_overrides_file() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("prism.toml");
+        fs::write(&path, CONFIG)?;
+
+        let config = config_from(&[
+            "metric-collector",
+            "--config",
+            path.to_str().unwrap(),
+            "--period",
+            "2000",
+            "--pids",
+            "10,11",
+            "--plugin-dir",
+            "./plugins",
+            "--format",
+            "parquet",
+            "--metrics-listen",
+            "127.0.0.1:9464",
+            "--compression",
+            "zstd",
+            "--record-raw",
+            "--max-age-s",
+            "3600",
+            "--on-limit",
+            "degrade",
+            "--flight-recorder",
+            "600",
+            "--slo-source",
+            "-",
+            "--slo-threshold-ms",
+            "250",
+        ]);
+        assert_eq!(config.period, 2000);
+        assert_eq!(config.bpf.plugin_directory.as_deref(), Some("./plugins"));
+        assert_eq!(config.format, Format::Parquet);
+        assert_eq!(config.exporter.listen.as_deref(), Some("127.0.0.1:9464"));
+        assert_eq!(config.compression, Some(Compression::Zstd));
+        assert!(config.record_raw);
+        assert_eq!(config.retention.max_size_mb, Some(2048));
+        assert_eq!(config.retention.max_age_s, Some(3600));
+        assert_eq!(config.retention.on_limit, OnLimit::Degrade);
+        assert_eq!(config.flight_recorder.window_s, Some(600));
+        assert_eq!(config.slo.source.as_deref(), Some("-"));
+        assert_eq!(config.slo.threshold_ms, 250.0);
+        assert_eq!(config.slo.percentile, 99.0);
+        assert_eq!(config.pids, Some(vec![10, 11]));
+        assert_eq!(config.process_name, None);
+        assert!(config.data_directory.starts_with("/tmp/prism/"));
+
+        let resolved = toml::to_string(&config.resolved)?;
+        assert!(resolved.contains("period = 2000"));
+        assert!(resolved.contains("pids = [10, 11]"));
+        assert!(resolved.contains("format = \"parquet\""));
+        assert!(!resolved.contains("redis-server"));
+        Ok(())
+    }
+
+    #[test]
+    fn collectors() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("prism.toml");
+        fs::write(&path, CONFIG)?;
+
+        let config = config_from(&[
+            "metric-collector",
+            "--config",
+            path.to_str().unwrap(),
+            "--collectors",
+            "sched,ipc",
+        ]);
+        assert!(config.subsystems.sched);
+        assert!(!config.subsystems.futex);
+        assert!(config.subsystems.ipc);
+        assert!(!config.subsystems.iowait);
+
+        let matches = cmdline::register_args().try_get_matches_from([
+            "metric-collector",
+            "--pids",
+            "1",
+            "--collectors",
+            "sched,disk",
+        ]);
+        assert!(matches.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pipe_size_bounds() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("prism.toml");
+        for (pipe_size, valid) in [(0, false), (2147483647, true), (2147483648u32, false)] {
+            fs::write(
+                &path,
+                format!("[buffers]\nplugin_pipe_size = {}\n", pipe_size),
+            )?;
+            let matches = cmdline::register_args().get_matches_from([
+                "metric-collector",
+                "--config",
+                path.to_str().unwrap(),
+                "--pids",
+                "1",
+            ]);
+            assert_eq!(Config::try_from(matches).is_ok(), valid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_field() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("prism.toml");
+        fs::write(&path, "[buffers]\nfutex_pipe = 1\n")?;
+
+        let matches = cmdline::register_args().get_matches_from([
+            "metric-collector",
+            "--config",
+            path.to_str().unwrap(),
+            "--pids",
+            "1",
+        ]);
+        assert!(Config::try_from(matches).is_err());
+        Ok(())
+    }
+}
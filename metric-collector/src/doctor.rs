@@ -0,0 +1,544 @@
+use eyre::{eyre, Result};
+use regex::Regex;
+use std::{
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    configure::{Bpf, Config},
+    execute::{
+        plugins::{self, PluginSchema},
+        scripts,
+    },
+    manifest::{bpftrace_version, read_proc_value},
+};
+
+const TRACING_ROOTS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+const BTF_VMLINUX: &str = "/sys/kernel/btf/vmlinux";
+const MIN_OPEN_FILES: u64 = 32768;
+const MIN_BPFTRACE_VERSION: (u32, u32) = (0, 18);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "PASS"),
+            Self::Warn => write!(f, "WARN"),
+            Self::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, detail: String, hint: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            detail,
+            hint: match status {
+                Status::Pass => None,
+                _ => Some(hint.to_string()),
+            },
+        }
+    }
+}
+
+/// Preflight checks of the host's prerequisites for recording. Failed checks
+/// prevent the bpftrace programs from starting, whereas warnings degrade the
+/// collected data.
+pub struct Doctor {
+    bpf: Bpf,
+    scripts: Vec<&'static str>,
+    /// Plugins of the plugin directory, or why they could not be loaded.
+    plugins: Result<Vec<PluginSchema>, String>,
+    max_pipe_size: u32,
+    /// Mount point of procfs, which the kernel settings are read from.
+    procfs: String,
+    tracing_root: Option<PathBuf>,
+}
+
+impl Doctor {
+    pub fn new(config: &Config) -> Self {
+        let subsystems = &config.subsystems;
+        let mut scripts = vec!["clone.bt"];
+        if subsystems.futex {
+            scripts.push("futex_wait.bt");
+        }
+        if subsystems.iowait {
+            scripts.push("io_wait.bt");
+        }
+        if subsystems.ipc {
+            scripts.push("ipc.bt");
+        }
+
+        let plugins = match &config.bpf.plugin_directory {
+            Some(directory) => plugins::load(directory).map_err(|e| e.to_string()),
+            None => Ok(Vec::new()),
+        };
+
+        let buffers = &config.buffers;
+        let mut max_pipe_size = 0;
+        for (enabled, pipe_size) in [
+            (subsystems.futex, buffers.futex_pipe_size),
+            (subsystems.iowait, buffers.iowait_pipe_size),
+            (subsystems.ipc, buffers.ipc_pipe_size),
+            (
+                config.bpf.plugin_directory.is_some(),
+                buffers.plugin_pipe_size,
+            ),
+        ] {
+            if enabled {
+                max_pipe_size = max_pipe_size.max(pipe_size);
+            }
+        }
+
+        Self {
+            bpf: config.bpf.clone(),
+            scripts,
+            plugins,
+            max_pipe_size,
+            procfs: config.procfs.clone(),
+            tracing_root: TRACING_ROOTS
+                .iter()
+                .map(PathBuf::from)
+                .find(|root| root.join("events").exists()),
+        }
+    }
+
+    pub fn run(&self) -> Vec<Check> {
+        let mut checks = vec![
+            self.check_root(),
+            self.check_bpftrace(),
+            self.check_btf(),
+            self.check_schedstats(),
+            self.check_pipe_max_size(),
+            self.check_open_files(),
+        ];
+        for script in self.scripts.iter().copied() {
+            checks.push(self.check_probes(script));
+        }
+        match &self.plugins {
+            Ok(plugins) => {
+                for plugin in plugins {
+                    checks.push(self.check_plugin_probes(plugin));
+                }
+            }
+            Err(e) => checks.push(Check::new(
+                "plugins",
+                Status::Fail,
+                e.clone(),
+                "Fix the scripts and descriptors of --plugin-dir",
+            )),
+        }
+        checks
+    }
+
+    fn check_root(&self) -> Check {
+        let euid = unsafe { libc::geteuid() };
+        Check::new(
+            "root",
+            if euid == 0 {
+                Status::Pass
+            } else {
+                Status::Fail
+            },
+            format!("euid {}", euid),
+            "Run the collector with sudo",
+        )
+    }
+
+    fn check_bpftrace(&self) -> Check {
+        let hint = "Install bpftrace v0.18 or later, e.g. sudo apt install -y bpftrace";
+        let version = match bpftrace_version(self.bpf.bpftrace()) {
+            Some(version) => version,
+            None => return Check::new("bpftrace", Status::Fail, "bpftrace not found".into(), hint),
+        };
+
+        let status = match parse_version(&version) {
+            Some(parsed) if parsed >= MIN_BPFTRACE_VERSION => Status::Pass,
+            _ => Status::Warn,
+        };
+        Check::new("bpftrace", status, version, hint)
+    }
+
+    fn check_btf(&self) -> Check {
+        let exists = Path::new(BTF_VMLINUX).exists();
+        Check::new(
+            "btf",
+            if exists { Status::Pass } else { Status::Fail },
+            format!(
+                "{} {}",
+                BTF_VMLINUX,
+                if exists { "found" } else { "missing" }
+            ),
+            "kfunc probes require a kernel built with CONFIG_DEBUG_INFO_BTF=y",
+        )
+    }
+
+    fn check_schedstats(&self) -> Check {
+        let value = read_proc_value(&format!("{}/sys/kernel/sched_schedstats", self.procfs));
+        Check::new(
+            "kernel.sched_schedstats",
+            if value.as_deref() == Some("1") {
+                Status::Pass
+            } else {
+                Status::Warn
+            },
+            value.unwrap_or("unknown".into()),
+            "sudo sysctl \"kernel.sched_schedstats=1\"",
+        )
+    }
+
+    fn check_pipe_max_size(&self) -> Check {
+        let value = read_proc_value(&format!("{}/sys/fs/pipe-max-size", self.procfs));
+        let size = value.as_ref().and_then(|value| value.parse::<u32>().ok());
+        Check::new(
+            "fs.pipe-max-size",
+            match size {
+                Some(size) if size >= self.max_pipe_size => Status::Pass,
+                _ => Status::Warn,
+            },
+            format!(
+                "{}, {} required",
+                value.unwrap_or("unknown".into()),
+                self.max_pipe_size
+            ),
+            &format!("sudo sysctl \"fs.pipe-max-size={}\"", self.max_pipe_size),
+        )
+    }
+
+    fn check_open_files(&self) -> Check {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let res = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+        Check::new(
+            "open files",
+            if res == 0 && limit.rlim_cur >= MIN_OPEN_FILES {
+                Status::Pass
+            } else {
+                Status::Warn
+            },
+            format!("{}, {} required", limit.rlim_cur, MIN_OPEN_FILES),
+            &format!("ulimit -n {}", MIN_OPEN_FILES),
+        )
+    }
+
+    fn check_probes(&self, script: &str) -> Check {
+        let name = format!("probes {}", script);
+        let content =
+            match scripts::read(&self.bpf, script) {
+                Ok(content) => content,
+                Err(e) => return Check::new(
+                    &name,
+                    Status::Fail,
+                    e.to_string(),
+                    "Pass a --bpf-dir containing the script, or omit it to use the bundled scripts",
+                ),
+            };
+        self.check_script_probes(&name, &content)
+    }
+
+    fn check_plugin_probes(&self, plugin: &PluginSchema) -> Check {
+        let name = format!("probes plugin {}", plugin.name);
+        match fs::read_to_string(&plugin.script) {
+            Ok(content) => self.check_script_probes(&name, &content),
+            Err(e) => Check::new(
+                &name,
+                Status::Fail,
+                format!("Could not read {}. {}", plugin.script, e),
+                "Fix the scripts and descriptors of --plugin-dir",
+            ),
+        }
+    }
+
+    /// Checks that the kernel provides the probes of a script.
+    fn check_script_probes(&self, name: &str, content: &str) -> Check {
+        let tracing_root = match &self.tracing_root {
+            Some(tracing_root) => tracing_root,
+            None => {
+                return Check::new(
+                    name,
+                    Status::Fail,
+                    "tracefs not mounted".into(),
+                    "sudo mount -t tracefs nodev /sys/kernel/tracing",
+                )
+            }
+        };
+
+        let probes = script_probes(content);
+        let missing = match missing_probes(tracing_root, &probes) {
+            Ok(missing) => missing,
+            Err(e) => {
+                return Check::new(
+                    name,
+                    Status::Fail,
+                    e.to_string(),
+                    "Run the collector with sudo",
+                )
+            }
+        };
+        if missing.is_empty() {
+            Check::new(
+                name,
+                Status::Pass,
+                format!("{} probes found", probes.len()),
+                "",
+            )
+        } else {
+            Check::new(
+                name,
+                Status::Fail,
+                format!("missing {}", missing.join(" ")),
+                "The running kernel does not provide these functions or tracepoints",
+            )
+        }
+    }
+}
+
+pub fn print_table(checks: &[Check]) {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    for check in checks {
+        println!(
+            "{:<width$}  {}  {}",
+            check.name,
+            check.status,
+            check.detail,
+            width = width
+        );
+        if let Some(hint) = &check.hint {
+            println!("{:<width$}        -> {}", "", hint, width = width);
+        }
+    }
+}
+
+/// Runs the checks before recording. The table is only printed if a check did
+/// not pass, and recording is aborted if a check failed.
+pub fn preflight(config: &Config) -> Result<()> {
+    let checks = Doctor::new(config).run();
+    if checks.iter().all(|check| check.status == Status::Pass) {
+        return Ok(());
+    }
+
+    print_table(&checks);
+    if checks.iter().any(|check| check.status == Status::Fail) {
+        return Err(eyre!(
+            "Preflight checks failed. Fix the failures above or pass --skip-doctor"
+        ));
+    }
+    Ok(())
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let re = Regex::new(r"v(\d+)\.(\d+)").unwrap();
+    let captures = re.captures(version)?;
+    Some((captures[1].parse().ok()?, captures[2].parse().ok()?))
+}
+
+/// Returns the kernel probes attached to by a bpftrace script, e.g.
+/// `kfunc:vmlinux:vfs_read` or `tracepoint:syscalls:sys_enter_clone*`.
+fn script_probes(content: &str) -> Vec<String> {
+    let re = Regex::new(r"^(kfunc|kretfunc|fentry|fexit|kprobe|kretprobe|tracepoint):").unwrap();
+    let mut probes: Vec<String> = content
+        .lines()
+        .filter(|line| re.is_match(line))
+        .flat_map(|line| line.split(','))
+        .map(|probe| probe.trim().to_string())
+        .filter(|probe| !probe.is_empty())
+        .collect();
+    probes.sort();
+    probes.dedup();
+    probes
+}
+
+/// Checks the probes against the tracepoints and the traceable kernel
+/// functions listed by tracefs.
+fn missing_probes(tracing_root: &Path, probes: &[String]) -> Result<Vec<String>> {
+    let functions = fs::read_to_string(tracing_root.join("available_filter_functions"))
+        .map_err(|e| eyre!("Could not read available_filter_functions. {}", e))?;
+    let functions: Vec<&str> = functions
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+
+    let mut missing = Vec::new();
+    for probe in probes {
+        let elements: Vec<&str> = probe.split(':').collect();
+        let found = match elements[..] {
+            ["tracepoint", category, event] => {
+                let category_pattern = glob_regex(category);
+                let event_pattern = glob_regex(event);
+                fs::read_dir(tracing_root.join("events"))?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| category_pattern.is_match(&entry.file_name().to_string_lossy()))
+                    .any(|entry| {
+                        fs::read_dir(entry.path())
+                            .map(|events| {
+                                events.filter_map(|event| event.ok()).any(|event| {
+                                    event_pattern.is_match(&event.file_name().to_string_lossy())
+                                })
+                            })
+                            .unwrap_or(false)
+                    })
+            }
+            [_, function] | [_, _, function] => {
+                let pattern = glob_regex(function);
+                functions.iter().any(|name| pattern.is_match(name))
+            }
+            _ => false,
+        };
+
+        if !found {
+            missing.push(probe.clone());
+        }
+    }
+
+    Ok(missing)
+}
+
+fn glob_regex(glob: &str) -> Regex {
+    let pattern = regex::escape(glob).replace(r"\*", ".*");
+    Regex::new(&format!("^{}$", pattern)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{missing_probes, parse_version, script_probes, Doctor, Status};
+    use crate::{cmdline, configure::Config};
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{fs, path::Path};
+    use tempdir::TempDir;
+
+    #[test]
+    fn probes_of_script() {
+        let script = indoc! {"
+            #!/usr/bin/bpftrace
+
+            tracepoint:syscalls:sys_enter_clone*
+            / @pids[pid] /
+            {
+                @enter[tid] = 1;
+            }
+
+            kfunc:vmlinux:vfs_read
+            {
+            }
+
+            kprobe:ep_item_poll*, kretprobe:ep_send_events
+            {
+            }
+
+            interval:s:1
+            {
+            }
+        "};
+        assert_eq!(
+            script_probes(script),
+            vec![
+                "kfunc:vmlinux:vfs_read",
+                "kprobe:ep_item_poll*",
+                "kretprobe:ep_send_events",
+                "tracepoint:syscalls:sys_enter_clone*",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_kernel_probes() -> Result<()> {
+        let root = TempDir::new("")?;
+        fs::write(
+            root.path().join("available_filter_functions"),
+            "vfs_read\nep_item_poll.isra.0\n__ep_remove [vmlinux]\n",
+        )?;
+        fs::create_dir_all(root.path().join("events/syscalls/sys_enter_clone3"))?;
+
+        let probes: Vec<String> = [
+            "kfunc:vmlinux:vfs_read",
+            "kfunc:vmlinux:__ep_remove",
+            "kprobe:ep_item_poll*",
+            "kretfunc:vmlinux:vfs_write",
+            "tracepoint:syscalls:sys_enter_clone*",
+            "tracepoint:sched:sched_process_exec",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        assert_eq!(
+            missing_probes(root.path(), &probes)?,
+            vec![
+                "kretfunc:vmlinux:vfs_write",
+                "tracepoint:sched:sched_process_exec",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bpftrace_version() {
+        assert_eq!(parse_version("bpftrace v0.18.0-42-g6404"), Some((0, 18)));
+        assert_eq!(parse_version("bpftrace v0.9.4"), Some((0, 9)));
+        assert_eq!(parse_version("bpftrace"), None);
+    }
+
+    #[test]
+    fn plugins_and_procfs() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let procfs = dir.path().join("proc");
+        fs::create_dir_all(procfs.join("sys/fs"))?;
+        fs::write(procfs.join("sys/fs/pipe-max-size"), "4194304\n")?;
+        let plugin_directory = dir.path().join("plugins");
+        fs::create_dir_all(&plugin_directory)?;
+        for file in ["mutex_wait.bt", "mutex_wait.toml"] {
+            fs::copy(Path::new("plugins").join(file), plugin_directory.join(file))?;
+        }
+        let matches = cmdline::register_args().get_matches_from([
+            "metric-collector",
+            "--pids",
+            "1",
+            "--procfs",
+            procfs.to_str().unwrap(),
+            "--plugin-dir",
+            plugin_directory.to_str().unwrap(),
+        ]);
+        let mut config = Config::try_from(matches).unwrap();
+
+        config.buffers.plugin_pipe_size = 1 << 23;
+        let check = Doctor::new(&config).check_pipe_max_size();
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(check.detail, "4194304, 8388608 required");
+        config.buffers.plugin_pipe_size = 1 << 22;
+        let doctor = Doctor::new(&config);
+        assert_eq!(doctor.check_pipe_max_size().status, Status::Pass);
+        assert!(doctor
+            .run()
+            .iter()
+            .any(|check| check.name == "probes plugin mutex_wait"));
+
+        fs::write(plugin_directory.join("mutex_wait.toml"), "map = 1\n")?;
+        let checks = Doctor::new(&config).run();
+        let check = checks.iter().find(|check| check.name == "plugins").unwrap();
+        assert_eq!(check.status, Status::Fail);
+        Ok(())
+    }
+}
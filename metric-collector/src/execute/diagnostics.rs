@@ -0,0 +1,130 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    io::{BufRead, BufReader, Read},
+    mem,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+lazy_static! {
+    static ref LOST_EVENTS: Regex = Regex::new(r"^Lost (\d+) events$").unwrap();
+    static ref MAP_FULL: Regex =
+        Regex::new(r"Map full|map_update_elem: Argument list too long").unwrap();
+}
+
+/// Output of a bpftrace program that was lost or could not be trusted during
+/// a sampling interval.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// Events dropped by bpftrace as its perf buffer was full.
+    pub lost_events: u64,
+    /// Failed map updates, as the map reached its maximum number of keys.
+    pub map_full: u64,
+    /// Map statistics blocks that were not closed, or closed without being
+    /// opened, whose partial content was dropped.
+    pub truncated_closures: u64,
+    /// Map statistics blocks whose sample instant was not after the previous
+    /// one, which were dropped.
+    pub out_of_order_instants: u64,
+    /// Map entries of a block that could not be converted into an event,
+    /// which were dropped.
+    pub invalid_entries: u64,
+}
+
+/// Counters of a program, shared with the thread reading its stderr.
+#[derive(Clone, Default)]
+pub struct Diagnostics {
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Diagnostics {
+    pub fn record<F: FnOnce(&mut Counters)>(&self, update: F) {
+        update(&mut self.counters.lock().unwrap());
+    }
+
+    /// Returns the counters since the previous call, and resets them.
+    pub fn take(&self) -> Counters {
+        mem::take(&mut *self.counters.lock().unwrap())
+    }
+
+    /// Counts the lost events reported as text, which bpftrace prints on
+    /// stderr when a report is not a JSON record. Returns whether the line
+    /// was a report.
+    fn record_lost_events(&self, line: &[u8]) -> bool {
+        let line = String::from_utf8_lossy(line);
+        match LOST_EVENTS.captures(line.trim()) {
+            Some(captures) => {
+                let lost: u64 = captures[1].parse().unwrap_or(0);
+                self.record(|counters| counters.lost_events += lost);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts the lost events and map-full warnings printed on the stderr of a
+    /// program, and forwards the other lines to the collector's stderr.
+    pub fn watch_stderr<R: Read + Send + 'static>(&self, stderr: R, program: &str) {
+        let diagnostics = self.clone();
+        let program = program.to_string();
+        thread::Builder::new()
+            .name(format!("{}_stderr", program))
+            .spawn(move || {
+                for line in BufReader::new(stderr).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if diagnostics.record_lost_events(line.as_bytes()) {
+                        continue;
+                    }
+                    if MAP_FULL.is_match(&line) {
+                        diagnostics.record(|counters| counters.map_full += 1);
+                        continue;
+                    }
+                    eprintln!("bpftrace {}: {}", program, line);
+                }
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counters, Diagnostics};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn stderr_counters() {
+        let diagnostics = Diagnostics::default();
+        let stderr = "Lost 12 events\n\
+            stdin:1:1-10: WARNING: Failed to map_update_elem: Argument list too long (-7)\n\
+            WARNING: Map full; can't update element. Try increasing max_map_keys config\n\
+            Attaching 3 probes...\n\
+            Lost 3 events\n";
+        diagnostics.watch_stderr(stderr.as_bytes(), "test");
+
+        let mut counters = Counters::default();
+        for _ in 0..100 {
+            let taken = diagnostics.take();
+            counters.lost_events += taken.lost_events;
+            counters.map_full += taken.map_full;
+            if counters.lost_events == 15 && counters.map_full == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(counters.lost_events, 15);
+        assert_eq!(counters.map_full, 2);
+        assert_eq!(diagnostics.take(), Counters::default());
+    }
+
+    #[test]
+    fn lost_events_line() {
+        let diagnostics = Diagnostics::default();
+        assert!(diagnostics.record_lost_events(b"Lost 7 events"));
+        assert!(!diagnostics.record_lost_events(b"NewThread\tredis\t1\t2"));
+        assert_eq!(diagnostics.take().lost_events, 7);
+    }
+}
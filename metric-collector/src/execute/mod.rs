@@ -1,28 +1,88 @@
-use eyre::Result;
+use eyre::{eyre, Result};
 use nix::time::{self, ClockId};
+use std::collections::HashSet;
 use std::ffi::CString;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{cell::RefCell, rc::Rc};
 
+use crate::configure::{Buffers, Config};
+use crate::metrics::{Field, Kind, Row, Value};
+use crate::replay;
+
+pub mod diagnostics;
+pub mod plugins;
 pub mod programs;
+pub mod record;
+pub mod scripts;
 
+use diagnostics::Diagnostics;
 use programs::clone::CloneProgram;
 use programs::futex::FutexProgram;
 use programs::iowait::IOWaitProgram;
 use programs::ipc::IpcProgram;
-use programs::BOOT_EPOCH_NS;
+use programs::plugin::PluginProgram;
+use programs::{Launcher, BOOT_EPOCH_NS};
+use scripts::ScriptDirectory;
 
+/// Handles to the running bpftrace programs. The clone program is always
+/// started, as it is required to discover new targets, whereas the others are
+/// only started if their subsystem is enabled, or found in the plugin
+/// directory.
 pub struct Executor {
     pub clone: CloneProgram,
-    pub futex: Rc<RefCell<FutexProgram>>,
-    pub ipc: Rc<RefCell<IpcProgram>>,
-    pub io_wait: Rc<RefCell<IOWaitProgram>>,
+    pub futex: Option<Rc<RefCell<FutexProgram>>>,
+    pub ipc: Option<Rc<RefCell<IpcProgram>>>,
+    pub io_wait: Option<Rc<RefCell<IOWaitProgram>>>,
+    pub plugins: Vec<Rc<RefCell<PluginProgram>>>,
+    /// Kept for the lifetime of the programs, as the bundled scripts are
+    /// removed from disk once it is dropped.
+    pub scripts: ScriptDirectory,
+    launcher: Launcher,
+    terminate_flag: Arc<Mutex<bool>>,
+    buffers: Buffers,
+    registration: Registration,
+    /// Processes registered with the programs, which are registered again
+    /// with a restarted program.
+    monitored: HashSet<usize>,
+    last_supervised_ms: u128,
+    /// Whether the programs other than the clone program are stopped.
+    suspended: bool,
+}
+
+/// A bpftrace program which exited and was relaunched. Its subsystem was not
+/// traced between the last check that found it running and the relaunch.
+pub struct ProgramRestart {
+    pub program: &'static str,
+    pub status: String,
+    pub gap_start_ms: u128,
+    pub gap_end_ms: u128,
+}
+
+impl Row for ProgramRestart {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("program", Kind::Text),
+            Field::new("status", Kind::Text),
+            Field::new("gap_start_ms", Kind::Unsigned),
+            Field::new("gap_end_ms", Kind::Unsigned),
+        ];
+        FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Text(self.program.to_string()),
+            Value::Text(self.status.clone()),
+            Value::Unsigned(self.gap_start_ms as u64),
+            Value::Unsigned(self.gap_end_ms as u64),
+        ]
+    }
 }
 
 impl Executor {
-    pub fn new(terminate_flag: Arc<Mutex<bool>>) -> Result<Self> {
+    pub fn new(terminate_flag: Arc<Mutex<bool>>, config: &Config) -> Result<Self> {
         if *BOOT_EPOCH_NS.read().unwrap() == 0 {
             let ns_since_boot =
                 Duration::from(time::clock_gettime(ClockId::CLOCK_BOOTTIME).unwrap()).as_nanos();
@@ -35,45 +95,409 @@ impl Executor {
         }
 
         let pid = std::process::id();
-        let mut clone = CloneProgram::new(pid)?;
-        let mut futex = FutexProgram::new(pid, terminate_flag.clone())?;
-        let mut io_wait = IOWaitProgram::new(terminate_flag.clone())?;
-        let mut ipc = IpcProgram::new(terminate_flag, pid)?;
-
-        while (true, true, true, true)
-            != (
-                clone.header_read(),
-                futex.header_read(),
-                io_wait.header_read(),
-                ipc.header_read(),
-            )
-        {
-            clone.poll_events()?;
-            futex.poll_events()?;
-            io_wait.poll_events()?;
-            ipc.poll_events()?;
-            thread::sleep(std::time::Duration::from_millis(1000));
+        let mut launcher = Launcher::new(config);
+        if config.record_raw {
+            launcher.raw_directory = Some(replay::record(&config.data_directory)?);
         }
+        let (buffers, subsystems) = (&config.buffers, &config.subsystems);
+        let bpf = ScriptDirectory::new(&config.bpf)?;
+        let registration = Registration::new();
+        let token = registration.token();
+        let clone = CloneProgram::new(&launcher, pid, token, &bpf.script("clone.bt"))?;
+        let futex = match subsystems.futex {
+            true => Some(FutexProgram::new(
+                &launcher,
+                pid,
+                token,
+                terminate_flag.clone(),
+                &bpf.script("futex_wait.bt"),
+                buffers.futex_pipe_size,
+            )?),
+            false => None,
+        };
+        let io_wait = match subsystems.iowait {
+            true => Some(IOWaitProgram::new(
+                &launcher,
+                terminate_flag.clone(),
+                &bpf.script("io_wait.bt"),
+                buffers.iowait_pipe_size,
+            )?),
+            false => None,
+        };
+        let ipc = match subsystems.ipc {
+            true => Some(IpcProgram::new(
+                &launcher,
+                terminate_flag.clone(),
+                pid,
+                token,
+                &bpf.script("ipc.bt"),
+                buffers.ipc_pipe_size,
+            )?),
+            false => None,
+        };
+        let schemas = match &config.bpf.plugin_directory {
+            Some(directory) => plugins::load(directory)?,
+            None => Vec::new(),
+        };
+        let plugins = schemas
+            .into_iter()
+            .map(|schema| {
+                let plugin = PluginProgram::new(
+                    &launcher,
+                    Rc::new(schema),
+                    terminate_flag.clone(),
+                    pid,
+                    token,
+                    buffers.plugin_pipe_size,
+                )?;
+                Ok(Rc::new(RefCell::new(plugin)))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        Ok(Executor {
+        let mut executor = Executor {
             clone,
-            io_wait: Rc::new(RefCell::new(io_wait)),
-            futex: Rc::new(RefCell::new(futex)),
-            ipc: Rc::new(RefCell::new(ipc)),
-        })
+            io_wait: io_wait.map(|io_wait| Rc::new(RefCell::new(io_wait))),
+            futex: futex.map(|futex| Rc::new(RefCell::new(futex))),
+            ipc: ipc.map(|ipc| Rc::new(RefCell::new(ipc))),
+            plugins,
+            scripts: bpf,
+            launcher,
+            terminate_flag,
+            buffers: *buffers,
+            registration,
+            monitored: HashSet::new(),
+            last_supervised_ms: epoch_ms(),
+            suspended: false,
+        };
+        executor.wait_for_headers()?;
+        Ok(executor)
+    }
+
+    /// Waits until the programs printed their header, i.e. attached their
+    /// probes. Events of the clone program read meanwhile are dropped.
+    fn wait_for_headers(&mut self) -> Result<()> {
+        loop {
+            let mut ready = true;
+            if !self.clone.header_read() {
+                ready = false;
+                self.clone.poll_events()?;
+                if let Some(status) = self.clone.try_wait()? {
+                    return Err(eyre!("bpftrace clone program exited with {}", status));
+                }
+            }
+            if let Some(futex) = &self.futex {
+                let mut futex = futex.borrow_mut();
+                if !futex.header_read() {
+                    ready = false;
+                    futex.poll_events()?;
+                    if let Some(status) = futex.try_wait()? {
+                        return Err(eyre!("bpftrace futex program exited with {}", status));
+                    }
+                }
+            }
+            if let Some(io_wait) = &self.io_wait {
+                let mut io_wait = io_wait.borrow_mut();
+                if !io_wait.header_read() {
+                    ready = false;
+                    io_wait.poll_events()?;
+                    if let Some(status) = io_wait.try_wait()? {
+                        return Err(eyre!("bpftrace iowait program exited with {}", status));
+                    }
+                }
+            }
+            if let Some(ipc) = &self.ipc {
+                let mut ipc = ipc.borrow_mut();
+                if !ipc.header_read() {
+                    ready = false;
+                    ipc.poll_events()?;
+                    if let Some(status) = ipc.try_wait()? {
+                        return Err(eyre!("bpftrace ipc program exited with {}", status));
+                    }
+                }
+            }
+            for plugin in &self.plugins {
+                let mut plugin = plugin.borrow_mut();
+                if !plugin.header_read() {
+                    ready = false;
+                    plugin.poll_events()?;
+                    if let Some(status) = plugin.try_wait()? {
+                        return Err(eyre!(
+                            "bpftrace {} plugin exited with {}",
+                            plugin.schema().name,
+                            status
+                        ));
+                    }
+                }
+            }
+
+            if ready {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Returns the diagnostics counters of the running programs, by program.
+    pub fn diagnostics(&self) -> Vec<(&'static str, Diagnostics)> {
+        let mut diagnostics = vec![("clone", self.clone.diagnostics())];
+        if let Some(futex) = &self.futex {
+            diagnostics.push(("futex", futex.borrow().diagnostics()));
+        }
+        if let Some(io_wait) = &self.io_wait {
+            diagnostics.push(("iowait", io_wait.borrow().diagnostics()));
+        }
+        if let Some(ipc) = &self.ipc {
+            diagnostics.push(("ipc", ipc.borrow().diagnostics()));
+        }
+        for plugin in &self.plugins {
+            let mut plugin = plugin.borrow_mut();
+            diagnostics.push((plugin.schema().name, plugin.diagnostics()));
+        }
+        diagnostics
+    }
+
+    /// Stops the futex, iowait, ipc and plugin programs, so that only the
+    /// clone program keeps its probes attached. The events already read are
+    /// still taken by the collectors.
+    pub fn suspend(&mut self) -> Result<()> {
+        if self.suspended {
+            return Ok(());
+        }
+        if let Some(futex) = &self.futex {
+            futex.borrow_mut().stop()?;
+        }
+        if let Some(io_wait) = &self.io_wait {
+            io_wait.borrow_mut().stop()?;
+        }
+        if let Some(ipc) = &self.ipc {
+            ipc.borrow_mut().stop()?;
+        }
+        for plugin in &self.plugins {
+            plugin.borrow_mut().stop()?;
+        }
+        self.suspended = true;
+        Ok(())
+    }
+
+    /// Relaunches the programs stopped by [`Executor::suspend`], and registers
+    /// the monitored processes with them.
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.suspended {
+            return Ok(());
+        }
+        let pid = std::process::id();
+        let token = self.registration.token();
+        if let Some(futex) = &self.futex {
+            futex.borrow_mut().restart(
+                &self.launcher,
+                pid,
+                token,
+                self.terminate_flag.clone(),
+                &self.scripts.script("futex_wait.bt"),
+                self.buffers.futex_pipe_size,
+            )?;
+        }
+        if let Some(io_wait) = &self.io_wait {
+            io_wait.borrow_mut().restart(
+                &self.launcher,
+                self.terminate_flag.clone(),
+                &self.scripts.script("io_wait.bt"),
+                self.buffers.iowait_pipe_size,
+            )?;
+        }
+        if let Some(ipc) = &self.ipc {
+            ipc.borrow_mut().restart(
+                &self.launcher,
+                self.terminate_flag.clone(),
+                pid,
+                token,
+                &self.scripts.script("ipc.bt"),
+                self.buffers.ipc_pipe_size,
+            )?;
+        }
+        for plugin in &self.plugins {
+            plugin.borrow_mut().restart(
+                &self.launcher,
+                self.terminate_flag.clone(),
+                pid,
+                token,
+                self.buffers.plugin_pipe_size,
+            )?;
+        }
+        self.suspended = false;
+        self.wait_for_headers()?;
+        for pid in self.monitored.iter().copied() {
+            self.registration.add(pid);
+        }
+        Ok(())
+    }
+
+    /// Relaunches the programs which exited since the last call, and signals
+    /// the monitored processes to them again.
+    pub fn supervise(&mut self) -> Result<Vec<ProgramRestart>> {
+        let pid = std::process::id();
+        let token = self.registration.token();
+        let mut exited = Vec::new();
+        if let Some(status) = self.clone.try_wait()? {
+            self.clone
+                .restart(&self.launcher, pid, token, &self.scripts.script("clone.bt"))?;
+            exited.push(("clone", status));
+        }
+        if let Some(futex) = &self.futex {
+            let mut futex = futex.borrow_mut();
+            if let Some(status) = futex.try_wait()? {
+                futex.restart(
+                    &self.launcher,
+                    pid,
+                    token,
+                    self.terminate_flag.clone(),
+                    &self.scripts.script("futex_wait.bt"),
+                    self.buffers.futex_pipe_size,
+                )?;
+                exited.push(("futex", status));
+            }
+        }
+        if let Some(io_wait) = &self.io_wait {
+            let mut io_wait = io_wait.borrow_mut();
+            if let Some(status) = io_wait.try_wait()? {
+                io_wait.restart(
+                    &self.launcher,
+                    self.terminate_flag.clone(),
+                    &self.scripts.script("io_wait.bt"),
+                    self.buffers.iowait_pipe_size,
+                )?;
+                exited.push(("iowait", status));
+            }
+        }
+        if let Some(ipc) = &self.ipc {
+            let mut ipc = ipc.borrow_mut();
+            if let Some(status) = ipc.try_wait()? {
+                ipc.restart(
+                    &self.launcher,
+                    self.terminate_flag.clone(),
+                    pid,
+                    token,
+                    &self.scripts.script("ipc.bt"),
+                    self.buffers.ipc_pipe_size,
+                )?;
+                exited.push(("ipc", status));
+            }
+        }
+        for plugin in &self.plugins {
+            let mut plugin = plugin.borrow_mut();
+            if let Some(status) = plugin.try_wait()? {
+                plugin.restart(
+                    &self.launcher,
+                    self.terminate_flag.clone(),
+                    pid,
+                    token,
+                    self.buffers.plugin_pipe_size,
+                )?;
+                exited.push((plugin.schema().name, status));
+            }
+        }
+
+        let gap_start_ms = self.last_supervised_ms;
+        if !exited.is_empty() {
+            self.wait_for_headers()?;
+            // The programs that kept running ignore pids they already track
+            for pid in self.monitored.iter().copied() {
+                self.registration.add(pid);
+            }
+        }
+        self.last_supervised_ms = epoch_ms();
+
+        Ok(exited
+            .into_iter()
+            .map(|(program, status)| {
+                println!(
+                    "Restarted bpftrace {} program, which exited with {}",
+                    program, status
+                );
+                ProgramRestart {
+                    program,
+                    status: status.to_string(),
+                    gap_start_ms,
+                    gap_end_ms: self.last_supervised_ms,
+                }
+            })
+            .collect())
     }
 }
 
 impl Executor {
     pub fn monitor(&mut self, pid: usize) {
         println!("Monitoring new process {}", pid);
-        let event_id = CString::new("metric-collector-new-pid").unwrap();
+        self.monitored.insert(pid);
+        self.registration.add(pid);
+    }
+
+    /// Stops tracing a process, e.g. once all its threads exited.
+    pub fn unmonitor(&mut self, pid: usize) {
+        if self.monitored.remove(&pid) {
+            println!("Stop monitoring process {}", pid);
+            self.registration.remove(pid);
+        }
+    }
+}
+
+/// Registers the processes traced by the bpftrace programs of a session. An
+/// `access` syscall by the collector on the session's token registers the pid
+/// passed as its mode, or unregisters it if negated. The token is unique to the
+/// session, so that the programs of other sessions on the host ignore it.
+struct Registration {
+    token: CString,
+}
+
+impl Registration {
+    fn new() -> Self {
+        let token = format!(
+            "prism-session-{}-{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_nanos()
+        );
+        Self {
+            token: CString::new(token).unwrap(),
+        }
+    }
+
+    fn token(&self) -> &str {
+        self.token.to_str().unwrap()
+    }
+
+    fn add(&self, pid: usize) {
+        self.signal(Self::mode(pid, true));
+    }
+
+    fn remove(&self, pid: usize) {
+        self.signal(Self::mode(pid, false));
+    }
+
+    /// The mode of the `access` syscall which registers or unregisters `pid`.
+    fn mode(pid: usize, register: bool) -> i32 {
+        match register {
+            true => pid as i32,
+            false => -(pid as i32),
+        }
+    }
+
+    fn signal(&self, mode: i32) {
         unsafe {
-            libc::access(event_id.as_ptr(), pid as i32);
+            libc::access(self.token.as_ptr(), mode);
         }
     }
 }
 
+fn epoch_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
 pub fn boot_to_epoch(boot_ns: u128) -> u128 {
     *BOOT_EPOCH_NS.read().unwrap() + boot_ns
 }
@@ -112,3 +536,67 @@ pub trait BpfReader {
         return None;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{scripts::SCRIPTS, Registration};
+    use indoc::indoc;
+    use std::collections::HashSet;
+
+    /// Body of the `sys_enter_access` probe of every script tracing the
+    /// registered pids.
+    const ACCESS_PROBE: &str = indoc! {"
+        {
+            $mode = args->mode;
+            if ($mode > 0) {
+                @pids[(uint32) $mode] = 1;
+            } else {
+                delete(@pids[(uint32) -$mode]);
+            }
+        }
+    "};
+
+    /// Applies a registration to `pids` as [`ACCESS_PROBE`] does.
+    fn access_probe(pids: &mut HashSet<u32>, mode: i32) {
+        if mode > 0 {
+            pids.insert(mode as u32);
+        } else {
+            pids.remove(&(-mode as u32));
+        }
+    }
+
+    #[test]
+    fn registration_token() {
+        let first = Registration::new();
+        let second = Registration::new();
+        assert_ne!(first.token(), second.token());
+        // bpftrace compares at most 64 bytes of a string, including the NUL
+        assert!(first.token().len() < 64);
+    }
+
+    #[test]
+    fn register_and_unregister_pid() {
+        let scripts = SCRIPTS
+            .iter()
+            .filter(|(name, _)| *name != "io_wait.bt")
+            .map(|(_, script)| *script)
+            .chain([include_str!("../../plugins/mutex_wait.bt")]);
+        for script in scripts {
+            let probe = script
+                .split_once("tracepoint:syscalls:sys_enter_access\n")
+                .and_then(|(_, probe)| probe.split_once('\n'))
+                .map(|(_, body)| body)
+                .unwrap();
+            assert!(probe.starts_with(ACCESS_PROBE));
+        }
+
+        let mut pids = HashSet::new();
+        // The highest pid allowed by pid_max
+        for pid in [8877, 4194304] {
+            access_probe(&mut pids, Registration::mode(pid, true));
+            assert!(pids.contains(&(pid as u32)));
+            access_probe(&mut pids, Registration::mode(pid, false));
+            assert!(pids.is_empty());
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use crate::metrics::{Field, Kind};
+
+/// Names of the directories and programs of the built-in subsystems, which a
+/// plugin cannot take.
+const RESERVED: [&str; 6] = ["clone", "futex", "ipc", "iowait", "sched", "schedstat"];
+
+/// Layout of the `<plugin>.toml` descriptor next to a plugin's script.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Descriptor {
+    map: String,
+    key: Vec<String>,
+    value: Vec<String>,
+    /// Fields of the value holding strings, e.g. a comm. The others are
+    /// integers.
+    #[serde(default)]
+    text: Vec<String>,
+}
+
+/// A user-supplied bpftrace script, and the layout of the map it prints in its
+/// stats closures. The map is keyed by the thread's `tid`, along with the
+/// other fields of `key`, and the fields of `value` are stored as integer
+/// columns, or text ones for the fields listed in `text`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PluginSchema {
+    /// File stem of the script, which names the plugin's directories and its
+    /// program in the diagnostics.
+    pub name: &'static str,
+    pub script: String,
+    pub map: String,
+    pub key: Vec<String>,
+    pub value: Vec<String>,
+    /// Position of the `tid` field within the key.
+    pub tid_index: usize,
+    /// Schema of the plugin's samples, the epoch followed by the values.
+    pub fields: &'static [Field],
+}
+
+impl PluginSchema {
+    fn new(name: &str, script: String, descriptor: Descriptor) -> Result<Self> {
+        if !is_identifier(name) || RESERVED.contains(&name) {
+            return Err(eyre!(
+                "Invalid plugin name {}. It must be made of letters, digits, '_' and '-', and not be one of {}",
+                name,
+                RESERVED.join(", ")
+            ));
+        }
+        let mut fields = descriptor.key.iter().chain(descriptor.value.iter());
+        if let Some(field) = fields.find(|field| !is_identifier(field)) {
+            return Err(eyre!("Invalid field name {:?} of plugin {}", field, name));
+        }
+        let tid_index = descriptor
+            .key
+            .iter()
+            .position(|field| field == "tid")
+            .ok_or(eyre!("The key of plugin {} has no tid field", name))?;
+        if descriptor.value.is_empty() {
+            return Err(eyre!("Plugin {} has no value field", name));
+        }
+        if let Some(field) = descriptor
+            .text
+            .iter()
+            .find(|field| !descriptor.value.contains(field))
+        {
+            return Err(eyre!(
+                "Text field {} of plugin {} is not a value field",
+                field,
+                name
+            ));
+        }
+
+        // Both are referenced for the lifetime of the session, by the
+        // diagnostics and the data files of every target
+        let fields: Vec<Field> = [Field::new("epoch_ms", Kind::Unsigned)]
+            .into_iter()
+            .chain(descriptor.value.iter().map(|field| {
+                let name: &'static str = Box::leak(field.as_str().into());
+                let kind = match descriptor.text.contains(field) {
+                    true => Kind::Text,
+                    false => Kind::Signed,
+                };
+                Field::new(name, kind)
+            }))
+            .collect();
+        Ok(Self {
+            name: Box::leak(name.into()),
+            script,
+            map: descriptor.map.trim_start_matches('@').to_string(),
+            key: descriptor.key,
+            value: descriptor.value,
+            tid_index,
+            fields: Box::leak(fields.into()),
+        })
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Loads the plugins of `directory`, i.e. every `<plugin>.bt` script along
+/// with its `<plugin>.toml` descriptor, sorted by name.
+pub fn load(directory: &str) -> Result<Vec<PluginSchema>> {
+    let entries = fs::read_dir(directory)
+        .map_err(|e| eyre!("Could not read plugin directory {}. {}", directory, e))?;
+    let mut scripts = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) == Some("bt") {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+
+    scripts
+        .into_iter()
+        .map(|script| {
+            let name = script
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or(eyre!("Invalid plugin script {}", script.display()))?;
+            let descriptor_path = script.with_extension("toml");
+            let descriptor = read_descriptor(&descriptor_path)?;
+            PluginSchema::new(name, script.to_string_lossy().into_owned(), descriptor)
+        })
+        .collect()
+}
+
+fn read_descriptor(path: &Path) -> Result<Descriptor> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| eyre!("Could not read plugin descriptor {}. {}", path.display(), e))?;
+    toml::from_str(&content)
+        .map_err(|e| eyre!("Invalid plugin descriptor {}. {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use crate::metrics::{Field, Kind};
+    use eyre::Result;
+    use indoc::indoc;
+    use std::fs;
+    use tempdir::TempDir;
+
+    const DESCRIPTOR: &str = indoc! {r#"
+        map = "@lock_wait"
+        key = ["tid", "lock"]
+        value = ["wait_ns", "count", "comm"]
+        text = ["comm"]
+    "#};
+
+    #[test]
+    fn load_plugins() -> Result<()> {
+        let dir = TempDir::new("")?;
+        fs::write(dir.path().join("mutex.bt"), "BEGIN {}\n")?;
+        fs::write(dir.path().join("mutex.toml"), DESCRIPTOR)?;
+        fs::write(dir.path().join("README.md"), "Notes\n")?;
+
+        let plugins = load(dir.path().to_str().unwrap())?;
+        assert_eq!(plugins.len(), 1);
+        let plugin = &plugins[0];
+        assert_eq!(plugin.name, "mutex");
+        assert_eq!(plugin.script, dir.path().join("mutex.bt").to_str().unwrap());
+        assert_eq!(plugin.map, "lock_wait");
+        assert_eq!(plugin.tid_index, 0);
+        assert_eq!(
+            plugin.fields,
+            [
+                Field::new("epoch_ms", Kind::Unsigned),
+                Field::new("wait_ns", Kind::Signed),
+                Field::new("count", Kind::Signed),
+                Field::new("comm", Kind::Text),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_plugins() -> Result<()> {
+        for (name, descriptor) in [
+            (
+                "mutex",
+                "map = \"lock_wait\"\nkey = [\"lock\"]\nvalue = [\"wait_ns\"]\n",
+            ),
+            (
+                "mutex",
+                "map = \"lock_wait\"\nkey = [\"tid\"]\nvalue = []\n",
+            ),
+            (
+                "mutex",
+                "map = \"lock_wait\"\nkey = [\"tid\"]\nvalue = [\"wait,ns\"]\n",
+            ),
+            ("mutex", "map = \"lock_wait\"\nkey = [\"tid\"]\n"),
+            (
+                "mutex",
+                "map = \"lock_wait\"\nkey = [\"tid\"]\nvalue = [\"wait_ns\"]\ntext = [\"comm\"]\n",
+            ),
+            ("futex", DESCRIPTOR),
+        ] {
+            let dir = TempDir::new("")?;
+            fs::write(dir.path().join(format!("{}.bt", name)), "BEGIN {}\n")?;
+            fs::write(dir.path().join(format!("{}.toml", name)), descriptor)?;
+            assert!(load(dir.path().to_str().unwrap()).is_err());
+        }
+
+        let dir = TempDir::new("")?;
+        fs::write(dir.path().join("mutex.bt"), "BEGIN {}\n")?;
+        assert!(load(dir.path().to_str().unwrap()).is_err());
+        Ok(())
+    }
+}
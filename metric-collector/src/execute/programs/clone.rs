@@ -1,10 +1,18 @@
-use eyre::Result;
+use eyre::{eyre, Result};
 use std::io::ErrorKind;
-use std::process::{Child, Command};
+use std::process::{Child, ExitStatus};
 use std::rc::Rc;
 use std::{fs::File, io::prelude::*};
 
-use crate::execute::BpfReader;
+use super::Launcher;
+use crate::execute::{
+    diagnostics::Diagnostics,
+    record::{BpfRecord, Fields},
+    BpfReader,
+};
+
+/// Maximum length of a comm, without its terminating nul byte.
+const COMM_LEN: usize = 15;
 
 pub enum CloneEvent {
     NewThread {
@@ -12,29 +20,77 @@ pub enum CloneEvent {
         pid: usize,
         tid: usize,
     },
-    NewProcess(Rc<str>, usize),
-    RemoveProcess(usize),
+    NewProcess {
+        comm: Rc<str>,
+        pid: usize,
+        parent: Option<usize>,
+    },
+    Exec {
+        old_comm: Rc<str>,
+        new_comm: Rc<str>,
+        pid: usize,
+        ns_since_boot: u64,
+    },
     Unexpected {
         data: String,
     },
 }
 
-impl From<Vec<u8>> for CloneEvent {
-    fn from(value: Vec<u8>) -> Self {
-        let event_string = String::from_utf8(value).unwrap();
-        let mut elements = event_string.split("\t");
-        match elements.next().unwrap().trim() {
+impl CloneEvent {
+    fn parse_line(line: &str) -> Result<Self> {
+        let mut elements = Fields::new(line.split('\t'));
+        let event = match elements.field()?.trim() {
             "NewThread" => Self::NewThread {
-                comm: Rc::from(elements.next().unwrap().trim()),
-                pid: elements.next().unwrap().parse().unwrap(),
-                tid: elements.next().unwrap().parse().unwrap(),
+                pid: elements.parse()?,
+                tid: elements.parse()?,
+                comm: Rc::from(elements.rest()?),
+            },
+            "NewProcess" => Self::NewProcess {
+                pid: elements.parse()?,
+                parent: Some(elements.parse()?),
+                comm: Rc::from(elements.rest()?),
+            },
+            "Exec" => {
+                let pid = elements.parse()?;
+                let ns_since_boot = elements.parse()?;
+                let comms = elements.rest()?;
+                let (old_comm, new_comm) = Self::exec_comms(&comms)?;
+                Self::Exec {
+                    old_comm: Rc::from(old_comm),
+                    new_comm: Rc::from(new_comm),
+                    pid,
+                    ns_since_boot,
+                }
+            }
+            _ => Self::Unexpected { data: line.into() },
+        };
+        Ok(event)
+    }
+
+    /// Splits the comms of an exec, the old one padded to the width of a comm
+    /// so that either may contain tabs.
+    fn exec_comms(comms: &str) -> Result<(&str, &str)> {
+        match (comms.get(..COMM_LEN), comms.get(COMM_LEN..)) {
+            (Some(old_comm), Some(new_comm)) if new_comm.starts_with('\t') => {
+                Ok((old_comm.trim_end(), &new_comm[1..]))
+            }
+            _ => comms
+                .split_once('\t')
+                .map(|(old_comm, new_comm)| (old_comm.trim_end(), new_comm))
+                .ok_or(eyre!("Missing comm of exec")),
+        }
+    }
+}
+
+impl From<BpfRecord> for CloneEvent {
+    fn from(record: BpfRecord) -> Self {
+        match record {
+            BpfRecord::Printf(line) => {
+                Self::parse_line(&line).unwrap_or(Self::Unexpected { data: line })
+            }
+            record => Self::Unexpected {
+                data: format!("{:?}", record),
             },
-            "NewProcess" => Self::NewProcess(
-                Rc::from(elements.next().unwrap()),
-                elements.next().unwrap().parse().unwrap(),
-            ),
-            "RemoveProcess" => Self::RemoveProcess(elements.next().unwrap().parse().unwrap()),
-            _ => Self::Unexpected { data: event_string },
         }
     }
 }
@@ -44,6 +100,7 @@ pub struct CloneProgram {
     child: Child,
     header_lines: u8,
     current_event: Option<Vec<u8>>,
+    diagnostics: Diagnostics,
 }
 
 impl BpfReader for CloneProgram {
@@ -69,21 +126,61 @@ impl BpfReader for CloneProgram {
 }
 
 impl CloneProgram {
-    pub fn new(pid: u32) -> Result<Self> {
-        let (mut reader, writer) = super::pipe();
-        super::fcntl_setfd(&mut reader, libc::O_RDONLY | libc::O_NONBLOCK);
-        let child = Command::new("bpftrace")
-            .args(["./metric-collector/src/bpf/clone.bt", &format!("{}", pid)])
-            .stdout(writer)
-            .spawn()?;
+    pub fn new(launcher: &Launcher, pid: u32, token: &str, script: &str) -> Result<Self> {
+        let diagnostics = Diagnostics::default();
+        let (reader, child) = Self::spawn(launcher, pid, token, script, &diagnostics)?;
         Ok(Self {
             child,
             reader,
             header_lines: 0,
             current_event: None,
+            diagnostics,
         })
     }
 
+    fn spawn(
+        launcher: &Launcher,
+        pid: u32,
+        token: &str,
+        script: &str,
+        diagnostics: &Diagnostics,
+    ) -> Result<(File, Child)> {
+        let (mut reader, writer) = super::pipe();
+        super::fcntl_setfd(&mut reader, libc::O_RDONLY | libc::O_NONBLOCK);
+        let child = launcher.spawn(
+            "clone",
+            &[script, &format!("{}", pid), token],
+            writer,
+            diagnostics,
+        )?;
+        Ok((reader, child))
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diagnostics.clone()
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Ok(self.child.try_wait()?)
+    }
+
+    /// Relaunches the bpftrace program after it exited. Events which were
+    /// written before the exit are dropped.
+    pub fn restart(
+        &mut self,
+        launcher: &Launcher,
+        pid: u32,
+        token: &str,
+        script: &str,
+    ) -> Result<()> {
+        let (reader, child) = Self::spawn(launcher, pid, token, script, &self.diagnostics)?;
+        self.reader = reader;
+        self.child = child;
+        self.header_lines = 0;
+        self.current_event = None;
+        Ok(())
+    }
+
     pub fn poll_events(&mut self) -> Result<Vec<CloneEvent>> {
         let mut res = Vec::new();
         loop {
@@ -111,9 +208,17 @@ impl CloneProgram {
             if !self.header_read() {
                 self.handle_header(&mut iterator);
             }
-            while let Some(event) = self.handle_event(&mut iterator) {
-                let event = CloneEvent::from(event);
-                res.push(event);
+            while let Some(line) = self.handle_event(&mut iterator) {
+                for record in BpfRecord::decode(&line) {
+                    match record {
+                        BpfRecord::LostEvents(lost) => {
+                            self.diagnostics
+                                .record(|counters| counters.lost_events += lost);
+                        }
+                        BpfRecord::Other => {}
+                        record => res.push(CloneEvent::from(record)),
+                    }
+                }
             }
         }
         Ok(res)
@@ -127,3 +232,84 @@ impl Drop for CloneProgram {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CloneEvent;
+    use crate::execute::record::BpfRecord;
+    use eyre::{eyre, Result};
+
+    #[test]
+    fn new_thread() -> Result<()> {
+        let line = "NewThread      \t3021\t3025\tredis\tserver";
+        let event = CloneEvent::from(BpfRecord::Printf(line.into()));
+        if let CloneEvent::NewThread { comm, pid, tid } = event {
+            assert_eq!(&*comm, "redis\tserver");
+            assert_eq!(pid, 3021);
+            assert_eq!(tid, 3025);
+        } else {
+            return Err(eyre!("Incorrect clone event"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn new_process() -> Result<()> {
+        let line = "NewProcess     \t3030\t3021\tredis-server";
+        let event = CloneEvent::from(BpfRecord::Printf(line.into()));
+        if let CloneEvent::NewProcess { comm, pid, parent } = event {
+            assert_eq!(&*comm, "redis-server");
+            assert_eq!(pid, 3030);
+            assert_eq!(parent, Some(3021));
+        } else {
+            return Err(eyre!("Incorrect clone event"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn exec() -> Result<()> {
+        let line = "Exec           \t3021\t65384570945103\tsh             \tjava";
+        let event = CloneEvent::from(BpfRecord::Printf(line.into()));
+        if let CloneEvent::Exec {
+            old_comm,
+            new_comm,
+            pid,
+            ns_since_boot,
+        } = event
+        {
+            assert_eq!(&*old_comm, "sh");
+            assert_eq!(&*new_comm, "java");
+            assert_eq!(pid, 3021);
+            assert_eq!(ns_since_boot, 65384570945103);
+        } else {
+            return Err(eyre!("Incorrect clone event"));
+        }
+
+        let line = "Exec           \t3021\t65384570945103\tsh\tx           \tjava\tx";
+        let event = CloneEvent::from(BpfRecord::Printf(line.into()));
+        if let CloneEvent::Exec {
+            old_comm, new_comm, ..
+        } = event
+        {
+            assert_eq!(&*old_comm, "sh\tx");
+            assert_eq!(&*new_comm, "java\tx");
+        } else {
+            return Err(eyre!("Incorrect clone event"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() {
+        for line in [
+            "NewThread      \tredis-server\t3021\t3025",
+            "NewProcess     \t3030\tredis-server",
+            "Exec           \t3021\t-1\tsh             \tjava",
+            "Exec           \t3021\t65384570945103\tsh",
+        ] {
+            let event = CloneEvent::from(BpfRecord::Printf(line.into()));
+            assert!(matches!(event, CloneEvent::Unexpected { .. }));
+        }
+    }
+}
@@ -1,32 +1,31 @@
 use eyre::{eyre, Result};
-use lazy_static::lazy_static;
-use regex::Regex;
 use std::{
-    collections::HashMap,
     io::prelude::*,
     mem,
-    process::{Child, Command},
     rc::Rc,
-    sync::{
-        mpsc::{self, Receiver, Sender, TryRecvError},
-        Arc, Mutex,
-    },
-    thread,
+    sync::{Arc, Mutex},
 };
 
-use crate::execute::BpfReader;
-
-lazy_static! {
-    static ref REGEX_PATTERN: Regex = Regex::new(r"^@(\w+)\[(.*)\]: (.*)$").unwrap();
+use super::{
+    stats::{Interval, Route, RoutedEvents, StatsDecoder, StatsProgram, StatsRecord},
+    Launcher,
+};
+use crate::execute::record::{BpfRecord, Fields};
+
+/// A process which accessed a futex of a monitored process. The futex is
+/// identified by its address in the address space of `root_pid`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FutexNewProcess {
+    pub comm: Rc<str>,
+    pub pid: usize,
+    pub uaddr: Rc<str>,
+    pub root_pid: usize,
 }
 
 #[derive(PartialEq, Eq, Debug)]
-enum FutexBpfEvent {
+pub enum FutexBpfEvent {
     NoOp,
-    NewProcess {
-        comm: Rc<str>,
-        pid: usize,
-    },
+    NewProcess(FutexNewProcess),
     Unexpected {
         data: String,
     },
@@ -60,70 +59,43 @@ enum FutexBpfEvent {
 }
 
 impl FutexBpfEvent {
-    fn parse_line(event_string: &str) -> Result<Self> {
-        if event_string.starts_with("@") {
-            Self::from_summary_stats_string(event_string)
-        } else if event_string.starts_with("=>") {
-            Self::from_stats_closure_string(event_string)
-        } else {
-            Self::from_trace_string(event_string)
+    fn parse_record(record: &BpfRecord) -> Result<Self> {
+        match record {
+            BpfRecord::Printf(line) if line.starts_with("=>") => {
+                Self::from_stats_closure_string(line)
+            }
+            BpfRecord::Printf(line) => Self::from_trace_string(line),
+            BpfRecord::MapEntry { map, key, value } => Self::from_map_entry(map, key, value),
+            BpfRecord::Malformed(data) => Ok(Self::Unexpected { data: data.clone() }),
+            BpfRecord::AttachedProbes(_) | BpfRecord::LostEvents(_) | BpfRecord::Other => {
+                Ok(Self::NoOp)
+            }
         }
     }
 
-    fn from_summary_stats_string(event_string: &str) -> Result<Self> {
-        let captures = REGEX_PATTERN
-            .captures(&event_string)
-            .ok_or(eyre!("Unexpected event string"))?;
-        let mut cap_iter = captures.iter();
-        cap_iter.next();
-
-        let map_type = cap_iter.next().unwrap().unwrap().as_str();
-        match map_type {
-            "wait_elapsed" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-                let value = &value[1..value.len() - 1];
-                let mut value_elements = value.split(", ");
-
-                Ok(Self::WaitElapsed {
-                    tid: key_elements.next().unwrap().parse().unwrap(),
-                    root_pid: key_elements.next().unwrap().parse().unwrap(),
-                    uaddr: key_elements.next().unwrap().into(),
-                    total_interval_wait_ns: value_elements.next().unwrap().parse().unwrap(),
-                    count_interval_wait: value_elements.next().unwrap().parse().unwrap(),
-                })
-            }
-            "wait_pending" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-                let value = &value[1..value.len() - 1];
-                let mut value_elements = value.split(", ");
-
-                Ok(Self::WaitPending {
-                    tid: key_elements.next().unwrap().parse().unwrap(),
-                    ns_since_boot: value_elements.next().unwrap().parse().unwrap(),
-                    root_pid: value_elements.next().unwrap().parse().unwrap(),
-                    uaddr: value_elements.next().unwrap().into(),
-                })
-            }
-            "wake" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-                let mut value_elements = value.split(", ");
-
-                Ok(Self::Wake {
-                    tid: key_elements.next().unwrap().parse().unwrap(),
-                    root_pid: key_elements.next().unwrap().parse().unwrap(),
-                    uaddr: key_elements.next().unwrap().into(),
-                    count: value_elements.next().unwrap().parse().unwrap(),
-                })
-            }
+    fn from_map_entry(map: &str, key: &[String], value: &[String]) -> Result<Self> {
+        let mut key_elements = Fields::new(key.iter().map(String::as_str));
+        let mut value_elements = Fields::new(value.iter().map(String::as_str));
+        match map {
+            "wait_elapsed" => Ok(Self::WaitElapsed {
+                tid: key_elements.parse()?,
+                root_pid: key_elements.parse()?,
+                uaddr: key_elements.address()?,
+                total_interval_wait_ns: value_elements.parse()?,
+                count_interval_wait: value_elements.parse()?,
+            }),
+            "wait_pending" => Ok(Self::WaitPending {
+                tid: key_elements.parse()?,
+                ns_since_boot: value_elements.parse()?,
+                root_pid: value_elements.parse()?,
+                uaddr: value_elements.address()?,
+            }),
+            "wake" => Ok(Self::Wake {
+                tid: key_elements.parse()?,
+                root_pid: key_elements.parse()?,
+                uaddr: key_elements.address()?,
+                count: value_elements.parse()?,
+            }),
             _ => Err(eyre!("Invalid map type")),
         }
     }
@@ -139,17 +111,19 @@ impl FutexBpfEvent {
     }
 
     fn from_trace_string(event_string: &str) -> Result<Self> {
-        let mut elements = event_string.split_whitespace();
-        match elements.next().unwrap() {
+        let mut elements = Fields::new(event_string.split('\t'));
+        match elements.field()?.trim() {
             "UnhandledOpcode" => Ok(Self::UnhandledOpcode {
-                opcode: elements.next().unwrap().into(),
-            }),
-            "NewProcess" => Ok(Self::NewProcess {
-                comm: elements.next().unwrap().into(),
-                pid: elements.next().unwrap().parse().unwrap(),
+                opcode: elements.field()?.trim().into(),
             }),
+            "NewProcess" => Ok(Self::NewProcess(FutexNewProcess {
+                pid: elements.parse()?,
+                uaddr: elements.address()?,
+                root_pid: elements.parse()?,
+                comm: elements.rest()?.into(),
+            })),
             "SampleInstant" => Ok(Self::SampleInstant {
-                ns_since_boot: elements.next().unwrap().parse().unwrap(),
+                ns_since_boot: elements.parse()?,
             }),
             _ => Ok(Self::Unexpected {
                 data: event_string.into(),
@@ -158,13 +132,11 @@ impl FutexBpfEvent {
     }
 }
 
-impl From<Vec<u8>> for FutexBpfEvent {
-    fn from(value: Vec<u8>) -> Self {
-        let event_string = String::from_utf8(value).unwrap();
-        if event_string.len() == 0 {
-            return Self::NoOp {};
-        }
-        Self::parse_line(&event_string).unwrap_or(Self::Unexpected { data: event_string })
+impl From<BpfRecord> for FutexBpfEvent {
+    fn from(record: BpfRecord) -> Self {
+        Self::parse_record(&record).unwrap_or_else(|_| Self::Unexpected {
+            data: format!("{:?}", record),
+        })
     }
 }
 
@@ -188,11 +160,8 @@ pub enum FutexEvent {
 }
 
 impl FutexEvent {
-    fn from_stats_closure_value(
-        entry: StatsClosureValue,
-        current_instant_ns: u64,
-        previous_instant_ns: Option<u64>,
-    ) -> Result<Self> {
+    fn from_stats_closure_value(entry: StatsClosureValue, interval: Interval) -> Result<Self> {
+        let current_instant_ns = interval.sample_instant_ns;
         match entry {
             StatsClosureValue::Wait(
                 None,
@@ -203,15 +172,7 @@ impl FutexEvent {
                     ns_since_boot,
                 }),
             ) => {
-                let pending = if let Some(prev) = previous_instant_ns {
-                    if ns_since_boot > prev {
-                        i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                    } else {
-                        current_instant_ns - prev
-                    }
-                } else {
-                    i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                };
+                let pending = interval.pending_ns(ns_since_boot);
 
                 Ok(Self::Wait {
                     tid,
@@ -249,15 +210,7 @@ impl FutexEvent {
                 }),
                 Some(FutexBpfEvent::WaitPending { ns_since_boot, .. }),
             ) => {
-                let pending = if let Some(prev) = previous_instant_ns {
-                    if ns_since_boot > prev {
-                        i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                    } else {
-                        current_instant_ns - prev
-                    }
-                } else {
-                    i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                };
+                let pending = interval.pending_ns(ns_since_boot);
 
                 Ok(Self::Wait {
                     tid,
@@ -335,13 +288,8 @@ impl FutexEvent {
 //     }
 // }
 
-enum FutexProgramState {
-    OutStatClosure,
-    InStatClosure(Option<u64>),
-}
-
 #[derive(PartialEq, Eq, Hash)]
-enum StatsClosureKey {
+pub enum StatsClosureKey {
     Wait {
         tid: usize,
         root_pid: usize,
@@ -355,297 +303,214 @@ enum StatsClosureKey {
 }
 
 #[derive(Debug)]
-enum StatsClosureValue {
+pub enum StatsClosureValue {
     Wait(Option<FutexBpfEvent>, Option<FutexBpfEvent>),
     Wake(FutexBpfEvent),
 }
 
-pub struct FutexProgram {
-    child: Option<Child>,
-    rx: Receiver<Arc<[u8]>>,
-    events: HashMap<usize, Vec<FutexEvent>>,
-    new_pids: Option<Vec<(Rc<str>, usize)>>,
-    header_lines: u8,
-    current_event: Option<Vec<u8>>,
-    state: FutexProgramState,
-    stats_closure_events: HashMap<StatsClosureKey, StatsClosureValue>,
-    prev_instant_ns: Option<u64>,
+/// Decodes the output of the futex program. New processes accessing a futex
+/// of a target are kept until taken by the extractor.
+#[derive(Default)]
+pub struct FutexDecoder {
+    new_pids: Vec<FutexNewProcess>,
 }
 
-impl BpfReader for FutexProgram {
-    fn header_read(&self) -> bool {
-        self.header_lines == 1
+impl StatsDecoder for FutexDecoder {
+    const PROGRAM: &'static str = "futex";
+
+    type Entry = FutexBpfEvent;
+    type Trace = FutexBpfEvent;
+    type Key = StatsClosureKey;
+    type Value = StatsClosureValue;
+    type Event = FutexEvent;
+
+    fn decode(&self, record: BpfRecord) -> StatsRecord<FutexBpfEvent, FutexBpfEvent> {
+        match FutexBpfEvent::from(record) {
+            FutexBpfEvent::NoOp => StatsRecord::NoOp,
+            FutexBpfEvent::MapStatsStart => StatsRecord::MapStatsStart,
+            FutexBpfEvent::SampleInstant { ns_since_boot } => {
+                StatsRecord::SampleInstant(ns_since_boot)
+            }
+            FutexBpfEvent::MapStatsEnd => StatsRecord::MapStatsEnd,
+            FutexBpfEvent::Unexpected { data } => StatsRecord::Unexpected(data),
+            event @ (FutexBpfEvent::WaitElapsed { .. }
+            | FutexBpfEvent::WaitPending { .. }
+            | FutexBpfEvent::Wake { .. }) => StatsRecord::Entry(event),
+            event @ (FutexBpfEvent::NewProcess(_) | FutexBpfEvent::UnhandledOpcode { .. }) => {
+                StatsRecord::Trace(event)
+            }
+        }
     }
 
-    fn header_lines_get_mut(&mut self) -> &mut u8 {
-        &mut self.header_lines
+    fn key(&self, entry: &FutexBpfEvent) -> StatsClosureKey {
+        match entry {
+            FutexBpfEvent::Wake {
+                tid,
+                root_pid,
+                uaddr,
+                ..
+            } => StatsClosureKey::Wake {
+                tid: *tid,
+                root_pid: *root_pid,
+                uaddr: uaddr.clone(),
+            },
+            FutexBpfEvent::WaitElapsed {
+                tid,
+                root_pid,
+                uaddr,
+                ..
+            }
+            | FutexBpfEvent::WaitPending {
+                tid,
+                root_pid,
+                uaddr,
+                ..
+            } => StatsClosureKey::Wait {
+                tid: *tid,
+                root_pid: *root_pid,
+                uaddr: uaddr.clone(),
+            },
+            _ => unreachable!("Only map entries are decoded as entries"),
+        }
     }
 
-    fn current_event_as_mut(&mut self) -> Option<&mut Vec<u8>> {
-        self.current_event.as_mut()
+    fn accumulate(
+        &self,
+        value: Option<StatsClosureValue>,
+        entry: FutexBpfEvent,
+    ) -> StatsClosureValue {
+        match (value, entry) {
+            (Some(value @ StatsClosureValue::Wake(_)), _) => value,
+            (None, entry @ FutexBpfEvent::Wake { .. }) => StatsClosureValue::Wake(entry),
+            (
+                Some(StatsClosureValue::Wait(_, pending)),
+                entry @ FutexBpfEvent::WaitElapsed { .. },
+            ) => StatsClosureValue::Wait(Some(entry), pending),
+            (None, entry @ FutexBpfEvent::WaitElapsed { .. }) => {
+                StatsClosureValue::Wait(Some(entry), None)
+            }
+            (Some(StatsClosureValue::Wait(elapsed, _)), entry) => {
+                StatsClosureValue::Wait(elapsed, Some(entry))
+            }
+            (None, entry) => StatsClosureValue::Wait(None, Some(entry)),
+        }
     }
 
-    fn set_current_event(&mut self, val: Vec<u8>) {
-        self.current_event = Some(val);
+    fn snapshot(
+        &self,
+        _key: StatsClosureKey,
+        value: StatsClosureValue,
+        interval: Interval,
+    ) -> Result<(Route, FutexEvent)> {
+        let event = FutexEvent::from_stats_closure_value(value, interval)?;
+        let tid = match event {
+            FutexEvent::Wait { tid, .. } | FutexEvent::Wake { tid, .. } => tid,
+        };
+        Ok((Route::Tid(tid), event))
     }
 
-    fn take_current_event(&mut self) -> Option<Vec<u8>> {
-        self.current_event.take()
+    fn trace(
+        &mut self,
+        trace: FutexBpfEvent,
+        _events: &mut RoutedEvents<FutexEvent>,
+    ) -> Result<()> {
+        match trace {
+            FutexBpfEvent::NewProcess(new_process) => self.new_pids.push(new_process),
+            event => println!("Futex unhandled opcode. {:?}", event),
+        }
+        Ok(())
     }
 }
 
-impl FutexProgram {
-    pub fn new(pid: u32, terminate_flag: Arc<Mutex<bool>>) -> Result<Self> {
-        let (bpf_pipe_rx, bpf_pipe_tx) = super::bpf_pipe(1_048_576);
-        let (tx, rx) = mpsc::channel();
-        let child = Command::new("bpftrace")
-            .args([
-                "./metric-collector/src/bpf/futex_wait.bt",
-                &format!("{}", pid),
-            ])
-            .stdout(bpf_pipe_tx)
-            .spawn()?;
-        Self::start_bpf_reader(tx, bpf_pipe_rx, terminate_flag);
-        Ok(Self {
-            child: Some(child),
-            rx,
-            events: HashMap::new(),
-            header_lines: 0,
-            current_event: None,
-            new_pids: None,
-            state: FutexProgramState::OutStatClosure,
-            stats_closure_events: HashMap::new(),
-            prev_instant_ns: None,
-        })
-    }
+pub type FutexProgram = StatsProgram<FutexDecoder>;
 
-    pub fn custom_reader<R: Read + Send + 'static>(
-        reader: R,
+impl FutexProgram {
+    pub fn new(
+        launcher: &Launcher,
+        pid: u32,
+        token: &str,
         terminate_flag: Arc<Mutex<bool>>,
+        script: &str,
+        pipe_size: u32,
     ) -> Result<Self> {
-        let (tx, rx) = std::sync::mpsc::channel();
-        Self::start_bpf_reader(tx, reader, terminate_flag);
-
-        Ok(Self {
-            rx,
-            child: None,
-            header_lines: 0,
-            current_event: None,
-            events: HashMap::new(),
-            new_pids: None,
-            stats_closure_events: HashMap::new(),
-            state: FutexProgramState::OutStatClosure,
-            prev_instant_ns: None,
-        })
+        Self::launch(
+            FutexDecoder::default(),
+            launcher,
+            &[script, &format!("{}", pid), token],
+            terminate_flag,
+            pipe_size,
+        )
     }
 
-    fn start_bpf_reader<R>(
-        tx: Sender<Arc<[u8]>>,
-        mut bpf_pipe_rx: R,
+    pub fn restart(
+        &mut self,
+        launcher: &Launcher,
+        pid: u32,
+        token: &str,
         terminate_flag: Arc<Mutex<bool>>,
-    ) where
-        R: Read + Send + 'static,
-    {
-        thread::Builder::new()
-            .name("futex_recv".to_string())
-            .spawn(move || loop {
-                if *terminate_flag.lock().unwrap() == true {
-                    break;
-                }
-                let mut buf: [u8; 65536] = [0; 65536];
-                let res = bpf_pipe_rx.read(&mut buf);
-                if let Ok(bytes) = res {
-                    if bytes == 0 {
-                        break;
-                    }
-
-                    if let Err(_) = tx.send(Arc::from(&buf[..bytes])) {
-                        break;
-                    };
-                }
-            })
-            .unwrap();
+        script: &str,
+        pipe_size: u32,
+    ) -> Result<()> {
+        self.relaunch(
+            launcher,
+            &[script, &format!("{}", pid), token],
+            terminate_flag,
+            pipe_size,
+        )
     }
 
-    pub fn poll_events(&mut self) -> Result<usize> {
-        loop {
-            let res = self.rx.try_recv();
-            let buf = match res {
-                Err(TryRecvError::Empty) => {
-                    break;
-                }
-                Err(e) => return Err(e.into()),
-                Ok(buf) => buf,
-            };
-
-            let mut iterator = buf.into_iter();
-            if !self.header_read() {
-                self.handle_header(&mut iterator);
-            }
-            while let Some(event) = self.handle_event(&mut iterator) {
-                let event = FutexBpfEvent::from(event);
-                match event {
-                    FutexBpfEvent::NewProcess { pid, comm } => {
-                        let new_pids = self.new_pids.get_or_insert_with(|| Vec::new());
-                        new_pids.push((comm.clone(), pid));
-                    }
-                    FutexBpfEvent::MapStatsStart => {
-                        self.state = FutexProgramState::InStatClosure(None);
-                    }
-                    FutexBpfEvent::SampleInstant { ns_since_boot } => {
-                        if let FutexProgramState::InStatClosure(sample_instant_ns) = &mut self.state
-                        {
-                            *sample_instant_ns = Some(ns_since_boot);
-                        }
-                    }
-                    FutexBpfEvent::MapStatsEnd => {
-                        let ns_since_boot =
-                            if let FutexProgramState::InStatClosure(Some(ns_since_boot)) =
-                                self.state
-                            {
-                                ns_since_boot
-                            } else {
-                                return Err(eyre!("Inconsistent ipc program state"));
-                            };
-                        self.state = FutexProgramState::OutStatClosure;
-
-                        let events = mem::replace(&mut self.stats_closure_events, HashMap::new());
-                        events.into_iter().for_each(|(_, entry)| {
-                            let event = FutexEvent::from_stats_closure_value(
-                                entry,
-                                ns_since_boot,
-                                self.prev_instant_ns,
-                            )
-                            .expect("Unsuccesful conversion from stat closure entry to FutexEvent");
-                            if let FutexEvent::Wait { tid, .. } | FutexEvent::Wake { tid, .. } =
-                                event
-                            {
-                                let tevents = self.events.entry(tid).or_insert_with(|| Vec::new());
-                                tevents.push(event);
-                            }
-                        });
-                        self.prev_instant_ns = Some(ns_since_boot);
-                    }
-                    FutexBpfEvent::UnhandledOpcode { .. } => {
-                        println!("Futex unhandled opcode. {:?}", event);
-                    }
-                    FutexBpfEvent::Unexpected { .. } => {
-                        println!("Futex unexpected event. {:?}", event);
-                    }
-                    FutexBpfEvent::WaitElapsed {
-                        tid,
-                        root_pid,
-                        ref uaddr,
-                        ..
-                    } => {
-                        let key = StatsClosureKey::Wait {
-                            tid,
-                            root_pid,
-                            uaddr: uaddr.clone(),
-                        };
-                        let entry = self
-                            .stats_closure_events
-                            .entry(key)
-                            .or_insert(StatsClosureValue::Wait(None, None));
-                        if let StatsClosureValue::Wait(elapsed, _pending) = entry {
-                            *elapsed = Some(event);
-                        }
-                    }
-                    FutexBpfEvent::WaitPending {
-                        tid,
-                        root_pid,
-                        ref uaddr,
-                        ..
-                    } => {
-                        let key = StatsClosureKey::Wait {
-                            tid,
-                            root_pid,
-                            uaddr: uaddr.clone(),
-                        };
-                        let entry = self
-                            .stats_closure_events
-                            .entry(key)
-                            .or_insert(StatsClosureValue::Wait(None, None));
-                        if let StatsClosureValue::Wait(_elapsed, pending) = entry {
-                            *pending = Some(event);
-                        }
-                    }
-                    FutexBpfEvent::Wake {
-                        tid,
-                        root_pid,
-                        ref uaddr,
-                        ..
-                    } => {
-                        let key = StatsClosureKey::Wake {
-                            tid,
-                            root_pid,
-                            uaddr: uaddr.clone(),
-                        };
-                        self.stats_closure_events
-                            .entry(key)
-                            .or_insert(StatsClosureValue::Wake(event));
-                    }
-                    FutexBpfEvent::NoOp => {}
-                }
-            }
-        }
-        Ok(self.events.len())
+    pub fn custom_reader<R: Read + Send + 'static>(
+        reader: R,
+        terminate_flag: Arc<Mutex<bool>>,
+    ) -> Result<Self> {
+        Ok(Self::with_reader(
+            FutexDecoder::default(),
+            reader,
+            terminate_flag,
+        ))
     }
 
     pub fn take_futex_events(&mut self, tid: usize) -> Result<Vec<FutexEvent>> {
-        let res = self.poll_events();
-        let events = self.events.remove(&tid).unwrap_or(Vec::new());
-        match (res, events.len() > 0) {
-            (_, true) => Ok(events),
-            (Ok(_), false) => Ok(events),
-            (Err(e), false) => Err(e),
-        }
+        self.take_tid_events(tid)
     }
 
-    pub fn take_new_pid_events(&mut self) -> Result<Vec<(Rc<str>, usize)>> {
+    pub fn take_new_pid_events(&mut self) -> Result<Vec<FutexNewProcess>> {
         self.poll_events()?;
-        Ok(self.new_pids.take().unwrap_or(Vec::new()))
-    }
-}
-
-impl Drop for FutexProgram {
-    fn drop(&mut self) {
-        if let None = self.child {
-            return;
-        }
-
-        if let Err(why) = self.child.as_mut().unwrap().kill() {
-            println!("Failed to kill futex {}", why);
-        }
+        Ok(mem::take(&mut self.decoder_mut().new_pids))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{FutexBpfEvent, FutexProgram};
+    use super::{FutexBpfEvent, FutexNewProcess, FutexProgram};
+    use crate::execute::record::BpfRecord;
     use eyre::{eyre, Result};
 
+    fn record(line: &str) -> BpfRecord {
+        BpfRecord::decode(line.as_bytes()).remove(0)
+    }
+
     mod futex_event {
         use super::*;
 
         #[test]
         fn trace_map_stats_start() {
             let line = "=> start map statistics";
-            let event = FutexBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = FutexBpfEvent::from(BpfRecord::Printf(line.into()));
             assert_eq!(event, FutexBpfEvent::MapStatsStart);
         }
 
         #[test]
         fn trace_map_stats_end() {
             let line = "=> end map statistics";
-            let event = FutexBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = FutexBpfEvent::from(BpfRecord::Printf(line.into()));
             assert_eq!(event, FutexBpfEvent::MapStatsEnd);
         }
 
         #[test]
         fn trace_sample_instant() {
             let line = "SampleInstant  	65383570923944";
-            let event = FutexBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = FutexBpfEvent::from(BpfRecord::Printf(line.into()));
             assert_eq!(
                 event,
                 FutexBpfEvent::SampleInstant {
@@ -654,10 +519,25 @@ mod tests {
             );
         }
 
+        #[test]
+        fn trace_new_process() {
+            let line = "NewProcess     \t3021\t0x7c3dd4f85fb0\t8877\tredis\tserver";
+            let event = FutexBpfEvent::from(BpfRecord::Printf(line.into()));
+            assert_eq!(
+                event,
+                FutexBpfEvent::NewProcess(FutexNewProcess {
+                    comm: "redis\tserver".into(),
+                    pid: 3021,
+                    uaddr: "0x7c3dd4f85fb0".into(),
+                    root_pid: 8877,
+                })
+            );
+        }
+
         #[test]
         fn map_wait_elapsed() -> Result<()> {
-            let line = "@wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)";
-            let event = FutexBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}"#;
+            let event = FutexBpfEvent::from(record(line));
             if let FutexBpfEvent::WaitElapsed {
                 tid,
                 root_pid,
@@ -679,8 +559,8 @@ mod tests {
 
         #[test]
         fn map_wait_pending() -> Result<()> {
-            let line = "@wait_pending[8955]: (65384418811815, 8877, 0x7c3dd4f85fb0)";
-            let event = FutexBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@wait_pending": {"8955": [65384418811815, 8877, "0x7c3dd4f85fb0"]}}}"#;
+            let event = FutexBpfEvent::from(record(line));
             if let FutexBpfEvent::WaitPending {
                 tid,
                 root_pid,
@@ -701,8 +581,8 @@ mod tests {
 
         #[test]
         fn map_wake() -> Result<()> {
-            let line = "@wake[8986, 8877, 0x7c3cfc00560c]: 1";
-            let event = FutexBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@wake": {"8986, 8877, 0x7c3cfc00560c": 1}}}"#;
+            let event = FutexBpfEvent::from(record(line));
             if let FutexBpfEvent::Wake {
                 tid,
                 root_pid,
@@ -720,6 +600,20 @@ mod tests {
 
             Ok(())
         }
+
+        #[test]
+        fn malformed() {
+            for line in [
+                r#"{"type": "map", "data": {"@wake": {"8986, 0x7c3cfc00560c": 1}}}"#,
+                r#"{"type": "map", "data": {"@wait_pending": {"8955": [65384418811815]}}}"#,
+                r#"{"type": "printf", "data": "SampleInstant  \t-1\n"}"#,
+                r#"{"type": "printf", "data": "NewProcess     \t3021\tredis-server\n"}"#,
+                r#"{"type": "printf", "data": "=> start map"#,
+            ] {
+                let event = FutexBpfEvent::from(record(line));
+                assert!(matches!(event, FutexBpfEvent::Unexpected { .. }));
+            }
+        }
     }
 
     mod futex_program {
@@ -738,14 +632,14 @@ mod tests {
         fn single_wait_elapsed() -> Result<()> {
             let (rx, mut tx) = programs::pipe();
             let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
-            let bpf_content = indoc! {"
-                HEADER 
-
-                => start map statistics
-                @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)
-                SampleInstant  	65384570945103
-                => end map statistics
-            "};
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write(bpf_content.as_bytes())?;
             while let Ok(0) = program.poll_events() {}
 
@@ -774,15 +668,15 @@ mod tests {
         fn single_wait_elapsed_and_pending() -> Result<()> {
             let (rx, mut tx) = programs::pipe();
             let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
-            let bpf_content = indoc! {"
-                HEADER 
-
-                => start map statistics
-                @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)
-                @wait_pending[8955]: (65384418811815, 8877, 0x7c3dd4f85fb0)
-                SampleInstant  	65384570945103
-                => end map statistics
-            "};
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+                {"type": "map", "data": {"@wait_pending": {"8955": [65384418811815, 8877, "0x7c3dd4f85fb0"]}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write(bpf_content.as_bytes())?;
             while let Ok(0) = program.poll_events() {}
 
@@ -811,14 +705,14 @@ mod tests {
         fn single_pending() -> Result<()> {
             let (rx, mut tx) = programs::pipe();
             let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
-            let bpf_content = indoc! {"
-                HEADER 
-
-                => start map statistics
-                @wait_pending[8955]: (65384418811815, 8877, 0x7c3dd4f85fb0)
-                SampleInstant  	65384570945103
-                => end map statistics
-            "};
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wait_pending": {"8955": [65384418811815, 8877, "0x7c3dd4f85fb0"]}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write(bpf_content.as_bytes())?;
             while let Ok(0) = program.poll_events() {}
 
@@ -847,14 +741,14 @@ mod tests {
         fn single_wake() -> Result<()> {
             let (rx, mut tx) = programs::pipe();
             let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
-            let bpf_content = indoc! {"
-                HEADER 
-
-                => start map statistics
-                @wake[8986, 8877, 0x7c3cfc00560c]: 1
-                SampleInstant  	65384570945103
-                => end map statistics
-            "};
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wake": {"8986, 8877, 0x7c3cfc00560c": 1}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write(bpf_content.as_bytes())?;
             while let Ok(0) = program.poll_events() {}
 
@@ -882,21 +776,21 @@ mod tests {
         fn two_consecutive_map_stat_closures() -> Result<()> {
             let (rx, mut tx) = programs::pipe();
             let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
-            let bpf_content = indoc! {"
-                HEADER 
-
-                => start map statistics
-                @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)
-                @wait_pending[8955]: (65384418811815, 8877, 0x7c3dd4f85fb0)
-                SampleInstant  	65384570945103
-                => end map statistics
-
-                => start map statistics
-                @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (748486373, 4)
-                @wait_pending[8955]: (65385319694788, 8877, 0x7c3dd4f85fb0)
-                SampleInstant  	65385570860594
-                => end map statistics
-            "};
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+                {"type": "map", "data": {"@wait_pending": {"8955": [65384418811815, 8877, "0x7c3dd4f85fb0"]}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [748486373, 4]}}}
+                {"type": "map", "data": {"@wait_pending": {"8955": [65385319694788, 8877, "0x7c3dd4f85fb0"]}}}
+                {"type": "printf", "data": "SampleInstant  \t65385570860594\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write(bpf_content.as_bytes())?;
             while let Ok(0) = program.poll_events() {}
 
@@ -946,5 +840,64 @@ mod tests {
 
             Ok(())
         }
+
+        #[test]
+        fn reader_disconnected() -> Result<()> {
+            let (rx, mut tx) = programs::pipe();
+            let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wake": {"8955, 8877, 0x7c3dd4f85fb0": 2}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
+            tx.write_all(bpf_content.as_bytes())?;
+            drop(tx);
+            while let Ok(0) = program.poll_events() {}
+
+            // The exited bpftrace program is restarted by the executor, so
+            // polling the disconnected reader is not an error
+            assert!(program.poll_events().is_ok());
+            assert_eq!(program.take_futex_events(8955)?.len(), 1);
+            assert!(program.take_futex_events(8955)?.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn truncated_and_out_of_order_closures() -> Result<()> {
+            let (rx, mut tx) = programs::pipe();
+            let mut program = FutexProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wake": {"8955, 8877, 0x7c3dd4f85fb0": 2}}}
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wake": {"8955, 8877, 0x7c3dd4f85fb0": 3}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+                {"type": "lost_events", "data": {"events": 42}}
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wake": {"8955, 8877, 0x7c3dd4f85fb0": 4}}}
+                {"type": "printf", "data": "SampleInstant  \t65384000000000\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
+            tx.write_all(bpf_content.as_bytes())?;
+            while let Ok(0) = program.poll_events() {}
+            program.poll_events()?;
+
+            let events = program.take_futex_events(8955)?;
+            assert_eq!(events.len(), 1);
+            assert!(matches!(events[0], FutexEvent::Wake { count: 3, .. }));
+
+            let counters = program.diagnostics().take();
+            assert_eq!(counters.truncated_closures, 2);
+            assert_eq!(counters.out_of_order_instants, 1);
+            assert_eq!(counters.lost_events, 42);
+            Ok(())
+        }
     }
 }
@@ -1,22 +1,14 @@
 use eyre::{eyre, Result};
-use lazy_static::lazy_static;
-use regex::Regex;
-use std::collections::HashMap;
-use std::os::unix::prelude::*;
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
-use std::sync::{Arc, Mutex};
 use std::{
     io::prelude::*,
-    mem,
-    process::{Child, Command},
-    thread,
+    sync::{Arc, Mutex},
 };
 
-use crate::execute::BpfReader;
-
-lazy_static! {
-    static ref REGEX_PATTERN: Regex = Regex::new(r"^@(\w+)\[(.*)\]: (.*)$").unwrap();
-}
+use super::{
+    stats::{Interval, Route, RoutedEvents, StatsDecoder, StatsProgram, StatsRecord},
+    Launcher,
+};
+use crate::execute::record::{BpfRecord, Fields};
 
 #[derive(Debug)]
 pub enum IowaitEvent {
@@ -80,13 +72,17 @@ pub enum IowaitBpfEvent {
 }
 
 impl IowaitBpfEvent {
-    fn parse_line(event_string: &str) -> Result<Self> {
-        if event_string.starts_with("@") {
-            Self::from_summary_stats_string(event_string)
-        } else if event_string.starts_with("=>") {
-            Self::from_stats_closure_string(event_string)
-        } else {
-            Self::from_trace_string(event_string)
+    fn parse_record(record: &BpfRecord) -> Result<Self> {
+        match record {
+            BpfRecord::Printf(line) if line.starts_with("=>") => {
+                Self::from_stats_closure_string(line)
+            }
+            BpfRecord::Printf(line) => Self::from_trace_string(line),
+            BpfRecord::MapEntry { map, key, value } => Self::from_map_entry(map, key, value),
+            BpfRecord::Malformed(data) => Ok(Self::Unexpected { data: data.clone() }),
+            BpfRecord::AttachedProbes(_) | BpfRecord::LostEvents(_) | BpfRecord::Other => {
+                Ok(Self::NoOp)
+            }
         }
     }
 
@@ -101,10 +97,10 @@ impl IowaitBpfEvent {
     }
 
     fn from_trace_string(event_string: &str) -> Result<Self> {
-        let mut elements = event_string.split_whitespace();
-        let event = match elements.next().unwrap() {
+        let mut elements = Fields::new(event_string.split_whitespace());
+        let event = match elements.field()? {
             "SampleInstant" => Self::SampleInstant {
-                ns_since_boot: elements.next().unwrap().parse().unwrap(),
+                ns_since_boot: elements.parse()?,
             },
             _ => {
                 return Err(eyre!("Invalid trace data"));
@@ -114,285 +110,163 @@ impl IowaitBpfEvent {
         Ok(event)
     }
 
-    fn from_summary_stats_string(event_string: &str) -> Result<Self> {
-        let captures = REGEX_PATTERN
-            .captures(&event_string)
-            .ok_or(eyre!("Unexpected event string"))?;
-        let mut cap_iter = captures.iter();
-        cap_iter.next();
-
-        let map_type = cap_iter.next().unwrap().unwrap().as_str();
-        match map_type {
-            "completed" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-
-                Ok(Self::MapCompleted {
-                    part0: key_elements.next().unwrap().parse().unwrap(),
-                    device: key_elements.next().unwrap().parse().unwrap(),
-                    tid: key_elements.next().unwrap().parse().unwrap(),
-                    pid: key_elements.next().unwrap().parse().unwrap(),
-                    sector_cnt: value.parse().unwrap(),
-                })
-            }
-            "pending" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-                let value = &value[1..value.len() - 1];
-                let mut value_elements = value.split(", ");
-
-                Ok(Self::MapPending {
-                    part0: key_elements.next().unwrap().parse().unwrap(),
-                    device: key_elements.next().unwrap().parse().unwrap(),
-                    sector: key_elements.next().unwrap().parse().unwrap(),
-                    is_write: key_elements.next().unwrap().parse::<i8>().unwrap() > 0,
-                    op: key_elements.next().unwrap().parse().unwrap(),
-                    status: key_elements.next().unwrap().parse().unwrap(),
-                    ns_since_boot: value_elements.next().unwrap().parse().unwrap(),
-                    tid: value_elements.next().unwrap().parse().unwrap(),
-                    pid: value_elements.next().unwrap().parse().unwrap(),
-                    sector_cnt: value_elements.next().unwrap().parse().unwrap(),
-                })
-            }
+    fn from_map_entry(map: &str, key: &[String], value: &[String]) -> Result<Self> {
+        let mut key_elements = Fields::new(key.iter().map(String::as_str));
+        let mut value_elements = Fields::new(value.iter().map(String::as_str));
+        match map {
+            "completed" => Ok(Self::MapCompleted {
+                part0: key_elements.parse()?,
+                device: key_elements.parse()?,
+                tid: key_elements.parse()?,
+                pid: key_elements.parse()?,
+                sector_cnt: value_elements.parse()?,
+            }),
+            "pending" => Ok(Self::MapPending {
+                part0: key_elements.parse()?,
+                device: key_elements.parse()?,
+                sector: key_elements.parse()?,
+                is_write: key_elements.parse::<i8>()? > 0,
+                op: key_elements.parse()?,
+                status: key_elements.parse()?,
+                ns_since_boot: value_elements.parse()?,
+                tid: value_elements.parse()?,
+                pid: value_elements.parse()?,
+                sector_cnt: value_elements.parse()?,
+            }),
             _ => Err(eyre!("Invalid map type")),
         }
     }
 }
 
-impl From<Vec<u8>> for IowaitBpfEvent {
-    fn from(value: Vec<u8>) -> Self {
-        let event_string = String::from_utf8(value).unwrap();
-        if event_string.len() == 0 {
-            return Self::NoOp {};
-        }
-        Self::parse_line(&event_string).unwrap_or(Self::Unexpected { data: event_string })
+impl From<BpfRecord> for IowaitBpfEvent {
+    fn from(record: BpfRecord) -> Self {
+        Self::parse_record(&record).unwrap_or_else(|_| Self::Unexpected {
+            data: format!("{:?}", record),
+        })
     }
 }
 
 #[derive(PartialEq, Eq, Hash)]
-struct StatsClosureKey {
+pub struct StatsClosureKey {
     part0: u32,
     device: u32,
     pid: usize,
     tid: usize,
 }
 
-pub struct IOWaitProgram {
-    child: Option<Child>,
-    header_lines: u8,
-    current_event: Option<Vec<u8>>,
-    events: Option<Vec<IowaitEvent>>,
-    stats_closure_events: HashMap<StatsClosureKey, usize>,
-    sample_instant_ns: Option<u64>,
-    rx: Receiver<Arc<[u8]>>,
-}
-
-impl IOWaitProgram {
-    pub fn new(terminate_flag: Arc<Mutex<bool>>) -> Result<Self> {
-        let (tx, rx) = std::sync::mpsc::channel();
-
-        let (bpf_pipe_rx, bpf_pipe_tx) = super::pipe();
-        let res = unsafe { libc::fcntl(bpf_pipe_rx.as_raw_fd(), libc::F_SETPIPE_SZ, 1048576) };
-        if res != 0 {
-            println!("Non-zero fcntl return {:?}", res);
-        }
-        let res = unsafe { libc::fcntl(bpf_pipe_tx.as_raw_fd(), libc::F_SETPIPE_SZ, 1048576) };
-        if res != 0 {
-            println!("Non-zero fcntl return {:?}", res);
+/// Decodes the output of the iowait program, whose requests are collected
+/// system-wide and thus routed as global events.
+pub struct IowaitDecoder;
+
+impl StatsDecoder for IowaitDecoder {
+    const PROGRAM: &'static str = "iowait";
+
+    type Entry = IowaitBpfEvent;
+    type Trace = IowaitBpfEvent;
+    type Key = StatsClosureKey;
+    type Value = usize;
+    type Event = IowaitEvent;
+
+    fn decode(&self, record: BpfRecord) -> StatsRecord<IowaitBpfEvent, IowaitBpfEvent> {
+        match IowaitBpfEvent::from(record) {
+            IowaitBpfEvent::NoOp => StatsRecord::NoOp,
+            IowaitBpfEvent::MapStatsStart => StatsRecord::MapStatsStart,
+            IowaitBpfEvent::SampleInstant { ns_since_boot } => {
+                StatsRecord::SampleInstant(ns_since_boot)
+            }
+            IowaitBpfEvent::MapStatsEnd => StatsRecord::MapStatsEnd,
+            IowaitBpfEvent::Unexpected { data } => StatsRecord::Unexpected(data),
+            event @ (IowaitBpfEvent::MapCompleted { .. } | IowaitBpfEvent::MapPending { .. }) => {
+                StatsRecord::Entry(event)
+            }
         }
-        let child = Command::new("bpftrace")
-            .args(["./metric-collector/src/bpf/io_wait.bt"])
-            .stdout(bpf_pipe_tx)
-            .spawn()?;
-        Self::start_bpf_reader(tx, bpf_pipe_rx, terminate_flag);
-        Ok(Self {
-            rx,
-            child: Some(child),
-            header_lines: 0,
-            current_event: None,
-            stats_closure_events: HashMap::new(),
-            sample_instant_ns: None,
-            events: None,
-        })
     }
 
-    fn start_bpf_reader<R>(
-        tx: Sender<Arc<[u8]>>,
-        mut bpf_pipe_rx: R,
-        terminate_flag: Arc<Mutex<bool>>,
-    ) where
-        R: Read + Send + 'static,
-    {
-        thread::Builder::new()
-            .name("iowait_recv".to_string())
-            .spawn(move || loop {
-                if *terminate_flag.lock().unwrap() == true {
-                    break;
-                }
-                let mut buf: [u8; 65536] = [0; 65536];
-                let res = bpf_pipe_rx.read(&mut buf);
-                if let Ok(bytes) = res {
-                    if bytes == 0 {
-                        break;
-                    }
-
-                    if let Err(_) = tx.send(Arc::from(&buf[..bytes])) {
-                        break;
-                    };
-                }
-            })
-            .unwrap();
+    fn key(&self, entry: &IowaitBpfEvent) -> StatsClosureKey {
+        match *entry {
+            IowaitBpfEvent::MapCompleted {
+                pid,
+                tid,
+                part0,
+                device,
+                ..
+            }
+            | IowaitBpfEvent::MapPending {
+                pid,
+                tid,
+                part0,
+                device,
+                ..
+            } => StatsClosureKey {
+                pid,
+                tid,
+                part0,
+                device,
+            },
+            _ => unreachable!("Only map entries are decoded as entries"),
+        }
     }
-}
 
-impl BpfReader for IOWaitProgram {
-    fn header_read(&self) -> bool {
-        self.header_lines == 1
+    fn accumulate(&self, value: Option<usize>, entry: IowaitBpfEvent) -> usize {
+        let sector_cnt = match entry {
+            IowaitBpfEvent::MapCompleted { sector_cnt, .. }
+            | IowaitBpfEvent::MapPending { sector_cnt, .. } => sector_cnt,
+            _ => 0,
+        };
+        value.unwrap_or(0) + sector_cnt
     }
 
-    fn header_lines_get_mut(&mut self) -> &mut u8 {
-        &mut self.header_lines
+    fn snapshot(
+        &self,
+        key: StatsClosureKey,
+        value: usize,
+        interval: Interval,
+    ) -> Result<(Route, IowaitEvent)> {
+        let event = IowaitEvent::from_stats_closure_entry(key, value, interval.sample_instant_ns);
+        Ok((Route::Global, event))
     }
 
-    fn current_event_as_mut(&mut self) -> Option<&mut Vec<u8>> {
-        self.current_event.as_mut()
+    fn trace(
+        &mut self,
+        trace: IowaitBpfEvent,
+        _events: &mut RoutedEvents<IowaitEvent>,
+    ) -> Result<()> {
+        println!("Unexpected iowait event. {:?}", trace);
+        Ok(())
     }
+}
+
+pub type IOWaitProgram = StatsProgram<IowaitDecoder>;
 
-    fn set_current_event(&mut self, val: Vec<u8>) {
-        self.current_event = Some(val);
+impl IOWaitProgram {
+    pub fn new(
+        launcher: &Launcher,
+        terminate_flag: Arc<Mutex<bool>>,
+        script: &str,
+        pipe_size: u32,
+    ) -> Result<Self> {
+        Self::launch(IowaitDecoder, launcher, &[script], terminate_flag, pipe_size)
     }
 
-    fn take_current_event(&mut self) -> Option<Vec<u8>> {
-        self.current_event.take()
+    pub fn restart(
+        &mut self,
+        launcher: &Launcher,
+        terminate_flag: Arc<Mutex<bool>>,
+        script: &str,
+        pipe_size: u32,
+    ) -> Result<()> {
+        self.relaunch(launcher, &[script], terminate_flag, pipe_size)
     }
-}
 
-impl IOWaitProgram {
     pub fn custom_reader<R: Read + Send + 'static>(
         reader: R,
         terminate_flag: Arc<Mutex<bool>>,
     ) -> Self {
-        let (tx, rx) = std::sync::mpsc::channel();
-        Self::start_bpf_reader(tx, reader, terminate_flag);
-        Self {
-            rx,
-            child: None,
-            header_lines: 0,
-            current_event: None,
-            stats_closure_events: HashMap::new(),
-            sample_instant_ns: None,
-            events: None,
-        }
-    }
-
-    pub fn poll_events(&mut self) -> Result<usize> {
-        loop {
-            let res = self.rx.try_recv();
-
-            let buf = match res {
-                Err(TryRecvError::Empty) => {
-                    break;
-                }
-                Err(e) => return Err(e.into()),
-                Ok(buf) => buf,
-            };
-
-            let mut iterator = buf.into_iter();
-            if !self.header_read() {
-                self.handle_header(&mut iterator);
-            }
-
-            while let Some(event) = self.handle_event(&mut iterator) {
-                let event = IowaitBpfEvent::from(event);
-                match event {
-                    IowaitBpfEvent::NoOp {} => {}
-                    IowaitBpfEvent::MapStatsStart {} => {}
-                    IowaitBpfEvent::MapStatsEnd {} => {
-                        let sample_instant_ns = self.sample_instant_ns.take().unwrap();
-                        let closure_events =
-                            mem::replace(&mut self.stats_closure_events, HashMap::new());
-                        closure_events.into_iter().for_each(|(key, value)| {
-                            let events = self.events.get_or_insert_with(|| Vec::new());
-                            events.push(IowaitEvent::from_stats_closure_entry(
-                                key,
-                                value,
-                                sample_instant_ns,
-                            ));
-                        });
-                    }
-                    IowaitBpfEvent::MapPending {
-                        pid,
-                        tid,
-                        part0,
-                        device,
-                        sector_cnt,
-                        ..
-                    } => {
-                        let key = StatsClosureKey {
-                            pid,
-                            tid,
-                            part0,
-                            device,
-                        };
-                        let entry = self.stats_closure_events.entry(key).or_insert(0);
-                        *entry += sector_cnt;
-                    }
-                    IowaitBpfEvent::MapCompleted {
-                        pid,
-                        tid,
-                        part0,
-                        device,
-                        sector_cnt,
-                    } => {
-                        let key = StatsClosureKey {
-                            pid,
-                            tid,
-                            part0,
-                            device,
-                        };
-                        let entry = self.stats_closure_events.entry(key).or_insert(0);
-                        *entry += sector_cnt;
-                    }
-                    IowaitBpfEvent::SampleInstant { ns_since_boot } => {
-                        self.sample_instant_ns = Some(ns_since_boot);
-                    }
-                    IowaitBpfEvent::Unexpected { data } => {
-                        println!("Unexpected iowait event. {:?}", data)
-                    }
-                }
-            }
-        }
-        Ok(self.events.as_ref().map_or(0, |events| events.len()))
+        Self::with_reader(IowaitDecoder, reader, terminate_flag)
     }
 
     pub fn take_events(&mut self) -> Result<Vec<IowaitEvent>> {
-        let res = self.poll_events();
-        match res {
-            Ok(_) => Ok(self.events.take().unwrap_or(Vec::new())),
-            Err(e) => {
-                let events = self.events.take();
-                if events.is_some() {
-                    Ok(events.unwrap())
-                } else {
-                    Err(e)
-                }
-            }
-        }
-    }
-}
-
-impl Drop for IOWaitProgram {
-    fn drop(&mut self) {
-        if let None = self.child {
-            return;
-        }
-
-        if let Err(why) = self.child.as_mut().unwrap().kill() {
-            println!("Failed to kill futex {}", why);
-        }
+        self.take_global_events()
     }
 }
 
@@ -400,19 +274,23 @@ impl Drop for IOWaitProgram {
 mod tests {
     mod iowaitbpfevents {
         use super::super::IowaitBpfEvent;
+        use crate::execute::record::BpfRecord;
         use eyre::{eyre, Result};
 
+        fn record(line: &str) -> BpfRecord {
+            BpfRecord::decode(line.as_bytes()).remove(0)
+        }
+
         #[test]
         fn no_op() {
-            let line = "";
-            let event = IowaitBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IowaitBpfEvent::from(BpfRecord::Other);
             assert_eq!(event, IowaitBpfEvent::NoOp {})
         }
 
         #[test]
         fn map_completed() -> Result<()> {
-            let line = "@completed[271581184, 271581187, 632, 631]: 22616";
-            let event = IowaitBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@completed": {"271581184, 271581187, 632, 631": 22616}}}"#;
+            let event = IowaitBpfEvent::from(record(line));
             if let IowaitBpfEvent::MapCompleted {
                 part0,
                 device,
@@ -434,9 +312,8 @@ mod tests {
 
         #[test]
         fn map_pending() -> Result<()> {
-            let line =
-                "@pending[271581184, 271581187, 1649015544, 1, 1, 0]: (1421282887324, 632, 631, 8)";
-            let event = IowaitBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@pending": {"271581184, 271581187, 1649015544, 1, 1, 0": [1421282887324, 632, 631, 8]}}}"#;
+            let event = IowaitBpfEvent::from(record(line));
             if let IowaitBpfEvent::MapPending {
                 ns_since_boot,
                 part0,
@@ -469,21 +346,21 @@ mod tests {
         #[test]
         fn map_stats_start() {
             let line = "=> start map statistics";
-            let event = IowaitBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IowaitBpfEvent::from(BpfRecord::Printf(line.into()));
             assert_eq!(event, IowaitBpfEvent::MapStatsStart {});
         }
 
         #[test]
         fn map_stats_end() {
             let line = "=> end map statistics";
-            let event = IowaitBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IowaitBpfEvent::from(BpfRecord::Printf(line.into()));
             assert_eq!(event, IowaitBpfEvent::MapStatsEnd {});
         }
 
         #[test]
         fn sample_instant() {
             let line = "SampleInstant   1421348499285";
-            let event = IowaitBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IowaitBpfEvent::from(BpfRecord::Printf(line.into()));
             assert_eq!(
                 event,
                 IowaitBpfEvent::SampleInstant {
@@ -491,5 +368,17 @@ mod tests {
                 }
             );
         }
+
+        #[test]
+        fn malformed() {
+            for line in [
+                r#"{"type": "map", "data": {"@completed": {"271581184, 271581187, 632": 22616}}}"#,
+                r#"{"type": "map", "data": {"@pending": {"271581184, 271581187, 1649015544, 1, 1, 0": [1421282887324, 632]}}}"#,
+                r#"{"type": "printf", "data": "SampleInstant  \t0x1f\n"}"#,
+            ] {
+                let event = IowaitBpfEvent::from(record(line));
+                assert!(matches!(event, IowaitBpfEvent::Unexpected { .. }));
+            }
+        }
     }
 }
@@ -1,28 +1,19 @@
 use eyre::{eyre, Result};
-use lazy_static::lazy_static;
-use regex::Regex;
 use std::{
-    collections::HashMap,
     io::Read,
     mem,
     net::{Ipv4Addr, Ipv6Addr},
-    process::{Child, Command},
     rc::Rc,
-    str::FromStr,
-    sync::{
-        mpsc::{Receiver, Sender, TryRecvError},
-        Arc, Mutex,
-    },
-    thread,
+    sync::{Arc, Mutex},
 };
 
-use crate::execute::BpfReader;
-
-lazy_static! {
-    static ref REGEX_PATTERN: Regex = Regex::new(r"^@(\w+)\[(.*)\]: (.*)$").unwrap();
-}
+use super::{
+    stats::{Interval, Route, RoutedEvents, StatsDecoder, StatsProgram, StatsRecord},
+    Launcher,
+};
+use crate::execute::record::{BpfRecord, Fields};
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Connection {
     Ipv4 {
         src_host: Ipv4Addr,
@@ -43,11 +34,12 @@ pub enum Connection {
 }
 
 #[derive(Debug, Clone)]
-enum IpcBpfEvent {
+pub enum IpcBpfEvent {
     NoOp,
     NewProcess {
         comm: Rc<str>,
         pid: usize,
+        resource: Rc<str>,
     },
     NewSocketMap {
         fs_type: Rc<str>,
@@ -153,90 +145,55 @@ enum IpcBpfEvent {
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum TargetFile {
-    AnonInode { name: Rc<str>, address: u64 },
+    AnonInode { name: Arc<str>, address: u64 },
     Inode { device: u32, inode_id: u64 },
     Epoll { address: u64 },
 }
 
 impl IpcBpfEvent {
-    fn parse_line(event_string: &str) -> Result<Self> {
-        if event_string.starts_with("@") {
-            Self::from_summary_stats_string(event_string)
-        } else if event_string.starts_with("=>") {
-            Self::from_stats_closure_string(event_string)
-        } else {
-            Self::from_trace_string(event_string)
-        }
-    }
-
-    fn from_summary_stats_string(event_string: &str) -> Result<Self> {
-        let captures = REGEX_PATTERN
-            .captures(&event_string)
-            .ok_or(eyre!("Unexpected event string"))?;
-        let mut cap_iter = captures.iter();
-        cap_iter.next();
-
-        let map_type = cap_iter.next().unwrap().unwrap().as_str();
-        match map_type {
-            "inode_map" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-                let value = &value[1..value.len() - 1];
-                let mut value_elements = value.split(", ");
-
-                let total_ns = value_elements.next().unwrap().parse().unwrap();
-                let count = value_elements.next().unwrap().parse().unwrap();
-
-                Ok(Self::InodeMapCached {
-                    comm: Rc::from(key_elements.next().unwrap()),
-                    tid: key_elements.next().unwrap().parse().unwrap(),
-                    fs_type: Rc::from(key_elements.next().unwrap().trim()),
-                    sb_id: key_elements.next().unwrap().parse().unwrap(),
-                    inode_id: key_elements.next().unwrap().parse().unwrap(),
-                    total_ns,
-                    count,
-                })
+    fn parse_record(record: &BpfRecord) -> Result<Self> {
+        match record {
+            BpfRecord::Printf(line) if line.starts_with("=>") => {
+                Self::from_stats_closure_string(line)
             }
-            "inode_pending" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-                let mut key_elements = key.split(", ");
-
-                Ok(Self::InodeMapPending {
-                    comm: Rc::from(key_elements.next().unwrap()),
-                    tid: key_elements.next().unwrap().parse().unwrap(),
-                    fs_type: Rc::from(key_elements.next().unwrap().trim()),
-                    sb_id: key_elements.next().unwrap().parse().unwrap(),
-                    inode_id: key_elements.next().unwrap().parse().unwrap(),
-                    ns_since_boot: value.parse().unwrap(),
-                })
+            BpfRecord::Printf(line) => Self::from_trace_string(line),
+            BpfRecord::MapEntry { map, key, value } => Self::from_map_entry(map, key, value),
+            BpfRecord::Malformed(data) => Ok(Self::Unexpected { data: data.clone() }),
+            BpfRecord::AttachedProbes(_) | BpfRecord::LostEvents(_) | BpfRecord::Other => {
+                Ok(Self::NoOp)
             }
-            "epoll_map" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-
-                let event_poll = key.trim_start_matches("0x");
-                let event_poll = u64::from_str_radix(event_poll, 16).unwrap();
-
-                Ok(Self::EpollMapCached {
-                    event_poll,
-                    total_ns: value.parse().unwrap(),
-                })
-            }
-            "epoll_pending" => {
-                let key = cap_iter.next().unwrap().unwrap().as_str();
-                let value = cap_iter.next().unwrap().unwrap().as_str();
-
-                let event_poll = key.trim_start_matches("0x");
-                let event_poll = u64::from_str_radix(event_poll, 16).unwrap();
+        }
+    }
 
-                Ok(Self::EpollMapPending {
-                    event_poll,
-                    ns_since_boot: value.parse().unwrap(),
-                })
-            }
+    fn from_map_entry(map: &str, key: &[String], value: &[String]) -> Result<Self> {
+        let mut key_elements = Fields::new(key.iter().map(String::as_str));
+        let mut value_elements = Fields::new(value.iter().map(String::as_str));
+        match map {
+            "inode_map" => Ok(Self::InodeMapCached {
+                tid: key_elements.parse()?,
+                fs_type: Rc::from(key_elements.field()?.trim()),
+                sb_id: key_elements.parse()?,
+                inode_id: key_elements.parse()?,
+                total_ns: value_elements.parse()?,
+                count: value_elements.parse()?,
+                comm: Rc::from(value_elements.field()?),
+            }),
+            "inode_pending" => Ok(Self::InodeMapPending {
+                tid: key_elements.parse()?,
+                fs_type: Rc::from(key_elements.field()?.trim()),
+                sb_id: key_elements.parse()?,
+                inode_id: key_elements.parse()?,
+                ns_since_boot: value_elements.parse()?,
+                comm: Rc::from(value_elements.field()?),
+            }),
+            "epoll_map" => Ok(Self::EpollMapCached {
+                event_poll: key_elements.hex()?,
+                total_ns: value_elements.parse()?,
+            }),
+            "epoll_pending" => Ok(Self::EpollMapPending {
+                event_poll: key_elements.hex()?,
+                ns_since_boot: value_elements.parse()?,
+            }),
             _ => Err(eyre!("Invalid map type")),
         }
     }
@@ -251,44 +208,58 @@ impl IpcBpfEvent {
         }
     }
 
+    fn connection(elements: &mut Fields) -> Result<Connection> {
+        let conn = match elements.field()? {
+            "AF_INET" => Connection::Ipv4 {
+                src_host: elements.parse()?,
+                src_port: elements.parse()?,
+                dst_host: elements.parse()?,
+                dst_port: elements.parse()?,
+            },
+            "AF_INET6" => Connection::Ipv6 {
+                src_host: elements.parse()?,
+                src_port: elements.parse()?,
+                dst_host: elements.parse()?,
+                dst_port: elements.parse()?,
+            },
+            "AF_UNIX" => Connection::Unix {
+                src_address: elements.hex()?,
+                dst_address: elements.hex()?,
+            },
+            _ => return Err(eyre!("Unexpected socket family type")),
+        };
+        Ok(conn)
+    }
+
+    fn target_file(fs: &str, elements: &mut Fields) -> Result<TargetFile> {
+        let target_file = if fs == "anon_inodefs" {
+            TargetFile::AnonInode {
+                name: Arc::from(elements.field()?),
+                address: elements.hex()?,
+            }
+        } else {
+            TargetFile::Inode {
+                device: elements.parse()?,
+                inode_id: elements.parse()?,
+            }
+        };
+        Ok(target_file)
+    }
+
     fn from_trace_string(event_string: &str) -> Result<Self> {
-        let mut elements = event_string.split("\t");
-        let event = match elements.next().unwrap().trim() {
+        let mut elements = Fields::new(event_string.split('\t'));
+        let event = match elements.field()?.trim() {
             "AcceptStart" => Self::AcceptStart,
             "AcceptEnd" => {
-                let comm = Rc::from(elements.next().unwrap());
-                let tid = elements.next().unwrap().parse().unwrap();
-                let fs_type = Rc::from(elements.next().unwrap());
-                let sb_id = elements.next().unwrap().parse().unwrap();
-                let inode_id = elements.next().unwrap().parse().unwrap();
-                let conn = match elements.next().unwrap() {
-                    "AF_INET" => Connection::Ipv4 {
-                        src_host: Ipv4Addr::from_str(elements.next().unwrap()).unwrap(),
-                        src_port: elements.next().unwrap().parse().unwrap(),
-                        dst_host: Ipv4Addr::from_str(elements.next().unwrap()).unwrap(),
-                        dst_port: elements.next().unwrap().parse().unwrap(),
-                    },
-                    "AF_INET6" => Connection::Ipv6 {
-                        src_host: Ipv6Addr::from_str(elements.next().unwrap()).unwrap(),
-                        src_port: elements.next().unwrap().parse().unwrap(),
-                        dst_host: Ipv6Addr::from_str(elements.next().unwrap()).unwrap(),
-                        dst_port: elements.next().unwrap().parse().unwrap(),
-                    },
-                    "AF_UNIX" => Connection::Unix {
-                        src_address: u64::from_str_radix(
-                            elements.next().unwrap().trim_start_matches("0x"),
-                            16,
-                        )
-                        .unwrap(),
-                        dst_address: u64::from_str_radix(
-                            elements.next().unwrap().trim_start_matches("0x"),
-                            16,
-                        )?,
-                    },
-                    _ => return Err(eyre!("Unexpected socket family type")),
-                };
+                let tid = elements.parse()?;
+                let fs_type = Rc::from(elements.field()?);
+                let sb_id = elements.parse()?;
+                let inode_id = elements.parse()?;
+                let conn = Self::connection(&mut elements)?;
+                // The instant the connection was accepted
+                elements.field()?;
                 Self::AcceptEnd {
-                    comm,
+                    comm: Rc::from(elements.rest()?),
                     tid,
                     fs_type,
                     sb_id,
@@ -298,40 +269,16 @@ impl IpcBpfEvent {
             }
             "ConnectStart" => Self::ConnectStart,
             "ConnectEnd" => {
-                let comm = Rc::from(elements.next().unwrap());
-                let tid = elements.next().unwrap().parse().unwrap();
-                let fs_type = Rc::from(elements.next().unwrap());
-                let sb_id = elements.next().unwrap().parse().unwrap();
-                let inode_id = elements.next().unwrap().parse().unwrap();
-                let conn = match elements.next().unwrap() {
-                    "AF_INET" => Connection::Ipv4 {
-                        src_host: Ipv4Addr::from_str(elements.next().unwrap()).unwrap(),
-                        src_port: elements.next().unwrap().parse().unwrap(),
-                        dst_host: Ipv4Addr::from_str(elements.next().unwrap()).unwrap(),
-                        dst_port: elements.next().unwrap().parse().unwrap(),
-                    },
-                    "AF_INET6" => Connection::Ipv6 {
-                        src_host: Ipv6Addr::from_str(elements.next().unwrap()).unwrap(),
-                        src_port: elements.next().unwrap().parse().unwrap(),
-                        dst_host: Ipv6Addr::from_str(elements.next().unwrap()).unwrap(),
-                        dst_port: elements.next().unwrap().parse().unwrap(),
-                    },
-                    "AF_UNIX" => Connection::Unix {
-                        src_address: u64::from_str_radix(
-                            elements.next().unwrap().trim_start_matches("0x"),
-                            16,
-                        )
-                        .unwrap(),
-                        dst_address: u64::from_str_radix(
-                            elements.next().unwrap().trim_start_matches("0x"),
-                            16,
-                        )
-                        .unwrap(),
-                    },
-                    _ => return Err(eyre!("Unexpected socket family type")),
-                };
+                let tid = elements.parse()?;
+                let fs_type = Rc::from(elements.field()?);
+                let sb_id = elements.parse()?;
+                let inode_id = elements.parse()?;
+                let conn = Self::connection(&mut elements)?;
+                // The instant the connection was established, and its latency
+                elements.field()?;
+                elements.field()?;
                 Self::ConnectEnd {
-                    comm,
+                    comm: Rc::from(elements.rest()?),
                     tid,
                     fs_type,
                     sb_id,
@@ -340,145 +287,79 @@ impl IpcBpfEvent {
                 }
             }
             "EpollAdd" => {
-                let comm = Rc::from(elements.next().unwrap());
-                let tid = elements.next().unwrap().parse().unwrap();
-                let event_poll = elements.next().unwrap().trim_start_matches("0x");
-                let event_poll = u64::from_str_radix(event_poll, 16).unwrap();
-                let fs: Rc<str> = Rc::from(elements.next().unwrap());
-                let target_file = if &*fs == "anon_inodefs" {
-                    let name = Rc::from(elements.next().unwrap());
-                    let address = elements.next().unwrap().trim_start_matches("0x");
-                    let address = u64::from_str_radix(address, 16).unwrap();
-                    TargetFile::AnonInode { name, address }
-                } else {
-                    TargetFile::Inode {
-                        device: elements.next().unwrap().parse().unwrap(),
-                        inode_id: elements.next().unwrap().parse().unwrap(),
-                    }
-                };
+                let tid = elements.parse()?;
+                let event_poll = elements.hex()?;
+                let fs: Rc<str> = Rc::from(elements.field()?);
+                let target_file = Self::target_file(&fs, &mut elements)?;
                 Self::EpollItemAdd {
-                    comm,
                     tid,
                     event_poll,
-                    fs,
                     target_file,
-                    ns_since_boot: elements.next().unwrap().parse().unwrap(),
-                    contrib_snapshot: elements.next().unwrap().parse().unwrap(),
+                    fs,
+                    ns_since_boot: elements.parse()?,
+                    contrib_snapshot: elements.parse()?,
+                    comm: Rc::from(elements.rest()?),
                 }
             }
             "EpollRemove" => {
-                let comm = Rc::from(elements.next().unwrap());
-                let tid = elements.next().unwrap().parse().unwrap();
-                let event_poll = elements.next().unwrap().trim_start_matches("0x");
-                let event_poll = u64::from_str_radix(event_poll, 16).unwrap();
-                let fs: Rc<str> = Rc::from(elements.next().unwrap());
-                let target_file = if &*fs == "anon_inodefs" {
-                    let name = Rc::from(elements.next().unwrap());
-                    let address = elements.next().unwrap().trim_start_matches("0x");
-                    let address = u64::from_str_radix(address, 16).unwrap();
-                    TargetFile::AnonInode { name, address }
-                } else {
-                    TargetFile::Inode {
-                        device: elements.next().unwrap().parse().unwrap(),
-                        inode_id: elements.next().unwrap().parse().unwrap(),
-                    }
-                };
+                let tid = elements.parse()?;
+                let event_poll = elements.hex()?;
+                let fs: Rc<str> = Rc::from(elements.field()?);
+                let target_file = Self::target_file(&fs, &mut elements)?;
                 Self::EpollItemRemove {
-                    comm,
                     tid,
                     event_poll,
-                    fs,
                     target_file,
-                    ns_since_boot: elements.next().unwrap().parse().unwrap(),
-                    contrib_snapshot: elements.next().unwrap().parse().unwrap(),
+                    fs,
+                    ns_since_boot: elements.parse()?,
+                    contrib_snapshot: elements.parse()?,
+                    comm: Rc::from(elements.rest()?),
                 }
             }
             "EpiPoll" => {
-                let comm = Rc::from(elements.next().unwrap());
-                let tid = elements.next().unwrap().parse().unwrap();
-                let event_poll = elements.next().unwrap().trim_start_matches("0x");
-                let event_poll = u64::from_str_radix(event_poll, 16).unwrap();
-                let fs: Rc<str> = Rc::from(elements.next().unwrap());
-                let target_file = if &*fs == "anon_inodefs" {
-                    let name = Rc::from(elements.next().unwrap());
-                    let address = elements.next().unwrap().trim_start_matches("0x");
-                    let address = u64::from_str_radix(address, 16).unwrap();
-                    TargetFile::AnonInode { name, address }
-                } else {
-                    TargetFile::Inode {
-                        device: elements.next().unwrap().parse().unwrap(),
-                        inode_id: elements.next().unwrap().parse().unwrap(),
-                    }
-                };
+                let tid = elements.parse()?;
+                let event_poll = elements.hex()?;
+                let fs: Rc<str> = Rc::from(elements.field()?);
+                let target_file = Self::target_file(&fs, &mut elements)?;
                 Self::EpollItem {
-                    comm,
                     tid,
                     event_poll,
-                    fs,
                     target_file,
-                    ns_since_boot: elements.next().unwrap().parse().unwrap(),
-                    contrib_snapshot: elements.next().unwrap().parse().unwrap(),
-                }
-            }
-            "NewSocketMap" => {
-                let fs_type = Rc::from(elements.next().unwrap());
-                let sb_id = elements.next().unwrap().parse().unwrap();
-                let inode_id = elements.next().unwrap().parse().unwrap();
-                let conn = match elements.next().unwrap() {
-                    "AF_INET" => Connection::Ipv4 {
-                        src_host: Ipv4Addr::from_str(elements.next().unwrap()).unwrap(),
-                        src_port: elements.next().unwrap().parse().unwrap(),
-                        dst_host: Ipv4Addr::from_str(elements.next().unwrap()).unwrap(),
-                        dst_port: elements.next().unwrap().parse().unwrap(),
-                    },
-                    "AF_INET6" => Connection::Ipv6 {
-                        src_host: Ipv6Addr::from_str(elements.next().unwrap()).unwrap(),
-                        src_port: elements.next().unwrap().parse().unwrap(),
-                        dst_host: Ipv6Addr::from_str(elements.next().unwrap()).unwrap(),
-                        dst_port: elements.next().unwrap().parse().unwrap(),
-                    },
-                    "AF_UNIX" => Connection::Unix {
-                        src_address: u64::from_str_radix(
-                            elements.next().unwrap().trim_start_matches("0x"),
-                            16,
-                        )
-                        .unwrap(),
-                        dst_address: u64::from_str_radix(
-                            elements.next().unwrap().trim_start_matches("0x"),
-                            16,
-                        )?,
-                    },
-                    _ => return Err(eyre!("Unexpected socket family type")),
-                };
-                Self::NewSocketMap {
-                    fs_type,
-                    sb_id,
-                    inode_id,
-                    conn,
+                    fs,
+                    ns_since_boot: elements.parse()?,
+                    contrib_snapshot: elements.parse()?,
+                    comm: Rc::from(elements.rest()?),
                 }
             }
+            "NewSocketMap" => Self::NewSocketMap {
+                fs_type: Rc::from(elements.field()?),
+                sb_id: elements.parse()?,
+                inode_id: elements.parse()?,
+                conn: Self::connection(&mut elements)?,
+            },
             "NewProcess" => Self::NewProcess {
-                comm: Rc::from(elements.next().unwrap()),
-                pid: elements.next().unwrap().parse().unwrap(),
+                pid: elements.parse()?,
+                resource: Rc::from(elements.field()?),
+                comm: Rc::from(elements.rest()?),
             },
             "SampleInstant" => Self::SampleInstant {
-                ns_since_boot: elements.next().unwrap().parse().unwrap(),
+                ns_since_boot: elements.parse()?,
             },
             "UnhandledFileMode" => Self::UnhandledFileMode {
-                comm: Rc::from(elements.next().unwrap()),
-                tid: elements.next().unwrap().parse().unwrap(),
-                fs: elements.next().unwrap().into(),
-                sb_id: elements.next().unwrap().parse().unwrap(),
-                inode_id: elements.next().unwrap().parse().unwrap(),
-                mode: u64::from_str_radix(elements.next().unwrap(), 16).unwrap(),
+                tid: elements.parse()?,
+                fs: elements.field()?.into(),
+                sb_id: elements.parse()?,
+                inode_id: elements.parse()?,
+                mode: u64::from_str_radix(elements.field()?.trim(), 16)?,
+                comm: Rc::from(elements.rest()?),
             },
             "UnhandledSockFam" => Self::UnhandledSockFam {
-                comm: Rc::from(elements.next().unwrap()),
-                tid: elements.next().unwrap().parse().unwrap(),
-                fs: elements.next().unwrap().into(),
-                sb_id: elements.next().unwrap().parse().unwrap(),
-                inode_id: elements.next().unwrap().parse().unwrap(),
-                family: elements.next().unwrap().parse().unwrap(),
+                tid: elements.parse()?,
+                fs: elements.field()?.into(),
+                sb_id: elements.parse()?,
+                inode_id: elements.parse()?,
+                family: elements.parse()?,
+                comm: Rc::from(elements.rest()?),
             },
             _ => {
                 return Err(eyre!("Invalid trace data"));
@@ -489,21 +370,22 @@ impl IpcBpfEvent {
     }
 }
 
-impl From<Vec<u8>> for IpcBpfEvent {
-    fn from(value: Vec<u8>) -> Self {
-        let event_string = String::from_utf8(value).unwrap();
-        if event_string.len() == 0 {
-            return Self::NoOp {};
-        }
-        Self::parse_line(&event_string).unwrap_or(Self::Unexpected { data: event_string })
+impl From<BpfRecord> for IpcBpfEvent {
+    fn from(record: BpfRecord) -> Self {
+        Self::parse_record(&record).unwrap_or_else(|_| Self::Unexpected {
+            data: format!("{:?}", record),
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum IpcEvent {
+    /// A process which used a file or socket of a monitored process, as
+    /// described by `resource`.
     NewProcess {
         comm: Rc<str>,
         pid: usize,
+        resource: Rc<str>,
     },
     NewSocketMap {
         fs_type: Rc<str>,
@@ -592,9 +474,9 @@ impl IpcEvent {
 
     fn from_stats_closure_entry(
         entry: (Option<IpcBpfEvent>, Option<IpcBpfEvent>),
-        current_instant_ns: u64,
-        previous_instant_ns: Option<u64>,
+        interval: Interval,
     ) -> Result<Self> {
+        let current_instant_ns = interval.sample_instant_ns;
         match entry {
             (
                 Some(IpcBpfEvent::InodeMapCached {
@@ -608,15 +490,7 @@ impl IpcEvent {
                 }),
                 Some(IpcBpfEvent::InodeMapPending { ns_since_boot, .. }),
             ) => {
-                let pending = if let Some(prev) = previous_instant_ns {
-                    if ns_since_boot > prev {
-                        current_instant_ns - ns_since_boot
-                    } else {
-                        current_instant_ns - prev
-                    }
-                } else {
-                    current_instant_ns - ns_since_boot
-                };
+                let pending = interval.pending_ns(ns_since_boot);
 
                 let cached = total_ns;
 
@@ -663,15 +537,7 @@ impl IpcEvent {
                     ns_since_boot,
                 }),
             ) => {
-                let pending = if let Some(prev) = previous_instant_ns {
-                    if ns_since_boot > prev {
-                        current_instant_ns - ns_since_boot
-                    } else {
-                        current_instant_ns - prev
-                    }
-                } else {
-                    i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                };
+                let pending = interval.pending_ns(ns_since_boot);
 
                 Ok(IpcEvent::InodeWait {
                     comm,
@@ -691,15 +557,7 @@ impl IpcEvent {
                 }),
                 Some(IpcBpfEvent::EpollMapPending { ns_since_boot, .. }),
             ) => {
-                let pending = if let Some(prev) = previous_instant_ns {
-                    if ns_since_boot > prev {
-                        current_instant_ns - ns_since_boot
-                    } else {
-                        current_instant_ns - prev
-                    }
-                } else {
-                    i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                };
+                let pending = interval.pending_ns(ns_since_boot);
 
                 let cached = total_ns;
 
@@ -727,15 +585,7 @@ impl IpcEvent {
                     ns_since_boot,
                 }),
             ) => {
-                let pending = if let Some(prev) = previous_instant_ns {
-                    if ns_since_boot > prev {
-                        current_instant_ns - ns_since_boot
-                    } else {
-                        current_instant_ns - prev
-                    }
-                } else {
-                    i64::max(current_instant_ns as i64 - ns_since_boot as i64, 0) as u64
-                };
+                let pending = interval.pending_ns(ns_since_boot);
                 Ok(IpcEvent::EpollWait {
                     event_poll,
                     sample_instant_ns: current_instant_ns,
@@ -763,7 +613,15 @@ impl TryFrom<IpcBpfEvent> for IpcEvent {
 
     fn try_from(event: IpcBpfEvent) -> Result<Self, Self::Error> {
         match event {
-            IpcBpfEvent::NewProcess { comm, pid } => Ok(Self::NewProcess { comm, pid }),
+            IpcBpfEvent::NewProcess {
+                comm,
+                pid,
+                resource,
+            } => Ok(Self::NewProcess {
+                comm,
+                pid,
+                resource,
+            }),
             IpcBpfEvent::AcceptEnd {
                 comm,
                 tid,
@@ -861,13 +719,8 @@ impl TryFrom<IpcBpfEvent> for IpcEvent {
     }
 }
 
-enum IpcProgramState {
-    OutStatClosure,
-    InStatClosure(Option<u64>),
-}
-
 #[derive(PartialEq, Eq, Hash)]
-enum StatsClosureKey {
+pub enum StatsClosureKey {
     Inode {
         comm: Rc<str>,
         tid: usize,
@@ -879,304 +732,174 @@ enum StatsClosureKey {
     },
 }
 
-pub struct IpcProgram {
-    header_lines: u8,
-    current_event: Option<Vec<u8>>,
-    child: Option<Child>,
-    rx: Receiver<Arc<[u8]>>,
-    events: HashMap<usize, Vec<IpcEvent>>,
-    global_events: Option<Vec<IpcEvent>>,
-    new_process_events: Option<Vec<IpcEvent>>,
-    state: IpcProgramState,
-    stats_closure_events: HashMap<StatsClosureKey, (Option<IpcBpfEvent>, Option<IpcBpfEvent>)>,
-    prev_instant_ns: Option<u64>,
-    latest_instant_ns: Option<u64>,
+/// Decodes the output of the ipc program. Events of new processes accessing
+/// a resource of a target are kept until taken by the extractor.
+#[derive(Default)]
+pub struct IpcDecoder {
+    process_events: Vec<IpcEvent>,
 }
 
-impl IpcProgram {
-    pub fn new(terminate_flag: Arc<Mutex<bool>>, pid: u32) -> Result<Self> {
-        let (tx, rx) = std::sync::mpsc::channel();
-        let (bpf_pipe_rx, bpf_pipe_tx) = super::bpf_pipe(1 << 21);
-        let child = Command::new("bpftrace")
-            .args(["./metric-collector/src/bpf/ipc.bt", &format!("{:?}", pid)])
-            .stdout(bpf_pipe_tx)
-            .spawn()?;
-        Self::start_bpf_reader(tx, bpf_pipe_rx, terminate_flag);
-
-        Ok(Self {
-            rx,
-            child: Some(child),
-            header_lines: 0,
-            current_event: None,
-            events: HashMap::new(),
-            global_events: None,
-            new_process_events: None,
-            stats_closure_events: HashMap::new(),
-            state: IpcProgramState::OutStatClosure,
-            prev_instant_ns: None,
-            latest_instant_ns: None,
-        })
-    }
-
-    pub fn custom_reader<R>(reader: R, terminate_flag: Arc<Mutex<bool>>) -> Result<Self>
-    where
-        R: Read + Send + 'static,
-    {
-        let (tx, rx) = std::sync::mpsc::channel();
-        Self::start_bpf_reader(tx, reader, terminate_flag);
-
-        Ok(Self {
-            rx,
-            child: None,
-            header_lines: 0,
-            current_event: None,
-            events: HashMap::new(),
-            global_events: None,
-            new_process_events: None,
-            stats_closure_events: HashMap::new(),
-            state: IpcProgramState::OutStatClosure,
-            prev_instant_ns: None,
-            latest_instant_ns: None,
-        })
-    }
-
-    fn start_bpf_reader<R>(
-        tx: Sender<Arc<[u8]>>,
-        mut bpf_pipe_rx: R,
-        terminate_flag: Arc<Mutex<bool>>,
-    ) where
-        R: Read + Send + 'static,
-    {
-        thread::Builder::new()
-            .name("ipc_recv".to_string())
-            .spawn(move || loop {
-                if *terminate_flag.lock().unwrap() == true {
-                    break;
-                }
-                let mut buf: [u8; 65536] = [0; 65536];
-                let res = bpf_pipe_rx.read(&mut buf);
-                if let Ok(bytes) = res {
-                    if bytes == 0 {
-                        break;
-                    }
-
-                    if let Err(_) = tx.send(Arc::from(&buf[..bytes])) {
-                        break;
-                    };
-                }
-            })
-            .unwrap();
-    }
-
-    pub fn poll_events(&mut self) -> Result<usize> {
-        loop {
-            let res = self.rx.try_recv();
-
-            let buf = match res {
-                Err(TryRecvError::Empty) => {
-                    break;
-                }
-                Err(e) => return Err(e.into()),
-                Ok(buf) => buf,
-            };
-
-            let mut iterator = buf.into_iter();
-            if !self.header_read() {
-                self.handle_header(&mut iterator);
+impl StatsDecoder for IpcDecoder {
+    const PROGRAM: &'static str = "ipc";
+
+    type Entry = IpcBpfEvent;
+    type Trace = IpcBpfEvent;
+    type Key = StatsClosureKey;
+    type Value = (Option<IpcBpfEvent>, Option<IpcBpfEvent>);
+    type Event = IpcEvent;
+
+    fn decode(&self, record: BpfRecord) -> StatsRecord<IpcBpfEvent, IpcBpfEvent> {
+        match IpcBpfEvent::from(record) {
+            IpcBpfEvent::NoOp => StatsRecord::NoOp,
+            IpcBpfEvent::MapStatsStart => StatsRecord::MapStatsStart,
+            IpcBpfEvent::SampleInstant { ns_since_boot } => {
+                StatsRecord::SampleInstant(ns_since_boot)
             }
+            IpcBpfEvent::MapStatsEnd => StatsRecord::MapStatsEnd,
+            IpcBpfEvent::Unexpected { data } => StatsRecord::Unexpected(data),
+            event @ (IpcBpfEvent::InodeMapCached { .. }
+            | IpcBpfEvent::InodeMapPending { .. }
+            | IpcBpfEvent::EpollMapCached { .. }
+            | IpcBpfEvent::EpollMapPending { .. }) => StatsRecord::Entry(event),
+            event => StatsRecord::Trace(event),
+        }
+    }
 
-            while let Some(event) = self.handle_event(&mut iterator) {
-                let event = IpcBpfEvent::from(event);
-                match event {
-                    IpcBpfEvent::NewProcess { .. } => {
-                        let events = self.new_process_events.get_or_insert_with(|| Vec::new());
-                        events.push(IpcEvent::try_from(event)?);
-                    }
-                    IpcBpfEvent::NewSocketMap { .. } => {
-                        let event = IpcEvent::try_from(event)?;
-                        let events = self.global_events.get_or_insert_with(|| Vec::new());
-                        events.push(event);
-                    }
-                    IpcBpfEvent::AcceptEnd { tid, .. } | IpcBpfEvent::ConnectEnd { tid, .. } => {
-                        let event = IpcEvent::try_from(event)?;
-                        let entry = self.events.entry(tid).or_insert(Vec::new());
-                        entry.push(event);
-                    }
-                    IpcBpfEvent::EpollItemAdd { ns_since_boot, .. }
-                    | IpcBpfEvent::EpollItemRemove { ns_since_boot, .. }
-                    | IpcBpfEvent::EpollItem { ns_since_boot, .. } => {
-                        let event = IpcEvent::try_from(event)?;
-                        let events = self.global_events.get_or_insert_with(|| Vec::new());
-                        events.push(event);
-                        self.latest_instant_ns = Some(ns_since_boot);
-                    }
-                    IpcBpfEvent::MapStatsStart => {
-                        self.state = IpcProgramState::InStatClosure(None);
-                    }
-                    IpcBpfEvent::SampleInstant { ns_since_boot } => {
-                        if let IpcProgramState::InStatClosure(sample_interval) = &mut self.state {
-                            sample_interval
-                                .replace(ns_since_boot)
-                                .map_or(Ok(()), |_| Err(eyre!("Unexpected state.\nUpon receiving a 'SampleInstant' event InStatClosure should not have a sample interval measurement.")))?;
-                        } else {
-                            return Err(eyre!("Ipc program should be in state InStatClosure. In state OutStatClosure"));
-                        }
-                    }
-                    IpcBpfEvent::MapStatsEnd => {
-                        let ns_since_boot =
-                            if let IpcProgramState::InStatClosure(Some(ns_since_boot)) = self.state
-                            {
-                                ns_since_boot
-                            } else {
-                                return Err(eyre!("Inconsistent ipc program state"));
-                            };
-                        self.state = IpcProgramState::OutStatClosure;
-                        self.latest_instant_ns = Some(ns_since_boot);
-
-                        let closure_events =
-                            mem::replace(&mut self.stats_closure_events, HashMap::new());
-                        closure_events
-                            .into_iter()
-                            .map(|(_, entry)| {
-                                let event = IpcEvent::from_stats_closure_entry(
-                                    entry,
-                                    ns_since_boot,
-                                    self.prev_instant_ns,
-                                )?;
-
-                                if event.is_global() {
-                                    self.global_events
-                                        .get_or_insert_with(|| Vec::new())
-                                        .push(event);
-                                } else {
-                                    self.events
-                                        .entry(event.get_inode_wait_tid()?)
-                                        .or_insert(Vec::new())
-                                        .push(event);
-                                }
-
-                                Ok(())
-                            })
-                            .collect::<Result<Vec<()>>>()?;
-                        self.prev_instant_ns = Some(ns_since_boot)
-                    }
-                    IpcBpfEvent::InodeMapCached {
-                        ref comm,
-                        ref tid,
-                        ref sb_id,
-                        ref inode_id,
-                        ..
-                    } => {
-                        let key = StatsClosureKey::Inode {
-                            comm: comm.clone(),
-                            tid: *tid,
-                            inode_id: *inode_id,
-                            device: *sb_id,
-                        };
-                        let entry = self.stats_closure_events.entry(key).or_insert((None, None));
-                        entry.0 = Some(event);
-                    }
-                    IpcBpfEvent::InodeMapPending {
-                        ref comm,
-                        ref tid,
-                        ref sb_id,
-                        ref inode_id,
-                        ..
-                    } => {
-                        let key = StatsClosureKey::Inode {
-                            comm: comm.clone(),
-                            tid: *tid,
-                            inode_id: *inode_id,
-                            device: *sb_id,
-                        };
-                        let entry = self.stats_closure_events.entry(key).or_insert((None, None));
-                        entry.1 = Some(event);
-                    }
-                    IpcBpfEvent::EpollMapCached { event_poll, .. } => {
-                        let key = StatsClosureKey::EventPoll {
-                            address: event_poll,
-                        };
-                        let entry = self.stats_closure_events.entry(key).or_insert((None, None));
-                        entry.0 = Some(event);
-                    }
-                    IpcBpfEvent::EpollMapPending { event_poll, .. } => {
-                        let key = StatsClosureKey::EventPoll {
-                            address: event_poll,
-                        };
-                        let entry = self.stats_closure_events.entry(key).or_insert((None, None));
-                        entry.1 = Some(event)
-                    }
-                    IpcBpfEvent::NoOp | IpcBpfEvent::AcceptStart | IpcBpfEvent::ConnectStart => {}
-                    _ => {
-                        println!("Unexpected ipc event {:?}", event);
-                    }
-                }
+    fn key(&self, entry: &IpcBpfEvent) -> StatsClosureKey {
+        match entry {
+            IpcBpfEvent::InodeMapCached {
+                comm,
+                tid,
+                sb_id,
+                inode_id,
+                ..
             }
+            | IpcBpfEvent::InodeMapPending {
+                comm,
+                tid,
+                sb_id,
+                inode_id,
+                ..
+            } => StatsClosureKey::Inode {
+                comm: comm.clone(),
+                tid: *tid,
+                inode_id: *inode_id,
+                device: *sb_id,
+            },
+            IpcBpfEvent::EpollMapCached { event_poll, .. }
+            | IpcBpfEvent::EpollMapPending { event_poll, .. } => StatsClosureKey::EventPoll {
+                address: *event_poll,
+            },
+            _ => unreachable!("Only map entries are decoded as entries"),
         }
-        Ok(self.events.len() + self.global_events.as_ref().map(|v| v.len()).unwrap_or(0))
     }
 
-    pub fn take_tid_events(&mut self, tid: usize) -> Result<Vec<IpcEvent>> {
-        let res = self.poll_events();
-        let events = self.events.remove(&tid).unwrap_or(Vec::new());
-        match (res, events.len() > 0) {
-            (_, true) => Ok(events),
-            (Ok(_), false) => Ok(events),
-            (Err(e), false) => Err(e),
+    fn accumulate(
+        &self,
+        value: Option<(Option<IpcBpfEvent>, Option<IpcBpfEvent>)>,
+        entry: IpcBpfEvent,
+    ) -> (Option<IpcBpfEvent>, Option<IpcBpfEvent>) {
+        let (cached, pending) = value.unwrap_or((None, None));
+        match entry {
+            IpcBpfEvent::InodeMapCached { .. } | IpcBpfEvent::EpollMapCached { .. } => {
+                (Some(entry), pending)
+            }
+            _ => (cached, Some(entry)),
         }
     }
 
-    pub fn take_global_events(&mut self) -> Result<Vec<IpcEvent>> {
-        let res = self.poll_events();
-        let events = self.global_events.take().unwrap_or(Vec::new());
-        match (res, events.len() > 0) {
-            (_, true) => Ok(events),
-            (Ok(_), false) => Ok(events),
-            (Err(e), false) => Err(e),
-        }
+    fn snapshot(
+        &self,
+        _key: StatsClosureKey,
+        value: (Option<IpcBpfEvent>, Option<IpcBpfEvent>),
+        interval: Interval,
+    ) -> Result<(Route, IpcEvent)> {
+        let event = IpcEvent::from_stats_closure_entry(value, interval)?;
+        let route = match event.is_global() {
+            true => Route::Global,
+            false => Route::Tid(event.get_inode_wait_tid()?),
+        };
+        Ok((route, event))
     }
 
-    pub fn take_process_events(&mut self) -> Result<Vec<IpcEvent>> {
-        let res = self.poll_events();
-        let events = self.new_process_events.take().unwrap_or(Vec::new());
-        match (res, events.len() > 0) {
-            (_, true) => Ok(events),
-            (Ok(_), false) => Ok(events),
-            (Err(e), false) => Err(e),
+    fn trace(&mut self, trace: IpcBpfEvent, events: &mut RoutedEvents<IpcEvent>) -> Result<()> {
+        match trace {
+            IpcBpfEvent::NewProcess { .. } => {
+                self.process_events.push(IpcEvent::try_from(trace)?);
+            }
+            IpcBpfEvent::NewSocketMap { .. }
+            | IpcBpfEvent::EpollItemAdd { .. }
+            | IpcBpfEvent::EpollItemRemove { .. }
+            | IpcBpfEvent::EpollItem { .. } => {
+                events.push(Route::Global, IpcEvent::try_from(trace)?);
+            }
+            IpcBpfEvent::AcceptEnd { tid, .. } | IpcBpfEvent::ConnectEnd { tid, .. } => {
+                events.push(Route::Tid(tid), IpcEvent::try_from(trace)?);
+            }
+            IpcBpfEvent::AcceptStart | IpcBpfEvent::ConnectStart => {}
+            _ => {
+                println!("Unexpected ipc event {:?}", trace);
+            }
         }
+        Ok(())
     }
 }
 
-impl BpfReader for IpcProgram {
-    fn header_read(&self) -> bool {
-        self.header_lines == 1
-    }
+pub type IpcProgram = StatsProgram<IpcDecoder>;
 
-    fn header_lines_get_mut(&mut self) -> &mut u8 {
-        &mut self.header_lines
+impl IpcProgram {
+    pub fn new(
+        launcher: &Launcher,
+        terminate_flag: Arc<Mutex<bool>>,
+        pid: u32,
+        token: &str,
+        script: &str,
+        pipe_size: u32,
+    ) -> Result<Self> {
+        Self::launch(
+            IpcDecoder::default(),
+            launcher,
+            &[script, &format!("{:?}", pid), token],
+            terminate_flag,
+            pipe_size,
+        )
     }
 
-    fn current_event_as_mut(&mut self) -> Option<&mut Vec<u8>> {
-        self.current_event.as_mut()
+    pub fn restart(
+        &mut self,
+        launcher: &Launcher,
+        terminate_flag: Arc<Mutex<bool>>,
+        pid: u32,
+        token: &str,
+        script: &str,
+        pipe_size: u32,
+    ) -> Result<()> {
+        self.relaunch(
+            launcher,
+            &[script, &format!("{:?}", pid), token],
+            terminate_flag,
+            pipe_size,
+        )
     }
 
-    fn set_current_event(&mut self, val: Vec<u8>) {
-        self.current_event = Some(val);
+    pub fn custom_reader<R>(reader: R, terminate_flag: Arc<Mutex<bool>>) -> Result<Self>
+    where
+        R: Read + Send + 'static,
+    {
+        Ok(Self::with_reader(
+            IpcDecoder::default(),
+            reader,
+            terminate_flag,
+        ))
     }
 
-    fn take_current_event(&mut self) -> Option<Vec<u8>> {
-        self.current_event.take()
-    }
-}
-
-impl Drop for IpcProgram {
-    fn drop(&mut self) {
-        if let None = self.child {
-            return;
-        }
-
-        if let Err(why) = self.child.as_mut().unwrap().kill() {
-            println!("Failed to kill futex {}", why);
+    pub fn take_process_events(&mut self) -> Result<Vec<IpcEvent>> {
+        let res = self.poll_events();
+        let events = mem::take(&mut self.decoder_mut().process_events);
+        match (res, events.len() > 0) {
+            (_, true) => Ok(events),
+            (Ok(_), false) => Ok(events),
+            (Err(e), false) => Err(e),
         }
     }
 }
@@ -1190,11 +913,16 @@ mod tests {
         use eyre::{eyre, Result};
 
         use super::super::{Connection, IpcBpfEvent, TargetFile};
+        use crate::execute::record::BpfRecord;
+
+        fn record(line: &str) -> BpfRecord {
+            BpfRecord::decode(line.as_bytes()).remove(0)
+        }
 
         #[test]
         fn inode_map() -> Result<()> {
-            let line = "@inode_map[tokio-runtime-w, 1257489, devpts, 24, 8]: (25617349, 1)";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@inode_map": {"1257489, devpts, 24, 8": [25617349, 1, "tokio, runtime"]}}}"#;
+            let event = IpcBpfEvent::from(record(line));
 
             if let IpcBpfEvent::InodeMapCached {
                 comm,
@@ -1206,7 +934,7 @@ mod tests {
                 total_ns,
             } = event
             {
-                assert_eq!(comm, Rc::from("tokio-runtime-w"));
+                assert_eq!(comm, Rc::from("tokio, runtime"));
                 assert_eq!(tid, 1257489);
                 assert_eq!(fs_type, Rc::from("devpts"));
                 assert_eq!(sb_id, 24);
@@ -1222,8 +950,8 @@ mod tests {
 
         #[test]
         fn inode_pending() -> Result<()> {
-            let line = "@inode_pending[tokio-runtime-w, 1257489, devpts, 24, 8]: 1234";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = r#"{"type": "map", "data": {"@inode_pending": {"1257489, devpts, 24, 8": [1234, "tokio, runtime"]}}}"#;
+            let event = IpcBpfEvent::from(record(line));
 
             if let IpcBpfEvent::InodeMapPending {
                 comm,
@@ -1234,7 +962,7 @@ mod tests {
                 ns_since_boot,
             } = event
             {
-                assert_eq!(comm, Rc::from("tokio-runtime-w"));
+                assert_eq!(comm, Rc::from("tokio, runtime"));
                 assert_eq!(tid, 1257489);
                 assert_eq!(fs_type, Rc::from("devpts"));
                 assert_eq!(sb_id, 24);
@@ -1249,8 +977,9 @@ mod tests {
 
         #[test]
         fn epoll_map() -> Result<()> {
-            let line = "@epoll_map[0xffff8b2c5b49c000]: 25721222";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line =
+                r#"{"type": "map", "data": {"@epoll_map": {"0xffff8b2c5b49c000": 25721222}}}"#;
+            let event = IpcBpfEvent::from(record(line));
 
             if let IpcBpfEvent::EpollMapCached {
                 event_poll,
@@ -1268,8 +997,9 @@ mod tests {
 
         #[test]
         fn epoll_pending() -> Result<()> {
-            let line = "@epoll_pending[0xffff8b2c5b49c000]: 25721222";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line =
+                r#"{"type": "map", "data": {"@epoll_pending": {"0xffff8b2c5b49c000": 25721222}}}"#;
+            let event = IpcBpfEvent::from(record(line));
 
             if let IpcBpfEvent::EpollMapPending {
                 event_poll,
@@ -1288,7 +1018,7 @@ mod tests {
         #[test]
         fn map_stats_start() -> Result<()> {
             let line = "=> start map statistics";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             if let IpcBpfEvent::MapStatsStart {} = event {
             } else {
@@ -1301,7 +1031,7 @@ mod tests {
         #[test]
         fn map_stats_end() -> Result<()> {
             let line = "=> end map statistics";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             if let IpcBpfEvent::MapStatsEnd {} = event {
             } else {
@@ -1313,8 +1043,8 @@ mod tests {
 
         #[test]
         fn accept_end() -> Result<()> {
-            let line = "AcceptEnd  \tepoll_server\t339840\tsockfs\t8\t16067228\tAF_INET\t127.0.0.1\t3001\t127.0.0.1\t53520\t535862448460614\t1111111111";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = "AcceptEnd  \t339840\tsockfs\t8\t16067228\tAF_INET\t127.0.0.1\t3001\t127.0.0.1\t53520\t535862448460614\tepoll\tserver";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             let conn_cmp = Connection::Ipv4 {
                 src_host: Ipv4Addr::new(127, 0, 0, 1),
@@ -1332,7 +1062,7 @@ mod tests {
                 conn,
             } = event
             {
-                assert_eq!(&*comm, "epoll_server");
+                assert_eq!(&*comm, "epoll\tserver");
                 assert_eq!(tid, 339840);
                 assert_eq!(&*fs_type, "sockfs");
                 assert_eq!(sb_id, 8);
@@ -1347,9 +1077,8 @@ mod tests {
 
         #[test]
         fn connect_start() -> Result<()> {
-            let line =
-                "ConnectEnd\tepoll_server\t339840\tsockfs\t8\t16052952\tAF_INET\t127.0.0.1\t39432\t127.0.0.1\t7878\t111111111111\t1034";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = "ConnectEnd\t339840\tsockfs\t8\t16052952\tAF_INET\t127.0.0.1\t39432\t127.0.0.1\t7878\t111111111111\t1034\tepoll_server";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             let conn_cmp = Connection::Ipv4 {
                 src_host: Ipv4Addr::new(127, 0, 0, 1),
@@ -1384,7 +1113,7 @@ mod tests {
         fn new_socket_map() -> Result<()> {
             let line =
                 "NewSocketMap\tsockfs\t8\t16052952\tAF_INET\t127.0.0.1\t39432\t127.0.0.1\t7878";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
             let conn_cmp = Connection::Ipv4 {
                 src_host: Ipv4Addr::new(127, 0, 0, 1),
                 src_port: 39432,
@@ -1412,11 +1141,17 @@ mod tests {
 
         #[test]
         fn new_process() -> Result<()> {
-            let line = "NewProcess\texample-applica\t334444";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
-            if let IpcBpfEvent::NewProcess { comm, pid } = event {
+            let line = "NewProcess\t334444\tsocket AF_INET 127.0.0.1:50058 127.0.0.1:7878\texample-applica";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
+            if let IpcBpfEvent::NewProcess {
+                comm,
+                pid,
+                resource,
+            } = event
+            {
                 assert_eq!(&*comm, "example-applica");
                 assert_eq!(pid, 334444);
+                assert_eq!(&*resource, "socket AF_INET 127.0.0.1:50058 127.0.0.1:7878");
             } else {
                 return Err(eyre!("Incorrect bpf event"));
             }
@@ -1426,8 +1161,8 @@ mod tests {
 
         #[test]
         fn epoll_add() -> Result<()> {
-            let line = "EpollAdd\tepoll_server\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t16052950\t534439341112792\t600572454";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = "EpollAdd\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t16052950\t534439341112792\t600572454\tepoll_server";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             if let IpcBpfEvent::EpollItemAdd {
                 comm,
@@ -1461,8 +1196,8 @@ mod tests {
 
         #[test]
         fn epoll_remove() -> Result<()> {
-            let line = "EpollRemove\tepoll_server\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t16052952\t534439657922636\t1206546100";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = "EpollRemove\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t16052952\t534439657922636\t1206546100\tepoll_server";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             if let IpcBpfEvent::EpollItemRemove {
                 comm,
@@ -1496,8 +1231,8 @@ mod tests {
 
         #[test]
         fn epoll_item_inode() -> Result<()> {
-            let line = "EpiPoll\tepoll_server\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t3754233\t538189292768153\t948293676";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = "EpiPoll\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t3754233\t538189292768153\t948293676\tepoll_server";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             if let IpcBpfEvent::EpollItem {
                 comm,
@@ -1531,8 +1266,8 @@ mod tests {
 
         #[test]
         fn epoll_item_anon_inode() -> Result<()> {
-            let line = "EpiPoll\tepoll_server\t339840\t0xffff8b2c5b49c000\tanon_inodefs\t[eventfd]\t0xffff8b2c5fb38000\t538285701874848\t159578";
-            let event = IpcBpfEvent::from(Vec::from(line.as_bytes()));
+            let line = "EpiPoll\t339840\t0xffff8b2c5b49c000\tanon_inodefs\t[eventfd]\t0xffff8b2c5fb38000\t538285701874848\t159578\tepoll_server";
+            let event = IpcBpfEvent::from(BpfRecord::Printf(line.into()));
 
             if let IpcBpfEvent::EpollItem {
                 comm,
@@ -1563,6 +1298,20 @@ mod tests {
 
             Ok(())
         }
+
+        #[test]
+        fn malformed() {
+            for line in [
+                r#"{"type": "map", "data": {"@inode_map": {"1257489, devpts": [25617349, 1, "tokio-runtime-w"]}}}"#,
+                r#"{"type": "map", "data": {"@epoll_map": {"0xffff8b2c5b49c00g": 25721222}}}"#,
+                r#"{"type": "printf", "data": "AcceptEnd  \t339840\tsockfs\t8\t16067228\tAF_INET\t127.0.0.256\t3001\t127.0.0.1\t53520\tepoll_server\n"}"#,
+                r#"{"type": "printf", "data": "EpiPoll\t339840\t0xffff8b2c5b49c000\tanon_inodefs\t[eventfd]\tepoll_server\n"}"#,
+                r#"{"type": "printf", "data": "UnhandledFileMode\t1\tproc\t4\t5\tmode\tcat\n"}"#,
+            ] {
+                let event = IpcBpfEvent::from(record(line));
+                assert!(matches!(event, IpcBpfEvent::Unexpected { .. }));
+            }
+        }
     }
 
     mod ipcprogram {
@@ -1580,11 +1329,11 @@ mod tests {
         fn account_global() -> Result<()> {
             let (rx, mut tx) = programs::pipe();
             let mut program = IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap();
-            let block = indoc! {"
-                HEADER
+            let block = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t16052950\t125\t25721222
-            "};
+                {"type": "printf", "data": "EpollAdd\t339840\t0xffff8b2c5b49c000\tsockfs\t8\t16052950\t125\t25721222\tepoll_server\n"}
+            "#};
             tx.write(block.as_bytes())?;
             for _ in 0..5 {
                 if program.poll_events()? != 0 {
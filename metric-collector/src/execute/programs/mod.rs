@@ -1,10 +1,22 @@
+use eyre::{eyre, Result};
 use libc::{self, c_int};
-use std::{fs::File, os::unix::prelude::*, process, sync::RwLock};
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::prelude::*,
+    path::PathBuf,
+    process::{self, Child, Command, Stdio},
+    sync::RwLock,
+};
+
+use super::diagnostics::Diagnostics;
+use crate::configure::Config;
 
 pub mod clone;
 pub mod futex;
 pub mod iowait;
 pub mod ipc;
+pub mod plugin;
+pub mod stats;
 
 pub static BOOT_EPOCH_NS: RwLock<u128> = RwLock::new(0);
 
@@ -29,10 +41,14 @@ pub fn fcntl_setfd(file: &mut File, flags: c_int) {
     }
 }
 
-pub fn bpf_pipe(buf_size: u32) -> (Receiver, Sender) {
+/// Creates the pipe of a bpftrace program, with the buffer size of its
+/// subsystem, which the configuration bounds to `i32::MAX`.
+pub fn bpf_pipe(buf_size: u32) -> Result<(Receiver, Sender)> {
+    let buf_size: c_int = buf_size
+        .try_into()
+        .map_err(|_| eyre!("Pipe size {} is larger than {}", buf_size, c_int::MAX))?;
     let (bpf_pipe_rx, bpf_pipe_tx) = pipe();
     let res = unsafe { libc::fcntl(bpf_pipe_rx.as_raw_fd(), libc::F_SETPIPE_SZ, buf_size) };
-    let buf_size: i32 = buf_size.try_into().unwrap();
     if res != buf_size {
         println!("Could not change pipe rx buffer to {:?}", buf_size);
     }
@@ -40,5 +56,60 @@ pub fn bpf_pipe(buf_size: u32) -> (Receiver, Sender) {
     if res != buf_size {
         println!("Could not change pipe tx buffer to {:?}", buf_size);
     }
-    (bpf_pipe_rx, bpf_pipe_tx)
+    Ok((bpf_pipe_rx, bpf_pipe_tx))
+}
+
+/// How the bpftrace programs are launched, as configured. It is passed to the
+/// programs when they are started, and relaunched.
+#[derive(Clone, Debug)]
+pub struct Launcher {
+    /// bpftrace binary the programs are launched with.
+    pub bpftrace: String,
+    /// Directory the output of the stats programs is recorded to, with
+    /// `--record-raw`.
+    pub raw_directory: Option<PathBuf>,
+}
+
+impl Launcher {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            bpftrace: config.bpf.bpftrace().to_string(),
+            raw_directory: None,
+        }
+    }
+
+    /// Opens the file recording the output of a program when it is recorded.
+    /// A relaunched program appends to the output of the previous one.
+    pub fn raw_stream(&self, stream: &str) -> Result<Option<File>> {
+        let Some(directory) = self.raw_directory.as_ref() else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(format!("{}.ndjson", stream)))?;
+        Ok(Some(file))
+    }
+
+    /// Launches a bpftrace program writing its events to `stdout` as JSON
+    /// records (`-f json`). Helper errors are reported (`-k`), so that failed
+    /// map updates are counted from stderr.
+    pub fn spawn(
+        &self,
+        program: &str,
+        args: &[&str],
+        stdout: File,
+        diagnostics: &Diagnostics,
+    ) -> Result<Child> {
+        let mut child = Command::new(&self.bpftrace)
+            .args(["-f", "json", "-k"])
+            .args(args)
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(stderr) = child.stderr.take() {
+            diagnostics.watch_stderr(stderr, program);
+        }
+        Ok(child)
+    }
 }
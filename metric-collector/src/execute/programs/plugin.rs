@@ -0,0 +1,296 @@
+use eyre::{eyre, Result};
+use std::{
+    io::prelude::*,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    stats::{Interval, Route, RoutedEvents, StatsDecoder, StatsProgram, StatsRecord},
+    Launcher,
+};
+use crate::{
+    execute::{
+        plugins::PluginSchema,
+        record::{BpfRecord, Fields},
+    },
+    metrics::Value,
+};
+
+/// A row of a plugin's map at a sample instant. The key excludes the `tid`,
+/// which the event is routed by.
+#[derive(Debug, PartialEq)]
+pub struct PluginEvent {
+    pub key: Vec<String>,
+    pub value: Vec<Value>,
+    pub sample_instant_ns: u64,
+}
+
+/// An entry of a plugin's map, its key and value fields.
+type Row = (Vec<String>, Vec<String>);
+
+/// Decodes the output of a plugin, whose map is printed in stats closures as
+/// described by its schema. The other maps and printf lines of the script are
+/// ignored, so that it can print e.g. its own debugging output.
+pub struct PluginDecoder {
+    schema: Rc<PluginSchema>,
+}
+
+impl PluginDecoder {
+    pub fn new(schema: Rc<PluginSchema>) -> Self {
+        Self { schema }
+    }
+
+    pub fn schema(&self) -> &Rc<PluginSchema> {
+        &self.schema
+    }
+
+    fn decode_printf(line: &str) -> Result<StatsRecord<Row, ()>> {
+        if line.starts_with("=> start") {
+            return Ok(StatsRecord::MapStatsStart);
+        } else if line.starts_with("=> end") {
+            return Ok(StatsRecord::MapStatsEnd);
+        }
+
+        let mut elements = Fields::new(line.split_whitespace());
+        match elements.field()? {
+            "SampleInstant" => Ok(StatsRecord::SampleInstant(elements.parse()?)),
+            _ => Ok(StatsRecord::NoOp),
+        }
+    }
+}
+
+impl StatsDecoder for PluginDecoder {
+    const PROGRAM: &'static str = "plugin";
+
+    fn stream(&self) -> &'static str {
+        self.schema.name
+    }
+
+    type Entry = Row;
+    type Trace = ();
+    type Key = Vec<String>;
+    type Value = Vec<String>;
+    type Event = PluginEvent;
+
+    fn decode(&self, record: BpfRecord) -> StatsRecord<Row, ()> {
+        match record {
+            BpfRecord::Printf(line) => Self::decode_printf(&line)
+                .unwrap_or_else(|e| StatsRecord::Unexpected(format!("{}. {}", e, line))),
+            BpfRecord::MapEntry { map, key, value } if map == self.schema.map => {
+                if key.len() == self.schema.key.len() && value.len() == self.schema.value.len() {
+                    StatsRecord::Entry((key, value))
+                } else {
+                    StatsRecord::Unexpected(format!(
+                        "{} entry {:?}: {:?} does not match the schema of {}",
+                        map, key, value, self.schema.name
+                    ))
+                }
+            }
+            BpfRecord::Malformed(data) => StatsRecord::Unexpected(data),
+            _ => StatsRecord::NoOp,
+        }
+    }
+
+    fn key(&self, entry: &Row) -> Vec<String> {
+        entry.0.clone()
+    }
+
+    /// Keys are unique within a map print, so the last entry is kept.
+    fn accumulate(&self, _value: Option<Vec<String>>, entry: Row) -> Vec<String> {
+        entry.1
+    }
+
+    fn snapshot(
+        &self,
+        mut key: Vec<String>,
+        value: Vec<String>,
+        interval: Interval,
+    ) -> Result<(Route, PluginEvent)> {
+        let tid = key.remove(self.schema.tid_index);
+        let tid = tid
+            .parse()
+            .map_err(|e| eyre!("Malformed tid {:?} of {}. {}", tid, self.schema.name, e))?;
+        // The first field of the schema is the epoch of the sample
+        let value = self.schema.fields[1..]
+            .iter()
+            .zip(&value)
+            .map(|(field, value)| {
+                Value::parse(field.kind, value).map_err(|e| {
+                    eyre!("Malformed value {:?} of {}. {}", value, self.schema.name, e)
+                })
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        let event = PluginEvent {
+            key,
+            value,
+            sample_instant_ns: interval.sample_instant_ns,
+        };
+        Ok((Route::Tid(tid), event))
+    }
+
+    fn trace(&mut self, _trace: (), _events: &mut RoutedEvents<PluginEvent>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A plugin's script, launched with the collector's pid and the session's
+/// token as its `$1` and `$2` parameters, like the ipc program.
+pub type PluginProgram = StatsProgram<PluginDecoder>;
+
+impl PluginProgram {
+    pub fn new(
+        launcher: &Launcher,
+        schema: Rc<PluginSchema>,
+        terminate_flag: Arc<Mutex<bool>>,
+        pid: u32,
+        token: &str,
+        pipe_size: u32,
+    ) -> Result<Self> {
+        let script = schema.script.clone();
+        Self::launch(
+            PluginDecoder::new(schema),
+            launcher,
+            &[&script, &format!("{:?}", pid), token],
+            terminate_flag,
+            pipe_size,
+        )
+    }
+
+    pub fn restart(
+        &mut self,
+        launcher: &Launcher,
+        terminate_flag: Arc<Mutex<bool>>,
+        pid: u32,
+        token: &str,
+        pipe_size: u32,
+    ) -> Result<()> {
+        let script = self.schema().script.clone();
+        self.relaunch(
+            launcher,
+            &[&script, &format!("{:?}", pid), token],
+            terminate_flag,
+            pipe_size,
+        )
+    }
+
+    pub fn custom_reader<R: Read + Send + 'static>(
+        schema: Rc<PluginSchema>,
+        reader: R,
+        terminate_flag: Arc<Mutex<bool>>,
+    ) -> Self {
+        Self::with_reader(PluginDecoder::new(schema), reader, terminate_flag)
+    }
+
+    pub fn schema(&mut self) -> Rc<PluginSchema> {
+        self.decoder_mut().schema().clone()
+    }
+
+    pub fn take_plugin_events(&mut self, tid: usize) -> Result<Vec<PluginEvent>> {
+        self.take_tid_events(tid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PluginEvent, PluginProgram};
+    use crate::{
+        execute::{plugins::PluginSchema, programs},
+        metrics::{Field, Kind, Value},
+    };
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{
+        io::prelude::*,
+        rc::Rc,
+        sync::{Arc, Mutex},
+    };
+
+    fn schema() -> Rc<PluginSchema> {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("wait_ns", Kind::Signed),
+            Field::new("count", Kind::Signed),
+        ];
+        Rc::new(PluginSchema {
+            name: "mutex",
+            script: "mutex.bt".to_string(),
+            map: "lock_wait".to_string(),
+            key: vec!["lock".to_string(), "tid".to_string()],
+            value: vec!["wait_ns".to_string(), "count".to_string()],
+            tid_index: 1,
+            fields: FIELDS,
+        })
+    }
+
+    #[test]
+    fn stats_closure() -> Result<()> {
+        let (rx, mut tx) = programs::pipe();
+        let mut program = PluginProgram::custom_reader(schema(), rx, Arc::new(Mutex::new(false)));
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 3}}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@lock_wait": {"0xffff9a7a3f5e0240, 8955": [847638877, 4], "0xffff9a7a3f5e0260, 8956": [1000, 1]}}}
+            {"type": "map", "data": {"@pids": {"8877": 1}}}
+            {"type": "printf", "data": "lock contended\n"}
+            {"type": "printf", "data": "SampleInstant\t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
+        tx.write_all(bpf_content.as_bytes())?;
+        while let Ok(0) = program.poll_events() {}
+
+        assert_eq!(
+            program.take_plugin_events(8955)?,
+            vec![PluginEvent {
+                key: vec!["0xffff9a7a3f5e0240".to_string()],
+                value: vec![Value::Signed(847638877), Value::Signed(4)],
+                sample_instant_ns: 65384570945103,
+            }]
+        );
+        assert_eq!(program.take_plugin_events(8956)?.len(), 1);
+        assert!(program.take_plugin_events(8877)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_entries() -> Result<()> {
+        let (rx, mut tx) = programs::pipe();
+        let mut program = PluginProgram::custom_reader(schema(), rx, Arc::new(Mutex::new(false)));
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 3}}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@lock_wait": {"8955": [847638877, 4]}}}
+            {"type": "map", "data": {"@lock_wait": {"0xffff9a7a3f5e0240, 8956": 1000}}}
+            {"type": "map", "data": {"@lock_wait": {"0xffff9a7a3f5e0260, 8957": [1000, 1]}}}
+            {"type": "printf", "data": "SampleInstant\t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
+        tx.write_all(bpf_content.as_bytes())?;
+        while let Ok(0) = program.poll_events() {}
+
+        assert!(program.take_plugin_events(8955)?.is_empty());
+        assert!(program.take_plugin_events(8956)?.is_empty());
+        assert_eq!(program.take_plugin_events(8957)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_entry() -> Result<()> {
+        let (rx, mut tx) = programs::pipe();
+        let mut program = PluginProgram::custom_reader(schema(), rx, Arc::new(Mutex::new(false)));
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 3}}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@lock_wait": {"0xffff9a7a3f5e0240, kworker": [1000, 1], "0xffff9a7a3f5e0260, 8957": [1000, 1], "0xffff9a7a3f5e0280, 8957": [1000, "kworker"]}}}
+            {"type": "printf", "data": "SampleInstant\t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
+        tx.write_all(bpf_content.as_bytes())?;
+        while let Ok(0) = program.poll_events() {}
+
+        // The other entries of the closure are kept
+        assert_eq!(program.take_plugin_events(8957)?.len(), 1);
+        assert_eq!(program.diagnostics().take().invalid_entries, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,574 @@
+use eyre::Result;
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::Hash,
+    io::{ErrorKind, Read, Write},
+    mem,
+    process::{Child, ExitStatus},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::Launcher;
+use crate::execute::{diagnostics::Diagnostics, record::BpfRecord, BpfReader};
+
+/// Output of a bpftrace program which prints its maps periodically, between
+/// a `=> start map statistics` and a `=> end map statistics` line followed by
+/// the `SampleInstant` of the snapshot. Such a block is a stats closure.
+#[derive(Debug)]
+pub enum StatsRecord<E, T> {
+    NoOp,
+    MapStatsStart,
+    SampleInstant(u64),
+    MapStatsEnd,
+    /// An entry of a map printed within a stats closure.
+    Entry(E),
+    /// An event printed as it happens, e.g. a new process.
+    Trace(T),
+    Unexpected(String),
+}
+
+/// The sample instant of a stats closure, and the one of the previous closure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub sample_instant_ns: u64,
+    pub prev_instant_ns: Option<u64>,
+}
+
+impl Interval {
+    /// Time spent within the interval by a wait which started at `start_ns`
+    /// and is still pending at the sample instant. The time before the
+    /// previous instant was accounted by the previous closure.
+    pub fn pending_ns(&self, start_ns: u64) -> u64 {
+        let start_ns = match self.prev_instant_ns {
+            Some(prev_instant_ns) => u64::max(start_ns, prev_instant_ns),
+            None => start_ns,
+        };
+        self.sample_instant_ns.saturating_sub(start_ns)
+    }
+}
+
+/// Whether an event is about a single thread, or shared by the targets, e.g.
+/// an epoll instance or a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Tid(usize),
+    Global,
+}
+
+/// Events read from a program and not taken by a collector yet.
+pub struct RoutedEvents<E> {
+    tids: HashMap<usize, Vec<E>>,
+    global: Vec<E>,
+}
+
+impl<E> RoutedEvents<E> {
+    fn new() -> Self {
+        Self {
+            tids: HashMap::new(),
+            global: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, route: Route, event: E) {
+        match route {
+            Route::Tid(tid) => self.tids.entry(tid).or_default().push(event),
+            Route::Global => self.global.push(event),
+        }
+    }
+
+    /// Number of threads with events, plus the number of global events.
+    fn len(&self) -> usize {
+        self.tids.len() + self.global.len()
+    }
+}
+
+/// Decodes the records of a stats closure program into the events of its
+/// subsystem. The entries of a closure are grouped by key, e.g. the elapsed
+/// and pending wait of a thread on a resource, and converted into an event
+/// once the closure ends.
+pub trait StatsDecoder {
+    /// Name of the program, which names its threads and log lines.
+    const PROGRAM: &'static str;
+
+    /// Name of the raw stream of the program, unique among the programs of a
+    /// session.
+    fn stream(&self) -> &'static str {
+        Self::PROGRAM
+    }
+
+    type Entry;
+    type Trace;
+    type Key: Eq + Hash;
+    type Value;
+    type Event;
+
+    fn decode(&self, record: BpfRecord) -> StatsRecord<Self::Entry, Self::Trace>;
+
+    fn key(&self, entry: &Self::Entry) -> Self::Key;
+
+    /// Merges an entry into the value of its key within the closure.
+    fn accumulate(&self, value: Option<Self::Value>, entry: Self::Entry) -> Self::Value;
+
+    fn snapshot(
+        &self,
+        key: Self::Key,
+        value: Self::Value,
+        interval: Interval,
+    ) -> Result<(Route, Self::Event)>;
+
+    fn trace(&mut self, trace: Self::Trace, events: &mut RoutedEvents<Self::Event>) -> Result<()>;
+}
+
+/// A bpftrace program printing stats closures, read by a thread into a
+/// channel. Closures which are truncated, or whose sample instant is not after
+/// the previous one, are dropped and counted in the program's diagnostics.
+pub struct StatsProgram<D: StatsDecoder> {
+    decoder: D,
+    child: Option<Child>,
+    rx: Receiver<Arc<[u8]>>,
+    header_lines: u8,
+    current_event: Option<Vec<u8>>,
+    in_stats_closure: bool,
+    sample_instant_ns: Option<u64>,
+    prev_instant_ns: Option<u64>,
+    stats_closure_events: HashMap<D::Key, D::Value>,
+    events: RoutedEvents<D::Event>,
+    /// Whether the reader reached the end of the output.
+    disconnected: bool,
+    diagnostics: Diagnostics,
+}
+
+impl<D: StatsDecoder> BpfReader for StatsProgram<D> {
+    fn header_read(&self) -> bool {
+        self.header_lines == 1
+    }
+
+    fn header_lines_get_mut(&mut self) -> &mut u8 {
+        &mut self.header_lines
+    }
+
+    fn current_event_as_mut(&mut self) -> Option<&mut Vec<u8>> {
+        self.current_event.as_mut()
+    }
+
+    fn set_current_event(&mut self, val: Vec<u8>) {
+        self.current_event = Some(val);
+    }
+
+    fn take_current_event(&mut self) -> Option<Vec<u8>> {
+        self.current_event.take()
+    }
+}
+
+impl<D: StatsDecoder> StatsProgram<D> {
+    /// Starts bpftrace with `args`, i.e. the script followed by its
+    /// positional parameters.
+    pub fn launch(
+        decoder: D,
+        launcher: &Launcher,
+        args: &[&str],
+        terminate_flag: Arc<Mutex<bool>>,
+        pipe_size: u32,
+    ) -> Result<Self> {
+        let diagnostics = Diagnostics::default();
+        let raw = launcher.raw_stream(decoder.stream())?;
+        let (child, rx) =
+            Self::spawn(launcher, args, raw, terminate_flag, pipe_size, &diagnostics)?;
+        Ok(Self::from_parts(decoder, Some(child), rx, diagnostics))
+    }
+
+    /// Reads the output of a program from `reader`, e.g. a pipe in tests.
+    pub fn with_reader<R: Read + Send + 'static>(
+        decoder: D,
+        reader: R,
+        terminate_flag: Arc<Mutex<bool>>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self::start_bpf_reader(tx, reader, None, terminate_flag);
+        Self::from_parts(decoder, None, rx, Diagnostics::default())
+    }
+
+    fn from_parts(
+        decoder: D,
+        child: Option<Child>,
+        rx: Receiver<Arc<[u8]>>,
+        diagnostics: Diagnostics,
+    ) -> Self {
+        Self {
+            decoder,
+            child,
+            rx,
+            header_lines: 0,
+            current_event: None,
+            in_stats_closure: false,
+            sample_instant_ns: None,
+            prev_instant_ns: None,
+            stats_closure_events: HashMap::new(),
+            events: RoutedEvents::new(),
+            disconnected: false,
+            diagnostics,
+        }
+    }
+
+    fn spawn(
+        launcher: &Launcher,
+        args: &[&str],
+        raw: Option<File>,
+        terminate_flag: Arc<Mutex<bool>>,
+        pipe_size: u32,
+        diagnostics: &Diagnostics,
+    ) -> Result<(Child, Receiver<Arc<[u8]>>)> {
+        let (bpf_pipe_rx, bpf_pipe_tx) = super::bpf_pipe(pipe_size)?;
+        let (tx, rx) = mpsc::channel();
+        let child = launcher.spawn(D::PROGRAM, args, bpf_pipe_tx, diagnostics)?;
+        Self::start_bpf_reader(tx, bpf_pipe_rx, raw, terminate_flag);
+        Ok((child, rx))
+    }
+
+    /// Reads the output on a thread, and copies it to `raw` when recorded.
+    fn start_bpf_reader<R>(
+        tx: Sender<Arc<[u8]>>,
+        mut bpf_pipe_rx: R,
+        mut raw: Option<File>,
+        terminate_flag: Arc<Mutex<bool>>,
+    ) where
+        R: Read + Send + 'static,
+    {
+        thread::Builder::new()
+            .name(format!("{}_recv", D::PROGRAM))
+            .spawn(move || {
+                let mut buf: [u8; 65536] = [0; 65536];
+                while !*terminate_flag.lock().unwrap() {
+                    let bytes = match bpf_pipe_rx.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(bytes) => bytes,
+                        // The output may not be ready yet, so the read is retried
+                        Err(e) => match e.kind() {
+                            ErrorKind::WouldBlock | ErrorKind::Interrupted => {
+                                thread::sleep(Duration::from_millis(10));
+                                continue;
+                            }
+                            _ => {
+                                println!("Stop reading {} output. {e}", D::PROGRAM);
+                                break;
+                            }
+                        },
+                    };
+
+                    if let Some(file) = raw.as_mut() {
+                        if let Err(e) = file.write_all(&buf[..bytes]) {
+                            println!("Stop recording raw {} output. {e}", D::PROGRAM);
+                            raw = None;
+                        }
+                    }
+                    if tx.send(Arc::from(&buf[..bytes])).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diagnostics.clone()
+    }
+
+    /// Whether the output was read up to its end, e.g. the end of a replayed
+    /// stream.
+    pub fn exhausted(&self) -> bool {
+        self.disconnected
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        match self.child.as_mut() {
+            Some(child) => Ok(child.try_wait()?),
+            None => Ok(None),
+        }
+    }
+
+    /// Relaunches the bpftrace program after it exited. The events read
+    /// before the exit are kept, whereas an incomplete stats closure is
+    /// dropped, as the new program starts with empty maps.
+    pub fn relaunch(
+        &mut self,
+        launcher: &Launcher,
+        args: &[&str],
+        terminate_flag: Arc<Mutex<bool>>,
+        pipe_size: u32,
+    ) -> Result<()> {
+        self.poll_events()?;
+        let raw = launcher.raw_stream(self.decoder.stream())?;
+        let (child, rx) = Self::spawn(
+            launcher,
+            args,
+            raw,
+            terminate_flag,
+            pipe_size,
+            &self.diagnostics,
+        )?;
+        self.child = Some(child);
+        self.rx = rx;
+        self.disconnected = false;
+        self.header_lines = 0;
+        self.current_event = None;
+        if self.in_stats_closure {
+            self.diagnostics
+                .record(|counters| counters.truncated_closures += 1);
+        }
+        self.in_stats_closure = false;
+        self.sample_instant_ns = None;
+        self.prev_instant_ns = None;
+        self.stats_closure_events.clear();
+        Ok(())
+    }
+
+    /// Stops the bpftrace program until it is relaunched, e.g. while its
+    /// subsystem is not escalated. The events read before are kept, whereas
+    /// an incomplete stats closure is dropped.
+    pub fn stop(&mut self) -> Result<()> {
+        self.poll_events()?;
+        if let Some(mut child) = self.child.take() {
+            child.kill()?;
+            child.wait()?;
+        }
+        self.in_stats_closure = false;
+        self.stats_closure_events.clear();
+        Ok(())
+    }
+
+    /// Reads the available output, and returns the number of threads with
+    /// events plus the number of global events.
+    pub fn poll_events(&mut self) -> Result<usize> {
+        loop {
+            let res = self.rx.try_recv();
+            let buf = match res {
+                Err(TryRecvError::Empty) => break,
+                // The reader disconnects once bpftrace exits, which is
+                // handled by restarting the program
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    break;
+                }
+                Ok(buf) => buf,
+            };
+
+            let mut iterator = buf.iter();
+            if !self.header_read() {
+                self.handle_header(&mut iterator);
+            }
+            while let Some(line) = self.handle_event(&mut iterator) {
+                for record in BpfRecord::decode(&line) {
+                    if let BpfRecord::LostEvents(lost) = record {
+                        self.diagnostics
+                            .record(|counters| counters.lost_events += lost);
+                        continue;
+                    }
+                    let record = self.decoder.decode(record);
+                    self.handle_record(record)?;
+                }
+            }
+        }
+        Ok(self.events.len())
+    }
+
+    fn handle_record(&mut self, record: StatsRecord<D::Entry, D::Trace>) -> Result<()> {
+        match record {
+            StatsRecord::NoOp => {}
+            StatsRecord::MapStatsStart => {
+                if self.in_stats_closure {
+                    // The previous block ended without its closing line
+                    self.diagnostics
+                        .record(|counters| counters.truncated_closures += 1);
+                }
+                self.stats_closure_events.clear();
+                self.sample_instant_ns = None;
+                self.in_stats_closure = true;
+            }
+            StatsRecord::SampleInstant(ns_since_boot) => {
+                if self.in_stats_closure {
+                    self.sample_instant_ns = Some(ns_since_boot);
+                } else {
+                    // The block's opening line was lost
+                    self.diagnostics
+                        .record(|counters| counters.truncated_closures += 1);
+                }
+            }
+            StatsRecord::MapStatsEnd => self.end_stats_closure()?,
+            StatsRecord::Entry(entry) => {
+                let key = self.decoder.key(&entry);
+                let value = self.stats_closure_events.remove(&key);
+                let value = self.decoder.accumulate(value, entry);
+                self.stats_closure_events.insert(key, value);
+            }
+            StatsRecord::Trace(trace) => self.decoder.trace(trace, &mut self.events)?,
+            StatsRecord::Unexpected(data) => {
+                println!("{} unexpected event. {:?}", D::PROGRAM, data);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_stats_closure(&mut self) -> Result<()> {
+        let in_stats_closure = mem::replace(&mut self.in_stats_closure, false);
+        let closure_events = mem::take(&mut self.stats_closure_events);
+        let sample_instant_ns = match self.sample_instant_ns.take() {
+            Some(sample_instant_ns) if in_stats_closure => sample_instant_ns,
+            _ => {
+                self.diagnostics
+                    .record(|counters| counters.truncated_closures += 1);
+                return Ok(());
+            }
+        };
+        if self
+            .prev_instant_ns
+            .is_some_and(|prev_instant_ns| sample_instant_ns <= prev_instant_ns)
+        {
+            self.diagnostics
+                .record(|counters| counters.out_of_order_instants += 1);
+            return Ok(());
+        }
+
+        let interval = Interval {
+            sample_instant_ns,
+            prev_instant_ns: self.prev_instant_ns,
+        };
+        self.prev_instant_ns = Some(sample_instant_ns);
+        for (key, value) in closure_events {
+            match self.decoder.snapshot(key, value, interval) {
+                Ok((route, event)) => self.events.push(route, event),
+                Err(e) => {
+                    println!("{} invalid entry. {e}", D::PROGRAM);
+                    self.diagnostics
+                        .record(|counters| counters.invalid_entries += 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn take_tid_events(&mut self, tid: usize) -> Result<Vec<D::Event>> {
+        let res = self.poll_events();
+        let events = self.events.tids.remove(&tid).unwrap_or_default();
+        match (res, !events.is_empty()) {
+            (_, true) => Ok(events),
+            (Ok(_), false) => Ok(events),
+            (Err(e), false) => Err(e),
+        }
+    }
+
+    pub fn take_global_events(&mut self) -> Result<Vec<D::Event>> {
+        let res = self.poll_events();
+        let events = mem::take(&mut self.events.global);
+        match (res, !events.is_empty()) {
+            (_, true) => Ok(events),
+            (Ok(_), false) => Ok(events),
+            (Err(e), false) => Err(e),
+        }
+    }
+}
+
+impl<D: StatsDecoder> Drop for StatsProgram<D> {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            if let Err(why) = child.kill() {
+                println!("Failed to kill {} {}", D::PROGRAM, why);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interval, StatsProgram};
+    use crate::execute::programs::futex::FutexDecoder;
+    use eyre::Result;
+    use std::{
+        fs::{self, File},
+        io::{self, ErrorKind, Read},
+        sync::{mpsc, Arc, Mutex},
+    };
+    use tempdir::TempDir;
+
+    /// Returns its reads in order, then fails.
+    struct ScriptedReader(Vec<io::Result<&'static [u8]>>);
+
+    impl Read for ScriptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("closed"));
+            }
+            let read = self.0.remove(0)?;
+            buf[..read.len()].copy_from_slice(read);
+            Ok(read.len())
+        }
+    }
+
+    #[test]
+    fn pending_wait() {
+        let first = Interval {
+            sample_instant_ns: 1000,
+            prev_instant_ns: None,
+        };
+        assert_eq!(first.pending_ns(400), 600);
+        // A wait started after the sample instant was read is not accounted
+        assert_eq!(first.pending_ns(1200), 0);
+
+        let next = Interval {
+            sample_instant_ns: 2000,
+            prev_instant_ns: Some(1000),
+        };
+        assert_eq!(next.pending_ns(400), 1000);
+        assert_eq!(next.pending_ns(1500), 500);
+    }
+
+    #[test]
+    fn record_raw() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let path = dir.path().join("futex.ndjson");
+        let output = "{\"type\": \"attached_probes\", \"data\": {\"probes\": 4}}\n";
+        let (tx, rx) = mpsc::channel();
+        StatsProgram::<FutexDecoder>::start_bpf_reader(
+            tx,
+            output.as_bytes(),
+            Some(File::create(&path)?),
+            Arc::new(Mutex::new(false)),
+        );
+
+        // The reader disconnects once the output ends
+        let read: Vec<u8> = rx.iter().flat_map(|buf| buf.to_vec()).collect();
+        assert_eq!(read, output.as_bytes());
+        assert_eq!(fs::read_to_string(path)?, output);
+        Ok(())
+    }
+
+    #[test]
+    fn read_errors() {
+        let reader = ScriptedReader(vec![
+            Err(ErrorKind::WouldBlock.into()),
+            Ok(b"first\n"),
+            Err(ErrorKind::Interrupted.into()),
+            Ok(b"second\n"),
+        ]);
+        let (tx, rx) = mpsc::channel();
+        StatsProgram::<FutexDecoder>::start_bpf_reader(
+            tx,
+            reader,
+            None,
+            Arc::new(Mutex::new(false)),
+        );
+
+        // Transient errors are retried, and the reader disconnects on others
+        let read: Vec<u8> = rx.iter().flat_map(|buf| buf.to_vec()).collect();
+        assert_eq!(read, b"first\nsecond\n");
+    }
+}
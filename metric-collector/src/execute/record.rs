@@ -0,0 +1,256 @@
+use eyre::{eyre, Result};
+use serde_json::Value;
+use std::{fmt::Display, rc::Rc, str::FromStr};
+
+/// Output of a bpftrace program started with `-f json`, which prints one JSON
+/// record per line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BpfRecord {
+    AttachedProbes(u64),
+    /// A line printed with `printf`, without its trailing newline.
+    Printf(String),
+    /// An entry of a map printed with `print`. The key and value are split
+    /// into their fields, e.g. the elements of a tuple.
+    MapEntry {
+        map: String,
+        key: Vec<String>,
+        value: Vec<String>,
+    },
+    LostEvents(u64),
+    /// A record the collector does not consume, e.g. the output of `time`.
+    Other,
+    Malformed(String),
+}
+
+impl BpfRecord {
+    /// Decodes a line of output into records. A map print is decoded into a
+    /// record per entry, and a printf of several lines into a record per line.
+    pub fn decode(line: &[u8]) -> Vec<Self> {
+        let line = String::from_utf8_lossy(line);
+        if line.trim().is_empty() {
+            return Vec::new();
+        }
+
+        match Self::decode_json(&line) {
+            Some(records) => records,
+            None => vec![Self::Malformed(line.into_owned())],
+        }
+    }
+
+    fn decode_json(line: &str) -> Option<Vec<Self>> {
+        let record: Value = serde_json::from_str(line).ok()?;
+        let data = record.get("data")?;
+        let records = match record.get("type")?.as_str()? {
+            "attached_probes" => vec![Self::AttachedProbes(data.get("probes")?.as_u64()?)],
+            "lost_events" => vec![Self::LostEvents(data.get("events")?.as_u64()?)],
+            "printf" => data
+                .as_str()?
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| Self::Printf(line.to_string()))
+                .collect(),
+            "map" => {
+                let mut records = Vec::new();
+                for (map, entries) in data.as_object()? {
+                    let map = map.trim_start_matches('@').to_string();
+                    match entries {
+                        Value::Object(entries) => {
+                            for (key, value) in entries {
+                                records.push(Self::MapEntry {
+                                    map: map.clone(),
+                                    key: key.split(',').map(|k| k.trim().to_string()).collect(),
+                                    value: value_fields(value),
+                                });
+                            }
+                        }
+                        // Maps without keys print their value directly
+                        value => records.push(Self::MapEntry {
+                            map,
+                            key: Vec::new(),
+                            value: value_fields(value),
+                        }),
+                    }
+                }
+                records
+            }
+            _ => vec![Self::Other],
+        };
+        Some(records)
+    }
+}
+
+fn value_fields(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(elements) => elements.iter().map(value_field).collect(),
+        value => vec![value_field(value)],
+    }
+}
+
+fn value_field(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Cursor over the fields of a record, which returns an error on a missing or
+/// malformed field rather than panicking.
+pub struct Fields<'a> {
+    fields: Vec<&'a str>,
+    position: usize,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new<I: IntoIterator<Item = &'a str>>(fields: I) -> Self {
+        Self {
+            fields: fields.into_iter().collect(),
+            position: 0,
+        }
+    }
+
+    pub fn field(&mut self) -> Result<&'a str> {
+        self.next()
+            .ok_or(eyre!("Missing field {}", self.position + 1))
+    }
+
+    pub fn parse<T>(&mut self) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let field = self.field()?;
+        field
+            .trim()
+            .parse()
+            .map_err(|e| eyre!("Malformed field {:?}. {}", field, e))
+    }
+
+    /// Returns the remaining fields as printed, e.g. a comm printed last so
+    /// that the tabs it may contain do not shift the other fields.
+    pub fn rest(&mut self) -> Result<String> {
+        if self.position >= self.fields.len() {
+            return Err(eyre!("Missing field {}", self.position + 1));
+        }
+        let rest = self.fields[self.position..].join("\t");
+        self.position = self.fields.len();
+        Ok(rest)
+    }
+
+    /// Parses an address printed in hexadecimal with a `0x` prefix, or in
+    /// decimal without it.
+    pub fn hex(&mut self) -> Result<u64> {
+        let field = self.field()?.trim();
+        let res = match field.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => field.parse(),
+        };
+        res.map_err(|e| eyre!("Malformed address {:?}. {}", field, e))
+    }
+
+    /// Returns an address in the `0x` prefixed form printed by `%p`, which
+    /// identifies e.g. a futex across programs and records.
+    pub fn address(&mut self) -> Result<Rc<str>> {
+        Ok(Rc::from(format!("{:#x}", self.hex()?)))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let field = self.fields.get(self.position).copied();
+        if field.is_some() {
+            self.position += 1;
+        }
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BpfRecord, Fields};
+
+    #[test]
+    fn decode_records() {
+        let decode = |line: &str| BpfRecord::decode(line.as_bytes());
+        assert_eq!(
+            decode(r#"{"type": "attached_probes", "data": {"probes": 4}}"#),
+            vec![BpfRecord::AttachedProbes(4)]
+        );
+        assert_eq!(
+            decode(
+                r#"{"type": "printf", "data": "SampleInstant  \t6538\n=> end map statistics\n"}"#
+            ),
+            vec![
+                BpfRecord::Printf("SampleInstant  \t6538".into()),
+                BpfRecord::Printf("=> end map statistics".into()),
+            ]
+        );
+        assert_eq!(
+            decode(
+                r#"{"type": "map", "data": {"@wait_pending": {"8955": [65384418811815, 8877, "0x7c3dd4f85fb0"]}}}"#
+            ),
+            vec![BpfRecord::MapEntry {
+                map: "wait_pending".into(),
+                key: vec!["8955".into()],
+                value: vec![
+                    "65384418811815".into(),
+                    "8877".into(),
+                    "0x7c3dd4f85fb0".into()
+                ],
+            }]
+        );
+        assert_eq!(
+            decode(r#"{"type": "map", "data": {"@sample_instant": 6538}}"#),
+            vec![BpfRecord::MapEntry {
+                map: "sample_instant".into(),
+                key: vec![],
+                value: vec!["6538".into()],
+            }]
+        );
+        assert_eq!(decode(r#"{"type": "map", "data": {"@wake": {}}}"#), vec![]);
+        assert_eq!(
+            decode(r#"{"type": "lost_events", "data": {"events": 12}}"#),
+            vec![BpfRecord::LostEvents(12)]
+        );
+        assert_eq!(
+            decode(r#"{"type": "time", "data": "10:00:00\n"}"#),
+            vec![BpfRecord::Other]
+        );
+        assert_eq!(decode(""), vec![]);
+    }
+
+    #[test]
+    fn malformed_records() {
+        for line in [
+            "@wake[8986, 8877, 0x7c3cfc00560c]: 1",
+            r#"{"type": "printf", "data": "trunc"#,
+            r#"{"type": "printf", "data": 12}"#,
+            r#"{"type": "lost_events", "data": {}}"#,
+            "\u{fffd}\u{0}",
+        ] {
+            assert!(matches!(
+                BpfRecord::decode(line.as_bytes())[..],
+                [BpfRecord::Malformed(_)]
+            ));
+        }
+    }
+
+    #[test]
+    fn fields() {
+        let mut fields = Fields::new("NewThread\tredis\t12\t0x1f\t31".split('\t'));
+        assert_eq!(fields.field().unwrap(), "NewThread");
+        assert!(fields.parse::<usize>().is_err());
+        assert_eq!(fields.parse::<usize>().unwrap(), 12);
+        assert_eq!(&*fields.address().unwrap(), "0x1f");
+        assert_eq!(fields.hex().unwrap(), 31);
+        assert!(fields.field().is_err());
+        assert!(fields.next().is_none());
+
+        let mut fields = Fields::new("NewThread\t12\tredis\tserver ".split('\t'));
+        assert_eq!(fields.field().unwrap(), "NewThread");
+        assert_eq!(fields.parse::<usize>().unwrap(), 12);
+        assert_eq!(fields.rest().unwrap(), "redis\tserver ");
+        assert!(fields.rest().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use eyre::{eyre, Result};
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use tempdir::TempDir;
+
+use crate::configure::Bpf;
+
+/// The bpftrace scripts are bundled into the binary, so that the collector
+/// does not depend on being started from the repository root.
+pub(super) const SCRIPTS: [(&str, &str); 4] = [
+    ("clone.bt", include_str!("../bpf/clone.bt")),
+    ("futex_wait.bt", include_str!("../bpf/futex_wait.bt")),
+    ("io_wait.bt", include_str!("../bpf/io_wait.bt")),
+    ("ipc.bt", include_str!("../bpf/ipc.bt")),
+];
+
+/// Returns the source of a script, read from the `--bpf-dir` override if one
+/// was passed, or from the bundled scripts otherwise.
+pub fn read(bpf: &Bpf, name: &str) -> Result<String> {
+    match &bpf.script_directory {
+        Some(directory) => fs::read_to_string(Path::new(directory).join(name))
+            .map_err(|e| eyre!("Could not read {}/{}. {}", directory, name, e)),
+        None => SCRIPTS
+            .iter()
+            .find(|(script, _)| *script == name)
+            .map(|(_, source)| source.to_string())
+            .ok_or(eyre!("No bundled script named {}", name)),
+    }
+}
+
+/// Directory bpftrace loads the scripts from. The bundled scripts are written
+/// to a private temporary directory, which is removed once dropped.
+pub struct ScriptDirectory {
+    path: PathBuf,
+    _temp: Option<TempDir>,
+}
+
+impl ScriptDirectory {
+    pub fn new(bpf: &Bpf) -> Result<Self> {
+        if let Some(directory) = &bpf.script_directory {
+            return Ok(Self {
+                path: PathBuf::from(directory),
+                _temp: None,
+            });
+        }
+
+        let temp = TempDir::new("prism-bpf")?;
+        fs::set_permissions(temp.path(), Permissions::from_mode(0o700))?;
+        for (name, source) in SCRIPTS {
+            fs::write(temp.path().join(name), source)?;
+        }
+        Ok(Self {
+            path: temp.path().to_path_buf(),
+            _temp: Some(temp),
+        })
+    }
+
+    pub fn script(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, ScriptDirectory, SCRIPTS};
+    use crate::configure::Bpf;
+    use eyre::Result;
+    use std::{fs, path::Path};
+    use tempdir::TempDir;
+
+    #[test]
+    fn bundled_scripts() -> Result<()> {
+        let bpf = Bpf::default();
+        let directory = ScriptDirectory::new(&bpf)?;
+        for (name, source) in SCRIPTS {
+            assert_eq!(fs::read_to_string(directory.script(name))?, source);
+            assert_eq!(read(&bpf, name)?, source);
+        }
+
+        let path = directory.script("clone.bt");
+        drop(directory);
+        assert!(!Path::new(&path).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn script_directory_override() -> Result<()> {
+        let dir = TempDir::new("")?;
+        fs::write(dir.path().join("clone.bt"), "BEGIN {}\n")?;
+        let bpf = Bpf {
+            script_directory: Some(dir.path().to_str().unwrap().to_string()),
+            ..Bpf::default()
+        };
+
+        let directory = ScriptDirectory::new(&bpf)?;
+        assert_eq!(
+            directory.script("clone.bt"),
+            dir.path().join("clone.bt").to_str().unwrap()
+        );
+        assert_eq!(read(&bpf, "clone.bt")?, "BEGIN {}\n");
+        assert!(read(&bpf, "ipc.bt").is_err());
+        drop(directory);
+        assert!(dir.path().join("clone.bt").exists());
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use chrono;
+use chrono::Utc;
 use ctrlc;
 use eyre::Result;
 use std::{
@@ -7,7 +7,7 @@ use std::{
     fs,
     rc::Rc,
     sync::{
-        mpsc::{Receiver, Sender},
+        mpsc::{self, Receiver},
         Arc, Mutex,
     },
     thread,
@@ -15,14 +15,38 @@ use std::{
 };
 
 use crate::{
-    configure::Config,
-    execute::programs::{clone::CloneEvent, ipc::Connection},
-    metrics::{iowait::IOWait, Collect},
-    target::TimeSensitive,
+    cgroup::Cgroup,
+    configure::{self, Config},
+    doctor,
+    execute::programs::{
+        clone::CloneEvent,
+        iowait::IOWaitProgram,
+        ipc::{Connection, IpcEvent, IpcProgram},
+    },
+    flight::Recorder,
+    launch::LaunchedCommand,
+    manifest::Manifest,
+    metrics::{
+        diagnostics::ProgramDiagnostics,
+        iowait::IOWait,
+        sink::{self, Scope, Series, SharedSink, Sink, Subsystem},
+        Collect, Field, Kind, Row, Value,
+    },
+    replay::{self, RawStreams},
+    retention::RetentionPolicy,
+    slo::Escalation,
+    target::{TargetAction, TargetContext, TargetLog, TargetLogEntry, TimeSensitive},
+};
+use crate::{
+    execute::{boot_to_epoch, Executor, ProgramRestart},
+    metrics::ipc::KFile,
 };
-use crate::{execute::Executor, metrics::ipc::KFile};
 use crate::{metrics::ipc::EventPollCollection, target::Target};
 
+/// Interval between two samples of the collectors while replaying, for the
+/// readers of the raw streams to catch up.
+const REPLAY_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct Extractor {
     terminate_flag: Arc<Mutex<bool>>,
     config: Config,
@@ -30,18 +54,52 @@ pub struct Extractor {
     system_metrics: Vec<Box<dyn Collect>>,
     rx_timer: Option<Receiver<bool>>,
     kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
+    cgroup: Option<Cgroup>,
+    launched: Option<LaunchedCommand>,
+    sink: SharedSink,
+    target_log: TargetLog,
+    retention: RetentionPolicy,
+    recorder: Option<Recorder>,
+    escalation: Option<Escalation>,
 }
 
 impl Extractor {
-    pub fn new(config: Config) -> Self {
-        Self {
+    pub fn new(config: Config) -> Result<Self> {
+        let retention = RetentionPolicy::new(&config.data_directory, &config.retention);
+        let recorder = config
+            .flight_recorder
+            .window()
+            .map(|_| Recorder::new(&config.data_directory, &config.flight_recorder));
+        let escalation = config
+            .slo
+            .source
+            .as_ref()
+            .map(|_| Escalation::new(&config.slo));
+        let files: Box<dyn Sink + Send> = match &recorder {
+            Some(recorder) => Box::new(recorder.window()),
+            None => Box::new(retention.degraded(sink::files(&config, &config.data_directory)?)),
+        };
+        let sink = sink::shared(&config, files)?;
+        Ok(Self {
+            retention,
+            escalation,
+            recorder,
+            target_log: TargetLog::new(sink.clone()),
+            sink,
             config,
             terminate_flag: Arc::new(Mutex::new(false)),
             targets: HashMap::new(),
             system_metrics: Vec::new(),
             rx_timer: None,
             kfile_socket_map: Rc::new(RefCell::new(HashMap::new())),
-        }
+            cgroup: None,
+            launched: None,
+        })
+    }
+
+    /// Flag ending the session once set, as on Ctrl-C.
+    pub fn terminate_flag(&self) -> Arc<Mutex<bool>> {
+        self.terminate_flag.clone()
     }
 
     fn register_sighandler(&self) {
@@ -53,120 +111,210 @@ impl Extractor {
         .expect("Error setting Ctrl-C handler");
     }
 
+    /// Registers the threads of a process as targets, and has the bpftrace
+    /// programs monitor the process.
+    fn add_process(
+        &mut self,
+        executor: &mut Executor,
+        context: &TargetContext,
+        pid: usize,
+        cause: &str,
+    ) -> Result<()> {
+        executor.monitor(pid);
+        for tid in Target::get_threads(&self.config.procfs, pid)? {
+            self.add_target(context, pid, tid, cause);
+        }
+        Ok(())
+    }
+
+    fn add_target(&mut self, context: &TargetContext, pid: usize, tid: usize, cause: &str) {
+        if self.targets.contains_key(&tid) {
+            return;
+        }
+
+        let target = Target::new(pid, tid, context);
+        let entry = TargetLogEntry::now(&target, TargetAction::Added, cause.to_string());
+        if let Err(e) = self.target_log.record(&entry) {
+            println!("Failed to record new target {tid}. {e}");
+        }
+        self.targets.insert(tid, target);
+    }
+
+    /// Replaces the target of the thread calling exec, which takes the pid of
+    /// the process, with a new generation running the new program. The other
+    /// threads of the process no longer exist, and are removed once they fail
+    /// to be sampled.
+    fn exec_target(
+        &mut self,
+        context: &TargetContext,
+        pid: usize,
+        old_comm: &str,
+        new_comm: &str,
+        epoch_ms: u128,
+    ) -> Result<()> {
+        let mut target = match self.targets.contains_key(&pid) {
+            true => Target::exec(pid, context),
+            false => Target::new(pid, pid, context),
+        };
+        target.comm = Rc::from(new_comm);
+        let entry = TargetLogEntry {
+            epoch_ms,
+            pid,
+            tid: pid,
+            comm: target.comm.clone(),
+            action: TargetAction::Exec,
+            cause: format!("exec from {}", old_comm),
+        };
+        self.targets.insert(pid, target);
+        self.target_log.record(&entry)
+    }
+
+    /// Removes a target, e.g. once its thread exited, and returns it.
+    fn remove_target(&mut self, tid: usize, cause: String) -> Option<Target> {
+        let target = self.targets.remove(&tid)?;
+        let entry = TargetLogEntry::now(&target, TargetAction::Removed, cause);
+        if let Err(e) = self.target_log.record(&entry) {
+            println!("Failed to record removed target {tid}. {e}");
+        }
+        Some(target)
+    }
+
     fn register_new_targets(
         &mut self,
         executor: &mut Executor,
-        time_sensitive_collector_tx: Sender<Box<dyn Collect + Send>>,
+        context: &TargetContext,
     ) -> Result<()> {
-        executor
-            .clone
-            .poll_events()?
-            .into_iter()
-            .for_each(|clone_event| match clone_event {
+        for clone_event in executor.clone.poll_events()? {
+            match clone_event {
                 CloneEvent::NewThread { pid, tid, .. } => {
-                    if let Some(_) = self.targets.get(&tid) {
-                        return;
-                    }
-
-                    self.targets.insert(
-                        tid,
-                        Target::new(
-                            tid,
-                            executor.futex.clone(),
-                            executor.ipc.clone(),
-                            self.config.data_directory.clone(),
-                            &format!("thread/{}/{}", pid, tid),
-                            self.kfile_socket_map.clone(),
-                            time_sensitive_collector_tx.clone(),
-                        ),
-                    );
+                    self.add_target(context, pid, tid, &format!("clone of process {}", pid));
                 }
-                CloneEvent::NewProcess(_, pid) => {
-                    executor.monitor(pid);
-                    if let Ok(targets) = Target::get_threads(pid) {
-                        targets.into_iter().for_each(|tid| {
-                            self.targets.insert(
-                                tid,
-                                Target::new(
-                                    tid,
-                                    executor.futex.clone(),
-                                    executor.ipc.clone(),
-                                    self.config.data_directory.clone(),
-                                    &format!("thread/{}/{}", pid, tid),
-                                    self.kfile_socket_map.clone(),
-                                    time_sensitive_collector_tx.clone(),
-                                ),
-                            );
-                        });
+                CloneEvent::NewProcess { pid, parent, .. } => {
+                    let cause = match parent {
+                        Some(parent) => format!("fork of process {}", parent),
+                        None => "fork".to_string(),
+                    };
+                    if let Err(e) = self.add_process(executor, context, pid, &cause) {
+                        println!("Failed to register process {pid}. {e}");
                     }
                 }
-                CloneEvent::RemoveProcess(pid) => {
-                    if let Ok(targets) = Target::get_threads(pid) {
-                        targets.into_iter().for_each(|tid| {
-                            self.targets.remove(&tid);
-                        });
+                CloneEvent::Exec {
+                    old_comm,
+                    new_comm,
+                    pid,
+                    ns_since_boot,
+                } => {
+                    if let Err(e) = self.record_exec(pid, &old_comm, &new_comm, ns_since_boot) {
+                        println!("Failed to record exec of process {pid}. {e}");
+                    }
+                    executor.monitor(pid);
+                    let epoch_ms = boot_to_epoch(ns_since_boot as u128) / 1_000_000;
+                    if let Err(e) = self.exec_target(context, pid, &old_comm, &new_comm, epoch_ms) {
+                        println!("Failed to record exec of process {pid}. {e}");
                     }
                 }
                 _ => {}
-            });
-
-        let new_pids = executor.futex.borrow_mut().take_new_pid_events()?;
-        for (_, pid) in new_pids {
-            executor.monitor(pid);
-            if let Ok(targets) = Target::get_threads(pid) {
-                targets.into_iter().for_each(|tid| {
-                    self.targets.insert(
-                        tid,
-                        Target::new(
-                            tid,
-                            executor.futex.clone(),
-                            executor.ipc.clone(),
-                            self.config.data_directory.clone(),
-                            &format!("thread/{}/{}", pid, tid),
-                            self.kfile_socket_map.clone(),
-                            time_sensitive_collector_tx.clone(),
-                        ),
-                    );
-                });
             }
         }
 
-        let events = executor.ipc.borrow_mut().take_process_events()?;
+        let new_pids = match &executor.futex {
+            Some(futex) => futex.borrow_mut().take_new_pid_events()?,
+            None => Vec::new(),
+        };
+        for new_process in new_pids {
+            let cause = format!(
+                "futex {} of process {}",
+                new_process.uaddr, new_process.root_pid
+            );
+            let pid = new_process.pid;
+            if let Err(e) = self.add_process(executor, context, pid, &cause) {
+                println!("Failed to register process {pid}. {e}");
+            }
+        }
+
+        let events = match &executor.ipc {
+            Some(ipc) => ipc.borrow_mut().take_process_events()?,
+            None => Vec::new(),
+        };
         for event in events {
-            if let IpcEvent::NewProcess { pid, .. } = event {
-                executor.monitor(pid);
-                if let Ok(targets) = Target::get_threads(pid) {
-                    targets.into_iter().for_each(|tid| {
-                        self.targets.insert(
-                            tid,
-                            Target::new(
-                                tid,
-                                executor.futex.clone(),
-                                executor.ipc.clone(),
-                                self.config.data_directory.clone(),
-                                &format!("thread/{}/{}", pid, tid),
-                                self.kfile_socket_map.clone(),
-                            ),
-                        );
-                    });
+            if let IpcEvent::NewProcess { pid, resource, .. } = event {
+                let cause = format!("ipc {}", resource);
+                if let Err(e) = self.add_process(executor, context, pid, &cause) {
+                    println!("Failed to register process {pid}. {e}");
                 }
             }
         }
 
+        self.register_cgroup_targets(executor, context)?;
+
         Ok(())
     }
 
-    fn sample_targets(&mut self) {
+    fn register_cgroup_targets(
+        &mut self,
+        executor: &mut Executor,
+        context: &TargetContext,
+    ) -> Result<()> {
+        let (pids, cause) = match self.cgroup.as_mut() {
+            Some(cgroup) => (
+                cgroup.take_new_pids()?,
+                format!("cgroup {}", cgroup.path().display()),
+            ),
+            None => return Ok(()),
+        };
+
+        for pid in pids {
+            if let Err(e) = self.add_process(executor, context, pid, &cause) {
+                println!("Failed to register process {pid}. {e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_exec(
+        &self,
+        pid: usize,
+        old_comm: &str,
+        new_comm: &str,
+        ns_since_boot: u64,
+    ) -> Result<()> {
+        let sample = ExecSample {
+            epoch_ms: boot_to_epoch(ns_since_boot as u128) / 1_000_000,
+            old_comm: old_comm.into(),
+            new_comm: new_comm.into(),
+        };
+        let series = Series::new(Scope::Process { pid }, Subsystem::Exec, None);
+        self.sink
+            .lock()
+            .unwrap()
+            .write(&series, sample.epoch_ms, &sample)
+    }
+
+    fn record_restart(&self, restart: &ProgramRestart) -> Result<()> {
+        let series = Series::new(Scope::Session, Subsystem::Restarts, None);
+        self.sink
+            .lock()
+            .unwrap()
+            .write(&series, restart.gap_end_ms, restart)
+    }
+
+    fn sample_targets(&mut self, executor: &mut Executor) {
         let mut targets_remove = Vec::new();
         self.targets.iter_mut().for_each(|(tid, target)| {
-            if let Err(_e) = target.sample() {
+            if let Err(e) = target.sample() {
                 println!("Remove target {tid}");
-                targets_remove.push(*tid)
+                targets_remove.push((*tid, e))
             }
         });
 
-        for tid in targets_remove {
-            self.targets.remove(&tid);
+        for (tid, e) in targets_remove {
+            if let Some(target) = self.remove_target(tid, format!("sample failed: {}", e)) {
+                let pid = target.pid;
+                if !self.targets.values().any(|target| target.pid == pid) {
+                    executor.unmonitor(pid);
+                }
+            }
         }
     }
 
@@ -196,95 +344,385 @@ impl Extractor {
             metric.store()?;
         }
 
+        self.sink.lock().unwrap().flush()
+    }
+
+    fn write_fs_version(&self, data_directory: &str) -> Result<()> {
+        fs::create_dir_all(data_directory)?;
+        fs::write(format!("{}/version.txt", data_directory), "0.2.0\n")?;
         Ok(())
     }
 
-    fn write_fs_version(&self) -> Result<()> {
-        fs::create_dir_all(&*self.config.data_directory)?;
+    fn write_config(&self, data_directory: &str) -> Result<()> {
         fs::write(
-            format!("{}/version.txt", self.config.data_directory),
-            "0.2.0\n",
+            format!("{}/config.toml", data_directory),
+            toml::to_string(&self.config.resolved)?,
         )?;
         Ok(())
     }
 
-    pub fn run(mut self) -> Result<()> {
-        self.write_fs_version()?;
+    /// Writes the window of the flight recorder to a new session directory.
+    fn dump(&self, recorder: &Recorder, trigger: &str) -> Result<()> {
+        let root_directory = self
+            .config
+            .resolved
+            .output
+            .data_directory
+            .as_deref()
+            .expect("Resolved on configuration");
+        let data_directory = configure::session_directory(root_directory, Utc::now());
+        self.write_fs_version(&data_directory)?;
+        self.write_config(&data_directory)?;
+
+        let mut files = sink::files(&self.config, &data_directory)?;
+        recorder.replay(&mut files)?;
+        files.close()?;
+
+        let mut manifest = Manifest::new(&self.config);
+        manifest.finalize(format!("Flight recorder dump triggered by {}", trigger));
+        manifest.write(&data_directory)?;
+        println!("Dumped the flight recorder to {}", data_directory);
+        Ok(())
+    }
+
+    pub fn run(self) -> Result<()> {
+        if !self.config.skip_doctor {
+            doctor::preflight(&self.config)?;
+        }
+        self.session(Self::collect)
+    }
+
+    /// Rebuilds the data files of a session recorded with `--record-raw` from
+    /// its raw streams.
+    pub fn replay(self, session: &str) -> Result<()> {
+        self.session(|extractor| extractor.replay_streams(session))
+    }
+
+    /// Writes the session directory around `collect`, and its manifest with
+    /// the reason the session ended.
+    fn session(mut self, collect: impl FnOnce(&mut Self) -> Result<String>) -> Result<()> {
+        let data_directory = self.config.data_directory.clone();
+        self.write_fs_version(&data_directory)?;
+        self.write_config(&data_directory)?;
+        let mut manifest = Manifest::new(&self.config);
+        manifest.write(&self.config.data_directory)?;
+
+        let result = collect(&mut self);
+        *self.terminate_flag.lock().unwrap() = true;
+        self.retention.wait();
+        if let Err(e) = self.sink.lock().unwrap().close() {
+            println!("Failed to flush the data files. {e}");
+        }
+
+        manifest.finalize(match &result {
+            Ok(exit_reason) => exit_reason.clone(),
+            Err(e) => format!("Error: {}", e),
+        });
+        manifest.write(&self.config.data_directory)?;
+
+        result.map(|_| ())
+    }
+
+    /// Collects metrics until the session ends, returning the reason it ended.
+    fn collect(&mut self) -> Result<String> {
         self.register_sighandler();
-        let mut executor = Executor::new(self.terminate_flag.clone())?;
-        self.start_timer_thread();
-        let time_sensitive_collector_tx = TimeSensitive::init_thread(
-            self.terminate_flag.clone(),
-            Duration::from_millis(self.config.period),
+        let mut executor = Executor::new(self.terminate_flag.clone(), &self.config)?;
+        self.retention.plugins(
+            executor
+                .plugins
+                .iter()
+                .map(|plugin| plugin.borrow_mut().schema().name)
+                .collect(),
         );
+        // The programs attached once, so that a script failing to compile
+        // ends the session before the SLO is breached
+        if self.escalation.is_some() {
+            executor.suspend()?;
+        }
+        self.start_timer_thread();
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.listen()?;
+        }
+        if let Some(escalation) = &self.escalation {
+            escalation.start(self.terminate_flag.clone());
+        }
+        let context = TargetContext {
+            sched: self.config.subsystems.sched,
+            procfs: Rc::from(self.config.procfs.as_str()),
+            futex_program: executor.futex.clone(),
+            ipc_program: executor.ipc.clone(),
+            plugin_programs: executor.plugins.clone(),
+            sink: self.sink.clone(),
+            kfile_socket_map: self.kfile_socket_map.clone(),
+            time_sensitive_collector_tx: TimeSensitive::init_thread(
+                self.terminate_flag.clone(),
+                Duration::from_millis(self.config.period),
+            ),
+        };
 
-        let targets = Target::search_targets_regex(
-            "jbd2",
-            true,
-            self.config.data_directory.clone(),
-            &mut executor,
-            self.kfile_socket_map.clone(),
-            time_sensitive_collector_tx.clone(),
-        )?;
-        targets.into_iter().for_each(|target| {
-            self.targets.insert(target.tid, target);
-        });
+        for pattern in self.config.kernel_threads.clone() {
+            for pid in Target::search_pids_regex(&self.config.procfs, &pattern, true)? {
+                let cause = format!("kernel thread {}", pattern);
+                if let Err(e) = self.add_process(&mut executor, &context, pid, &cause) {
+                    println!("Failed to register process {pid}. {e}");
+                }
+            }
+        }
 
-        if let Some(process_name) = &self.config.process_name {
-            let targets = Target::search_targets_regex(
-                &process_name,
-                false,
-                self.config.data_directory.clone(),
-                &mut executor,
-                self.kfile_socket_map.clone(),
-                time_sensitive_collector_tx.clone(),
-            )?;
-            targets.into_iter().for_each(|target| {
-                self.targets.insert(target.tid, target);
-            });
-        } else if let Some(pids) = &self.config.pids {
+        if let Some(process_name) = self.config.process_name.clone() {
+            for pid in Target::search_pids_regex(&self.config.procfs, &process_name, false)? {
+                let cause = format!("process name {}", process_name);
+                if let Err(e) = self.add_process(&mut executor, &context, pid, &cause) {
+                    println!("Failed to register process {pid}. {e}");
+                }
+            }
+        } else if let Some(pids) = self.config.pids.clone() {
             for pid in pids {
-                executor.monitor(*pid as usize);
-                let tids = Target::get_threads(*pid as usize)?;
-                tids.into_iter().for_each(|tid| {
-                    self.targets.insert(
-                        tid,
-                        Target::new(
-                            tid,
-                            executor.futex.clone(),
-                            executor.ipc.clone(),
-                            self.config.data_directory.clone(),
-                            &format!("thread/{}/{}", pid, tid),
-                            self.kfile_socket_map.clone(),
-                            time_sensitive_collector_tx.clone(),
-                        ),
-                    );
-                });
+                self.add_process(&mut executor, &context, pid as usize, "pids argument")?;
             }
+        } else if let Some(command) = &self.config.command {
+            let mut launched = LaunchedCommand::spawn(command)?;
+            let pid = launched.pid();
+            self.add_process(&mut executor, &context, pid, "launched command")?;
+            launched.release()?;
+            self.launched = Some(launched);
+        } else if let Some(path) = &self.config.cgroup {
+            self.cgroup = Some(Cgroup::new(&self.config.cgroupfs, path)?);
+        } else if let Some(container) = &self.config.container {
+            self.cgroup = Some(Cgroup::from_container(
+                &self.config.procfs,
+                &self.config.cgroupfs,
+                container,
+            )?);
         }
+        self.register_cgroup_targets(&mut executor, &context)?;
 
-        self.system_metrics.push(Box::new(IOWait::new(
-            executor.io_wait.clone(),
-            Some(self.config.data_directory.clone()),
-        )));
-        self.system_metrics.push(Box::new(EventPollCollection::new(
-            executor.ipc.clone(),
-            self.kfile_socket_map.clone(),
-            self.config.data_directory.clone(),
-        )));
+        self.add_system_metrics(executor.io_wait.as_ref(), executor.ipc.as_ref());
+
+        for (program, diagnostics) in executor.diagnostics() {
+            self.system_metrics.push(Box::new(ProgramDiagnostics::new(
+                program,
+                diagnostics,
+                self.sink.clone(),
+            )));
+        }
 
         let rx_timer = self.rx_timer.take().unwrap();
         loop {
             rx_timer.recv().unwrap();
             if *self.terminate_flag.lock().unwrap() == true {
-                break;
+                return Ok("Interrupted".to_string());
             }
 
-            self.sample_targets();
+            for restart in executor.supervise()? {
+                if let Err(e) = self.record_restart(&restart) {
+                    println!("Failed to record restart of {}. {e}", restart.program);
+                }
+            }
+            self.sample_targets(&mut executor);
             self.sample_system_metrics()?;
-            self.register_new_targets(&mut executor, time_sensitive_collector_tx.clone())?;
+            if let Some(escalation) = self.escalation.as_mut() {
+                if escalation.poll(&self.sink)? {
+                    match escalation.escalated() {
+                        true => executor.resume()?,
+                        false => executor.suspend()?,
+                    }
+                }
+            }
+            let trigger = self.recorder.as_mut().and_then(Recorder::poll);
+            if let (Some(trigger), Some(recorder)) = (trigger, &self.recorder) {
+                if let Err(e) = self.dump(recorder, &trigger) {
+                    println!("Failed to dump the flight recorder. {e}");
+                }
+            }
+            if self.retention.enforce(&self.sink)? {
+                println!("Session directory reached its maximum size");
+                return Ok("Session directory reached its maximum size".to_string());
+            }
+            self.register_new_targets(&mut executor, &context)?;
+
+            if let Some(launched) = self.launched.as_mut() {
+                if let Some(status) = launched.try_wait()? {
+                    println!("Launched command exited with {}", status);
+                    return Ok(format!("Launched command exited with {}", status));
+                }
+            }
         }
+    }
+}
 
+impl Extractor {
+    /// Adds the collectors of the events shared by the targets.
+    fn add_system_metrics(
+        &mut self,
+        io_wait: Option<&Rc<RefCell<IOWaitProgram>>>,
+        ipc: Option<&Rc<RefCell<IpcProgram>>>,
+    ) {
+        if let Some(io_wait) = io_wait {
+            self.system_metrics
+                .push(Box::new(IOWait::new(io_wait.clone(), self.sink.clone())));
+        }
+        if let Some(ipc) = ipc {
+            self.system_metrics.push(Box::new(EventPollCollection::new(
+                ipc.clone(),
+                self.kfile_socket_map.clone(),
+                self.sink.clone(),
+            )));
+        }
+    }
+
+    /// Samples the collectors of the targets of `session` on its raw streams,
+    /// until their end. The scheduler statistics, read from procfs rather
+    /// than bpftrace, are not replayed.
+    fn replay_streams(&mut self, session: &str) -> Result<String> {
+        let streams = RawStreams::open(session, &self.config, self.terminate_flag.clone())?;
+        let context = TargetContext {
+            sched: false,
+            procfs: Rc::from(self.config.procfs.as_str()),
+            futex_program: streams.futex.clone(),
+            ipc_program: streams.ipc.clone(),
+            plugin_programs: streams.plugins.clone(),
+            sink: self.sink.clone(),
+            kfile_socket_map: self.kfile_socket_map.clone(),
+            time_sensitive_collector_tx: mpsc::channel().0,
+        };
+        // Without per-target streams, a target would look up its thread in
+        // the procfs of this host instead
+        let per_target = context.futex_program.is_some()
+            || context.ipc_program.is_some()
+            || !context.plugin_programs.is_empty();
+        for entry in replay::recorded_targets(session)? {
+            // An exec starts a new generation of a target already replayed
+            let generation = match entry.action {
+                TargetAction::Added => !self.targets.contains_key(&entry.tid),
+                TargetAction::Exec => true,
+                TargetAction::Removed => false,
+            };
+            if per_target && generation {
+                let mut target = match entry.action {
+                    TargetAction::Exec => Target::exec(entry.pid, &context),
+                    _ => Target::new(entry.pid, entry.tid, &context),
+                };
+                target.comm = entry.comm.clone();
+                self.targets.insert(entry.tid, target);
+            }
+            self.target_log.record(&entry)?;
+        }
+        self.add_system_metrics(streams.io_wait.as_ref(), streams.ipc.as_ref());
+
+        loop {
+            // Polled before sampling, so that the last sample takes the end
+            // of the streams
+            let exhausted = streams.poll()?;
+            for (tid, target) in self.targets.iter_mut() {
+                if let Err(e) = target.sample() {
+                    println!("Failed to sample target {tid}. {e}");
+                }
+            }
+            self.sample_system_metrics()?;
+            if exhausted {
+                println!("Replayed {} to {}", session, self.config.data_directory);
+                return Ok(format!("Replay of {}", session));
+            }
+            thread::sleep(REPLAY_INTERVAL);
+        }
+    }
+}
+
+struct ExecSample {
+    epoch_ms: u128,
+    old_comm: String,
+    new_comm: String,
+}
+
+impl Row for ExecSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("old_comm", Kind::Text),
+            Field::new("new_comm", Kind::Text),
+        ];
+        FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Text(self.old_comm.clone()),
+            Value::Text(self.new_comm.clone()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExecSample, Extractor};
+    use crate::{cmdline, configure::Config, metrics::ToCsv, target::TargetContext};
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{cell::RefCell, collections::HashMap, fs, rc::Rc, sync::mpsc};
+    use tempdir::TempDir;
+
+    #[test]
+    fn exec_sample() {
+        let sample = ExecSample {
+            epoch_ms: 1722794820000,
+            old_comm: "sh,-c".to_string(),
+            new_comm: "redis\nserver".to_string(),
+        };
+        assert_eq!(sample.to_csv_row(), "1722794820000,sh -c,redis server\n");
+    }
+
+    #[test]
+    fn exec_generation() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let procfs = dir.path().join("proc");
+        fs::create_dir_all(procfs.join("8877/task/8877"))?;
+        fs::write(procfs.join("8877/task/8877/comm"), "sh\n")?;
+        let matches = cmdline::register_args().get_matches_from([
+            "metric-collector",
+            "--data-directory",
+            dir.path().join("data").to_str().unwrap(),
+            "--procfs",
+            procfs.to_str().unwrap(),
+            "--pids",
+            "8877",
+        ]);
+        let config = Config::try_from(matches).unwrap();
+        let data_directory = config.data_directory.clone();
+        let mut extractor = Extractor::new(config)?;
+        let context = TargetContext {
+            sched: false,
+            procfs: Rc::from(procfs.to_str().unwrap()),
+            futex_program: None,
+            ipc_program: None,
+            plugin_programs: Vec::new(),
+            sink: extractor.sink.clone(),
+            kfile_socket_map: Rc::new(RefCell::new(HashMap::new())),
+            time_sensitive_collector_tx: mpsc::channel().0,
+        };
+
+        extractor.add_target(&context, 8877, 8877, "pids argument");
+        extractor.exec_target(&context, 8877, "sh", "redis-server", 1722794821000)?;
+        // The rows following the exec name the new program
+        extractor.remove_target(8877, "sample failed".to_string());
+        extractor.sink.lock().unwrap().close()?;
+
+        let targets = fs::read_to_string(format!("{}/targets.csv", data_directory))?;
+        let rows: Vec<&str> = targets
+            .lines()
+            .skip(1)
+            .map(|row| row.split_once(',').unwrap().1)
+            .collect();
+        assert_eq!(
+            rows,
+            indoc! {"
+                8877,8877,sh,added,pids argument
+                8877,8877,redis-server,exec,exec from sh
+                8877,8877,redis-server,removed,sample failed"}
+            .lines()
+            .collect::<Vec<_>>()
+        );
         Ok(())
     }
 }
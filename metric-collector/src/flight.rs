@@ -0,0 +1,464 @@
+use eyre::{eyre, Result};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    configure::FlightRecorder,
+    metrics::{
+        sink::{Series, Sink, Subsystem},
+        Field, Row, Value,
+    },
+    target::TargetAction,
+};
+
+/// Name of the control socket within the session directory.
+const CONTROL_SOCKET: &str = "control.sock";
+/// Name of the file within the session directory whose touch triggers a dump.
+const TRIGGER_FILE: &str = "dump";
+
+/// Set by the SIGUSR1 handler, which may not lock.
+static SIGUSR1_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    SIGUSR1_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// A sample as handed to the sink, kept until it leaves the window.
+struct Recorded {
+    series: Series,
+    epoch_ms: u128,
+    recorded: Instant,
+    schema: &'static [Field],
+    fields: Vec<Value>,
+}
+
+impl Row for Recorded {
+    fn schema(&self) -> &'static [Field] {
+        self.schema
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        self.fields.clone()
+    }
+}
+
+/// Returns the tid and action of an entry of the targets log.
+fn target_entry(recorded: &Recorded) -> Option<(usize, String)> {
+    let tid = recorded.field("tid")?.as_u64()?;
+    let action = recorded.field("action")?.as_str()?.to_string();
+    Some((tid as usize, action))
+}
+
+struct Window {
+    samples: VecDeque<Recorded>,
+    /// Latest `added` or `exec` entry of the targets log of every live thread,
+    /// which is kept for the whole session to make sense of the samples of any
+    /// window.
+    live: HashMap<usize, Recorded>,
+    /// Other entries of the targets, restarts, exec and escalations logs,
+    /// which leave the window like the samples. The `added` entry of a
+    /// removed thread leaves along with its `removed` entry.
+    logs: VecDeque<Recorded>,
+    duration: Duration,
+    max_samples: usize,
+}
+
+impl Window {
+    fn push(queue: &mut VecDeque<Recorded>, max_samples: usize, recorded: Recorded) {
+        if queue.len() >= max_samples {
+            queue.pop_front();
+        }
+        queue.push_back(recorded);
+    }
+
+    fn push_log(&mut self, recorded: Recorded) {
+        if recorded.series.subsystem == Subsystem::Targets {
+            let added = TargetAction::Added.to_string();
+            let exec = TargetAction::Exec.to_string();
+            let removed = TargetAction::Removed.to_string();
+            match target_entry(&recorded) {
+                // An exec starts a new generation of the target, named after
+                // the new program
+                Some((tid, action)) if action == added || action == exec => {
+                    self.live.insert(tid, recorded);
+                    return;
+                }
+                Some((tid, action)) if action == removed => {
+                    if let Some(mut entry) = self.live.remove(&tid) {
+                        entry.recorded = recorded.recorded;
+                        Self::push(&mut self.logs, self.max_samples, entry);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self::push(&mut self.logs, self.max_samples, recorded);
+    }
+}
+
+/// Sink keeping the samples within the window of the flight recorder.
+pub struct WindowSink {
+    window: Arc<Mutex<Window>>,
+}
+
+impl Sink for WindowSink {
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        let recorded = Recorded {
+            series: series.clone(),
+            epoch_ms,
+            recorded: Instant::now(),
+            schema: sample.schema(),
+            fields: sample.fields(),
+        };
+        let mut window = self.window.lock().unwrap();
+        match series.subsystem {
+            Subsystem::Targets | Subsystem::Restarts | Subsystem::Exec | Subsystem::Escalations => {
+                window.push_log(recorded)
+            }
+            _ => {
+                let max_samples = window.max_samples;
+                Window::push(&mut window.samples, max_samples, recorded);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut window = self.window.lock().unwrap();
+        let duration = window.duration;
+        let window = &mut *window;
+        for queue in [&mut window.samples, &mut window.logs] {
+            while queue
+                .front()
+                .is_some_and(|recorded| recorded.recorded.elapsed() > duration)
+            {
+                queue.pop_front();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the samples of the last minutes in memory instead of writing them,
+/// until a dump is triggered by `SIGUSR1`, a `dump` request on the control
+/// socket of the session directory, or a touch of its `dump` file.
+pub struct Recorder {
+    data_directory: PathBuf,
+    window: Arc<Mutex<Window>>,
+    /// Trigger received on the control socket, until polled.
+    requested: Arc<Mutex<Option<String>>>,
+    /// Path of the control socket, once listening.
+    control_socket: Option<PathBuf>,
+    trigger_modified: Option<SystemTime>,
+}
+
+impl Recorder {
+    pub fn new(data_directory: &str, config: &FlightRecorder) -> Self {
+        Self {
+            data_directory: PathBuf::from(data_directory),
+            window: Arc::new(Mutex::new(Window {
+                samples: VecDeque::new(),
+                live: HashMap::new(),
+                logs: VecDeque::new(),
+                duration: config.window().unwrap_or_default(),
+                max_samples: config.max_samples,
+            })),
+            requested: Arc::new(Mutex::new(None)),
+            control_socket: None,
+            trigger_modified: None,
+        }
+    }
+
+    /// Sink keeping the samples of the collectors in the window.
+    pub fn window(&self) -> WindowSink {
+        WindowSink {
+            window: self.window.clone(),
+        }
+    }
+
+    /// Starts listening for the triggers, once the session directory exists.
+    pub fn listen(&mut self) -> Result<()> {
+        let action = SigAction::new(
+            SigHandler::Handler(on_sigusr1),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { signal::sigaction(Signal::SIGUSR1, &action) }?;
+
+        // A trigger file left from before the session does not trigger
+        self.trigger_modified = self.modified(TRIGGER_FILE);
+
+        let path = self.data_directory.join(CONTROL_SOCKET);
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| eyre!("Could not listen on {}. {}", path.display(), e))?;
+        self.control_socket = Some(path);
+        let requested = self.requested.clone();
+        thread::Builder::new()
+            .name("flight-control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Err(e) = control(stream, &requested) {
+                        println!("Failed to handle control request. {e}");
+                    }
+                }
+            })
+            .expect("Failed to create flight-control thread");
+        Ok(())
+    }
+
+    fn modified(&self, file: &str) -> Option<SystemTime> {
+        fs::metadata(self.data_directory.join(file))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Returns the trigger of a dump, if one was received since the previous
+    /// call.
+    pub fn poll(&mut self) -> Option<String> {
+        if SIGUSR1_RECEIVED.swap(false, Ordering::SeqCst) {
+            return Some("SIGUSR1".to_string());
+        }
+        if let Some(trigger) = self.requested.lock().unwrap().take() {
+            return Some(trigger);
+        }
+        let modified = self.modified(TRIGGER_FILE);
+        if modified.is_some() && modified != self.trigger_modified {
+            self.trigger_modified = modified;
+            return Some(format!("touch of {}", TRIGGER_FILE));
+        }
+        None
+    }
+
+    /// Hands the logs, in order, and the samples of the window to `sink`.
+    pub fn replay(&self, sink: &mut dyn Sink) -> Result<()> {
+        let window = self.window.lock().unwrap();
+        let mut logs: Vec<&Recorded> = window.live.values().chain(window.logs.iter()).collect();
+        logs.sort_by_key(|recorded| recorded.epoch_ms);
+        for recorded in logs.into_iter().chain(window.samples.iter()) {
+            sink.write(&recorded.series, recorded.epoch_ms, recorded)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(path) = &self.control_socket {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn control(stream: UnixStream, requested: &Mutex<Option<String>>) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut stream = reader.into_inner();
+    match line.trim() {
+        "dump" => {
+            *requested.lock().unwrap() = Some("control socket".to_string());
+            writeln!(stream, "Dump requested")?;
+        }
+        command => writeln!(stream, "Unknown command {}", command)?,
+    }
+    Ok(())
+}
+
+/// Asks the flight recorder of the session recording to `data_directory` to
+/// dump its window, as the `dump` subcommand.
+pub fn request_dump(data_directory: &str) -> Result<String> {
+    let path = Path::new(data_directory).join(CONTROL_SOCKET);
+    let mut stream = UnixStream::connect(&path).map_err(|e| {
+        eyre!(
+            "Could not reach the flight recorder on {}. {}",
+            path.display(),
+            e
+        )
+    })?;
+    writeln!(stream, "dump")?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{request_dump, Recorder};
+    use crate::{
+        configure::{Buffers, FlightRecorder},
+        metrics::{
+            sink::{csv::CsvSink, Resource, Scope, Series, Sink, Subsystem},
+            Field, Kind, Row, Value,
+        },
+        target::{TargetAction, TargetLogEntry},
+    };
+    use eyre::Result;
+    use nix::sys::signal::{self, Signal};
+    use std::{fs, rc::Rc};
+    use tempdir::TempDir;
+
+    struct Sample(u128);
+
+    impl Row for Sample {
+        fn schema(&self) -> &'static [Field] {
+            const FIELDS: &[Field] = &[Field::new("epoch_ms", Kind::Unsigned)];
+            FIELDS
+        }
+
+        fn fields(&self) -> Vec<Value> {
+            vec![Value::Unsigned(self.0 as u64)]
+        }
+    }
+
+    #[test]
+    fn window() -> Result<()> {
+        let recorder = Recorder::new(
+            "",
+            &FlightRecorder {
+                window_s: Some(600),
+                max_samples: 2,
+            },
+        );
+        let mut window = recorder.window();
+        let entry = TargetLogEntry {
+            epoch_ms: 1722794820000,
+            pid: 8877,
+            tid: 8955,
+            comm: Rc::from("redis-server"),
+            action: TargetAction::Added,
+            cause: "pids argument".to_string(),
+        };
+        window.write(
+            &Series::new(Scope::Session, Subsystem::Targets, None),
+            entry.epoch_ms,
+            &entry,
+        )?;
+        let series = Series::new(
+            Scope::Thread {
+                pid: 8877,
+                tid: 8955,
+            },
+            Subsystem::FutexWait,
+            Some(Resource::Futex {
+                root_pid: 8877,
+                uaddr: "0x7c3dd4f85fb0".to_string(),
+            }),
+        );
+        for epoch_ms in [1722794820000, 1722794821000, 1722794822000] {
+            window.write(&series, epoch_ms, &Sample(epoch_ms))?;
+        }
+        window.flush()?;
+
+        let dir = TempDir::new("")?;
+        let mut sink = CsvSink::new(dir.path().to_str().unwrap(), &Buffers::default());
+        recorder.replay(&mut sink)?;
+        sink.close()?;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("targets.csv"))?
+                .lines()
+                .count(),
+            2
+        );
+        assert_eq!(
+            fs::read_to_string(
+                dir.path()
+                    .join("thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv")
+            )?,
+            "epoch_ms\n1722794821000\n1722794822000\n"
+        );
+
+        // Samples older than the window are dropped, unlike the live targets
+        recorder.window.lock().unwrap().duration = Default::default();
+        window.flush()?;
+        assert!(recorder.window.lock().unwrap().samples.is_empty());
+        assert_eq!(recorder.window.lock().unwrap().live.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn logs() -> Result<()> {
+        let recorder = Recorder::new(
+            "",
+            &FlightRecorder {
+                window_s: Some(600),
+                max_samples: 3,
+            },
+        );
+        let mut window = recorder.window();
+        let series = Series::new(Scope::Session, Subsystem::Targets, None);
+        let mut entry = TargetLogEntry {
+            epoch_ms: 1722794820000,
+            pid: 8877,
+            tid: 8955,
+            comm: Rc::from("redis, server"),
+            action: TargetAction::Added,
+            cause: "pids argument".to_string(),
+        };
+        for tid in [8955, 8956] {
+            entry.tid = tid;
+            window.write(&series, entry.epoch_ms, &entry)?;
+        }
+        entry.action = TargetAction::Removed;
+        entry.epoch_ms += 1000;
+        window.write(&series, entry.epoch_ms, &entry)?;
+
+        // The entries of the removed thread are dumped until they leave the
+        // window, and count towards its samples
+        let dir = TempDir::new("")?;
+        let mut sink = CsvSink::new(dir.path().to_str().unwrap(), &Buffers::default());
+        recorder.replay(&mut sink)?;
+        sink.close()?;
+        let targets = fs::read_to_string(dir.path().join("targets.csv"))?;
+        assert_eq!(targets.lines().count(), 4);
+        assert!(targets.ends_with("8956,redis  server,removed,pids argument\n"));
+
+        let restarts = Series::new(Scope::Session, Subsystem::Restarts, None);
+        window.write(&restarts, entry.epoch_ms, &Sample(entry.epoch_ms))?;
+        window.write(&restarts, entry.epoch_ms, &Sample(entry.epoch_ms))?;
+        assert_eq!(recorder.window.lock().unwrap().logs.len(), 3);
+
+        recorder.window.lock().unwrap().duration = Default::default();
+        window.flush()?;
+        let window = recorder.window.lock().unwrap();
+        assert!(window.logs.is_empty());
+        assert_eq!(window.live.keys().collect::<Vec<_>>(), vec![&8955]);
+        Ok(())
+    }
+
+    #[test]
+    fn triggers() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let data_directory = dir.path().to_str().unwrap();
+        let mut recorder = Recorder::new(data_directory, &FlightRecorder::default());
+        recorder.listen()?;
+        assert_eq!(recorder.poll(), None);
+
+        assert_eq!(request_dump(data_directory)?, "Dump requested");
+        assert_eq!(recorder.poll().as_deref(), Some("control socket"));
+        assert_eq!(recorder.poll(), None);
+
+        fs::write(dir.path().join("dump"), "")?;
+        assert_eq!(recorder.poll().as_deref(), Some("touch of dump"));
+        assert_eq!(recorder.poll(), None);
+
+        signal::raise(Signal::SIGUSR1)?;
+        assert_eq!(recorder.poll().as_deref(), Some("SIGUSR1"));
+
+        drop(recorder);
+        assert!(!dir.path().join("control.sock").exists());
+        Ok(())
+    }
+}
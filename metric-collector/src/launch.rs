@@ -0,0 +1,117 @@
+use eyre::{eyre, Result};
+use nix::{
+    sys::{
+        ptrace,
+        wait::{self, WaitStatus},
+    },
+    unistd::Pid,
+};
+use std::{
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus},
+};
+
+/// A command spawned by the collector. The child is held at its first
+/// instruction after `execve` until `release` is called, so that the
+/// collector can register it with the bpftrace programs before it is able to
+/// create any threads or processes.
+pub struct LaunchedCommand {
+    child: Child,
+    released: bool,
+}
+
+impl LaunchedCommand {
+    pub fn spawn(command: &[String]) -> Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or(eyre!("Missing command to run"))?;
+
+        let mut command = Command::new(program);
+        command.args(args);
+        unsafe {
+            // The tracee stops with a SIGTRAP once execve succeeds.
+            command.pre_exec(|| ptrace::traceme().map_err(|errno| errno.into()));
+        }
+        let child = command.spawn()?;
+
+        Ok(Self {
+            child,
+            released: false,
+        })
+    }
+
+    pub fn pid(&self) -> usize {
+        self.child.id() as usize
+    }
+
+    pub fn release(&mut self) -> Result<()> {
+        if self.released {
+            return Ok(());
+        }
+
+        let pid = Pid::from_raw(self.child.id() as i32);
+        match wait::waitpid(pid, None)? {
+            WaitStatus::Stopped(_, _) => {
+                ptrace::detach(pid, None)?;
+            }
+            status => {
+                return Err(eyre!(
+                    "Launched command did not stop after exec. {:?}",
+                    status
+                ));
+            }
+        }
+        self.released = true;
+        Ok(())
+    }
+
+    /// Must only be called once the command is released, otherwise the
+    /// ptrace stop is reported as the command's exit status.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Ok(self.child.try_wait()?)
+    }
+}
+
+impl Drop for LaunchedCommand {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
+        // The killed command is reaped, so that it does not linger as a zombie
+        if let Err(why) = self.child.kill().and_then(|_| self.child.wait()) {
+            println!("Failed to kill launched command {}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LaunchedCommand;
+    use eyre::Result;
+    use std::{path::Path, thread, time::Duration};
+
+    #[test]
+    fn release_and_exit() -> Result<()> {
+        let mut launched = LaunchedCommand::spawn(&["sh".into(), "-c".into(), "exit 3".into()])?;
+        launched.release()?;
+        for _ in 0..50 {
+            if let Some(status) = launched.try_wait()? {
+                assert_eq!(status.code(), Some(3));
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        panic!("Launched command did not exit");
+    }
+
+    #[test]
+    fn kill_unreleased() -> Result<()> {
+        let launched = LaunchedCommand::spawn(&["sleep".into(), "10".into()])?;
+        let pid = launched.pid();
+        drop(launched);
+        assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+        Ok(())
+    }
+}
@@ -1,6 +1,14 @@
+pub mod cgroup;
 pub mod cmdline;
 pub mod configure;
+pub mod doctor;
 pub mod execute;
 pub mod extract;
+pub mod flight;
+pub mod launch;
+pub mod manifest;
 pub mod metrics;
+pub mod replay;
+pub mod retention;
+pub mod slo;
 mod target;
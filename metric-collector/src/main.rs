@@ -1,6 +1,8 @@
 use collector::cmdline;
 use collector::configure::Config;
+use collector::doctor::{self, Doctor, Status};
 use collector::extract::Extractor;
+use collector::flight;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut command = cmdline::register_args();
@@ -13,7 +15,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok(config) => config,
     };
 
-    let extractor = Extractor::new(config);
+    if config.doctor {
+        let checks = Doctor::new(&config).run();
+        doctor::print_table(&checks);
+        if checks.iter().any(|check| check.status == Status::Fail) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(session) = &config.dump {
+        println!("{}", flight::request_dump(session)?);
+        return Ok(());
+    }
+
+    if let Some(session) = config.replay.clone() {
+        Extractor::new(config)?.replay(&session)?;
+        return Ok(());
+    }
+
+    let extractor = Extractor::new(config)?;
     extractor.run()?;
 
     Ok(())
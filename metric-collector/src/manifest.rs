@@ -0,0 +1,166 @@
+use chrono::prelude::*;
+use eyre::Result;
+use serde::Serialize;
+use std::{collections::BTreeMap, env, ffi::CStr, fs, mem, process::Command};
+
+use crate::configure::{Config, ConfigFile};
+
+const SYSCTLS: [&str; 2] = ["kernel.sched_schedstats", "fs.pipe-max-size"];
+
+/// Provenance of a session, written to `manifest.json` when the session
+/// starts, and rewritten with its end time and exit reason at shutdown.
+#[derive(Serialize)]
+pub struct Manifest {
+    hostname: Option<String>,
+    uname: Uname,
+    bpftrace_version: Option<String>,
+    cpu_count: i64,
+    sysctls: BTreeMap<&'static str, Option<String>>,
+    arguments: Vec<String>,
+    config: ConfigFile,
+    start: String,
+    end: Option<String>,
+    exit_reason: Option<String>,
+}
+
+#[derive(Default, Serialize)]
+struct Uname {
+    sysname: String,
+    release: String,
+    version: String,
+    machine: String,
+}
+
+impl Uname {
+    fn new() -> Self {
+        let mut utsname: libc::utsname = unsafe { mem::zeroed() };
+        if unsafe { libc::uname(&mut utsname) } != 0 {
+            return Self::default();
+        }
+
+        let field = |field: &[libc::c_char]| {
+            unsafe { CStr::from_ptr(field.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        Self {
+            sysname: field(&utsname.sysname),
+            release: field(&utsname.release),
+            version: field(&utsname.version),
+            machine: field(&utsname.machine),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            hostname: read_proc_value(&format!("{}/sys/kernel/hostname", config.procfs)),
+            uname: Uname::new(),
+            bpftrace_version: bpftrace_version(config.bpf.bpftrace()),
+            cpu_count: unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) },
+            sysctls: SYSCTLS
+                .into_iter()
+                .map(|sysctl| {
+                    let path = format!("{}/sys/{}", config.procfs, sysctl.replace('.', "/"));
+                    (sysctl, read_proc_value(&path))
+                })
+                .collect(),
+            arguments: env::args().collect(),
+            config: config.resolved.clone(),
+            start: Utc::now().to_rfc3339(),
+            end: None,
+            exit_reason: None,
+        }
+    }
+
+    pub fn finalize(&mut self, exit_reason: String) {
+        self.end = Some(Utc::now().to_rfc3339());
+        self.exit_reason = Some(exit_reason);
+    }
+
+    pub fn write(&self, data_directory: &str) -> Result<()> {
+        fs::write(
+            format!("{}/manifest.json", data_directory),
+            serde_json::to_string_pretty(self)? + "\n",
+        )?;
+        Ok(())
+    }
+}
+
+pub(crate) fn read_proc_value(path: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+pub(crate) fn bpftrace_version(bpftrace: &str) -> Option<String> {
+    let output = Command::new(bpftrace).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Manifest;
+    use crate::{cmdline, configure::Config};
+    use eyre::Result;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn sysctls_of_procfs() -> Result<()> {
+        let procfs = TempDir::new("")?;
+        fs::create_dir_all(procfs.path().join("sys/kernel"))?;
+        fs::create_dir_all(procfs.path().join("sys/fs"))?;
+        fs::write(procfs.path().join("sys/kernel/hostname"), "worker-1\n")?;
+        fs::write(procfs.path().join("sys/kernel/sched_schedstats"), "1\n")?;
+        let matches = cmdline::register_args().get_matches_from([
+            "metric-collector",
+            "--pids",
+            "1",
+            "--procfs",
+            procfs.path().to_str().unwrap(),
+        ]);
+        let config = Config::try_from(matches).unwrap();
+
+        let manifest = Manifest::new(&config);
+        assert_eq!(manifest.hostname.as_deref(), Some("worker-1"));
+        assert_eq!(
+            manifest.sysctls["kernel.sched_schedstats"].as_deref(),
+            Some("1")
+        );
+        assert_eq!(manifest.sysctls["fs.pipe-max-size"], None);
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_finalize() -> Result<()> {
+        let matches =
+            cmdline::register_args().get_matches_from(["metric-collector", "--pids", "1"]);
+        let config = Config::try_from(matches).unwrap();
+        let dir = TempDir::new("")?;
+        let data_directory = dir.path().to_str().unwrap();
+
+        let mut manifest = Manifest::new(&config);
+        manifest.write(data_directory)?;
+        let value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join("manifest.json"))?)?;
+        assert_eq!(value["config"]["targets"]["pids"], serde_json::json!([1]));
+        assert!(value["sysctls"]
+            .as_object()
+            .unwrap()
+            .contains_key("kernel.sched_schedstats"));
+        assert!(value["end"].is_null());
+
+        manifest.finalize("Interrupted".to_string());
+        manifest.write(data_directory)?;
+        let value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join("manifest.json"))?)?;
+        assert!(value["end"].is_string());
+        assert_eq!(value["exit_reason"], "Interrupted");
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use eyre::Result;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::execute::diagnostics::{Counters, Diagnostics};
+
+use super::{
+    sink::{Resource, Scope, Series, SharedSink, Subsystem},
+    Collect, Field, Kind, Row, Value,
+};
+
+struct DiagnosticsSample {
+    epoch_ms: u128,
+    counters: Counters,
+}
+
+impl Row for DiagnosticsSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("lost_events", Kind::Unsigned),
+            Field::new("map_full", Kind::Unsigned),
+            Field::new("truncated_closures", Kind::Unsigned),
+            Field::new("out_of_order_instants", Kind::Unsigned),
+            Field::new("invalid_entries", Kind::Unsigned),
+        ];
+        FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        let counters = &self.counters;
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(counters.lost_events),
+            Value::Unsigned(counters.map_full),
+            Value::Unsigned(counters.truncated_closures),
+            Value::Unsigned(counters.out_of_order_instants),
+            Value::Unsigned(counters.invalid_entries),
+        ]
+    }
+}
+
+/// Writes the output lost by a bpftrace program during each interval, e.g. to
+/// `diagnostics/<program>.csv`. Intervals with nonzero counters have missing
+/// or partial data for the program's subsystem.
+pub struct ProgramDiagnostics {
+    series: Series,
+    diagnostics: Diagnostics,
+    sink: SharedSink,
+    sample: Option<DiagnosticsSample>,
+}
+
+impl ProgramDiagnostics {
+    pub fn new(program: &'static str, diagnostics: Diagnostics, sink: SharedSink) -> Self {
+        Self {
+            series: Series::new(
+                Scope::Session,
+                Subsystem::Diagnostics,
+                Some(Resource::Program(program)),
+            ),
+            diagnostics,
+            sink,
+            sample: None,
+        }
+    }
+}
+
+impl Collect for ProgramDiagnostics {
+    fn sample(&mut self) -> Result<()> {
+        self.sample = Some(DiagnosticsSample {
+            epoch_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis(),
+            counters: self.diagnostics.take(),
+        });
+        Ok(())
+    }
+
+    fn store(&mut self) -> Result<()> {
+        let sample = match self.sample.take() {
+            Some(sample) => sample,
+            None => return Ok(()),
+        };
+
+        self.sink
+            .lock()
+            .unwrap()
+            .write(&self.series, sample.epoch_ms, &sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProgramDiagnostics;
+    use crate::{
+        configure::Buffers,
+        execute::diagnostics::Diagnostics,
+        metrics::{sink::csv::CsvSink, Collect},
+    };
+    use eyre::Result;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn interval_counters() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let diagnostics = Diagnostics::default();
+        let mut collector = ProgramDiagnostics::new(
+            "futex",
+            diagnostics.clone(),
+            CsvSink::shared(dir.path().to_str().unwrap(), &Buffers::default()),
+        );
+
+        diagnostics.record(|counters| counters.lost_events += 5);
+        diagnostics.record(|counters| counters.truncated_closures += 1);
+        collector.sample()?;
+        collector.store()?;
+        collector.sample()?;
+        collector.store()?;
+
+        let content = fs::read_to_string(dir.path().join("diagnostics/futex.csv"))?;
+        let rows: Vec<Vec<&str>> = content
+            .lines()
+            .map(|line| line.splitn(2, ',').collect())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[0][1],
+            "lost_events,map_full,truncated_closures,out_of_order_instants,invalid_entries"
+        );
+        assert_eq!(rows[1][1], "5,0,1,0,0");
+        assert_eq!(rows[2][1], "0,0,0,0,0");
+        Ok(())
+    }
+}
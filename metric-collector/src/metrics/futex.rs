@@ -1,18 +1,16 @@
 use eyre::Result;
-use lru::LruCache;
 use std::{
     cell::RefCell,
     collections::{HashMap, VecDeque},
     error::Error,
     fmt,
-    fs::{self, File},
-    io::prelude::*,
-    num::NonZeroUsize,
-    path::Path,
     rc::Rc,
 };
 
-use super::{Collect, ToCsv};
+use super::{
+    sink::{Resource, Scope, Series, SharedSink, Subsystem},
+    Collect, Field, Kind, Row, Value,
+};
 use crate::execute::{
     boot_to_epoch,
     programs::futex::{FutexEvent, FutexProgram},
@@ -48,54 +46,30 @@ enum SnapshotStat {
 }
 
 pub struct Futex {
+    pid: usize,
     tid: usize,
     futex_program: Rc<RefCell<FutexProgram>>,
     futex_stats_map: HashMap<FutexKey, WaitStat>,
     snapshots: HashMap<FutexKey, VecDeque<(u64, SnapshotStat)>>,
-    data_files: LruCache<String, File>,
-    target_subdirectory: String,
+    sink: SharedSink,
 }
 
 impl Futex {
     pub fn new(
         futex_program: Rc<RefCell<FutexProgram>>,
+        pid: usize,
         tid: usize,
-        root_directory: Rc<str>,
-        target_subdirectory: &str,
+        sink: SharedSink,
     ) -> Self {
         Self {
+            pid,
             tid,
             futex_program,
             futex_stats_map: HashMap::new(),
             snapshots: HashMap::new(),
-            data_files: LruCache::new(NonZeroUsize::new(4).unwrap()),
-            target_subdirectory: format!("{}/{}/futex", root_directory, target_subdirectory),
+            sink,
         }
     }
-
-    fn get_or_create_file<'a>(
-        data_files: &'a mut LruCache<String, File>,
-        filepath: &Path,
-        headers: &str,
-    ) -> Result<&'a File> {
-        let file = data_files.get(filepath.to_str().unwrap());
-        if let None = file {
-            let file = File::options().append(true).open(filepath);
-            let file = match file {
-                Err(_) => {
-                    fs::create_dir_all(filepath.parent().unwrap())?;
-                    let mut file = File::options().append(true).create(true).open(filepath)?;
-                    file.write_all(headers.as_bytes())?;
-                    file
-                }
-                Ok(file) => file,
-            };
-            data_files.put(filepath.to_str().unwrap().into(), file);
-        }
-
-        let file = data_files.get(filepath.to_str().unwrap());
-        Ok(file.unwrap())
-    }
 }
 
 impl Collect for Futex {
@@ -161,43 +135,38 @@ impl Collect for Futex {
         for (futex, snapshots) in self.snapshots.iter_mut() {
             while let Some((sample_instant_ns, snapshot)) = snapshots.pop_front() {
                 let sample_epoch_ms = boot_to_epoch(sample_instant_ns as u128) / 1_000_000;
-                let (sample, filename): (Box<dyn ToCsv>, String) = match snapshot {
+                let (sample, subsystem): (Box<dyn Row>, Subsystem) = match snapshot {
                     SnapshotStat::Wait(wait) => {
                         let sample = Box::new(FutexWaitSample {
                             epoch_ms: sample_epoch_ms,
                             cumulative_futex_wait: wait.accumulated_wait,
                             count: wait.count,
                         });
-                        let filename = format!(
-                            "{}/wait/{}/{}-{}.csv",
-                            self.target_subdirectory,
-                            (sample_epoch_ms / (1000 * 60)) * 60,
-                            futex.root_pid,
-                            futex.uaddr,
-                        );
-                        (sample, filename)
+                        (sample, Subsystem::FutexWait)
                     }
                     SnapshotStat::Wake { count } => {
                         let sample = Box::new(FutexWakeSample {
                             epoch_ms: sample_epoch_ms,
                             count,
                         });
-                        let filename = format!(
-                            "{}/wake/{}/{}-{}.csv",
-                            self.target_subdirectory,
-                            (sample_epoch_ms / (1000 * 60)) * 60,
-                            futex.root_pid,
-                            futex.uaddr,
-                        );
-                        (sample, filename)
+                        (sample, Subsystem::FutexWake)
                     }
                 };
-                let mut file = Self::get_or_create_file(
-                    &mut self.data_files,
-                    Path::new(&filename),
-                    sample.csv_headers(),
-                )?;
-                file.write_all(sample.to_csv_row().as_bytes())?;
+                let series = Series::new(
+                    Scope::Thread {
+                        pid: self.pid,
+                        tid: self.tid,
+                    },
+                    subsystem,
+                    Some(Resource::Futex {
+                        root_pid: futex.root_pid,
+                        uaddr: futex.uaddr.to_string(),
+                    }),
+                );
+                self.sink
+                    .lock()
+                    .unwrap()
+                    .write(&series, sample_epoch_ms, sample.as_ref())?;
             }
         }
 
@@ -212,16 +181,22 @@ struct FutexWaitSample {
     count: usize,
 }
 
-impl ToCsv for FutexWaitSample {
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,futex_wait_ns,futex_count\n"
+impl Row for FutexWaitSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("futex_wait_ns", Kind::Unsigned),
+            Field::new("futex_count", Kind::Unsigned),
+        ];
+        FIELDS
     }
 
-    fn to_csv_row(&self) -> String {
-        format!(
-            "{},{},{}\n",
-            self.epoch_ms, self.cumulative_futex_wait, self.count
-        )
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(self.cumulative_futex_wait),
+            Value::Unsigned(self.count as u64),
+        ]
     }
 }
 
@@ -231,13 +206,20 @@ struct FutexWakeSample {
     count: usize,
 }
 
-impl ToCsv for FutexWakeSample {
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,futex_count\n"
+impl Row for FutexWakeSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("futex_count", Kind::Unsigned),
+        ];
+        FIELDS
     }
 
-    fn to_csv_row(&self) -> String {
-        format!("{},{}\n", self.epoch_ms, self.count)
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(self.count as u64),
+        ]
     }
 }
 
@@ -257,8 +239,10 @@ mod tests {
     use tempdir::TempDir;
 
     use super::Futex;
+    use crate::configure::Buffers;
     use crate::metrics::{
         futex::{SnapshotStat, WaitStat},
+        sink::csv::CsvSink,
         Collect,
     };
     use crate::{
@@ -280,23 +264,23 @@ mod tests {
 
     #[test]
     fn single_snapshot_wait() -> Result<()> {
-        let bpf_content = indoc! {"
-            HEADER 
-
-            => start map statistics
-            @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)
-            SampleInstant  	65384570945103
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         let (mut program, mut tx) = new_custom_futex();
         write_and_poll(&mut program, &mut tx, bpf_content.as_bytes())?;
 
         let root_directory = TempDir::new("")?;
         let mut futex = Futex::new(
             Rc::new(RefCell::new(program)),
+            8877,
             8955,
-            Rc::from(root_directory.path().to_str().unwrap()),
-            &format!("thread/{}/{}", 8877, 8955),
+            CsvSink::shared(root_directory.path().to_str().unwrap(), &Buffers::default()),
         );
 
         futex.sample()?;
@@ -336,14 +320,14 @@ mod tests {
 
     #[test]
     fn single_snapshot_wake() -> Result<()> {
-        let bpf_content = indoc! {"
-            HEADER 
-
-            => start map statistics
-            @wake[8986, 8877, 0x7c3cfc00560c]: 1
-            SampleInstant  	65384570945103
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wake": {"8986, 8877, 0x7c3cfc00560c": 1}}}
+            {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         let (pid, tid, address): (usize, usize, Rc<str>) = (8877, 8986, Rc::from("0x7c3cfc00560c"));
         let (mut program, mut tx) = new_custom_futex();
         write_and_poll(&mut program, &mut tx, bpf_content.as_bytes())?;
@@ -351,9 +335,9 @@ mod tests {
         let root_directory = TempDir::new("")?;
         let mut futex = Futex::new(
             Rc::new(RefCell::new(program)),
+            pid,
             tid,
-            Rc::from(root_directory.path().to_str().unwrap()),
-            &format!("thread/{}/{}", pid, tid),
+            CsvSink::shared(root_directory.path().to_str().unwrap(), &Buffers::default()),
         );
 
         futex.sample()?;
@@ -391,19 +375,19 @@ mod tests {
 
     #[test]
     fn double_snapshot_wait() -> Result<()> {
-        let bpf_content = indoc! {"
-            HEADER 
-
-            => start map statistics
-            @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)
-            SampleInstant  	65384570945103
-            => end map statistics
-
-            => start map statistics
-            @wait_elapsed[8955, 8877, 0x7c3dd4f85fb0]: (847638877, 4)
-            SampleInstant  	65385570945103
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant  \t65385570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         let (pid, tid, address): (usize, usize, Rc<str>) = (8877, 8955, Rc::from("0x7c3dd4f85fb0"));
         let (mut program, mut tx) = new_custom_futex();
         write_and_poll(&mut program, &mut tx, bpf_content.as_bytes())?;
@@ -411,9 +395,9 @@ mod tests {
         let root_directory = TempDir::new("")?;
         let mut futex = Futex::new(
             Rc::new(RefCell::new(program)),
+            pid,
             tid,
-            Rc::from(root_directory.path().to_str().unwrap()),
-            &format!("thread/{}/{}", pid, tid),
+            CsvSink::shared(root_directory.path().to_str().unwrap(), &Buffers::default()),
         );
 
         futex.sample()?;
@@ -468,19 +452,19 @@ mod tests {
 
     #[test]
     fn double_snapshot_wake() -> Result<()> {
-        let bpf_content = indoc! {"
-            HEADER 
-
-            => start map statistics
-            @wake[8986, 8877, 0x7c3cfc00560c]: 1
-            SampleInstant  	65384570945103
-            => end map statistics
-
-            => start map statistics
-            @wake[8986, 8877, 0x7c3cfc00560c]: 1
-            SampleInstant  	65385570945103
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wake": {"8986, 8877, 0x7c3cfc00560c": 1}}}
+            {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wake": {"8986, 8877, 0x7c3cfc00560c": 1}}}
+            {"type": "printf", "data": "SampleInstant  \t65385570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         let (pid, tid, address): (usize, usize, Rc<str>) = (8877, 8986, Rc::from("0x7c3cfc00560c"));
         let (mut program, mut tx) = new_custom_futex();
         write_and_poll(&mut program, &mut tx, bpf_content.as_bytes())?;
@@ -488,9 +472,9 @@ mod tests {
         let root_directory = TempDir::new("")?;
         let mut futex = Futex::new(
             Rc::new(RefCell::new(program)),
+            pid,
             tid,
-            Rc::from(root_directory.path().to_str().unwrap()),
-            &format!("thread/{}/{}", pid, tid),
+            CsvSink::shared(root_directory.path().to_str().unwrap(), &Buffers::default()),
         );
 
         futex.sample()?;
@@ -533,15 +517,15 @@ mod tests {
 
     #[test]
     fn single_wait_and_wake() -> Result<()> {
-        let bpf_content = indoc! {"
-            HEADER 
-
-            => start map statistics
-            @wake[8955, 8877, 0x7c3cfc00560c]: 1
-            @wait_elapsed[8955, 8877, 0x7c3cfc00560c]: (847638877, 4)
-            SampleInstant  	65384570945103
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wake": {"8955, 8877, 0x7c3cfc00560c": 1}}}
+            {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3cfc00560c": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         let (pid, tid, address): (usize, usize, Rc<str>) = (8877, 8955, Rc::from("0x7c3cfc00560c"));
         let (mut program, mut tx) = new_custom_futex();
         write_and_poll(&mut program, &mut tx, bpf_content.as_bytes())?;
@@ -549,9 +533,9 @@ mod tests {
         let root_directory = TempDir::new("")?;
         let mut futex = Futex::new(
             Rc::new(RefCell::new(program)),
+            pid,
             tid,
-            Rc::from(root_directory.path().to_str().unwrap()),
-            &format!("thread/{}/{}", pid, tid),
+            CsvSink::shared(root_directory.path().to_str().unwrap(), &Buffers::default()),
         );
 
         futex.sample()?;
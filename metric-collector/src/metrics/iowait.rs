@@ -1,64 +1,58 @@
 use eyre::Result;
 use lru_time_cache::LruCache;
-use std::{
-    cell::RefCell,
-    collections::HashMap,
-    fs::{self, File},
-    io::Write,
-    mem,
-    path::Path,
-    rc::Rc,
-    time::Duration,
-};
+use std::{cell::RefCell, collections::HashMap, mem, rc::Rc, time::Duration};
 
 use crate::execute::{
     self,
     programs::iowait::{IOWaitProgram, IowaitEvent},
 };
 
-use super::Collect;
+use super::{
+    sink::{Resource, Scope, Series, SharedSink, Subsystem},
+    Collect, Field, Kind, Row, Value,
+};
+
+struct IoWaitSample {
+    epoch_s: u64,
+    sector_cnt: usize,
+}
+
+impl Row for IoWaitSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_s", Kind::Unsigned),
+            Field::new("sector_cnt", Kind::Unsigned),
+        ];
+        FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_s),
+            Value::Unsigned(self.sector_cnt as u64),
+        ]
+    }
+}
 
 struct ThreadDeviceStats {
-    device: u32,
     minute_map: LruCache<u64, HashMap<u64, usize>>,
-    dir: Rc<str>,
-    data_files: LruCache<String, File>,
+    series: Series,
+    sink: SharedSink,
 }
 
 impl ThreadDeviceStats {
-    fn new(device: u32, parent: Rc<str>) -> Self {
+    fn new(device: u32, scope: Scope, sink: SharedSink) -> Self {
         Self {
-            device,
             minute_map: LruCache::with_expiry_duration(Duration::from_millis(1000 * 120)),
-            dir: parent,
-            data_files: LruCache::with_expiry_duration(Duration::from_millis(1000 * 120)),
+            series: Series::new(
+                scope,
+                Subsystem::IoWait,
+                Some(Resource::BlockDevice(device)),
+            ),
+            sink,
         }
     }
 
-    fn get_or_create_file<'a>(
-        data_files: &'a mut LruCache<String, File>,
-        filepath: &Path,
-        headers: &str,
-    ) -> Result<&'a File> {
-        let file = data_files.get(filepath.to_str().unwrap());
-        if let None = file {
-            let file = File::options().append(true).open(filepath);
-            let file = match file {
-                Err(_) => {
-                    fs::create_dir_all(filepath.parent().unwrap())?;
-                    let mut file = File::options().append(true).create(true).open(filepath)?;
-                    file.write_all(headers.as_bytes())?;
-                    file
-                }
-                Ok(file) => file,
-            };
-            data_files.insert(filepath.to_str().unwrap().into(), file);
-        }
-
-        let file = data_files.get(filepath.to_str().unwrap());
-        Ok(file.unwrap())
-    }
-
     fn store(&mut self) -> Result<()> {
         if self.minute_map.len() == 0 {
             return Ok(());
@@ -70,44 +64,24 @@ impl ThreadDeviceStats {
         );
         minute_map
             .iter()
-            .map(|(minute, map)| {
-                Self::store_minute(
-                    &mut self.data_files,
-                    self.dir.clone(),
-                    self.device,
-                    minute,
-                    map,
-                )
-            })
+            .map(|(_, map)| Self::store_minute(&self.sink, &self.series, map))
             .collect()
     }
 
-    fn store_minute(
-        data_files: &mut LruCache<String, File>,
-        dir: Rc<str>,
-        device: u32,
-        minute: &u64,
-        map: &HashMap<u64, usize>,
-    ) -> Result<()> {
-        let minute_s = minute * 60;
-        let file_path = format!("{}/{:?}/{:?}.csv", dir, minute_s, device);
-        let mut file = Self::get_or_create_file(
-            data_files,
-            &Path::new(file_path.as_str()),
-            "epoch_s,sector_cnt\n",
-        )?;
-        let mut data: Vec<u8> = Vec::with_capacity(1024);
-
+    fn store_minute(sink: &SharedSink, series: &Series, map: &HashMap<u64, usize>) -> Result<()> {
+        let mut sink = sink.lock().unwrap();
         let mut sorted = map.iter().collect::<Vec<(&u64, &usize)>>();
         sorted.sort_by(|a, b| a.0.partial_cmp(b.0).unwrap());
         for (second, sectors) in sorted {
             if *sectors == 0 {
                 continue;
             }
-            data.extend(format!("{:?},{:?}\n", second, sectors).as_bytes());
+            let sample = IoWaitSample {
+                epoch_s: *second,
+                sector_cnt: *sectors,
+            };
+            sink.write(series, *second as u128 * 1000, &sample)?;
         }
-
-        file.write_all(&data)?;
         Ok(())
     }
 
@@ -135,26 +109,25 @@ impl ThreadDeviceStats {
 }
 
 struct ThreadIOStats {
-    tid: usize,
     device_map: HashMap<u32, ThreadDeviceStats>,
-    dir: Rc<str>,
+    scope: Scope,
+    sink: SharedSink,
 }
 
 impl ThreadIOStats {
-    fn new(tid: usize, pid: usize, parent: Rc<str>) -> Self {
+    fn new(tid: usize, pid: usize, sink: SharedSink) -> Self {
         Self {
-            tid,
             device_map: HashMap::new(),
-            dir: Rc::from(format!("{}/{}/{:?}", parent, pid, tid)),
+            scope: Scope::Thread { pid, tid },
+            sink,
         }
     }
 
     fn process(&mut self, event: IowaitEvent) {
         let IowaitEvent::Requests { ref device, .. } = event;
-        let thread_device = self
-            .device_map
-            .entry(*device)
-            .or_insert(ThreadDeviceStats::new(*device, self.dir.clone()));
+        let thread_device = self.device_map.entry(*device).or_insert_with(|| {
+            ThreadDeviceStats::new(*device, self.scope.clone(), self.sink.clone())
+        });
         thread_device.process(event);
     }
 
@@ -169,21 +142,15 @@ impl ThreadIOStats {
 pub struct IOWait {
     iowait_program: Rc<RefCell<IOWaitProgram>>,
     thread_map: HashMap<usize, ThreadIOStats>,
-    dir: Rc<str>,
+    sink: SharedSink,
 }
 
 impl IOWait {
-    pub fn new(iowait_program: Rc<RefCell<IOWaitProgram>>, parent: Option<Rc<str>>) -> Self {
-        let dir = if let None = parent {
-            Rc::from("iowait")
-        } else {
-            Rc::from(&*format!("{}/global/iowait", parent.unwrap()))
-        };
-
+    pub fn new(iowait_program: Rc<RefCell<IOWaitProgram>>, sink: SharedSink) -> Self {
         Self {
             iowait_program,
             thread_map: HashMap::new(),
-            dir,
+            sink,
         }
     }
 
@@ -191,10 +158,10 @@ impl IOWait {
         let IowaitEvent::Requests {
             ref tid, ref pid, ..
         } = event;
-        let thread_acc =
-            self.thread_map
-                .entry(*tid)
-                .or_insert(ThreadIOStats::new(*tid, *pid, self.dir.clone()));
+        let thread_acc = self
+            .thread_map
+            .entry(*tid)
+            .or_insert_with(|| ThreadIOStats::new(*tid, *pid, self.sink.clone()));
         thread_acc.process(event);
     }
 }
@@ -1,13 +1,8 @@
 use eyre::{eyre, Result};
-use lru_time_cache::LruCache;
 use std::{
     cell::RefCell,
     collections::{HashMap, HashSet, VecDeque},
-    fs::{self, File},
-    io::prelude::*,
-    path::Path,
     rc::Rc,
-    time::Duration,
 };
 
 use crate::execute::{
@@ -15,8 +10,9 @@ use crate::execute::{
     programs::ipc::{Connection, IpcEvent, IpcProgram, TargetFile},
 };
 
+use super::sink::{Resource, Scope, Series, SharedSink, Subsystem};
 use super::Collect;
-use super::ToCsv;
+use super::{Field, Kind, Row, Value};
 
 pub type KFile = (u32, u64);
 
@@ -30,19 +26,17 @@ pub struct Ipc {
 impl Ipc {
     pub fn new(
         ipc_program: Rc<RefCell<IpcProgram>>,
+        pid: usize,
         tid: usize,
-        root_directory: Rc<str>,
-        target_subdirectory: &str,
+        sink: SharedSink,
         kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
     ) -> Self {
+        let scope = Scope::Thread { pid, tid };
         Self {
             ipc_program,
             tid,
-            sockets: Sockets::new(
-                format!("{}/{}/ipc", root_directory, target_subdirectory),
-                kfile_socket_map,
-            ),
-            pipes: Pipes::new(format!("{}/{}/ipc", root_directory, target_subdirectory)),
+            sockets: Sockets::new(scope.clone(), sink.clone(), kfile_socket_map),
+            pipes: Pipes::new(scope, sink),
         }
     }
 
@@ -95,18 +89,18 @@ struct Stats {
 struct Pipes {
     stream_stats_map: HashMap<TargetFile, Stats>,
     snapshots: HashMap<TargetFile, VecDeque<(Option<u64>, Stats)>>,
-    data_files: LruCache<String, File>,
-    target_subdirectory: String,
+    scope: Scope,
+    sink: SharedSink,
     active: HashMap<TargetFile, u64>,
 }
 
 impl Pipes {
-    fn new(target_subdirectory: String) -> Self {
+    fn new(scope: Scope, sink: SharedSink) -> Self {
         Self {
-            target_subdirectory,
+            scope,
+            sink,
             stream_stats_map: HashMap::new(),
             snapshots: HashMap::new(),
-            data_files: LruCache::with_expiry_duration(Duration::from_millis(1000 * 120)),
             active: HashMap::new(),
         }
     }
@@ -280,41 +274,15 @@ impl Pipes {
                     count: stat.count,
                 };
 
-                let file_path = match target_file {
-                    TargetFile::Inode { device, inode_id } => {
-                        format!(
-                            "{}/streams/{:?}/{:?}_{:?}.csv",
-                            self.target_subdirectory,
-                            (epoch_ms / (1000 * 60)) * 60,
-                            device,
-                            inode_id,
-                        )
-                    }
-                    TargetFile::AnonInode { name, address } => {
-                        format!(
-                            "{}/streams/{:?}/{}_{:x}.csv",
-                            self.target_subdirectory,
-                            (epoch_ms / (1000 * 60)) * 60,
-                            name,
-                            address,
-                        )
-                    }
-                    TargetFile::Epoll { address } => {
-                        format!(
-                            "{}/streams/{:?}/epoll_{:x}.csv",
-                            self.target_subdirectory,
-                            (epoch_ms / (1000 * 60)) * 60,
-                            address,
-                        )
-                    }
-                };
-
-                let mut file = Self::get_or_create_file(
-                    &mut self.data_files,
-                    Path::new(&file_path),
-                    sample.csv_headers(),
-                )?;
-                file.write_all(sample.to_csv_row().as_bytes())?;
+                let series = Series::new(
+                    self.scope.clone(),
+                    Subsystem::Streams,
+                    Some(Resource::Stream(target_file.clone())),
+                );
+                self.sink
+                    .lock()
+                    .unwrap()
+                    .write(&series, sample.epoch_ms, &sample)?;
             }
 
             if snapshots.len() == 0 {
@@ -327,30 +295,6 @@ impl Pipes {
 
         Ok(())
     }
-
-    fn get_or_create_file<'a>(
-        data_files: &'a mut LruCache<String, File>,
-        filepath: &Path,
-        headers: &str,
-    ) -> Result<&'a File> {
-        let file = data_files.get(filepath.to_str().unwrap());
-        if let None = file {
-            let file = File::options().append(true).open(filepath);
-            let file = match file {
-                Err(_) => {
-                    fs::create_dir_all(filepath.parent().unwrap())?;
-                    let mut file = File::options().append(true).create(true).open(filepath)?;
-                    file.write_all(headers.as_bytes())?;
-                    file
-                }
-                Ok(file) => file,
-            };
-            data_files.insert(filepath.to_str().unwrap().into(), file);
-        }
-
-        let file = data_files.get(filepath.to_str().unwrap());
-        Ok(file.unwrap())
-    }
 }
 
 struct Sockets {
@@ -358,22 +302,23 @@ struct Sockets {
     kfile_stats_map: HashMap<KFile, Stats>,
     snapshots: HashMap<KFile, VecDeque<(Option<u64>, Stats)>>,
     active: HashMap<KFile, u64>,
-    data_files: LruCache<String, File>,
-    target_subdirectory: String,
+    scope: Scope,
+    sink: SharedSink,
 }
 
 impl Sockets {
     fn new(
-        target_subdirectory: String,
+        scope: Scope,
+        sink: SharedSink,
         kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
     ) -> Self {
         Self {
             kfile_socket_map,
-            target_subdirectory,
+            scope,
+            sink,
             kfile_stats_map: HashMap::new(),
             snapshots: HashMap::new(),
             active: HashMap::new(),
-            data_files: LruCache::with_expiry_duration(Duration::from_millis(1000 * 120)),
         }
     }
 
@@ -538,46 +483,6 @@ impl Sockets {
         Ok(())
     }
 
-    fn rename<'a>(
-        data_files: &'a mut LruCache<String, File>,
-        old: &Path,
-        new: &Path,
-    ) -> Result<()> {
-        let old = old.to_str().unwrap().into();
-        let new = new.to_str().unwrap().into();
-
-        let file = data_files.remove(old);
-        if let Some(file) = file {
-            fs::rename(&old, &new)?;
-            data_files.insert(new, file);
-        }
-        Ok(())
-    }
-
-    fn get_or_create_file<'a>(
-        data_files: &'a mut LruCache<String, File>,
-        filepath: &Path,
-        headers: &str,
-    ) -> Result<&'a File> {
-        let file = data_files.get(filepath.to_str().unwrap());
-        if let None = file {
-            let file = File::options().append(true).open(filepath);
-            let file = match file {
-                Err(_) => {
-                    fs::create_dir_all(filepath.parent().unwrap())?;
-                    let mut file = File::options().append(true).create(true).open(filepath)?;
-                    file.write_all(headers.as_bytes())?;
-                    file
-                }
-                Ok(file) => file,
-            };
-            data_files.insert(filepath.to_str().unwrap().into(), file);
-        }
-
-        let file = data_files.get(filepath.to_str().unwrap());
-        Ok(file.unwrap())
-    }
-
     fn store(&mut self) -> Result<()> {
         let mut remove = Vec::new();
 
@@ -595,81 +500,19 @@ impl Sockets {
                     cumulative_wait: stat.accumulated_wait,
                     count: stat.count,
                 };
-                let kfile_path = format!(
-                    "{}/sockets/{:?}/{}_{}.csv",
-                    self.target_subdirectory,
-                    (epoch_ms / (1000 * 60)) * 60,
-                    kfile.0,
-                    kfile.1,
+                let series = Series::new(
+                    self.scope.clone(),
+                    Subsystem::Sockets,
+                    Some(Resource::Socket {
+                        device: kfile.0,
+                        inode_id: kfile.1,
+                        connection: self.kfile_socket_map.borrow().get(kfile).cloned(),
+                    }),
                 );
-                let file_path = match self.kfile_socket_map.borrow().get(&kfile) {
-                    Some(Connection::Ipv4 {
-                        src_host,
-                        src_port,
-                        dst_host,
-                        dst_port,
-                    }) => format!(
-                        "{}/sockets/{:?}/ipv4_{}:{}_{}:{}.csv",
-                        self.target_subdirectory,
-                        (epoch_ms / (1000 * 60)) * 60,
-                        src_host.octets().map(|elem| elem.to_string()).join("."),
-                        src_port,
-                        dst_host.octets().map(|elem| elem.to_string()).join("."),
-                        dst_port,
-                    ),
-                    Some(Connection::Ipv6 {
-                        src_host,
-                        src_port,
-                        dst_host,
-                        dst_port,
-                    }) => format!(
-                        "{}/sockets/{:?}/ipv6_[{}]:{}_[{}]:{}.csv",
-                        self.target_subdirectory,
-                        (epoch_ms / (1000 * 60)) * 60,
-                        src_host
-                            .segments()
-                            .map(|elem| format!("{:x}", elem))
-                            .join(":"),
-                        src_port,
-                        dst_host
-                            .segments()
-                            .map(|elem| format!("{:x}", elem))
-                            .join(":"),
-                        dst_port,
-                    ),
-                    Some(Connection::Unix {
-                        src_address,
-                        dst_address,
-                    }) => format!(
-                        "{}/sockets/{:?}/unix_{:#x}_{:#x}.csv",
-                        self.target_subdirectory,
-                        (epoch_ms / (1000 * 60)) * 60,
-                        src_address,
-                        dst_address
-                    ),
-                    _ => format!(
-                        "{}/sockets/{:?}/{}_{}.csv",
-                        self.target_subdirectory,
-                        (epoch_ms / (1000 * 60)) * 60,
-                        kfile.0,
-                        kfile.1,
-                    ),
-                };
-
-                if kfile_path != file_path {
-                    Self::rename(
-                        &mut self.data_files,
-                        Path::new(&kfile_path),
-                        Path::new(&file_path),
-                    )?;
-                }
-
-                let mut file = Self::get_or_create_file(
-                    &mut self.data_files,
-                    Path::new(&file_path),
-                    sample.csv_headers(),
-                )?;
-                file.write_all(sample.to_csv_row().as_bytes())?;
+                self.sink
+                    .lock()
+                    .unwrap()
+                    .write(&series, sample.epoch_ms, &sample)?;
             }
 
             if snapshots.len() == 0 {
@@ -693,15 +536,13 @@ struct EventPoll {
 impl EventPoll {
     fn new(
         kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
-        data_directory: Rc<str>,
+        sink: SharedSink,
         address: u64,
     ) -> Self {
+        let scope = Scope::Epoll { address };
         Self {
-            sockets: Sockets::new(
-                format!("{}/{:x}", data_directory, address),
-                kfile_socket_map,
-            ),
-            pipes: Pipes::new(format!("{}/{:x}", data_directory, address)),
+            sockets: Sockets::new(scope.clone(), sink.clone(), kfile_socket_map),
+            pipes: Pipes::new(scope, sink),
         }
     }
 
@@ -737,19 +578,19 @@ pub struct EventPollCollection {
     event_poll_map: HashMap<u64, EventPoll>,
     ipc_program: Rc<RefCell<IpcProgram>>,
     kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
-    root_directory: Rc<str>,
+    sink: SharedSink,
 }
 
 impl EventPollCollection {
     pub fn new(
         ipc_program: Rc<RefCell<IpcProgram>>,
         kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
-        root_directory: Rc<str>,
+        sink: SharedSink,
     ) -> Self {
         Self {
             ipc_program,
             kfile_socket_map,
-            root_directory: Rc::from(format!("{}/global/epoll", root_directory)),
+            sink,
             event_poll_map: HashMap::new(),
         }
     }
@@ -761,11 +602,7 @@ impl EventPollCollection {
             | IpcEvent::EpollItem { event_poll, .. }
             | IpcEvent::EpollWait { event_poll, .. } => {
                 let event_poll = self.event_poll_map.entry(event_poll).or_insert_with(|| {
-                    EventPoll::new(
-                        self.kfile_socket_map.clone(),
-                        self.root_directory.clone(),
-                        event_poll,
-                    )
+                    EventPoll::new(self.kfile_socket_map.clone(), self.sink.clone(), event_poll)
                 });
                 event_poll.process_event(event)?;
             }
@@ -813,16 +650,22 @@ struct SocketSample {
     count: u64,
 }
 
-impl ToCsv for SocketSample {
-    fn to_csv_row(&self) -> String {
-        format!(
-            "{},{},{}\n",
-            self.epoch_ms, self.cumulative_wait, self.count
-        )
+impl Row for SocketSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("socket_wait", Kind::Unsigned),
+            Field::new("count", Kind::Unsigned),
+        ];
+        FIELDS
     }
 
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,socket_wait,count\n"
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(self.cumulative_wait),
+            Value::Unsigned(self.count),
+        ]
     }
 }
 
@@ -831,13 +674,20 @@ struct StreamAggregatedSample {
     cumulative_wait: u64,
 }
 
-impl ToCsv for StreamAggregatedSample {
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,stream_wait\n"
+impl Row for StreamAggregatedSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("stream_wait", Kind::Unsigned),
+        ];
+        FIELDS
     }
 
-    fn to_csv_row(&self) -> String {
-        format!("{},{}\n", self.epoch_ms, self.cumulative_wait)
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(self.cumulative_wait),
+        ]
     }
 }
 
@@ -847,16 +697,22 @@ struct StreamFileSample {
     count: u64,
 }
 
-impl ToCsv for StreamFileSample {
-    fn to_csv_row(&self) -> String {
-        format!(
-            "{},{},{}\n",
-            self.epoch_ms, self.cumulative_wait, self.count
-        )
+impl Row for StreamFileSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("stream_wait", Kind::Unsigned),
+            Field::new("count", Kind::Unsigned),
+        ];
+        FIELDS
     }
 
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,stream_wait,count\n"
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(self.cumulative_wait),
+            Value::Unsigned(self.count),
+        ]
     }
 }
 
@@ -875,12 +731,14 @@ mod tests {
     };
 
     use crate::{
+        configure::Buffers,
         execute::programs::{
             ipc::{IpcProgram, TargetFile},
             pipe,
         },
         metrics::{
             ipc::{EventPollCollection, Ipc, Stats},
+            sink::csv::CsvSink,
             Collect,
         },
     };
@@ -895,24 +753,24 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058
-                AcceptEnd\texample-applica\t24239\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\n"}
+                {"type": "printf", "data": "AcceptEnd\t24239\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009\texample-applica\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, sockfs, 8, 90098]: (2848, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, sockfs, 8, 90098": [2848, 1, "example-applica"]}}}
 
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, sockfs, 8, 90098]: (43106, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, sockfs, 8, 90098": [43106, 1, "example-applica"]}}}
 
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -920,8 +778,8 @@ mod tests {
             let mut ipc = Ipc::new(
                 ipc_program.clone(),
                 24239,
-                Rc::from(tmp_dir.to_str().unwrap()),
-                "thread/24239/24239",
+                24239,
+                CsvSink::shared(tmp_dir.to_str().unwrap(), &Buffers::default()),
                 Rc::new(RefCell::new(HashMap::new())),
             );
 
@@ -962,24 +820,24 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058
-                AcceptEnd\texample-applica\t24239\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\n"}
+                {"type": "printf", "data": "AcceptEnd\t24239\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009\texample-applica\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, sockfs, 8, 90098]: (2848, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, sockfs, 8, 90098": [2848, 1, "example-applica"]}}}
 
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, sockfs, 8, 90098]: (43106, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, sockfs, 8, 90098": [43106, 1, "example-applica"]}}}
 
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -987,8 +845,8 @@ mod tests {
             let mut ipc = Ipc::new(
                 ipc_program.clone(),
                 24239,
-                Rc::from(tmp_dir.path().to_str().unwrap()),
-                "thread/24239/24239",
+                24239,
+                CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
                 Rc::new(RefCell::new(HashMap::new())),
             );
 
@@ -1023,23 +881,23 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                AcceptEnd\texample-applica\t24239\tdevpts\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009
+                {"type": "printf", "data": "AcceptEnd\t24239\tdevpts\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009\texample-applica\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, devpts, 8, 90098]: (2848, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, devpts, 8, 90098": [2848, 1, "example-applica"]}}}
 
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, devpts, 8, 90098]: (43106, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, devpts, 8, 90098": [43106, 1, "example-applica"]}}}
 
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -1047,8 +905,8 @@ mod tests {
             let mut ipc = Ipc::new(
                 ipc_program.clone(),
                 24239,
-                Rc::from(tmp_dir.to_str().unwrap()),
-                "thread/24239/24239",
+                24239,
+                CsvSink::shared(tmp_dir.to_str().unwrap(), &Buffers::default()),
                 Rc::new(RefCell::new(HashMap::new())),
             );
 
@@ -1092,23 +950,23 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                AcceptEnd\texample-applica\t24239\tdevpts\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009
+                {"type": "printf", "data": "AcceptEnd\t24239\tdevpts\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t19446862145009\texample-applica\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, devpts, 8, 90098]: (2848, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, devpts, 8, 90098": [2848, 1, "example-applica"]}}}
 
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                => start map statistics
-                @inode_map[example-applica, 24239, devpts, 8, 90098]: (43106, 1)
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"24239, devpts, 8, 90098": [43106, 1, "example-applica"]}}}
 
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -1116,8 +974,8 @@ mod tests {
             let mut ipc = Ipc::new(
                 ipc_program.clone(),
                 24239,
-                Rc::from(tmp_dir.path().to_str().unwrap()),
-                "thread/24239/24239",
+                24239,
+                CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
                 Rc::new(RefCell::new(HashMap::new())),
             );
 
@@ -1153,20 +1011,20 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
-            "};
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1202,25 +1060,25 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1256,26 +1114,26 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1311,29 +1169,29 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
-            "};
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1381,34 +1239,34 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t80672\t127.0.0.1\t50046\t127.0.0.1\t7878\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 914973709
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 914973709}}}
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1456,28 +1314,28 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                NewSocketMap\tsockfs\t8\t80672\tAF_INET\t127.0.0.1\t50046\t127.0.0.1\t7878
-                ConnectEnd\tepoll_server\t24354\tsockfs\t8\t80672\tAF_INET\t127.0.0.1\t50046\t127.0.0.1\t7878\t111111111\t1034
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t80672\tAF_INET\t127.0.0.1\t50046\t127.0.0.1\t7878\n"}
+                {"type": "printf", "data": "ConnectEnd\t24354\tsockfs\t8\t80672\tAF_INET\t127.0.0.1\t50046\t127.0.0.1\t7878\t111111111\t1034\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tsockfs\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 914973709
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 914973709}}}
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -1485,7 +1343,7 @@ mod tests {
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(tmp_dir.path().to_str().unwrap()),
+                CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1527,19 +1385,19 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
-            "};
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1578,24 +1436,24 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1634,25 +1492,25 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1691,28 +1549,28 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
-            "};
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1763,33 +1621,33 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 914973709
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 914973709}}}
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(temp_dir().to_str().unwrap()),
+                CsvSink::shared(temp_dir().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1840,26 +1698,26 @@ mod tests {
                 IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
             ));
 
-            let bpf_content = indoc! {"
-                HEADER
+            let bpf_content = indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t200000000\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 289679399
-                SampleInstant\t19447107025962
-                => end map statistics
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 289679399}}}
+                {"type": "printf", "data": "SampleInstant\t19447107025962\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
 
-                EpollAdd\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291
-                EpollRemove\tepoll_server\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358
+                {"type": "printf", "data": "EpollAdd\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446544512965\t437501291\tepoll_server\n"}
+                {"type": "printf", "data": "EpollRemove\t24354\t0xffff98dd8179e0c0\tdevpts\t8\t80672\t19446834063942\t1016301358\tepoll_server\n"}
 
-                => start map statistics
-                @epoll_map[0xffff98dd8179e0c0]: 914973709
-                SampleInstant\t19448107034740
-                => end map statistics
-            "};
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@epoll_map": {"0xffff98dd8179e0c0": 914973709}}}
+                {"type": "printf", "data": "SampleInstant\t19448107034740\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#};
             tx.write_all(bpf_content.as_bytes())?;
             while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -1867,7 +1725,7 @@ mod tests {
             let mut event_polls = EventPollCollection::new(
                 ipc_program.clone(),
                 Rc::new(RefCell::new(HashMap::new())),
-                Rc::from(tmp_dir.path().to_str().unwrap()),
+                CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
             );
 
             event_polls.sample()?;
@@ -1906,21 +1764,21 @@ mod tests {
             IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
         ));
 
-        let bpf_content = indoc! {"
-            HEADER
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
 
-            NewSocketMap\tsockfs\t8\t2519815\tAF_INET\t127.0.0.1\t60958\t127.0.0.1\t7878
-            EpollAdd\tepoll_server\t357171\t0xffff96892f1e7b00\tsockfs\t8\t2519815\t521457873233008\t861999000
+            {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t2519815\tAF_INET\t127.0.0.1\t60958\t127.0.0.1\t7878\n"}
+            {"type": "printf", "data": "EpollAdd\t357171\t0xffff96892f1e7b00\tsockfs\t8\t2519815\t521457873233008\t861999000\tepoll_server\n"}
 
-            => start map statistics
-            @inode_map[epoll_server, 357171,                           epoll, 0, -115959031497984]: (862007004, 12)
-            @inode_pending[epoll_server, 357171,                           epoll, 0, -115959031497984]: 521457873417016
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@inode_map": {"357171,                           epoll, 0, -115959031497984": [862007004, 12, "epoll_server"]}}}
+            {"type": "map", "data": {"@inode_pending": {"357171,                           epoll, 0, -115959031497984": [521457873417016, "epoll_server"]}}}
 
-            @epoll_map[0xffff96892f1e7b00]: 861999871
-            @epoll_pending[0xffff96892f1e7b00]: 521457873417016
-            SampleInstant\t521457925386742
-            => end map statistics
-        "};
+            {"type": "map", "data": {"@epoll_map": {"0xffff96892f1e7b00": 861999871}}}
+            {"type": "map", "data": {"@epoll_pending": {"0xffff96892f1e7b00": 521457873417016}}}
+            {"type": "printf", "data": "SampleInstant\t521457925386742\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         tx.write_all(bpf_content.as_bytes())?;
         while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -1928,7 +1786,7 @@ mod tests {
         let mut event_polls = EventPollCollection::new(
             ipc_program.clone(),
             Rc::new(RefCell::new(HashMap::new())),
-            Rc::from(tmp_dir.path().to_str().unwrap()),
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
         );
 
         event_polls.sample()?;
@@ -1958,8 +1816,8 @@ mod tests {
         let mut ipc = Ipc::new(
             ipc_program.clone(),
             357171,
-            Rc::from(tmp_dir.path().to_str().unwrap()),
-            "thread/357171/357171",
+            357171,
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
             Rc::new(RefCell::new(HashMap::new())),
         );
 
@@ -1989,21 +1847,21 @@ mod tests {
             IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
         ));
 
-        let bpf_content = indoc! {"
-            HEADER
-
-            NewSocketMap\tsockfs\t8\t1031455\tAF_INET6\t::1\t3001\t::1\t55774
-            AcceptEnd\tepoll_server\t67967\tsockfs\t8\t1031455\tAF_INET6\t::1\t3001\t::1\t55774\t330488529582598
-            EpollAdd\tepoll_server\t67967\t0xffff9d5c4f7ca840\tsockfs\t8\t1031455\t330488529608026\t248242602
-
-            => start map statistics
-            @inode_map[epoll_server, 67967, sockfs, 8, 1031455]: (30594, 5)
-            @inode_pending[epoll_server, 67967,                           epoll, 0, -108455180588992]: 330489199386315
-            @epoll_map[0xffff9d5c4f7ca840]: 586287502
-            @epoll_pending[0xffff9d5c4f7ca840]: 330489199386315
-            SampleInstant\t330489281294037
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t1031455\tAF_INET6\t::1\t3001\t::1\t55774\n"}
+            {"type": "printf", "data": "AcceptEnd\t67967\tsockfs\t8\t1031455\tAF_INET6\t::1\t3001\t::1\t55774\t330488529582598\tepoll_server\n"}
+            {"type": "printf", "data": "EpollAdd\t67967\t0xffff9d5c4f7ca840\tsockfs\t8\t1031455\t330488529608026\t248242602\tepoll_server\n"}
+
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@inode_map": {"67967, sockfs, 8, 1031455": [30594, 5, "epoll_server"]}}}
+            {"type": "map", "data": {"@inode_pending": {"67967,                           epoll, 0, -108455180588992": [330489199386315, "epoll_server"]}}}
+            {"type": "map", "data": {"@epoll_map": {"0xffff9d5c4f7ca840": 586287502}}}
+            {"type": "map", "data": {"@epoll_pending": {"0xffff9d5c4f7ca840": 330489199386315}}}
+            {"type": "printf", "data": "SampleInstant\t330489281294037\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         tx.write_all(bpf_content.as_bytes())?;
         while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -2011,7 +1869,7 @@ mod tests {
         let mut event_polls = EventPollCollection::new(
             ipc_program.clone(),
             Rc::new(RefCell::new(HashMap::new())),
-            Rc::from(tmp_dir.path().to_str().unwrap()),
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
         );
 
         event_polls.sample()?;
@@ -2041,8 +1899,8 @@ mod tests {
         let mut ipc = Ipc::new(
             ipc_program.clone(),
             67967,
-            Rc::from(tmp_dir.path().to_str().unwrap()),
-            "thread/67967/67967",
+            67967,
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
             Rc::new(RefCell::new(HashMap::new())),
         );
 
@@ -2085,25 +1943,25 @@ mod tests {
             IpcProgram::custom_reader(rx, Arc::new(Mutex::new(false))).unwrap(),
         ));
 
-        let bpf_content = indoc! {"
-            HEADER
-
-            NewSocketMap\tsockfs\t8\t1051498\tAF_UNIX\t0xffff9d5c5c4d3300\t0xffff9d5c5c4d1100
-            => start map statistics
-            @inode_pending[unix-accept-con, 143866, sockfs, 8, 1051498]: 332665199498858
-            SampleInstant\t332665569307417
-            => end map statistics
-
-            NewProcess\tunix-accept-con\t143868
-            NewSocketMap\tsockfs\t8\t1057237\tAF_UNIX\t0xffff9d5c5c4d1100\t0xffff9d5c5c4d3300
-            => start map statistics
-            @inode_map[unix-accept-con, 143866, devpts, 24, 8]: (8184, 1)
-            @inode_map[unix-accept-con, 143866, sockfs, 8, 1051498]: (630338761, 1)
-            @inode_map[unix-accept-con, 143868, sockfs, 8, 1057237]: (5879, 1)
-            @inode_pending[unix-accept-con, 143866, sockfs, 8, 1051498]: 332666199674298
-            SampleInstant\t332666569304709
-            => end map statistics
-        "};
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 4}}
+
+            {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t1051498\tAF_UNIX\t0xffff9d5c5c4d3300\t0xffff9d5c5c4d1100\n"}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@inode_pending": {"143866, sockfs, 8, 1051498": [332665199498858, "unix-accept-con"]}}}
+            {"type": "printf", "data": "SampleInstant\t332665569307417\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+
+            {"type": "printf", "data": "NewProcess\t143868\tsocket AF_UNIX 0xffff8b2c5b49c000 0xffff8b2c5b49c400\tunix-accept-con\n"}
+            {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t1057237\tAF_UNIX\t0xffff9d5c5c4d1100\t0xffff9d5c5c4d3300\n"}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@inode_map": {"143866, devpts, 24, 8": [8184, 1, "unix-accept-con"]}}}
+            {"type": "map", "data": {"@inode_map": {"143866, sockfs, 8, 1051498": [630338761, 1, "unix-accept-con"]}}}
+            {"type": "map", "data": {"@inode_map": {"143868, sockfs, 8, 1057237": [5879, 1, "unix-accept-con"]}}}
+            {"type": "map", "data": {"@inode_pending": {"143866, sockfs, 8, 1051498": [332666199674298, "unix-accept-con"]}}}
+            {"type": "printf", "data": "SampleInstant\t332666569304709\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
         tx.write_all(bpf_content.as_bytes())?;
         while let Ok(0) = ipc_program.borrow_mut().poll_events() {}
 
@@ -2112,7 +1970,7 @@ mod tests {
         let mut event_polls = EventPollCollection::new(
             ipc_program.clone(),
             kfile_socket_map.clone(),
-            Rc::from(tmp_dir.path().to_str().unwrap()),
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
         );
 
         event_polls.sample()?;
@@ -2121,8 +1979,8 @@ mod tests {
         let mut ipc = Ipc::new(
             ipc_program.clone(),
             143866,
-            Rc::from(tmp_dir.path().to_str().unwrap()),
-            "thread/143866/143866",
+            143866,
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
             kfile_socket_map.clone(),
         );
 
@@ -2146,8 +2004,8 @@ mod tests {
         let mut ipc = Ipc::new(
             ipc_program.clone(),
             143868,
-            Rc::from(tmp_dir.path().to_str().unwrap()),
-            "thread/143868/143868",
+            143868,
+            CsvSink::shared(tmp_dir.path().to_str().unwrap(), &Buffers::default()),
             kfile_socket_map,
         );
 
@@ -4,10 +4,13 @@ use std::{
     fmt::{self, Display},
 };
 
+pub mod diagnostics;
 pub mod futex;
 pub mod iowait;
 pub mod ipc;
+pub mod plugin;
 pub mod scheduler;
+pub mod sink;
 
 pub trait Collect {
     fn sample(&mut self) -> Result<()>;
@@ -15,10 +18,139 @@ pub trait Collect {
     fn store(&mut self) -> Result<()>;
 }
 
+/// Type of a field of the samples of a subsystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Epochs, counters, durations in ns and ids, which are never negative.
+    Unsigned,
+    Signed,
+    Float,
+    Text,
+}
+
+/// A field of the samples of a subsystem, e.g. a column of its CSV files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, kind: Kind) -> Self {
+        Self { name, kind }
+    }
+}
+
+/// Value of a field of a sample, of the kind of the field.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Text(String),
+    /// A field the sample has no value for, e.g. a percentile without
+    /// responses.
+    Missing,
+}
+
+impl Value {
+    /// Parses a field of `kind` as formatted in a CSV row, an empty field
+    /// being missing.
+    pub fn parse(kind: Kind, field: &str) -> Result<Self> {
+        if field.is_empty() && kind != Kind::Text {
+            return Ok(Self::Missing);
+        }
+        Ok(match kind {
+            Kind::Unsigned => Self::Unsigned(field.parse()?),
+            Kind::Signed => Self::Signed(field.parse()?),
+            Kind::Float => Self::Float(field.parse()?),
+            Kind::Text => Self::Text(field.to_string()),
+        })
+    }
+
+    /// The value as a measurement, unless it is text or missing.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Unsigned(value) => Some(*value as f64),
+            Self::Signed(value) => Some(*value as f64),
+            Self::Float(value) => Some(*value),
+            Self::Text(_) | Self::Missing => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Unsigned(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unsigned(value) => write!(f, "{}", value),
+            Self::Signed(value) => write!(f, "{}", value),
+            Self::Float(value) => write!(f, "{}", value),
+            Self::Text(value) => write!(f, "{}", value),
+            Self::Missing => Ok(()),
+        }
+    }
+}
+
+/// A sample as handed to the sinks, whose fields follow the schema of its
+/// subsystem so that every sink stores them with the same types.
+pub trait Row {
+    /// Fields of the samples, the same for every sample of the type.
+    fn schema(&self) -> &'static [Field];
+
+    /// Values of the fields, in the order of the schema.
+    fn fields(&self) -> Vec<Value>;
+
+    /// Value of the field named `name`, if the schema has one.
+    fn field(&self, name: &str) -> Option<Value> {
+        let index = self.schema().iter().position(|field| field.name == name)?;
+        self.fields().into_iter().nth(index)
+    }
+}
+
+/// Formats a row in the CSV layout, the names of its fields as header.
 pub trait ToCsv {
     fn to_csv_row(&self) -> String;
 
-    fn csv_headers(&self) -> &'static str;
+    fn csv_headers(&self) -> String;
+}
+
+impl<R: Row + ?Sized> ToCsv for R {
+    fn to_csv_row(&self) -> String {
+        let values: Vec<String> = self
+            .fields()
+            .iter()
+            .map(|value| match value {
+                Value::Text(value) => csv_field(value),
+                value => value.to_string(),
+            })
+            .collect();
+        format!("{}\n", values.join(","))
+    }
+
+    fn csv_headers(&self) -> String {
+        let names: Vec<&str> = self.schema().iter().map(|field| field.name).collect();
+        format!("{}\n", names.join(","))
+    }
+}
+
+/// Fits free text, e.g. a comm chosen by the process, in a single CSV field,
+/// by replacing the separators it may contain with spaces.
+pub fn csv_field(field: &str) -> String {
+    field.replace([',', '\n'], " ")
 }
 
 #[derive(Debug)]
@@ -0,0 +1,171 @@
+use eyre::Result;
+use std::{cell::RefCell, mem, rc::Rc};
+
+use super::{
+    sink::{Resource, Scope, Series, SharedSink, Subsystem},
+    Collect, Field, Row, Value,
+};
+use crate::execute::{
+    boot_to_epoch,
+    plugins::PluginSchema,
+    programs::plugin::{PluginEvent, PluginProgram},
+};
+
+struct PluginSample {
+    epoch_ms: u128,
+    value: Vec<Value>,
+    schema: &'static [Field],
+}
+
+impl Row for PluginSample {
+    fn schema(&self) -> &'static [Field] {
+        self.schema
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        [Value::Unsigned(self.epoch_ms as u64)]
+            .into_iter()
+            .chain(self.value.iter().cloned())
+            .collect()
+    }
+}
+
+/// Stores the rows of a plugin's map for a thread, as a series per key.
+pub struct Plugin {
+    pid: usize,
+    tid: usize,
+    plugin_program: Rc<RefCell<PluginProgram>>,
+    schema: Rc<PluginSchema>,
+    events: Vec<PluginEvent>,
+    sink: SharedSink,
+}
+
+impl Plugin {
+    pub fn new(
+        plugin_program: Rc<RefCell<PluginProgram>>,
+        pid: usize,
+        tid: usize,
+        sink: SharedSink,
+    ) -> Self {
+        let schema = plugin_program.borrow_mut().schema();
+        Self {
+            pid,
+            tid,
+            plugin_program,
+            schema,
+            events: Vec::new(),
+            sink,
+        }
+    }
+}
+
+impl Collect for Plugin {
+    fn sample(&mut self) -> Result<()> {
+        let events = self
+            .plugin_program
+            .borrow_mut()
+            .take_plugin_events(self.tid)?;
+        self.events.extend(events);
+        Ok(())
+    }
+
+    fn store(&mut self) -> Result<()> {
+        let mut sink = self.sink.lock().unwrap();
+        for event in mem::take(&mut self.events) {
+            let epoch_ms = boot_to_epoch(event.sample_instant_ns as u128) / 1_000_000;
+            let series = Series::new(
+                Scope::Thread {
+                    pid: self.pid,
+                    tid: self.tid,
+                },
+                Subsystem::Plugin(self.schema.name),
+                Some(Resource::PluginKey {
+                    map: self.schema.map.clone(),
+                    key: event.key,
+                }),
+            );
+            let sample = PluginSample {
+                epoch_ms,
+                value: event.value,
+                schema: self.schema.fields,
+            };
+            sink.write(&series, epoch_ms, &sample)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Plugin;
+    use crate::{
+        configure::Buffers,
+        execute::{plugins::PluginSchema, programs, programs::plugin::PluginProgram},
+        metrics::{sink::csv::CsvSink, Collect, Field, Kind},
+    };
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{
+        cell::RefCell,
+        fs,
+        io::prelude::*,
+        rc::Rc,
+        sync::{Arc, Mutex},
+    };
+    use tempdir::TempDir;
+
+    #[test]
+    fn store_rows() -> Result<()> {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("wait_ns", Kind::Signed),
+            Field::new("count", Kind::Signed),
+        ];
+        let schema = Rc::new(PluginSchema {
+            name: "mutex",
+            script: "mutex.bt".to_string(),
+            map: "lock_wait".to_string(),
+            key: vec!["tid".to_string(), "lock".to_string()],
+            value: vec!["wait_ns".to_string(), "count".to_string()],
+            tid_index: 0,
+            fields: FIELDS,
+        });
+        let (rx, mut tx) = programs::pipe();
+        let program = PluginProgram::custom_reader(schema, rx, Arc::new(Mutex::new(false)));
+        let dir = TempDir::new("")?;
+        let mut collector = Plugin::new(
+            Rc::new(RefCell::new(program)),
+            8877,
+            8955,
+            CsvSink::shared(dir.path().to_str().unwrap(), &Buffers::default()),
+        );
+
+        let bpf_content = indoc! {r#"
+            {"type": "attached_probes", "data": {"probes": 3}}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@lock_wait": {"8955, 0xffff9a7a3f5e0240": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant\t60000000000\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@lock_wait": {"8955, 0xffff9a7a3f5e0240": [1000, 1]}}}
+            {"type": "printf", "data": "SampleInstant\t61000000000\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#};
+        tx.write_all(bpf_content.as_bytes())?;
+        while collector.events.len() < 2 {
+            collector.sample()?;
+        }
+        collector.store()?;
+
+        let minute_dir = dir.path().join("thread/8877/8955/mutex/60");
+        assert_eq!(
+            fs::read_to_string(minute_dir.join("0xffff9a7a3f5e0240.csv"))?,
+            indoc! {"
+                epoch_ms,wait_ns,count
+                60000,847638877,4
+                61000,1000,1
+            "}
+        );
+        Ok(())
+    }
+}
@@ -3,15 +3,17 @@ use lazy_static::lazy_static;
 use nix::time::{self, ClockId};
 use regex::Regex;
 use std::fmt::Debug;
-use std::fs::{self, File};
-use std::io::prelude::*;
+use std::fs;
 use std::time::UNIX_EPOCH;
 use std::{
     concat,
     time::{Duration, SystemTime},
 };
 
-use super::{Collect, MissingSample, ToCsv};
+use super::{
+    sink::{Scope, Series, SharedSink, Subsystem},
+    Collect, Field, Kind, MissingSample, Row, Value,
+};
 
 lazy_static! {
     static ref REGEX_PATTERN: Regex = Regex::new(concat!(
@@ -29,9 +31,8 @@ lazy_static! {
 
 pub struct SchedStat {
     proc_file: String,
-    data_directory: String,
-    day_epoch: Option<u128>,
-    data_file: Option<File>,
+    series: Series,
+    sink: SharedSink,
     sample: Option<SchedStatSample>,
 }
 
@@ -60,26 +61,33 @@ impl From<Vec<&str>> for SchedStatSample {
     }
 }
 
-impl ToCsv for SchedStatSample {
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,runtime,rq_time,run_periods\n"
+impl Row for SchedStatSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("runtime", Kind::Unsigned),
+            Field::new("rq_time", Kind::Unsigned),
+            Field::new("run_periods", Kind::Unsigned),
+        ];
+        FIELDS
     }
 
-    fn to_csv_row(&self) -> String {
-        format!(
-            "{},{},{},{}\n",
-            self.epoch, self.runtime, self.rq_time, self.run_periods
-        )
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch as u64),
+            Value::Unsigned(self.runtime),
+            Value::Unsigned(self.rq_time),
+            Value::Unsigned(self.run_periods),
+        ]
     }
 }
 
 impl SchedStat {
-    pub fn new(tid: usize, data_directory: &str) -> Self {
+    pub fn new(pid: usize, tid: usize, procfs: &str, sink: SharedSink) -> Self {
         Self {
-            proc_file: format!("/proc/{}/schedstat", tid),
-            data_directory: format!("{}/schedstat", data_directory),
-            day_epoch: None,
-            data_file: None,
+            proc_file: format!("{}/{}/schedstat", procfs, tid),
+            series: Series::new(Scope::Thread { pid, tid }, Subsystem::SchedStat, None),
+            sink,
             sample: None,
         }
     }
@@ -99,46 +107,26 @@ impl Collect for SchedStat {
         }
 
         let sample = self.sample.take().unwrap();
-        let epoch_ms = sample.epoch;
-        let row = sample.to_csv_row();
-        let day_epoch = (epoch_ms / (1000 * 60 * 60 * 24)) * (1000 * 60 * 60 * 24);
-
-        if Some(day_epoch) != self.day_epoch {
-            let filepath = format!("{}/{}.csv", self.data_directory, day_epoch);
-            fs::create_dir_all(&self.data_directory)?;
-            self.day_epoch = Some(day_epoch);
-            let file = File::options().append(true).open(&filepath);
-            let file = match file {
-                Err(_) => {
-                    let mut file = File::options().append(true).create(true).open(&filepath)?;
-                    file.write_all(sample.csv_headers().as_bytes())?;
-                    file
-                }
-                Ok(file) => file,
-            };
-            self.data_file = Some(file);
-        }
-
-        self.data_file.as_ref().unwrap().write_all(row.as_bytes())?;
-        Ok(())
+        self.sink
+            .lock()
+            .unwrap()
+            .write(&self.series, sample.epoch, &sample)
     }
 }
 
 pub struct Sched {
     proc_file: String,
-    data_directory: String,
-    data_file: Option<File>,
-    day_epoch: Option<u128>,
+    series: Series,
+    sink: SharedSink,
     sample: Option<SchedSample>,
 }
 
 impl Sched {
-    pub fn new(tid: usize, data_directory: &str) -> Self {
+    pub fn new(pid: usize, tid: usize, procfs: &str, sink: SharedSink) -> Self {
         Self {
-            proc_file: format!("/proc/{tid}/sched"),
-            data_directory: format!("{}/sched", data_directory),
-            data_file: None,
-            day_epoch: None,
+            proc_file: format!("{procfs}/{tid}/sched"),
+            series: Series::new(Scope::Thread { pid, tid }, Subsystem::Sched, None),
+            sink,
             sample: None,
         }
     }
@@ -158,28 +146,10 @@ impl Collect for Sched {
         }
 
         let sample = self.sample.take().unwrap();
-        let epoch_ms = sample.epoch;
-        let row = sample.to_csv_row();
-        let day_epoch = (epoch_ms / (1000 * 60 * 60 * 24)) * (1000 * 60 * 60 * 24);
-
-        if Some(day_epoch) != self.day_epoch {
-            let filepath = format!("{}/{}.csv", self.data_directory, day_epoch);
-            fs::create_dir_all(&self.data_directory)?;
-            self.day_epoch = Some(day_epoch);
-            let file = File::options().append(true).open(&filepath);
-            let file = match file {
-                Err(_) => {
-                    let mut file = File::options().append(true).create(true).open(&filepath)?;
-                    file.write_all(sample.csv_headers().as_bytes())?;
-                    file
-                }
-                Ok(file) => file,
-            };
-            self.data_file = Some(file);
-        }
-
-        self.data_file.as_ref().unwrap().write_all(row.as_bytes())?;
-        Ok(())
+        self.sink
+            .lock()
+            .unwrap()
+            .write(&self.series, sample.epoch, &sample)
     }
 }
 
@@ -247,20 +217,27 @@ impl From<&str> for SchedSample {
     }
 }
 
-impl ToCsv for SchedSample {
-    fn csv_headers(&self) -> &'static str {
-        "epoch_ms,runtime,rq_time,sleep_time,block_time,iowait_time\n"
+impl Row for SchedSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("runtime", Kind::Float),
+            Field::new("rq_time", Kind::Float),
+            Field::new("sleep_time", Kind::Float),
+            Field::new("block_time", Kind::Float),
+            Field::new("iowait_time", Kind::Float),
+        ];
+        FIELDS
     }
 
-    fn to_csv_row(&self) -> String {
-        format!(
-            "{},{},{},{},{},{}\n",
-            self.epoch,
-            self.runtime,
-            self.rq_time,
-            self.sleep_time,
-            self.block_time,
-            self.iowait_time
-        )
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch as u64),
+            Value::Float(self.runtime),
+            Value::Float(self.rq_time),
+            Value::Float(self.sleep_time),
+            Value::Float(self.block_time),
+            Value::Float(self.iowait_time),
+        ]
     }
 }
@@ -0,0 +1,647 @@
+use eyre::{eyre, Result};
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use lru::LruCache;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{Resource, Scope, Series, SharedSink, Sink, Subsystem};
+use crate::{
+    configure::{Buffers, Compression},
+    metrics::{Field, Row, ToCsv, Value},
+};
+
+const MINUTE_MS: u128 = 1000 * 60;
+const DAY_MS: u128 = 1000 * 60 * 60 * 24;
+
+struct OpenFile {
+    file: File,
+    written: Instant,
+    /// End of the minute or day of the file, unset for the files holding the
+    /// whole session.
+    partition_end_ms: Option<u128>,
+}
+
+/// Writes every series to CSV files within the session directory, partitioned
+/// by minute or by day, with a header line. The files of a scope and
+/// subsystem are kept open until unused for the expiry of the buffers, and
+/// the futex files of a thread are additionally bounded in number.
+///
+/// With a compression, the file of a partition is compressed once closed,
+/// i.e. when its minute or day is over, it expired or was evicted. A sample
+/// arriving later reopens the plain file, which is appended as another frame
+/// to the compressed one when closed again.
+pub struct CsvSink {
+    data_directory: PathBuf,
+    files: HashMap<(Scope, Subsystem), LruCache<PathBuf, OpenFile>>,
+    futex_open_files: NonZeroUsize,
+    open_files_expiry: Duration,
+    compressor: Option<Compressor>,
+}
+
+/// Compresses the closed partitions on a thread, so that the collectors
+/// sharing the sink are not blocked meanwhile.
+struct Compressor {
+    tx: Sender<PathBuf>,
+    /// Partitions closed but not compressed yet.
+    pending: Arc<(Mutex<HashSet<PathBuf>>, Condvar)>,
+}
+
+impl Compressor {
+    fn new(compression: Compression) -> Self {
+        let (tx, rx) = mpsc::channel::<PathBuf>();
+        let pending: Arc<(Mutex<HashSet<PathBuf>>, Condvar)> = Arc::default();
+        let compressed = pending.clone();
+        thread::Builder::new()
+            .name("csv-compress".to_string())
+            .spawn(move || {
+                for path in rx {
+                    if let Err(e) = compress(&path, compression) {
+                        println!("Failed to compress {}. {e}", path.display());
+                    }
+                    let (paths, done) = &*compressed;
+                    paths.lock().unwrap().remove(&path);
+                    done.notify_all();
+                }
+            })
+            .expect("Failed to create csv-compress thread");
+        Self { tx, pending }
+    }
+
+    fn compress(&self, path: &Path) -> Result<()> {
+        self.pending.0.lock().unwrap().insert(path.to_path_buf());
+        self.tx.send(path.to_path_buf())?;
+        Ok(())
+    }
+
+    /// Waits until the partition at `path` is compressed, e.g. before a late
+    /// sample reopens it, or until all are with `None`.
+    fn wait(&self, path: Option<&Path>) {
+        let (paths, done) = &*self.pending;
+        let _paths = done
+            .wait_while(paths.lock().unwrap(), |paths| match path {
+                Some(path) => paths.contains(path),
+                None => !paths.is_empty(),
+            })
+            .unwrap();
+    }
+}
+
+impl CsvSink {
+    pub fn new(data_directory: &str, buffers: &Buffers) -> Self {
+        Self {
+            data_directory: PathBuf::from(data_directory),
+            files: HashMap::new(),
+            futex_open_files: buffers.futex_open_files(),
+            open_files_expiry: buffers.ipc_open_files_expiry(),
+            compressor: None,
+        }
+    }
+
+    pub fn compressed(mut self, compression: Option<Compression>) -> Self {
+        self.compressor = compression.map(Compressor::new);
+        self
+    }
+
+    pub fn shared(data_directory: &str, buffers: &Buffers) -> SharedSink {
+        Arc::new(Mutex::new(Self::new(data_directory, buffers)))
+    }
+
+    /// Path of the file holding the sample of `series` taken at `epoch_ms`,
+    /// relative to the session directory.
+    pub fn path(series: &Series, epoch_ms: u128) -> PathBuf {
+        let minute = (epoch_ms / MINUTE_MS) * 60;
+        let day = (epoch_ms / DAY_MS) * DAY_MS;
+        let scope = match series.scope {
+            Scope::Session => String::new(),
+            Scope::Process { pid } => format!("thread/{}", pid),
+            Scope::Thread { pid, tid } => format!("thread/{}/{}", pid, tid),
+            Scope::Epoll { address } => format!("global/epoll/{:x}", address),
+        };
+        let resource = series
+            .resource
+            .as_ref()
+            .map(Resource::name)
+            .unwrap_or_default();
+        let path = match (series.subsystem, &series.scope) {
+            (Subsystem::Sched, _) => format!("{}/sched/{}.csv", scope, day),
+            (Subsystem::SchedStat, _) => format!("{}/schedstat/{}.csv", scope, day),
+            (Subsystem::FutexWait, _) => {
+                format!("{}/futex/wait/{}/{}.csv", scope, minute, resource)
+            }
+            (Subsystem::FutexWake, _) => {
+                format!("{}/futex/wake/{}/{}.csv", scope, minute, resource)
+            }
+            (Subsystem::Sockets, Scope::Epoll { .. }) => {
+                format!("{}/sockets/{}/{}.csv", scope, minute, resource)
+            }
+            (Subsystem::Sockets, _) => format!("{}/ipc/sockets/{}/{}.csv", scope, minute, resource),
+            (Subsystem::Streams, Scope::Epoll { .. }) => {
+                format!("{}/streams/{}/{}.csv", scope, minute, resource)
+            }
+            (Subsystem::Streams, _) => format!("{}/ipc/streams/{}/{}.csv", scope, minute, resource),
+            // Block IO is collected system-wide, and stored under global
+            (Subsystem::IoWait, Scope::Thread { pid, tid }) => {
+                format!("global/iowait/{}/{}/{}/{}.csv", pid, tid, minute, resource)
+            }
+            (Subsystem::IoWait, _) => format!("global/iowait/{}/{}.csv", minute, resource),
+            (Subsystem::Plugin(name), _) => {
+                format!("{}/{}/{}/{}.csv", scope, name, minute, resource)
+            }
+            (Subsystem::Diagnostics, _) => format!("diagnostics/{}.csv", resource),
+            (Subsystem::Targets, _) => "targets.csv".to_string(),
+            (Subsystem::Restarts, _) => "restarts.csv".to_string(),
+            (Subsystem::Escalations, _) => "escalations.csv".to_string(),
+            (Subsystem::Exec, _) => format!("{}/exec.csv", scope),
+        };
+        PathBuf::from(path.trim_start_matches('/'))
+    }
+
+    /// End of the partition holding the sample of `series` taken at
+    /// `epoch_ms`, as of [`Self::path`].
+    fn partition_end(series: &Series, epoch_ms: u128) -> Option<u128> {
+        match series.subsystem {
+            Subsystem::Sched | Subsystem::SchedStat => Some((epoch_ms / DAY_MS + 1) * DAY_MS),
+            Subsystem::FutexWait
+            | Subsystem::FutexWake
+            | Subsystem::Sockets
+            | Subsystem::Streams
+            | Subsystem::IoWait
+            | Subsystem::Plugin(_) => Some((epoch_ms / MINUTE_MS + 1) * MINUTE_MS),
+            Subsystem::Diagnostics
+            | Subsystem::Targets
+            | Subsystem::Restarts
+            | Subsystem::Exec
+            | Subsystem::Escalations => None,
+        }
+    }
+
+    fn open(path: &Path, headers: &str) -> Result<File> {
+        match File::options().append(true).open(path) {
+            Ok(file) => Ok(file),
+            Err(_) => {
+                fs::create_dir_all(path.parent().unwrap())?;
+                let mut file = File::options().append(true).create(true).open(path)?;
+                // A reopened partition continues its compressed file, which
+                // holds the header
+                let compressed = [Compression::Zstd, Compression::Gzip]
+                    .iter()
+                    .any(|compression| compressed_path(path, *compression).exists());
+                if !compressed {
+                    file.write_all(headers.as_bytes())?;
+                }
+                Ok(file)
+            }
+        }
+    }
+
+    /// Closes a file, and has it compressed if it holds a partition.
+    fn close_file(&self, path: &Path, open_file: OpenFile) -> Result<()> {
+        drop(open_file.file);
+        match (&self.compressor, open_file.partition_end_ms) {
+            (Some(compressor), Some(_)) => compressor.compress(path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Renames the file of a socket named after its inode, once the socket's
+    /// endpoints are known.
+    fn rename_socket(
+        &self,
+        files: &mut LruCache<PathBuf, OpenFile>,
+        series: &Series,
+        epoch_ms: u128,
+        path: &Path,
+    ) -> Result<()> {
+        if let Some(Resource::Socket {
+            device,
+            inode_id,
+            connection: Some(_),
+        }) = &series.resource
+        {
+            let unnamed = Series {
+                resource: Some(Resource::Socket {
+                    device: *device,
+                    inode_id: *inode_id,
+                    connection: None,
+                }),
+                ..series.clone()
+            };
+            let unnamed = self.data_directory.join(Self::path(&unnamed, epoch_ms));
+            if let Some(file) = files.pop(&unnamed) {
+                fs::rename(&unnamed, path)?;
+                files.put(path.to_path_buf(), file);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for CsvSink {
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        let path = self.data_directory.join(Self::path(series, epoch_ms));
+        let group = (series.scope.clone(), series.subsystem);
+        let mut files = self
+            .files
+            .remove(&group)
+            .unwrap_or_else(|| match series.subsystem {
+                Subsystem::FutexWait | Subsystem::FutexWake => LruCache::new(self.futex_open_files),
+                _ => LruCache::unbounded(),
+            });
+
+        let res = self
+            .rename_socket(&mut files, series, epoch_ms, &path)
+            .and_then(|_| {
+                if !files.contains(&path) {
+                    if let Some(compressor) = &self.compressor {
+                        compressor.wait(Some(&path));
+                    }
+                    let file = Self::open(&path, &sample.csv_headers())?;
+                    let evicted = files.push(
+                        path.clone(),
+                        OpenFile {
+                            file,
+                            written: Instant::now(),
+                            partition_end_ms: Self::partition_end(series, epoch_ms),
+                        },
+                    );
+                    if let Some((path, open_file)) = evicted {
+                        self.close_file(&path, open_file)?;
+                    }
+                }
+                let open_file = files.get_mut(&path).unwrap();
+                open_file.written = Instant::now();
+                open_file.file.write_all(sample.to_csv_row().as_bytes())?;
+                Ok(())
+            });
+        self.files.insert(group, files);
+        res
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let expiry = self.open_files_expiry;
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        let mut closed = Vec::new();
+        for files in self.files.values_mut() {
+            let expired: Vec<PathBuf> = files
+                .iter()
+                .filter(|(_, open_file)| {
+                    // Samples of the bpftrace programs arrive up to a sampling
+                    // interval late, so a partition is kept open for a minute
+                    let ended = open_file
+                        .partition_end_ms
+                        .is_some_and(|end| end + MINUTE_MS <= now_ms);
+                    ended || open_file.written.elapsed() >= expiry
+                })
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in expired {
+                let open_file = files.pop(&path).unwrap();
+                closed.push((path, open_file));
+            }
+        }
+        // Drops the groups of e.g. the threads which exited
+        self.files.retain(|_, files| !files.is_empty());
+
+        for (path, open_file) in closed {
+            self.close_file(&path, open_file)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let closed: Vec<(PathBuf, OpenFile)> = self
+            .files
+            .drain()
+            .flat_map(|(_, files)| files.into_iter())
+            .collect();
+        for (path, open_file) in closed {
+            self.close_file(&path, open_file)?;
+        }
+        if let Some(compressor) = &self.compressor {
+            compressor.wait(None);
+        }
+        Ok(())
+    }
+}
+
+fn compressed_path(path: &Path, compression: Compression) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(match compression {
+        Compression::Zstd => ".zst",
+        Compression::Gzip => ".gz",
+    });
+    PathBuf::from(path)
+}
+
+/// Appends the content of a closed file as a frame of its compressed
+/// counterpart, e.g. `<device>.csv.zst`, and removes it.
+fn compress(path: &Path, compression: Compression) -> Result<()> {
+    let mut plain = File::open(path)?;
+    let compressed = File::options()
+        .append(true)
+        .create(true)
+        .open(compressed_path(path, compression))?;
+    match compression {
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(compressed, 0)?;
+            io::copy(&mut plain, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(compressed, flate2::Compression::default());
+            io::copy(&mut plain, &mut encoder)?;
+            encoder.finish()?;
+        }
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Reads the CSV file at `path`, e.g. `thread/<pid>/<tid>/sched/<day>.csv`,
+/// whether it was compressed, left plain, or both as it was reopened after
+/// being compressed.
+pub fn read_csv(path: &Path) -> Result<String> {
+    let mut content = String::new();
+    let zstd_path = compressed_path(path, Compression::Zstd);
+    if zstd_path.exists() {
+        zstd::Decoder::new(File::open(zstd_path)?)?.read_to_string(&mut content)?;
+    }
+    let gzip_path = compressed_path(path, Compression::Gzip);
+    if gzip_path.exists() {
+        MultiGzDecoder::new(File::open(gzip_path)?).read_to_string(&mut content)?;
+    }
+    if path.exists() || content.is_empty() {
+        File::open(path)?.read_to_string(&mut content)?;
+    }
+    Ok(content)
+}
+
+/// Reads back the values of a row written for the fields of `schema`, whose
+/// text fields hold no comma.
+pub fn parse_row(schema: &[Field], row: &str) -> Result<Vec<Value>> {
+    let fields: Vec<&str> = row.split(',').collect();
+    if fields.len() != schema.len() {
+        return Err(eyre!(
+            "Invalid row {}, expected {} fields",
+            row,
+            schema.len()
+        ));
+    }
+    schema
+        .iter()
+        .zip(fields)
+        .map(|(field, value)| Value::parse(field.kind, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_csv, CsvSink};
+    use crate::{
+        configure::{Buffers, Compression},
+        execute::programs::ipc::{Connection, TargetFile},
+        metrics::{
+            sink::{Resource, Scope, Series, Sink, Subsystem},
+            Field, Kind, Row, Value,
+        },
+    };
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{fs, net::Ipv4Addr, path::PathBuf};
+    use tempdir::TempDir;
+
+    struct Sample(u128);
+
+    impl Row for Sample {
+        fn schema(&self) -> &'static [Field] {
+            const FIELDS: &[Field] = &[Field::new("epoch_ms", Kind::Unsigned)];
+            FIELDS
+        }
+
+        fn fields(&self) -> Vec<Value> {
+            vec![Value::Unsigned(self.0 as u64)]
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let thread = Scope::Thread {
+            pid: 8877,
+            tid: 8955,
+        };
+        for (series, path) in [
+            (
+                Series::new(thread.clone(), Subsystem::Sched, None),
+                "thread/8877/8955/sched/1722729600000.csv",
+            ),
+            (
+                Series::new(
+                    thread.clone(),
+                    Subsystem::FutexWait,
+                    Some(Resource::Futex {
+                        root_pid: 8877,
+                        uaddr: "0x7c3dd4f85fb0".to_string(),
+                    }),
+                ),
+                "thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv",
+            ),
+            (
+                Series::new(
+                    Scope::Epoll {
+                        address: 0xffff9a7a3f5e0240,
+                    },
+                    Subsystem::Streams,
+                    Some(Resource::Stream(TargetFile::Inode {
+                        device: 14,
+                        inode_id: 95,
+                    })),
+                ),
+                "global/epoll/ffff9a7a3f5e0240/streams/1722794820/14_95.csv",
+            ),
+            (
+                Series::new(thread, Subsystem::IoWait, Some(Resource::BlockDevice(8))),
+                "global/iowait/8877/8955/1722794820/8.csv",
+            ),
+            (
+                Series::new(
+                    Scope::Session,
+                    Subsystem::Diagnostics,
+                    Some(Resource::Program("futex")),
+                ),
+                "diagnostics/futex.csv",
+            ),
+            (
+                Series::new(Scope::Process { pid: 8877 }, Subsystem::Exec, None),
+                "thread/8877/exec.csv",
+            ),
+        ] {
+            assert_eq!(CsvSink::path(&series, 1722794820000), PathBuf::from(path));
+        }
+    }
+
+    #[test]
+    fn rename_socket() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let mut sink = CsvSink::new(dir.path().to_str().unwrap(), &Buffers::default());
+        let scope = Scope::Thread {
+            pid: 8877,
+            tid: 8955,
+        };
+        let mut series = Series::new(
+            scope,
+            Subsystem::Sockets,
+            Some(Resource::Socket {
+                device: 8,
+                inode_id: 35961,
+                connection: None,
+            }),
+        );
+        sink.write(&series, 1722794820000, &Sample(1722794820000))?;
+        series.resource = Some(Resource::Socket {
+            device: 8,
+            inode_id: 35961,
+            connection: Some(Connection::Ipv4 {
+                src_host: Ipv4Addr::new(172, 26, 0, 2),
+                src_port: 58656,
+                dst_host: Ipv4Addr::new(172, 26, 0, 2),
+                dst_port: 29093,
+            }),
+        });
+        sink.write(&series, 1722794821000, &Sample(1722794821000))?;
+
+        let minute_dir = dir.path().join("thread/8877/8955/ipc/sockets/1722794820");
+        assert!(!minute_dir.join("8_35961.csv").exists());
+        assert_eq!(
+            fs::read_to_string(minute_dir.join("ipv4_172.26.0.2:58656_172.26.0.2:29093.csv"))?,
+            indoc! {"
+                epoch_ms
+                1722794820000
+                1722794821000
+            "}
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn futex_open_files() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let buffers = Buffers {
+            futex_open_files: 1,
+            ..Buffers::default()
+        };
+        let mut sink = CsvSink::new(dir.path().to_str().unwrap(), &buffers);
+        let thread = Scope::Thread {
+            pid: 8877,
+            tid: 8955,
+        };
+        for uaddr in ["0x7c3dd4f85fb0", "0x7c3dd4f85fb8", "0x7c3dd4f85fb0"] {
+            let series = Series::new(
+                thread.clone(),
+                Subsystem::FutexWait,
+                Some(Resource::Futex {
+                    root_pid: 8877,
+                    uaddr: uaddr.to_string(),
+                }),
+            );
+            sink.write(&series, 1722794820000, &Sample(1722794820000))?;
+            assert_eq!(
+                sink.files.values().map(|files| files.len()).sum::<usize>(),
+                1
+            );
+        }
+
+        let path = dir
+            .path()
+            .join("thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv");
+        assert_eq!(
+            fs::read_to_string(path)?,
+            "epoch_ms\n1722794820000\n1722794820000\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compress_closed_partitions() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let mut sink = CsvSink::new(dir.path().to_str().unwrap(), &Buffers::default())
+            .compressed(Some(Compression::Zstd));
+        let series = Series::new(
+            Scope::Thread {
+                pid: 8877,
+                tid: 8955,
+            },
+            Subsystem::Sched,
+            None,
+        );
+        let path = dir.path().join("thread/8877/8955/sched/1722729600000.csv");
+
+        // The day of the sample is over, so its partition is compressed, on
+        // the thread of the compressor
+        sink.write(&series, 1722794820000, &Sample(1722794820000))?;
+        sink.flush()?;
+        sink.compressor.as_ref().unwrap().wait(None);
+        assert!(!path.exists());
+        assert!(dir
+            .path()
+            .join("thread/8877/8955/sched/1722729600000.csv.zst")
+            .exists());
+
+        // A late sample reopens the partition without repeating the header
+        sink.write(&series, 1722794821000, &Sample(1722794821000))?;
+        assert!(path.exists());
+        assert_eq!(read_csv(&path)?, "epoch_ms\n1722794820000\n1722794821000\n");
+        sink.close()?;
+        assert!(!path.exists());
+        assert_eq!(read_csv(&path)?, "epoch_ms\n1722794820000\n1722794821000\n");
+        Ok(())
+    }
+
+    #[test]
+    fn compress_evicted_files() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let buffers = Buffers {
+            futex_open_files: 1,
+            ..Buffers::default()
+        };
+        let mut sink = CsvSink::new(dir.path().to_str().unwrap(), &buffers)
+            .compressed(Some(Compression::Gzip));
+        for uaddr in ["0x7c3dd4f85fb0", "0x7c3dd4f85fb8"] {
+            let series = Series::new(
+                Scope::Thread {
+                    pid: 8877,
+                    tid: 8955,
+                },
+                Subsystem::FutexWait,
+                Some(Resource::Futex {
+                    root_pid: 8877,
+                    uaddr: uaddr.to_string(),
+                }),
+            );
+            sink.write(&series, 1722794820000, &Sample(1722794820000))?;
+        }
+
+        sink.compressor.as_ref().unwrap().wait(None);
+
+        let minute_dir = dir.path().join("thread/8877/8955/futex/wait/1722794820");
+        let evicted = minute_dir.join("8877-0x7c3dd4f85fb0.csv");
+        assert!(!evicted.exists());
+        assert!(minute_dir.join("8877-0x7c3dd4f85fb0.csv.gz").exists());
+        assert_eq!(read_csv(&evicted)?, "epoch_ms\n1722794820000\n");
+        assert!(minute_dir.join("8877-0x7c3dd4f85fb8.csv").exists());
+        Ok(())
+    }
+}
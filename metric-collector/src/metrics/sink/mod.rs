@@ -0,0 +1,267 @@
+use eyre::Result;
+use std::sync::{Arc, Mutex};
+
+use super::Row;
+use crate::{
+    configure::{Config, Format},
+    execute::programs::ipc::{Connection, TargetFile},
+};
+
+pub mod csv;
+pub mod openmetrics;
+pub mod parquet;
+pub mod sqlite;
+
+/// What a series of samples is collected for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// The session as a whole, e.g. the log of its targets.
+    Session,
+    Process {
+        pid: usize,
+    },
+    Thread {
+        pid: usize,
+        tid: usize,
+    },
+    /// An epoll instance, whose waits are shared by the threads using it.
+    Epoll {
+        address: u64,
+    },
+}
+
+/// Kind of samples of a series.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    Sched,
+    SchedStat,
+    FutexWait,
+    FutexWake,
+    Sockets,
+    Streams,
+    IoWait,
+    Plugin(&'static str),
+    Diagnostics,
+    Targets,
+    Restarts,
+    Exec,
+    Escalations,
+}
+
+impl Subsystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sched => "sched",
+            Self::SchedStat => "schedstat",
+            Self::FutexWait => "futex_wait",
+            Self::FutexWake => "futex_wake",
+            Self::Sockets => "sockets",
+            Self::Streams => "streams",
+            Self::IoWait => "iowait",
+            Self::Plugin(name) => name,
+            Self::Diagnostics => "diagnostics",
+            Self::Targets => "targets",
+            Self::Restarts => "restarts",
+            Self::Exec => "exec",
+            Self::Escalations => "escalations",
+        }
+    }
+}
+
+/// Resource a series is about within its scope.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Resource {
+    Futex {
+        root_pid: usize,
+        uaddr: String,
+    },
+    /// A socket, along with its endpoints once they are known.
+    Socket {
+        device: u32,
+        inode_id: u64,
+        connection: Option<Connection>,
+    },
+    /// A pipe, character device, anonymous inode or nested epoll instance.
+    Stream(TargetFile),
+    BlockDevice(u32),
+    /// A bpftrace program, or the retention policy, e.g. of the diagnostics.
+    Program(&'static str),
+    /// A row of a plugin's map, by the fields of its key other than the tid.
+    PluginKey {
+        map: String,
+        key: Vec<String>,
+    },
+}
+
+impl Resource {
+    /// Name of the resource within a series, e.g. of its CSV files.
+    pub fn name(&self) -> String {
+        match self {
+            Resource::Futex { root_pid, uaddr } => format!("{}-{}", root_pid, uaddr),
+            Resource::Socket {
+                device,
+                inode_id,
+                connection,
+            } => match connection {
+                Some(Connection::Ipv4 {
+                    src_host,
+                    src_port,
+                    dst_host,
+                    dst_port,
+                }) => format!(
+                    "ipv4_{}:{}_{}:{}",
+                    src_host.octets().map(|elem| elem.to_string()).join("."),
+                    src_port,
+                    dst_host.octets().map(|elem| elem.to_string()).join("."),
+                    dst_port,
+                ),
+                Some(Connection::Ipv6 {
+                    src_host,
+                    src_port,
+                    dst_host,
+                    dst_port,
+                }) => format!(
+                    "ipv6_[{}]:{}_[{}]:{}",
+                    src_host
+                        .segments()
+                        .map(|elem| format!("{:x}", elem))
+                        .join(":"),
+                    src_port,
+                    dst_host
+                        .segments()
+                        .map(|elem| format!("{:x}", elem))
+                        .join(":"),
+                    dst_port,
+                ),
+                Some(Connection::Unix {
+                    src_address,
+                    dst_address,
+                }) => format!("unix_{:#x}_{:#x}", src_address, dst_address),
+                None => format!("{}_{}", device, inode_id),
+            },
+            Resource::Stream(TargetFile::Inode { device, inode_id }) => {
+                format!("{:?}_{:?}", device, inode_id)
+            }
+            Resource::Stream(TargetFile::AnonInode { name, address }) => {
+                format!("{}_{:x}", name, address)
+            }
+            Resource::Stream(TargetFile::Epoll { address }) => format!("epoll_{:x}", address),
+            Resource::BlockDevice(device) => format!("{:?}", device),
+            Resource::Program(program) => program.to_string(),
+            Resource::PluginKey { map, key } if key.is_empty() => map.clone(),
+            Resource::PluginKey { key, .. } => key
+                .iter()
+                .map(|field| field.replace(['/', '\0'], "_"))
+                .collect::<Vec<_>>()
+                .join("-"),
+        }
+    }
+}
+
+/// Identity of a series of samples, independent of how a sink stores it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Series {
+    pub scope: Scope,
+    pub subsystem: Subsystem,
+    pub resource: Option<Resource>,
+}
+
+impl Series {
+    pub fn new(scope: Scope, subsystem: Subsystem, resource: Option<Resource>) -> Self {
+        Self {
+            scope,
+            subsystem,
+            resource,
+        }
+    }
+}
+
+/// Pid, tid and command name of an entry of the targets log.
+fn target_comm(sample: &dyn Row) -> Option<(usize, usize, String)> {
+    let pid = sample.field("pid")?.as_u64()?;
+    let tid = sample.field("tid")?.as_u64()?;
+    let comm = sample.field("comm")?.as_str()?.to_string();
+    Some((pid as usize, tid as usize, comm))
+}
+
+/// Destination of the samples of the collectors, which decides how series are
+/// laid out on disk.
+pub trait Sink {
+    /// Stores a sample of `series`, taken at `epoch_ms`.
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()>;
+
+    /// Called once per sampling interval, after the collectors stored their
+    /// samples.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Called when the session ends, to store what is left buffered.
+    fn close(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// A sink shared by the collectors, including the time sensitive ones which
+/// are sampled on their own thread.
+pub type SharedSink = Arc<Mutex<dyn Sink + Send>>;
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        (**self).write(series, epoch_ms, sample)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        (**self).close()
+    }
+}
+
+/// Hands every sample to several sinks, e.g. the data files and the exporter.
+pub struct Tee(pub Vec<Box<dyn Sink + Send>>);
+
+impl Sink for Tee {
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        for sink in self.0.iter_mut() {
+            sink.write(series, epoch_ms, sample)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for sink in self.0.iter_mut() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        for sink in self.0.iter_mut() {
+            sink.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates the sink of the data files of the output format, within
+/// `data_directory`.
+pub fn files(config: &Config, data_directory: &str) -> Result<Box<dyn Sink + Send>> {
+    Ok(match config.format {
+        Format::Csv => Box::new(
+            csv::CsvSink::new(data_directory, &config.buffers).compressed(config.compression),
+        ),
+        Format::Parquet => Box::new(parquet::ParquetSink::new(data_directory)),
+        Format::Sqlite => Box::new(sqlite::SqliteSink::new(data_directory)?),
+    })
+}
+
+/// Shares the sink of the data files, or of the flight recorder's window,
+/// with the collectors, along with the exporter if it serves the metrics.
+pub fn shared(config: &Config, files: Box<dyn Sink + Send>) -> Result<SharedSink> {
+    if config.exporter.listen.is_none() {
+        return Ok(Arc::new(Mutex::new(files)));
+    }
+    let exporter = openmetrics::OpenMetricsSink::serve(&config.exporter)?;
+    Ok(Arc::new(Mutex::new(Tee(vec![files, Box::new(exporter)]))))
+}
@@ -0,0 +1,406 @@
+use eyre::{eyre, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{target_comm, Scope, Series, Sink, Subsystem};
+use crate::{configure::Exporter, metrics::Row};
+
+/// Connections served at once, each on its own thread. Beyond them, a
+/// connection is closed without a response.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Latest numeric fields of a series, by field name.
+struct Latest {
+    fields: Vec<(&'static str, f64)>,
+    updated: Instant,
+}
+
+/// Series of a subsystem, by their rendered labels.
+#[derive(Default)]
+struct Family {
+    series: BTreeMap<String, Latest>,
+    dropped: u64,
+}
+
+#[derive(Default)]
+struct Families {
+    families: BTreeMap<&'static str, Family>,
+}
+
+impl Families {
+    /// Renders the families in the OpenMetrics text format, as a gauge per
+    /// field of a subsystem's samples.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (subsystem, family) in &self.families {
+            let mut metrics: BTreeMap<&str, Vec<(&str, f64)>> = BTreeMap::new();
+            for (labels, latest) in &family.series {
+                for (field, value) in &latest.fields {
+                    metrics
+                        .entry(field)
+                        .or_default()
+                        .push((labels.as_str(), *value));
+                }
+            }
+            for (field, samples) in metrics {
+                let name = metric_name(&format!("prism_{}_{}", subsystem, field));
+                let _ = writeln!(out, "# TYPE {} gauge", name);
+                for (labels, value) in samples {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+        let _ = writeln!(out, "# TYPE prism_exporter_dropped_samples counter");
+        let _ = writeln!(
+            out,
+            "# HELP prism_exporter_dropped_samples Samples of series beyond max_series."
+        );
+        for (subsystem, family) in &self.families {
+            let _ = writeln!(
+                out,
+                "prism_exporter_dropped_samples_total{{subsystem=\"{}\"}} {}",
+                subsystem, family.dropped
+            );
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// Serves the latest sample of every series on `/metrics` while the session
+/// is recorded, with the `pid`, `tid`, `comm`, `epoll` and `resource` of the
+/// series as labels. The number of series of a subsystem is bounded, and a
+/// series is dropped once it had no sample for the expiry of the exporter.
+pub struct OpenMetricsSink {
+    families: Arc<Mutex<Families>>,
+    /// Command names of the threads, from the targets log.
+    comms: HashMap<(usize, usize), String>,
+    max_series: usize,
+    series_expiry: Duration,
+    /// Address the metrics are served on.
+    address: Option<SocketAddr>,
+}
+
+impl OpenMetricsSink {
+    pub fn new(exporter: &Exporter) -> Self {
+        Self {
+            families: Arc::new(Mutex::new(Families::default())),
+            comms: HashMap::new(),
+            max_series: exporter.max_series,
+            series_expiry: exporter.series_expiry(),
+            address: None,
+        }
+    }
+
+    /// Starts serving the metrics on the address of the exporter.
+    pub fn serve(exporter: &Exporter) -> Result<Self> {
+        let mut sink = Self::new(exporter);
+        let address = exporter
+            .listen
+            .as_deref()
+            .ok_or(eyre!("No address to serve metrics on"))?;
+        let listener = TcpListener::bind(address)
+            .map_err(|e| eyre!("Could not serve metrics on {}. {}", address, e))?;
+        let address = listener.local_addr()?;
+        println!("Serve metrics on http://{}/metrics", address);
+        sink.address = Some(address);
+
+        let families = sink.families.clone();
+        thread::Builder::new()
+            .name("metrics-http".to_string())
+            .spawn(move || {
+                // A slow or idle client only holds up its own connection
+                let connections = Arc::new(AtomicUsize::new(0));
+                for stream in listener.incoming().flatten() {
+                    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    let families = families.clone();
+                    let connections = connections.clone();
+                    let spawned = thread::Builder::new()
+                        .name("metrics-conn".to_string())
+                        .spawn(move || {
+                            if let Err(e) = respond(stream, &families) {
+                                println!("Failed to serve metrics. {e}");
+                            }
+                            connections.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    if let Err(e) = spawned {
+                        println!("Failed to serve metrics. {e}");
+                    }
+                }
+            })
+            .expect("Failed to create metrics-http thread");
+        Ok(sink)
+    }
+
+    fn labels(&self, series: &Series) -> String {
+        let mut labels = Vec::new();
+        match series.scope {
+            Scope::Session => {}
+            Scope::Process { pid } => labels.push(("pid", pid.to_string())),
+            Scope::Thread { pid, tid } => {
+                labels.push(("pid", pid.to_string()));
+                labels.push(("tid", tid.to_string()));
+                if let Some(comm) = self.comms.get(&(pid, tid)) {
+                    labels.push(("comm", comm.clone()));
+                }
+            }
+            Scope::Epoll { address } => labels.push(("epoll", format!("{:x}", address))),
+        }
+        if let Some(resource) = &series.resource {
+            labels.push(("resource", resource.name()));
+        }
+        labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl Sink for OpenMetricsSink {
+    fn write(&mut self, series: &Series, _epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        let subsystem = match series.subsystem {
+            Subsystem::Targets => {
+                if let Some((pid, tid, comm)) = target_comm(sample) {
+                    self.comms.insert((pid, tid), comm);
+                }
+                return Ok(());
+            }
+            // Logs of events rather than measurements
+            Subsystem::Restarts | Subsystem::Exec | Subsystem::Escalations => return Ok(()),
+            subsystem => subsystem.name(),
+        };
+        let fields = sample
+            .schema()
+            .iter()
+            .zip(sample.fields())
+            .filter(|(field, _)| !field.name.starts_with("epoch"))
+            .filter_map(|(field, value)| value.as_f64().map(|value| (field.name, value)))
+            .collect();
+
+        let labels = self.labels(series);
+        let mut families = self.families.lock().unwrap();
+        let family = families.families.entry(subsystem).or_default();
+        if !family.series.contains_key(&labels) && family.series.len() >= self.max_series {
+            family.dropped += 1;
+            return Ok(());
+        }
+        family.series.insert(
+            labels,
+            Latest {
+                fields,
+                updated: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut families = self.families.lock().unwrap();
+        for family in families.families.values_mut() {
+            family
+                .series
+                .retain(|_, latest| latest.updated.elapsed() < self.series_expiry);
+        }
+        Ok(())
+    }
+}
+
+fn respond(stream: TcpStream, families: &Mutex<Families>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers of the request are not used
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut stream = reader.into_inner();
+    let mut request = request_line.split_whitespace();
+    match (request.next(), request.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = families.lock().unwrap().render();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+        }
+        _ => write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?,
+    }
+    Ok(())
+}
+
+/// Replaces the characters not allowed in a metric name.
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpenMetricsSink;
+    use crate::{
+        configure::Exporter,
+        metrics::{
+            sink::{Resource, Scope, Series, Sink, Subsystem},
+            Field, Kind, Row, Value,
+        },
+        target::{TargetAction, TargetLogEntry},
+    };
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        rc::Rc,
+        time::{Duration, Instant},
+    };
+
+    struct WaitSample {
+        wait_ns: u64,
+        count: u64,
+    }
+
+    impl Row for WaitSample {
+        fn schema(&self) -> &'static [Field] {
+            const FIELDS: &[Field] = &[
+                Field::new("epoch_ms", Kind::Unsigned),
+                Field::new("futex_wait_ns", Kind::Unsigned),
+                Field::new("futex_count", Kind::Unsigned),
+            ];
+            FIELDS
+        }
+
+        fn fields(&self) -> Vec<Value> {
+            vec![
+                Value::Unsigned(1722794820000),
+                Value::Unsigned(self.wait_ns),
+                Value::Unsigned(self.count),
+            ]
+        }
+    }
+
+    fn futex(tid: usize, uaddr: &str) -> Series {
+        Series::new(
+            Scope::Thread { pid: 8877, tid },
+            Subsystem::FutexWait,
+            Some(Resource::Futex {
+                root_pid: 8877,
+                uaddr: uaddr.to_string(),
+            }),
+        )
+    }
+
+    #[test]
+    fn latest_samples() -> Result<()> {
+        let mut sink = OpenMetricsSink::new(&Exporter {
+            max_series: 2,
+            ..Exporter::default()
+        });
+        let entry = TargetLogEntry {
+            epoch_ms: 1722794820000,
+            pid: 8877,
+            tid: 8955,
+            comm: Rc::from("redis-server"),
+            action: TargetAction::Added,
+            cause: "pids argument".to_string(),
+        };
+        sink.write(
+            &Series::new(Scope::Session, Subsystem::Targets, None),
+            entry.epoch_ms,
+            &entry,
+        )?;
+        for (series, wait_ns) in [
+            (futex(8955, "0x7c3dd4f85fb0"), 1000),
+            (futex(8955, "0x7c3dd4f85fb0"), 3000),
+            (futex(8956, "0x7c3dd4f85fb0"), 2000),
+            (futex(8957, "0x7c3dd4f85fb0"), 4000),
+        ] {
+            sink.write(&series, 1722794820000, &WaitSample { wait_ns, count: 2 })?;
+        }
+
+        assert_eq!(
+            sink.families.lock().unwrap().render(),
+            indoc! {r#"
+                # TYPE prism_futex_wait_futex_count gauge
+                prism_futex_wait_futex_count{pid="8877",tid="8955",comm="redis-server",resource="8877-0x7c3dd4f85fb0"} 2
+                prism_futex_wait_futex_count{pid="8877",tid="8956",resource="8877-0x7c3dd4f85fb0"} 2
+                # TYPE prism_futex_wait_futex_wait_ns gauge
+                prism_futex_wait_futex_wait_ns{pid="8877",tid="8955",comm="redis-server",resource="8877-0x7c3dd4f85fb0"} 3000
+                prism_futex_wait_futex_wait_ns{pid="8877",tid="8956",resource="8877-0x7c3dd4f85fb0"} 2000
+                # TYPE prism_exporter_dropped_samples counter
+                # HELP prism_exporter_dropped_samples Samples of series beyond max_series.
+                prism_exporter_dropped_samples_total{subsystem="futex_wait"} 1
+                # EOF
+            "#}
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn serve() -> Result<()> {
+        let mut sink = OpenMetricsSink::serve(&Exporter {
+            listen: Some("127.0.0.1:0".to_string()),
+            ..Exporter::default()
+        })?;
+        sink.write(
+            &futex(8955, "0x7c3dd4f85fb0"),
+            1722794820000,
+            &WaitSample {
+                wait_ns: 1000,
+                count: 1,
+            },
+        )?;
+
+        let get = |path: &str| -> Result<String> {
+            let mut stream = TcpStream::connect(sink.address.unwrap())?;
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let response = get("/metrics")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("application/openmetrics-text"));
+        assert!(response.contains(
+            "prism_futex_wait_futex_wait_ns{pid=\"8877\",tid=\"8955\",resource=\"8877-0x7c3dd4f85fb0\"} 1000\n"
+        ));
+        assert!(response.ends_with("# EOF\n"));
+        assert!(get("/")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // A connection without a request does not hold up the scrapes
+        let _idle = TcpStream::connect(sink.address.unwrap())?;
+        let start = Instant::now();
+        assert!(get("/metrics")?.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+}
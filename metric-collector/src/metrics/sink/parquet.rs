@@ -0,0 +1,450 @@
+use eyre::{eyre, Result};
+use parquet::{
+    basic::{Compression, LogicalType, Repetition, Type as PhysicalType},
+    data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{Resource, Scope, Series, Sink, Subsystem};
+use crate::metrics::{Field, Kind, Row, Value};
+
+/// Length of a time partition, the minute of the CSV layout.
+const PARTITION_MS: u128 = 60 * 1000;
+
+/// Columns identifying the series of a row, which precede the sample's.
+const KEY_FIELDS: [Field; 4] = [
+    Field::new("pid", Kind::Unsigned),
+    Field::new("tid", Kind::Unsigned),
+    Field::new("epoll", Kind::Text),
+    Field::new("resource", Kind::Text),
+];
+
+struct Record {
+    pid: Option<usize>,
+    tid: Option<usize>,
+    epoll: Option<String>,
+    resource: Option<String>,
+    values: Vec<Value>,
+}
+
+/// Directory, sample headers and start of a partition. Samples of a
+/// subsystem with other headers are written to separate files.
+type PartitionKey = (String, Vec<&'static str>, u128);
+
+/// Rows of a partition, along with the schema of their samples.
+struct Partition {
+    schema: &'static [Field],
+    records: Vec<Record>,
+}
+
+/// Writes a Parquet file per subsystem and minute, to
+/// `parquet/<subsystem>/<minute>.parquet`, with the `pid`, `tid`, `epoll` and
+/// `resource` of the series as columns along with the sample's. The columns
+/// are typed after the schema of the subsystem, so that every file of a
+/// subsystem has the same schema. The rows of a minute are buffered until the
+/// next minute ended, and a sample arriving later is written to an additional
+/// file of its minute.
+pub struct ParquetSink {
+    data_directory: PathBuf,
+    partitions: BTreeMap<PartitionKey, Partition>,
+}
+
+impl ParquetSink {
+    pub fn new(data_directory: &str) -> Self {
+        Self {
+            data_directory: PathBuf::from(data_directory),
+            partitions: BTreeMap::new(),
+        }
+    }
+
+    /// Directory of the files of a series' subsystem, relative to the
+    /// session directory.
+    fn directory(series: &Series) -> String {
+        match (series.subsystem, &series.scope) {
+            (Subsystem::Plugin(name), _) => format!("parquet/plugin/{}", name),
+            (subsystem, Scope::Epoll { .. }) => format!("parquet/epoll/{}", subsystem.name()),
+            (subsystem, _) => format!("parquet/{}", subsystem.name()),
+        }
+    }
+
+    /// Writes the partitions which ended before `epoch_ms`.
+    fn write_before(&mut self, epoch_ms: u128) -> Result<()> {
+        let ended: Vec<PartitionKey> = self
+            .partitions
+            .keys()
+            .filter(|(_, _, start)| start + PARTITION_MS <= epoch_ms)
+            .cloned()
+            .collect();
+        for key in ended {
+            let partition = self.partitions.remove(&key).unwrap();
+            let (directory, _, start) = key;
+            let directory = self.data_directory.join(directory);
+            fs::create_dir_all(&directory)?;
+            write_file(&available_path(&directory, start / 1000), partition)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for ParquetSink {
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        let start = (epoch_ms / PARTITION_MS) * PARTITION_MS;
+        let schema = sample.schema();
+        let headers = schema.iter().map(|field| field.name).collect();
+        let partition = self
+            .partitions
+            .entry((Self::directory(series), headers, start))
+            .or_insert_with(|| Partition {
+                schema,
+                records: Vec::new(),
+            });
+        let (pid, tid, epoll) = match series.scope {
+            Scope::Session => (None, None, None),
+            Scope::Process { pid } => (Some(pid), None, None),
+            Scope::Thread { pid, tid } => (Some(pid), Some(tid), None),
+            Scope::Epoll { address } => (None, None, Some(format!("{:x}", address))),
+        };
+        partition.records.push(Record {
+            pid,
+            tid,
+            epoll,
+            resource: series.resource.as_ref().map(Resource::name),
+            values: sample.fields(),
+        });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        // Samples of the bpftrace programs arrive up to a sampling interval
+        // late, so a minute is kept until the next one ended
+        self.write_before(now_ms.saturating_sub(PARTITION_MS))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.write_before(u128::MAX)
+    }
+}
+
+/// Path of the file of the partition starting at `epoch_s`, suffixed when the
+/// partition was already written.
+fn available_path(directory: &Path, epoch_s: u128) -> PathBuf {
+    let mut path = directory.join(format!("{}.parquet", epoch_s));
+    let mut index = 1;
+    while path.exists() {
+        path = directory.join(format!("{}-{}.parquet", epoch_s, index));
+        index += 1;
+    }
+    path
+}
+
+/// Values of a column, of the type of its field. Missing values are null.
+enum Column {
+    UInt64(Vec<Option<u64>>),
+    Int64(Vec<Option<i64>>),
+    Double(Vec<Option<f64>>),
+    Utf8(Vec<Option<String>>),
+}
+
+impl Column {
+    fn new(field: &Field, values: Vec<Value>) -> Result<Self> {
+        let mismatch = |value: &Value| eyre!("Invalid value {:?} of field {}", value, field.name);
+        Ok(match field.kind {
+            Kind::Unsigned => Self::UInt64(
+                values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Unsigned(value) => Ok(Some(*value)),
+                        Value::Missing => Ok(None),
+                        value => Err(mismatch(value)),
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            Kind::Signed => Self::Int64(
+                values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Signed(value) => Ok(Some(*value)),
+                        Value::Missing => Ok(None),
+                        value => Err(mismatch(value)),
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            Kind::Float => Self::Double(
+                values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Float(value) => Ok(Some(*value)),
+                        Value::Missing => Ok(None),
+                        value => Err(mismatch(value)),
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            Kind::Text => Self::Utf8(
+                values
+                    .into_iter()
+                    .map(|value| match value {
+                        Value::Text(value) => Ok(Some(value)),
+                        Value::Missing => Ok(None),
+                        value => Err(mismatch(&value)),
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+}
+
+/// Type of the column of a field, e.g. `UINT_64` for the counters.
+fn column_type(field: &Field) -> Result<Type> {
+    let builder = match field.kind {
+        Kind::Unsigned => Type::primitive_type_builder(field.name, PhysicalType::INT64)
+            .with_logical_type(Some(LogicalType::Integer {
+                bit_width: 64,
+                is_signed: false,
+            })),
+        Kind::Signed => Type::primitive_type_builder(field.name, PhysicalType::INT64),
+        Kind::Float => Type::primitive_type_builder(field.name, PhysicalType::DOUBLE),
+        Kind::Text => Type::primitive_type_builder(field.name, PhysicalType::BYTE_ARRAY)
+            .with_logical_type(Some(LogicalType::String)),
+    };
+    Ok(builder.with_repetition(Repetition::OPTIONAL).build()?)
+}
+
+/// Splits optional values into the present ones and their definition levels.
+fn levels<T: Clone>(values: &[Option<T>]) -> (Vec<T>, Vec<i16>) {
+    let present = values.iter().flatten().cloned().collect();
+    let levels = values.iter().map(|value| value.is_some() as i16).collect();
+    (present, levels)
+}
+
+fn write_file(path: &Path, partition: Partition) -> Result<()> {
+    let Partition { schema, records } = partition;
+    // The keys named like a field of the sample are left out
+    let keys: Vec<&Field> = KEY_FIELDS
+        .iter()
+        .filter(|key| !schema.iter().any(|field| field.name == key.name))
+        .collect();
+
+    let mut columns: Vec<(&Field, Column)> = Vec::new();
+    for key in keys {
+        let values = records
+            .iter()
+            .map(|record| {
+                let value = match key.name {
+                    "pid" => record.pid.map(|pid| Value::Unsigned(pid as u64)),
+                    "tid" => record.tid.map(|tid| Value::Unsigned(tid as u64)),
+                    "epoll" => record.epoll.clone().map(Value::Text),
+                    _ => record.resource.clone().map(Value::Text),
+                };
+                value.unwrap_or(Value::Missing)
+            })
+            .collect();
+        columns.push((key, Column::new(key, values)?));
+    }
+    let mut rows: Vec<_> = records
+        .into_iter()
+        .map(|record| record.values.into_iter())
+        .collect();
+    for field in schema {
+        let values = rows
+            .iter_mut()
+            .map(|values| values.next().unwrap_or(Value::Missing))
+            .collect();
+        columns.push((field, Column::new(field, values)?));
+    }
+
+    let fields = columns
+        .iter()
+        .map(|(field, _)| column_type(field).map(Arc::new))
+        .collect::<Result<Vec<_>>>()?;
+    let schema = Type::group_type_builder("sample")
+        .with_fields(fields)
+        .build()?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer =
+        SerializedFileWriter::new(File::create(path)?, Arc::new(schema), Arc::new(properties))?;
+    let mut row_group = writer.next_row_group()?;
+    for (_, column) in columns {
+        let mut column_writer = row_group.next_column()?.expect("One writer per field");
+        match column {
+            Column::UInt64(values) => {
+                // Unsigned integers are stored as the bits of an INT64
+                let (values, levels) = levels(&values);
+                let values: Vec<i64> = values.into_iter().map(|value| value as i64).collect();
+                column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            Column::Int64(values) => {
+                let (values, levels) = levels(&values);
+                column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            Column::Double(values) => {
+                let (values, levels) = levels(&values);
+                column_writer
+                    .typed::<DoubleType>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            Column::Utf8(values) => {
+                let (values, levels) = levels(&values);
+                let values: Vec<ByteArray> =
+                    values.iter().map(|value| value.as_str().into()).collect();
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+        }
+        column_writer.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParquetSink;
+    use crate::metrics::{
+        sink::{Resource, Scope, Series, Sink, Subsystem},
+        Field, Kind, Row, Value,
+    };
+    use eyre::Result;
+    use parquet::{
+        file::{reader::FileReader, serialized_reader::SerializedFileReader},
+        record::RowAccessor,
+        schema::types::Type,
+    };
+    use std::{fs::File, path::Path, sync::Arc};
+    use tempdir::TempDir;
+
+    struct WaitSample {
+        epoch_ms: u128,
+        wait_ns: u64,
+        comm: &'static str,
+    }
+
+    impl Row for WaitSample {
+        fn schema(&self) -> &'static [Field] {
+            const FIELDS: &[Field] = &[
+                Field::new("epoch_ms", Kind::Unsigned),
+                Field::new("wait_ns", Kind::Unsigned),
+                Field::new("comm", Kind::Text),
+            ];
+            FIELDS
+        }
+
+        fn fields(&self) -> Vec<Value> {
+            vec![
+                Value::Unsigned(self.epoch_ms as u64),
+                Value::Unsigned(self.wait_ns),
+                match self.comm {
+                    "" => Value::Missing,
+                    comm => Value::Text(comm.to_string()),
+                },
+            ]
+        }
+    }
+
+    fn futex_series(tid: usize) -> Series {
+        Series::new(
+            Scope::Thread { pid: 8877, tid },
+            Subsystem::FutexWait,
+            Some(Resource::Futex {
+                root_pid: 8877,
+                uaddr: "0x7c3dd4f85fb0".to_string(),
+            }),
+        )
+    }
+
+    fn read_schema(path: &Path) -> Result<Vec<Arc<Type>>> {
+        let reader = SerializedFileReader::new(File::open(path)?)?;
+        Ok(reader
+            .metadata()
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .to_vec())
+    }
+
+    #[test]
+    fn partitions() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let mut sink = ParquetSink::new(dir.path().to_str().unwrap());
+        for (tid, epoch_ms, wait_ns, comm) in [
+            (8955, 1722794820000, 1000, "redis-server"),
+            (8956, 1722794821000, 2000, ""),
+            (8955, 1722794880000, 3000, "redis-server"),
+        ] {
+            let sample = WaitSample {
+                epoch_ms,
+                wait_ns,
+                comm,
+            };
+            sink.write(&futex_series(tid), epoch_ms, &sample)?;
+        }
+
+        sink.write_before(1722794880000)?;
+        let directory = dir.path().join("parquet/futex_wait");
+        assert!(directory.join("1722794820.parquet").exists());
+        assert!(!directory.join("1722794880.parquet").exists());
+
+        let reader = SerializedFileReader::new(File::open(directory.join("1722794820.parquet"))?)?;
+        let fields: Vec<String> = reader
+            .metadata()
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .iter()
+            .map(|field| field.name().to_string())
+            .collect();
+        assert_eq!(
+            fields,
+            ["pid", "tid", "epoll", "resource", "epoch_ms", "wait_ns", "comm"]
+        );
+        let rows = reader.into_iter().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_ulong(0)?, 8877);
+        assert_eq!(rows[1].get_ulong(1)?, 8956);
+        assert!(rows[0].get_string(2).is_err());
+        assert_eq!(rows[0].get_string(3)?, "8877-0x7c3dd4f85fb0");
+        assert_eq!(rows[1].get_ulong(4)?, 1722794821000);
+        assert_eq!(rows[1].get_ulong(5)?, 2000);
+        assert_eq!(rows[0].get_string(6)?, "redis-server");
+        assert!(rows[1].get_string(6).is_err());
+
+        // A late sample of a written minute goes to another file, whose
+        // columns have the same types although its comm is missing
+        let sample = WaitSample {
+            epoch_ms: 1722794822000,
+            wait_ns: u64::MAX,
+            comm: "",
+        };
+        sink.write(&futex_series(8955), sample.epoch_ms, &sample)?;
+        sink.close()?;
+        assert!(directory.join("1722794880.parquet").exists());
+        let late = directory.join("1722794820-1.parquet");
+        assert_eq!(
+            read_schema(&late)?,
+            read_schema(&directory.join("1722794820.parquet"))?
+        );
+        let reader = SerializedFileReader::new(File::open(late)?)?;
+        let rows = reader.into_iter().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(rows[0].get_ulong(5)?, u64::MAX);
+        Ok(())
+    }
+}
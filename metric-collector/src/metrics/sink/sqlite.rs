@@ -0,0 +1,572 @@
+use eyre::{eyre, Result};
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection as Database};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use super::{target_comm, Resource, Scope, Series, Sink, Subsystem};
+use crate::{
+    execute::programs::ipc::{Connection, TargetFile},
+    metrics::{Field, Kind, Row, Value},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS processes (
+        pid INTEGER PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS threads (
+        id INTEGER PRIMARY KEY,
+        pid INTEGER NOT NULL REFERENCES processes (pid),
+        tid INTEGER NOT NULL,
+        comm TEXT,
+        UNIQUE (pid, tid)
+    );
+    CREATE TABLE IF NOT EXISTS resources (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        key TEXT NOT NULL,
+        name TEXT NOT NULL,
+        root_pid INTEGER,
+        uaddr TEXT,
+        device INTEGER,
+        inode_id INTEGER,
+        address TEXT,
+        family TEXT,
+        src_host TEXT,
+        src_port INTEGER,
+        dst_host TEXT,
+        dst_port INTEGER,
+        UNIQUE (kind, key)
+    );
+";
+
+/// Columns of a sample table referencing the series of its rows, which
+/// precede the fields of the samples.
+const KEY_COLUMNS: [&str; 4] = ["thread_id", "pid", "epoll_id", "resource_id"];
+
+/// Writes the samples to `prism.db` in the session directory, in a table per
+/// subsystem, e.g. `futex_wait`, `epoll_sockets` or `plugin_<name>`. A
+/// sample table has a column per field of the samples, typed after the schema
+/// of the subsystem, along with the `thread_id`, `pid`, `epoll_id` and
+/// `resource_id` of the series referencing the `threads`, `processes` and
+/// `resources` tables. The samples of an interval are inserted in a single
+/// transaction, committed on flush.
+pub struct SqliteSink {
+    database: Database,
+    /// Schemas of the sample tables created so far.
+    tables: HashMap<String, &'static [Field]>,
+    processes: HashSet<usize>,
+    threads: HashMap<(usize, usize), i64>,
+    /// Ids of the resources, along with whether a socket's endpoints were
+    /// stored.
+    resources: HashMap<(&'static str, String), (i64, bool)>,
+    in_transaction: bool,
+}
+
+impl SqliteSink {
+    pub fn new(data_directory: &str) -> Result<Self> {
+        fs::create_dir_all(data_directory)?;
+        let database = Database::open(Path::new(data_directory).join("prism.db"))?;
+        database.pragma_update(None, "journal_mode", "WAL")?;
+        database.pragma_update(None, "synchronous", "NORMAL")?;
+        database.execute_batch(SCHEMA)?;
+        database.set_prepared_statement_cache_capacity(64);
+        Ok(Self {
+            database,
+            tables: HashMap::new(),
+            processes: HashSet::new(),
+            threads: HashMap::new(),
+            resources: HashMap::new(),
+            in_transaction: false,
+        })
+    }
+
+    fn table(series: &Series) -> String {
+        match (series.subsystem, &series.scope) {
+            (Subsystem::Plugin(name), _) => format!("plugin_{}", name.replace('-', "_")),
+            // The enforcements of the retention policy are not program diagnostics
+            (Subsystem::Diagnostics, _)
+                if series.resource == Some(Resource::Program("retention")) =>
+            {
+                "retention".to_string()
+            }
+            (subsystem, Scope::Epoll { .. }) => format!("epoll_{}", subsystem.name()),
+            (subsystem, _) => subsystem.name().to_string(),
+        }
+    }
+
+    fn process_id(&mut self, pid: usize) -> Result<usize> {
+        if self.processes.insert(pid) {
+            self.database
+                .prepare_cached("INSERT OR IGNORE INTO processes (pid) VALUES (?1)")?
+                .execute(params![pid])?;
+        }
+        Ok(pid)
+    }
+
+    fn thread_id(&mut self, pid: usize, tid: usize) -> Result<i64> {
+        if let Some(id) = self.threads.get(&(pid, tid)) {
+            return Ok(*id);
+        }
+        self.process_id(pid)?;
+        self.database
+            .prepare_cached("INSERT OR IGNORE INTO threads (pid, tid) VALUES (?1, ?2)")?
+            .execute(params![pid, tid])?;
+        let id = self
+            .database
+            .prepare_cached("SELECT id FROM threads WHERE pid = ?1 AND tid = ?2")?
+            .query_row(params![pid, tid], |row| row.get(0))?;
+        self.threads.insert((pid, tid), id);
+        Ok(id)
+    }
+
+    fn resource_id(&mut self, resource: &Resource) -> Result<i64> {
+        let (kind, key) = identity(resource);
+        let named = !matches!(
+            resource,
+            Resource::Socket {
+                connection: None,
+                ..
+            }
+        );
+        match self.resources.get(&(kind, key.clone())) {
+            Some((id, stored_named)) if *stored_named || !named => return Ok(*id),
+            _ => {}
+        }
+
+        let columns = Columns::of(resource);
+        self.database
+            .prepare_cached(
+                "INSERT INTO resources (kind, key, name, root_pid, uaddr, device, inode_id,
+                     address, family, src_host, src_port, dst_host, dst_port)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+                 ON CONFLICT (kind, key) DO UPDATE SET name = excluded.name,
+                     family = excluded.family, src_host = excluded.src_host,
+                     src_port = excluded.src_port, dst_host = excluded.dst_host,
+                     dst_port = excluded.dst_port",
+            )?
+            .execute(params![
+                kind,
+                key,
+                resource.name(),
+                columns.root_pid,
+                columns.uaddr,
+                columns.device,
+                columns.inode_id,
+                columns.address,
+                columns.family,
+                columns.src_host,
+                columns.src_port,
+                columns.dst_host,
+                columns.dst_port,
+            ])?;
+        let id = self
+            .database
+            .prepare_cached("SELECT id FROM resources WHERE kind = ?1 AND key = ?2")?
+            .query_row(params![kind, key], |row| row.get(0))?;
+        self.resources.insert((kind, key), (id, named));
+        Ok(id)
+    }
+
+    /// Names a thread after the command name of its latest entry in the
+    /// targets log.
+    fn record_comm(&mut self, sample: &dyn Row) -> Result<()> {
+        if let Some((pid, tid, comm)) = target_comm(sample) {
+            let id = self.thread_id(pid, tid)?;
+            self.database
+                .prepare_cached("UPDATE threads SET comm = ?1 WHERE id = ?2")?
+                .execute(params![comm, id])?;
+        }
+        Ok(())
+    }
+
+    /// Creates the table of a subsystem from the schema of its samples, with
+    /// the key columns not named like one of its fields.
+    fn ensure_table(&mut self, table: &str, schema: &'static [Field]) -> Result<()> {
+        match self.tables.get(table) {
+            Some(known) if *known == schema => return Ok(()),
+            Some(_) => return Err(eyre!("Samples of another schema for table {}", table)),
+            None => {}
+        }
+        let columns: Vec<String> = key_columns(schema)
+            .map(|column| format!("\"{}\" INTEGER", column))
+            .chain(schema.iter().map(|field| {
+                let kind = match field.kind {
+                    Kind::Unsigned | Kind::Signed => "INTEGER",
+                    Kind::Float => "REAL",
+                    Kind::Text => "TEXT",
+                };
+                format!("\"{}\" {}", field.name, kind)
+            }))
+            .collect();
+        self.database.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" ({}) STRICT",
+            table,
+            columns.join(", ")
+        ))?;
+        self.tables.insert(table.to_string(), schema);
+        Ok(())
+    }
+}
+
+impl Sink for SqliteSink {
+    fn write(&mut self, series: &Series, _epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        if !self.in_transaction {
+            self.database.execute_batch("BEGIN")?;
+            self.in_transaction = true;
+        }
+
+        let schema = sample.schema();
+        let mut keys = HashMap::new();
+        match series.scope {
+            Scope::Session => {}
+            Scope::Process { pid } => {
+                keys.insert("pid", self.process_id(pid)? as i64);
+            }
+            Scope::Thread { pid, tid } => {
+                keys.insert("thread_id", self.thread_id(pid, tid)?);
+            }
+            Scope::Epoll { address } => {
+                let epoll = Resource::Stream(TargetFile::Epoll { address });
+                keys.insert("epoll_id", self.resource_id(&epoll)?);
+            }
+        }
+        if let Some(resource) = &series.resource {
+            keys.insert("resource_id", self.resource_id(resource)?);
+        }
+        let columns: Vec<&str> = key_columns(schema)
+            .chain(schema.iter().map(|field| field.name))
+            .collect();
+        let mut row: Vec<SqlValue> = key_columns(schema)
+            .map(|column| match keys.get(column) {
+                Some(id) => SqlValue::Integer(*id),
+                None => SqlValue::Null,
+            })
+            .collect();
+        for (field, value) in schema.iter().zip(sample.fields()) {
+            row.push(sql_value(field, value)?);
+        }
+
+        if series.subsystem == Subsystem::Targets {
+            self.record_comm(sample)?;
+        }
+
+        let table = Self::table(series);
+        self.ensure_table(&table, schema)?;
+        let sql = format!(
+            "INSERT INTO \"{}\" ({}) VALUES ({})",
+            table,
+            columns
+                .iter()
+                .map(|column| format!("\"{}\"", column))
+                .collect::<Vec<_>>()
+                .join(", "),
+            vec!["?"; columns.len()].join(", ")
+        );
+        self.database
+            .prepare_cached(&sql)?
+            .execute(params_from_iter(row))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.in_transaction {
+            self.database.execute_batch("COMMIT")?;
+            self.in_transaction = false;
+        }
+        Ok(())
+    }
+}
+
+/// Key columns of a table whose samples have the fields of `schema`.
+fn key_columns(schema: &'static [Field]) -> impl Iterator<Item = &'static str> {
+    KEY_COLUMNS
+        .into_iter()
+        .filter(|column| !schema.iter().any(|field| field.name == *column))
+}
+
+/// Converts a value to the type of the column of its field. An unsigned value
+/// beyond the range of an SQLite integer is rejected rather than wrapped.
+fn sql_value(field: &Field, value: Value) -> Result<SqlValue> {
+    Ok(match (field.kind, value) {
+        (_, Value::Missing) => SqlValue::Null,
+        (Kind::Unsigned, Value::Unsigned(value)) => match i64::try_from(value) {
+            Ok(value) => SqlValue::Integer(value),
+            Err(_) => return Err(eyre!("{} of {} overflows an integer", value, field.name)),
+        },
+        (Kind::Signed, Value::Signed(value)) => SqlValue::Integer(value),
+        (Kind::Float, Value::Float(value)) => SqlValue::Real(value),
+        (Kind::Text, Value::Text(value)) => SqlValue::Text(value),
+        (_, value) => return Err(eyre!("Invalid value {:?} of field {}", value, field.name)),
+    })
+}
+
+/// Kind of a resource and the key identifying it among its kind. A socket is
+/// identified by its inode, whether or not its endpoints are known.
+fn identity(resource: &Resource) -> (&'static str, String) {
+    match resource {
+        Resource::Futex { root_pid, uaddr } => ("futex", format!("{}-{}", root_pid, uaddr)),
+        Resource::Socket {
+            device, inode_id, ..
+        } => ("socket", format!("{}_{}", device, inode_id)),
+        Resource::Stream(TargetFile::Inode { device, inode_id }) => {
+            ("pipe", format!("{}_{}", device, inode_id))
+        }
+        Resource::Stream(TargetFile::AnonInode { .. }) => ("anon_inode", resource.name()),
+        Resource::Stream(TargetFile::Epoll { address }) => ("epoll", format!("{:x}", address)),
+        Resource::BlockDevice(device) => ("block_device", device.to_string()),
+        Resource::Program(program) => ("program", program.to_string()),
+        Resource::PluginKey { map, .. } => ("plugin_key", format!("{}/{}", map, resource.name())),
+    }
+}
+
+/// Columns of the `resources` table describing a resource.
+#[derive(Default)]
+struct Columns {
+    root_pid: Option<usize>,
+    uaddr: Option<String>,
+    device: Option<u32>,
+    inode_id: Option<u64>,
+    address: Option<String>,
+    family: Option<&'static str>,
+    src_host: Option<String>,
+    src_port: Option<u64>,
+    dst_host: Option<String>,
+    dst_port: Option<u64>,
+}
+
+impl Columns {
+    fn of(resource: &Resource) -> Self {
+        match resource {
+            Resource::Futex { root_pid, uaddr } => Self {
+                root_pid: Some(*root_pid),
+                uaddr: Some(uaddr.clone()),
+                ..Self::default()
+            },
+            Resource::Socket {
+                device,
+                inode_id,
+                connection,
+            } => {
+                let mut columns = Self {
+                    device: Some(*device),
+                    inode_id: Some(*inode_id),
+                    ..Self::default()
+                };
+                match connection {
+                    Some(Connection::Ipv4 {
+                        src_host,
+                        src_port,
+                        dst_host,
+                        dst_port,
+                    }) => {
+                        columns.family = Some("ipv4");
+                        columns.src_host = Some(src_host.to_string());
+                        columns.src_port = Some(*src_port);
+                        columns.dst_host = Some(dst_host.to_string());
+                        columns.dst_port = Some(*dst_port);
+                    }
+                    Some(Connection::Ipv6 {
+                        src_host,
+                        src_port,
+                        dst_host,
+                        dst_port,
+                    }) => {
+                        columns.family = Some("ipv6");
+                        columns.src_host = Some(src_host.to_string());
+                        columns.src_port = Some(*src_port);
+                        columns.dst_host = Some(dst_host.to_string());
+                        columns.dst_port = Some(*dst_port);
+                    }
+                    Some(Connection::Unix {
+                        src_address,
+                        dst_address,
+                    }) => {
+                        columns.family = Some("unix");
+                        columns.src_host = Some(format!("{:#x}", src_address));
+                        columns.dst_host = Some(format!("{:#x}", dst_address));
+                    }
+                    None => {}
+                }
+                columns
+            }
+            Resource::Stream(TargetFile::Inode { device, inode_id }) => Self {
+                device: Some(*device),
+                inode_id: Some(*inode_id),
+                ..Self::default()
+            },
+            Resource::Stream(TargetFile::AnonInode { address, .. })
+            | Resource::Stream(TargetFile::Epoll { address }) => Self {
+                address: Some(format!("{:x}", address)),
+                ..Self::default()
+            },
+            Resource::BlockDevice(device) => Self {
+                device: Some(*device),
+                ..Self::default()
+            },
+            Resource::Program(_) | Resource::PluginKey { .. } => Self::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteSink;
+    use crate::{
+        execute::programs::ipc::Connection,
+        metrics::{
+            sink::{Resource, Scope, Series, Sink, Subsystem},
+            Field, Kind, Row, Value,
+        },
+    };
+    use eyre::Result;
+    use std::net::Ipv4Addr;
+    use tempdir::TempDir;
+
+    struct WaitSample {
+        epoch_ms: u128,
+        wait_ns: u64,
+    }
+
+    impl Row for WaitSample {
+        fn schema(&self) -> &'static [Field] {
+            const FIELDS: &[Field] = &[
+                Field::new("epoch_ms", Kind::Unsigned),
+                Field::new("wait_ns", Kind::Unsigned),
+            ];
+            FIELDS
+        }
+
+        fn fields(&self) -> Vec<Value> {
+            vec![
+                Value::Unsigned(self.epoch_ms as u64),
+                Value::Unsigned(self.wait_ns),
+            ]
+        }
+    }
+
+    #[test]
+    fn normalized_tables() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let mut sink = SqliteSink::new(dir.path().to_str().unwrap())?;
+        let futex = |tid| {
+            Series::new(
+                Scope::Thread { pid: 8877, tid },
+                Subsystem::FutexWait,
+                Some(Resource::Futex {
+                    root_pid: 8877,
+                    uaddr: "0x7c3dd4f85fb0".to_string(),
+                }),
+            )
+        };
+        let socket = |connection| {
+            Series::new(
+                Scope::Epoll {
+                    address: 0xffff9a7a3f5e0240,
+                },
+                Subsystem::Sockets,
+                Some(Resource::Socket {
+                    device: 8,
+                    inode_id: 92813,
+                    connection,
+                }),
+            )
+        };
+        let connection = Connection::Ipv4 {
+            src_host: Ipv4Addr::new(172, 26, 0, 2),
+            src_port: 58656,
+            dst_host: Ipv4Addr::new(172, 26, 0, 2),
+            dst_port: 29093,
+        };
+        for (series, epoch_ms, wait_ns) in [
+            (futex(8955), 1722794820000, 1000),
+            (futex(8956), 1722794821000, 2000),
+            (futex(8955), 1722794822000, 3000),
+            (socket(None), 1722794820000, 500),
+            (socket(Some(connection)), 1722794821000, 700),
+        ] {
+            sink.write(&series, epoch_ms, &WaitSample { epoch_ms, wait_ns })?;
+        }
+        sink.flush()?;
+
+        let database = &sink.database;
+        let waits: Vec<(usize, i64)> = database
+            .prepare(
+                "SELECT t.tid, SUM(f.wait_ns) FROM futex_wait f
+                 JOIN threads t ON t.id = f.thread_id
+                 JOIN resources r ON r.id = f.resource_id
+                 WHERE r.uaddr = '0x7c3dd4f85fb0' AND f.epoch_ms < 1722794823000
+                 GROUP BY t.tid ORDER BY 2 DESC",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(waits, vec![(8955, 4000), (8956, 2000)]);
+
+        let sockets: Vec<(String, Option<String>, i64)> = database
+            .prepare(
+                "SELECT e.address, r.family, COUNT(*) FROM epoll_sockets s
+                 JOIN resources e ON e.id = s.epoll_id
+                 JOIN resources r ON r.id = s.resource_id
+                 GROUP BY s.resource_id",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            sockets,
+            vec![("ffff9a7a3f5e0240".to_string(), Some("ipv4".to_string()), 2)]
+        );
+        let processes: i64 =
+            database.query_row("SELECT COUNT(*) FROM processes", [], |row| row.get(0))?;
+        assert_eq!(processes, 1);
+
+        let types: Vec<(String, String)> = database
+            .prepare("SELECT name, type FROM pragma_table_info('futex_wait')")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            types,
+            [
+                ("thread_id", "INTEGER"),
+                ("pid", "INTEGER"),
+                ("epoll_id", "INTEGER"),
+                ("resource_id", "INTEGER"),
+                ("epoch_ms", "INTEGER"),
+                ("wait_ns", "INTEGER"),
+            ]
+            .map(|(name, kind)| (name.to_string(), kind.to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unsigned_overflow() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let mut sink = SqliteSink::new(dir.path().to_str().unwrap())?;
+        let thread = Scope::Thread {
+            pid: 8877,
+            tid: 8955,
+        };
+        let series = Series::new(thread, Subsystem::FutexWait, None);
+        let sample = WaitSample {
+            epoch_ms: 1722794820000,
+            wait_ns: i64::MAX as u64,
+        };
+        sink.write(&series, sample.epoch_ms, &sample)?;
+        let sample = WaitSample {
+            epoch_ms: 1722794821000,
+            wait_ns: i64::MAX as u64 + 1,
+        };
+        assert!(sink.write(&series, sample.epoch_ms, &sample).is_err());
+        sink.flush()?;
+
+        let waits: Vec<i64> = sink
+            .database
+            .prepare("SELECT wait_ns FROM futex_wait")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(waits, [i64::MAX]);
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use eyre::{eyre, Result};
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    configure::{Config, ConfigFile},
+    execute::{
+        plugins,
+        programs::{
+            futex::FutexProgram, iowait::IOWaitProgram, ipc::IpcProgram, plugin::PluginProgram,
+            BOOT_EPOCH_NS,
+        },
+    },
+    metrics::sink::csv,
+    target::TargetLogEntry,
+};
+
+/// Name of the directory of the raw streams within the session directory.
+const RAW: &str = "raw";
+/// Name of the file holding the epoch of the boot, which the instants of the
+/// raw streams are relative to.
+const BOOT_EPOCH_FILE: &str = "boot_epoch_ns";
+
+/// Creates the `raw` directory of the session, which the stats programs record
+/// their output to, along with the epoch of the boot. Returns the directory.
+pub fn record(data_directory: &str) -> Result<PathBuf> {
+    let directory = Path::new(data_directory).join(RAW);
+    fs::create_dir_all(&directory)?;
+    fs::write(
+        directory.join(BOOT_EPOCH_FILE),
+        format!("{}\n", *BOOT_EPOCH_NS.read().unwrap()),
+    )?;
+    Ok(directory)
+}
+
+/// The stats programs of a recorded session, each reading its raw stream in
+/// place of bpftrace.
+pub struct RawStreams {
+    pub futex: Option<Rc<RefCell<FutexProgram>>>,
+    pub ipc: Option<Rc<RefCell<IpcProgram>>>,
+    pub io_wait: Option<Rc<RefCell<IOWaitProgram>>>,
+    pub plugins: Vec<Rc<RefCell<PluginProgram>>>,
+}
+
+impl RawStreams {
+    /// Opens the raw streams of `session`, and restores the epoch of the boot
+    /// it was recorded after. The plugins are loaded from the plugin
+    /// directory of `config`, or else of the session.
+    pub fn open(session: &str, config: &Config, terminate_flag: Arc<Mutex<bool>>) -> Result<Self> {
+        let directory = Path::new(session).join(RAW);
+        let boot_epoch_ns = fs::read_to_string(directory.join(BOOT_EPOCH_FILE)).map_err(|e| {
+            eyre!(
+                "Session {} was not recorded with --record-raw. {}",
+                session,
+                e
+            )
+        })?;
+        *BOOT_EPOCH_NS.write().unwrap() = boot_epoch_ns.trim().parse()?;
+
+        let futex = match open_stream(&directory, "futex")? {
+            Some(file) => Some(FutexProgram::custom_reader(file, terminate_flag.clone())?),
+            None => None,
+        };
+        let ipc = match open_stream(&directory, "ipc")? {
+            Some(file) => Some(IpcProgram::custom_reader(file, terminate_flag.clone())?),
+            None => None,
+        };
+        let io_wait = open_stream(&directory, "iowait")?
+            .map(|file| IOWaitProgram::custom_reader(file, terminate_flag.clone()));
+
+        let plugin_directory = match &config.bpf.plugin_directory {
+            Some(plugin_directory) => Some(plugin_directory.clone()),
+            None => {
+                ConfigFile::read(&format!("{}/config.toml", session))
+                    .map_err(|e| eyre!("{}", e))?
+                    .bpf
+                    .plugin_directory
+            }
+        };
+        let schemas = match &plugin_directory {
+            Some(plugin_directory) => plugins::load(plugin_directory)?,
+            None => Vec::new(),
+        };
+        let mut plugins = Vec::new();
+        for schema in schemas {
+            if let Some(file) = open_stream(&directory, schema.name)? {
+                let plugin =
+                    PluginProgram::custom_reader(Rc::new(schema), file, terminate_flag.clone());
+                plugins.push(Rc::new(RefCell::new(plugin)));
+            }
+        }
+
+        if futex.is_none() && ipc.is_none() && io_wait.is_none() && plugins.is_empty() {
+            return Err(eyre!("No raw stream in {}", directory.display()));
+        }
+        Ok(Self {
+            futex: futex.map(|futex| Rc::new(RefCell::new(futex))),
+            ipc: ipc.map(|ipc| Rc::new(RefCell::new(ipc))),
+            io_wait: io_wait.map(|io_wait| Rc::new(RefCell::new(io_wait))),
+            plugins,
+        })
+    }
+
+    /// Reads the available output of the streams, and returns whether they
+    /// were all read up to their end. The new processes are dropped, as the
+    /// targets are replayed from the targets log.
+    pub fn poll(&self) -> Result<bool> {
+        let mut exhausted = true;
+        if let Some(futex) = &self.futex {
+            let mut futex = futex.borrow_mut();
+            futex.take_new_pid_events()?;
+            exhausted &= futex.exhausted();
+        }
+        if let Some(ipc) = &self.ipc {
+            let mut ipc = ipc.borrow_mut();
+            ipc.take_process_events()?;
+            exhausted &= ipc.exhausted();
+        }
+        if let Some(io_wait) = &self.io_wait {
+            let mut io_wait = io_wait.borrow_mut();
+            io_wait.poll_events()?;
+            exhausted &= io_wait.exhausted();
+        }
+        for plugin in &self.plugins {
+            let mut plugin = plugin.borrow_mut();
+            plugin.poll_events()?;
+            exhausted &= plugin.exhausted();
+        }
+        Ok(exhausted)
+    }
+}
+
+fn open_stream(directory: &Path, stream: &str) -> Result<Option<File>> {
+    let path = directory.join(format!("{}.ndjson", stream));
+    match File::open(&path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(eyre!("Could not open {}. {}", path.display(), e)),
+    }
+}
+
+/// Reads the entries of the targets log of `session`.
+pub fn recorded_targets(session: &str) -> Result<Vec<TargetLogEntry>> {
+    let path = Path::new(session).join("targets.csv");
+    let content = csv::read_csv(&path)
+        .map_err(|e| eyre!("Could not read the targets of {}. {}", session, e))?;
+    content
+        .lines()
+        .skip(1)
+        .map(|line| TargetLogEntry::from_fields(csv::parse_row(TargetLogEntry::FIELDS, line)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cmdline, configure::Config, execute::programs::Launcher, extract::Extractor};
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{fs, io::Write, path::Path};
+    use tempdir::TempDir;
+
+    /// Replays the recorded session `session` of a thread 8955 of process
+    /// 8877, and returns the directory it was replayed to.
+    fn replay(dir: &Path, session: &Path) -> Result<String> {
+        // The instants of the streams are relative to the epoch
+        fs::write(session.join("raw/boot_epoch_ns"), "0\n")?;
+        fs::write(
+            session.join("targets.csv"),
+            indoc! {"
+                epoch_ms,pid,tid,comm,action,cause
+                65384000,8877,8955,redis-server,added,pids argument
+            "},
+        )?;
+        fs::write(session.join("config.toml"), "")?;
+
+        let root_directory = dir.join("replayed");
+        let matches = cmdline::register_args().get_matches_from([
+            "metric-collector",
+            "--data-directory",
+            root_directory.to_str().unwrap(),
+            "replay",
+            session.to_str().unwrap(),
+        ]);
+        let config = Config::try_from(matches).unwrap();
+        let data_directory = config.data_directory.to_string();
+        Extractor::new(config)?.replay(session.to_str().unwrap())?;
+        assert_eq!(
+            fs::read_to_string(format!("{}/targets.csv", data_directory))?,
+            fs::read_to_string(session.join("targets.csv"))?
+        );
+        Ok(data_directory)
+    }
+
+    #[test]
+    fn replay_futex_stream() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let session = dir.path().join("recorded/system-metrics");
+        fs::create_dir_all(session.join("raw"))?;
+        fs::write(
+            session.join("raw/futex.ndjson"),
+            indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+                {"type": "printf", "data": "SampleInstant  \t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#},
+        )?;
+
+        let data_directory = replay(dir.path(), &session)?;
+        assert_eq!(
+            fs::read_to_string(format!(
+                "{}/thread/8877/8955/futex/wait/65340/8877-0x7c3dd4f85fb0.csv",
+                data_directory
+            ))?,
+            indoc! {"
+                epoch_ms,futex_wait_ns,futex_count
+                65384570,847638877,4
+            "}
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replay_ipc_stream() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let session = dir.path().join("recorded/system-metrics");
+        fs::create_dir_all(session.join("raw"))?;
+        fs::write(
+            session.join("raw/ipc.ndjson"),
+            indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+                {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\n"}
+                {"type": "printf", "data": "AcceptEnd\t8955\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t65384000000000\tredis-server\n"}
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@inode_map": {"8955, sockfs, 8, 90098": [2848, 1, "redis-server"]}}}
+                {"type": "printf", "data": "SampleInstant\t65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#},
+        )?;
+
+        let data_directory = replay(dir.path(), &session)?;
+        assert_eq!(
+            fs::read_to_string(format!(
+                "{}/thread/8877/8955/ipc/sockets/65340/ipv4_127.0.0.1:7878_127.0.0.1:50058.csv",
+                data_directory
+            ))?
+            .lines()
+            .nth(1),
+            Some("65384570,2848,1")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replay_iowait_stream() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let session = dir.path().join("recorded/system-metrics");
+        fs::create_dir_all(session.join("raw"))?;
+        fs::write(
+            session.join("raw/iowait.ndjson"),
+            indoc! {r#"
+                {"type": "attached_probes", "data": {"probes": 4}}
+                {"type": "printf", "data": "=> start map statistics\n"}
+                {"type": "map", "data": {"@completed": {"271581184, 271581187, 8955, 8877": 22616}}}
+                {"type": "printf", "data": "SampleInstant   65384570945103\n"}
+                {"type": "printf", "data": "=> end map statistics\n"}
+            "#},
+        )?;
+
+        let data_directory = replay(dir.path(), &session)?;
+        assert_eq!(
+            fs::read_to_string(format!(
+                "{}/global/iowait/8877/8955/65340/271581187.csv",
+                data_directory
+            ))?
+            .lines()
+            .nth(1),
+            Some("65384,22616")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replay_relaunched_stream() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let session = dir.path().join("recorded/system-metrics");
+        fs::create_dir_all(session.join("raw"))?;
+
+        // A relaunched program appends its output, from its header, to the
+        // stream of the previous one
+        let launcher = Launcher {
+            bpftrace: "bpftrace".to_string(),
+            raw_directory: Some(session.join("raw")),
+        };
+        for (wait_ns, sample_instant_ns) in [(847638877, 65384570945103u64), (1000, 65385570945103)]
+        {
+            let mut stream = launcher.raw_stream("futex")?.unwrap();
+            write!(
+                stream,
+                indoc! {r#"
+                    {{"type": "attached_probes", "data": {{"probes": 4}}}}
+                    {{"type": "printf", "data": "=> start map statistics\n"}}
+                    {{"type": "map", "data": {{"@wait_elapsed": {{"8955, 8877, 0x7c3dd4f85fb0": [{}, 4]}}}}}}
+                    {{"type": "printf", "data": "SampleInstant  \t{}\n"}}
+                    {{"type": "printf", "data": "=> end map statistics\n"}}
+                "#},
+                wait_ns, sample_instant_ns
+            )?;
+        }
+
+        // The samples of both segments are replayed, the wait accumulated
+        // within the minute
+        let data_directory = replay(dir.path(), &session)?;
+        assert_eq!(
+            fs::read_to_string(format!(
+                "{}/thread/8877/8955/futex/wait/65340/8877-0x7c3dd4f85fb0.csv",
+                data_directory
+            ))?,
+            indoc! {"
+                epoch_ms,futex_wait_ns,futex_count
+                65384570,847638877,4
+                65385570,847639877,4
+            "}
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,717 @@
+use eyre::{eyre, Result};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    configure::{OnLimit, Retention},
+    metrics::{
+        sink::{Resource, Scope, Series, SharedSink, Sink, Subsystem},
+        Field, Kind, Row, Value,
+    },
+};
+
+/// Interval between two walks of the session directory.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Time without writes after which a partition is assumed closed by the
+/// sink, and may be dropped to free space.
+const SETTLE: Duration = Duration::from_secs(60);
+
+/// Subsystems disabled together when degrading, in the order they are
+/// disabled. The scheduler statistics are never disabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Group {
+    Ipc,
+    Futex,
+    Plugins,
+    IoWait,
+}
+
+impl Group {
+    const DEGRADE_ORDER: [Group; 4] = [Group::Ipc, Group::Futex, Group::Plugins, Group::IoWait];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Group::Ipc => "ipc",
+            Group::Futex => "futex",
+            Group::Plugins => "plugins",
+            Group::IoWait => "iowait",
+        }
+    }
+
+    fn of_subsystem(subsystem: Subsystem) -> Option<Self> {
+        match subsystem {
+            Subsystem::Sockets | Subsystem::Streams => Some(Group::Ipc),
+            Subsystem::FutexWait | Subsystem::FutexWake => Some(Group::Futex),
+            Subsystem::Plugin(_) => Some(Group::Plugins),
+            Subsystem::IoWait => Some(Group::IoWait),
+            _ => None,
+        }
+    }
+
+    /// Group of a partition, from its path relative to the session directory
+    /// in the CSV or Parquet layout. The partitions of a plugin are told apart
+    /// by the names of the plugins of the session.
+    fn of_partition(relative: &Path, plugins: &[&str]) -> Option<Self> {
+        let components: Vec<&str> = relative
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+        match components.as_slice() {
+            ["thread", _, _, "sched" | "schedstat", ..] => None,
+            ["thread", _, _, "ipc", ..] => Some(Group::Ipc),
+            ["thread", _, _, "futex", ..] => Some(Group::Futex),
+            ["thread", _, _, name, _, ..] if plugins.contains(name) => Some(Group::Plugins),
+            ["global", "epoll", ..] => Some(Group::Ipc),
+            ["global", "iowait", ..] => Some(Group::IoWait),
+            ["parquet", "sockets" | "streams" | "epoll", ..] => Some(Group::Ipc),
+            ["parquet", "futex_wait" | "futex_wake", ..] => Some(Group::Futex),
+            ["parquet", "plugin", ..] => Some(Group::Plugins),
+            ["parquet", "iowait", ..] => Some(Group::IoWait),
+            _ => None,
+        }
+    }
+}
+
+/// Drops the samples of the subsystems disabled by the retention policy
+/// before they reach the data files.
+pub struct Degraded {
+    sink: Box<dyn Sink + Send>,
+    disabled: Arc<Mutex<Vec<Group>>>,
+}
+
+impl Sink for Degraded {
+    fn write(&mut self, series: &Series, epoch_ms: u128, sample: &dyn Row) -> Result<()> {
+        if let Some(group) = Group::of_subsystem(series.subsystem) {
+            if self.disabled.lock().unwrap().contains(&group) {
+                return Ok(());
+            }
+        }
+        self.sink.write(series, epoch_ms, sample)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sink.flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.sink.close()
+    }
+}
+
+/// A data file of the session.
+struct DataFile {
+    relative: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl DataFile {
+    /// Whether the file holds samples of a minute, day or subsystem, rather
+    /// than a log of the session such as `targets.csv` or the diagnostics.
+    fn is_partition(&self) -> bool {
+        match self.relative.components().next() {
+            Some(Component::Normal(name)) if name == "global" || name == "parquet" => true,
+            // The exec log lies directly under the process directory
+            Some(Component::Normal(name)) if name == "thread" => {
+                self.relative.components().count() > 3
+            }
+            _ => false,
+        }
+    }
+}
+
+struct RetentionSample {
+    epoch_ms: u128,
+    action: &'static str,
+    target: String,
+    freed_bytes: u64,
+    total_bytes: u64,
+}
+
+impl Row for RetentionSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("action", Kind::Text),
+            Field::new("target", Kind::Text),
+            Field::new("freed_bytes", Kind::Unsigned),
+            Field::new("total_bytes", Kind::Unsigned),
+        ];
+        FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Text(self.action.to_string()),
+            Value::Text(self.target.clone()),
+            Value::Unsigned(self.freed_bytes),
+            Value::Unsigned(self.total_bytes),
+        ]
+    }
+}
+
+/// Bounds the size of the session directory and the age of its partitions.
+/// Each partition dropped, subsystem disabled or stop is recorded in
+/// `diagnostics/retention.csv`, along with the size of the directory after
+/// it. The session directory is walked on a background thread, so that the
+/// sampling is not held up meanwhile.
+pub struct RetentionPolicy {
+    enforcer: Enforcer,
+    checked: Option<Instant>,
+    /// Walk in progress, which returns whether the session must stop.
+    walk: Option<JoinHandle<Result<bool>>>,
+}
+
+impl RetentionPolicy {
+    pub fn new(data_directory: &str, retention: &Retention) -> Self {
+        Self {
+            enforcer: Enforcer {
+                data_directory: PathBuf::from(data_directory),
+                retention: retention.clone(),
+                disabled: Arc::new(Mutex::new(Vec::new())),
+                plugins: Vec::new(),
+                series: Series::new(
+                    Scope::Session,
+                    Subsystem::Diagnostics,
+                    Some(Resource::Program("retention")),
+                ),
+            },
+            checked: None,
+            walk: None,
+        }
+    }
+
+    /// Sets the plugins of the session, whose partitions are dropped with
+    /// the other plugins' when degrading.
+    pub fn plugins(&mut self, plugins: Vec<&'static str>) {
+        self.enforcer.plugins = plugins;
+    }
+
+    /// Wraps the sink of the data files, to drop the samples of the
+    /// subsystems disabled once degraded.
+    pub fn degraded(&self, sink: Box<dyn Sink + Send>) -> Degraded {
+        Degraded {
+            sink,
+            disabled: self.enforcer.disabled.clone(),
+        }
+    }
+
+    /// Starts enforcing the policy if it was last checked longer than the
+    /// check interval ago. Returns whether the session must stop, once the
+    /// walk which decided it ended.
+    pub fn enforce(&mut self, sink: &SharedSink) -> Result<bool> {
+        let retention = &self.enforcer.retention;
+        if retention.max_size_mb.is_none() && retention.max_age_s.is_none() {
+            return Ok(false);
+        }
+        if let Some(walk) = self.walk.take() {
+            if !walk.is_finished() {
+                self.walk = Some(walk);
+                return Ok(false);
+            }
+            if walk
+                .join()
+                .map_err(|_| eyre!("Retention walk panicked"))??
+            {
+                return Ok(true);
+            }
+        }
+        if self
+            .checked
+            .is_some_and(|checked| checked.elapsed() < CHECK_INTERVAL)
+        {
+            return Ok(false);
+        }
+        self.checked = Some(Instant::now());
+
+        let enforcer = self.enforcer.clone();
+        let sink = sink.clone();
+        self.walk = Some(
+            thread::Builder::new()
+                .name("retention".to_string())
+                .spawn(move || enforcer.enforce_at(SystemTime::now(), &sink))?,
+        );
+        Ok(false)
+    }
+
+    /// Waits for the walk in progress, e.g. before the sink is closed.
+    pub fn wait(&mut self) {
+        if let Some(walk) = self.walk.take() {
+            match walk.join() {
+                Ok(Err(e)) => println!("Failed to enforce the retention policy. {e}"),
+                Err(_) => println!("Retention walk panicked"),
+                Ok(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+/// State of the policy a walk of the session directory enforces.
+#[derive(Clone)]
+struct Enforcer {
+    data_directory: PathBuf,
+    retention: Retention,
+    disabled: Arc<Mutex<Vec<Group>>>,
+    plugins: Vec<&'static str>,
+    series: Series,
+}
+
+impl Enforcer {
+    fn enforce_at(&self, now: SystemTime, sink: &SharedSink) -> Result<bool> {
+        let epoch_ms = now
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        let mut files = Vec::new();
+        walk(&self.data_directory, Path::new(""), &mut files)?;
+        let mut total_bytes: u64 = files.iter().map(|file| file.size).sum();
+        let mut samples = Vec::new();
+        let mut record = |action, target: String, freed_bytes, total_bytes| {
+            samples.push(RetentionSample {
+                epoch_ms,
+                action,
+                target,
+                freed_bytes,
+                total_bytes,
+            })
+        };
+
+        let mut partitions: Vec<DataFile> =
+            files.into_iter().filter(DataFile::is_partition).collect();
+        partitions.sort_by_key(|partition| partition.modified);
+        if let Some(max_age) = self.retention.max_age() {
+            let (expired, kept): (Vec<DataFile>, Vec<DataFile>) = partitions
+                .into_iter()
+                .partition(|partition| partition.modified + max_age <= now);
+            partitions = kept;
+            for partition in expired {
+                self.remove(&partition)?;
+                total_bytes -= partition.size;
+                record(
+                    "expired",
+                    partition.relative.display().to_string(),
+                    partition.size,
+                    total_bytes,
+                );
+            }
+        }
+
+        let settled = |partition: &&DataFile| partition.modified + SETTLE <= now;
+        let mut stop = false;
+        if let Some(max_size) = self.retention.max_size_bytes() {
+            match self.retention.on_limit {
+                OnLimit::Stop => stop = total_bytes > max_size,
+                OnLimit::DropOldest => {
+                    let mut partitions = partitions.iter().filter(settled);
+                    while total_bytes > max_size {
+                        let Some(partition) = partitions.next() else {
+                            stop = true;
+                            break;
+                        };
+                        self.remove(partition)?;
+                        total_bytes -= partition.size;
+                        record(
+                            "dropped",
+                            partition.relative.display().to_string(),
+                            partition.size,
+                            total_bytes,
+                        );
+                    }
+                }
+                OnLimit::Degrade => {
+                    // The partitions of a disabled group are dropped once
+                    // settled, until which the space they hold is pending
+                    let mut pending_bytes = 0;
+                    let disabled = self.disabled.lock().unwrap().clone();
+                    for partition in partitions.iter().filter(|partition| {
+                        Group::of_partition(&partition.relative, &self.plugins)
+                            .is_some_and(|group| disabled.contains(&group))
+                    }) {
+                        if !settled(&partition) {
+                            pending_bytes += partition.size;
+                            continue;
+                        }
+                        self.remove(partition)?;
+                        total_bytes -= partition.size;
+                        record(
+                            "dropped",
+                            partition.relative.display().to_string(),
+                            partition.size,
+                            total_bytes,
+                        );
+                    }
+
+                    while total_bytes - pending_bytes > max_size {
+                        let mut disabled = self.disabled.lock().unwrap();
+                        let Some(group) = Group::DEGRADE_ORDER
+                            .into_iter()
+                            .find(|group| !disabled.contains(group))
+                        else {
+                            stop = true;
+                            break;
+                        };
+                        disabled.push(group);
+                        drop(disabled);
+
+                        let mut freed_bytes = 0;
+                        for partition in partitions.iter().filter(|partition| {
+                            Group::of_partition(&partition.relative, &self.plugins) == Some(group)
+                        }) {
+                            if !settled(&partition) {
+                                pending_bytes += partition.size;
+                                continue;
+                            }
+                            self.remove(partition)?;
+                            freed_bytes += partition.size;
+                        }
+                        total_bytes -= freed_bytes;
+                        record(
+                            "degraded",
+                            group.name().to_string(),
+                            freed_bytes,
+                            total_bytes,
+                        );
+                    }
+                }
+            }
+        }
+        if stop {
+            record("stopped", String::new(), 0, total_bytes);
+        }
+
+        let mut sink = sink.lock().unwrap();
+        for sample in samples {
+            sink.write(&self.series, sample.epoch_ms, &sample)?;
+        }
+        Ok(stop)
+    }
+
+    fn remove(&self, partition: &DataFile) -> Result<()> {
+        match fs::remove_file(self.data_directory.join(&partition.relative)) {
+            // e.g. compressed by the sink since the walk
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Lists the files within the `relative` directory of the session directory
+/// `root`, by their path relative to `root`.
+fn walk(root: &Path, relative: &Path, files: &mut Vec<DataFile>) -> Result<()> {
+    let entries = match fs::read_dir(root.join(relative)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_dir() {
+            walk(root, &relative, files)?;
+        } else {
+            files.push(DataFile {
+                relative,
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Group, RetentionPolicy, SETTLE};
+    use crate::{
+        configure::{Buffers, OnLimit, Retention},
+        metrics::{
+            sink::{csv::CsvSink, Scope, Series, Sink, Subsystem},
+            Field, Kind, Row, Value,
+        },
+    };
+    use eyre::Result;
+    use std::{
+        fs::{self, File},
+        path::Path,
+        thread,
+        time::{Duration, SystemTime},
+    };
+    use tempdir::TempDir;
+
+    const MIB: usize = 1 << 20;
+
+    struct Sample;
+
+    impl Row for Sample {
+        fn schema(&self) -> &'static [Field] {
+            const FIELDS: &[Field] = &[Field::new("epoch_ms", Kind::Unsigned)];
+            FIELDS
+        }
+
+        fn fields(&self) -> Vec<Value> {
+            vec![Value::Unsigned(1722794820000)]
+        }
+    }
+
+    /// Writes a file of `size` bytes last modified at `modified`.
+    fn partition(dir: &Path, relative: &str, size: usize, modified: SystemTime) -> Result<()> {
+        let path = dir.join(relative);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, vec![b'0'; size])?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)?;
+        Ok(())
+    }
+
+    fn diagnostics(dir: &Path) -> Result<Vec<String>> {
+        let content = fs::read_to_string(dir.join("diagnostics/retention.csv"))?;
+        Ok(content
+            .lines()
+            .map(|line| line.split_once(',').unwrap().1.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn drop_oldest() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1722794820);
+        partition(
+            dir.path(),
+            "thread/8877/8955/sched/1722729600000.csv",
+            MIB,
+            start,
+        )?;
+        partition(
+            dir.path(),
+            "thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv",
+            MIB,
+            start + Duration::from_secs(60),
+        )?;
+        partition(
+            dir.path(),
+            "global/iowait/1722794880/271581186.csv",
+            MIB,
+            start + Duration::from_secs(3600),
+        )?;
+        partition(dir.path(), "targets.csv", MIB, start)?;
+
+        let data_directory = dir.path().to_str().unwrap();
+        let sink = CsvSink::shared(data_directory, &Buffers::default());
+        let policy = RetentionPolicy::new(
+            data_directory,
+            &Retention {
+                max_size_mb: Some(2),
+                max_age_s: Some(7200),
+                on_limit: OnLimit::DropOldest,
+            },
+        );
+        let stop = policy
+            .enforcer
+            .enforce_at(start + Duration::from_secs(7230), &sink)?;
+        sink.lock().unwrap().close()?;
+
+        // The scheduler statistics expired, after which the futex partition
+        // is the oldest, while the targets log is never dropped
+        assert!(!stop);
+        assert!(!dir
+            .path()
+            .join("thread/8877/8955/sched")
+            .read_dir()?
+            .any(|_| true));
+        assert!(dir
+            .path()
+            .join("global/iowait/1722794880/271581186.csv")
+            .exists());
+        assert!(dir.path().join("targets.csv").exists());
+        assert_eq!(
+            diagnostics(dir.path())?,
+            vec![
+                "action,target,freed_bytes,total_bytes".to_string(),
+                format!(
+                    "expired,thread/8877/8955/sched/1722729600000.csv,{},{}",
+                    MIB,
+                    3 * MIB
+                ),
+                format!(
+                    "dropped,thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv,{},{}",
+                    MIB,
+                    2 * MIB
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn degrade() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let now = SystemTime::now();
+        partition(
+            dir.path(),
+            "thread/8877/8955/sched/1722729600000.csv",
+            MIB / 2,
+            now,
+        )?;
+        partition(
+            dir.path(),
+            "thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv",
+            MIB,
+            now,
+        )?;
+        partition(
+            dir.path(),
+            "global/epoll/ffff9a7a3f5e0240/sockets/1722794820/8_1234.csv",
+            MIB,
+            now,
+        )?;
+
+        let data_directory = dir.path().to_str().unwrap();
+        let policy = RetentionPolicy::new(
+            data_directory,
+            &Retention {
+                max_size_mb: Some(1),
+                max_age_s: None,
+                on_limit: OnLimit::Degrade,
+            },
+        );
+        let sink = CsvSink::shared(data_directory, &Buffers::default());
+        let mut degraded =
+            policy.degraded(Box::new(CsvSink::new(data_directory, &Buffers::default())));
+        assert!(!policy.enforcer.enforce_at(now, &sink)?);
+        assert_eq!(
+            *policy.enforcer.disabled.lock().unwrap(),
+            vec![Group::Ipc, Group::Futex]
+        );
+
+        // The partitions of the disabled subsystems may still be open, and
+        // are dropped once settled
+        let futex = "thread/8877/8955/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv";
+        assert!(dir.path().join(futex).exists());
+        let logged = fs::metadata(dir.path().join("diagnostics/retention.csv"))
+            .map_or(0, |metadata| metadata.len() as usize);
+        assert!(!policy.enforcer.enforce_at(now + SETTLE, &sink)?);
+        sink.lock().unwrap().close()?;
+        assert!(!dir.path().join(futex).exists());
+        assert_eq!(
+            *policy.enforcer.disabled.lock().unwrap(),
+            vec![Group::Ipc, Group::Futex]
+        );
+        assert_eq!(
+            diagnostics(dir.path())?[1..],
+            [
+                format!("degraded,ipc,0,{}", 5 * MIB / 2),
+                format!("degraded,futex,0,{}", 5 * MIB / 2),
+                format!(
+                    "dropped,global/epoll/ffff9a7a3f5e0240/sockets/1722794820/8_1234.csv,{},{}",
+                    MIB,
+                    3 * MIB / 2 + logged
+                ),
+                format!("dropped,{},{},{}", futex, MIB, MIB / 2 + logged),
+            ]
+        );
+
+        // The samples of the disabled subsystems are dropped
+        let thread = Scope::Thread {
+            pid: 8877,
+            tid: 8955,
+        };
+        degraded.write(
+            &Series::new(thread.clone(), Subsystem::FutexWake, None),
+            1722794820000,
+            &Sample,
+        )?;
+        degraded.write(
+            &Series::new(thread, Subsystem::Sched, None),
+            1722794820000,
+            &Sample,
+        )?;
+        degraded.close()?;
+        assert!(!dir.path().join("thread/8877/8955/futex/wake").exists());
+        assert!(dir
+            .path()
+            .join("thread/8877/8955/sched/1722729600000.csv")
+            .exists());
+
+        // Once only the scheduler statistics are left, the session stops
+        partition(
+            dir.path(),
+            "thread/8877/8955/sched/1722816000000.csv",
+            MIB,
+            now,
+        )?;
+        assert!(policy.enforcer.enforce_at(now, &sink)?);
+        Ok(())
+    }
+
+    #[test]
+    fn plugin_partitions() {
+        let plugins = ["mutex_wait"];
+        assert_eq!(
+            Group::of_partition(
+                Path::new("thread/8877/8955/mutex_wait/1722794820/0x7c3dd4f85fb0.csv"),
+                &plugins
+            ),
+            Some(Group::Plugins)
+        );
+        assert_eq!(
+            Group::of_partition(
+                Path::new("thread/8877/8955/other/1722794820/a.csv"),
+                &plugins
+            ),
+            None
+        );
+        assert_eq!(
+            Group::of_partition(
+                Path::new("parquet/plugin/mutex_wait/1722794820.parquet"),
+                &[]
+            ),
+            Some(Group::Plugins)
+        );
+    }
+
+    #[test]
+    fn background_walk() -> Result<()> {
+        let dir = TempDir::new("")?;
+        partition(
+            dir.path(),
+            "thread/8877/8955/sched/1722729600000.csv",
+            2 * MIB,
+            SystemTime::now(),
+        )?;
+        let data_directory = dir.path().to_str().unwrap();
+        let sink = CsvSink::shared(data_directory, &Buffers::default());
+        let mut policy = RetentionPolicy::new(
+            data_directory,
+            &Retention {
+                max_size_mb: Some(1),
+                max_age_s: None,
+                on_limit: OnLimit::Stop,
+            },
+        );
+
+        // The decision of the walk is returned once it ended
+        assert!(!policy.enforce(&sink)?);
+        while !policy.walk.as_ref().unwrap().is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(policy.enforce(&sink)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use chrono::DateTime;
+use eyre::Result;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    configure::Slo,
+    metrics::{
+        sink::{Scope, Series, SharedSink, Subsystem},
+        Field, Kind, Row, Value,
+    },
+};
+
+/// Interval between two reads of the application metric file once its end
+/// is reached.
+const TAIL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Response of the application, as of a row of its metric file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Response {
+    end_ms: i64,
+    duration_ms: f64,
+}
+
+impl Response {
+    /// Parses an `end_ts,duration_ms` row, whose end is an RFC 3339
+    /// timestamp as written by the workload generator, or in milliseconds
+    /// since the epoch.
+    fn parse(line: &str) -> Option<Self> {
+        let (end_ts, duration_ms) = line.trim().split_once(',')?;
+        let end_ms = match DateTime::parse_from_rfc3339(end_ts) {
+            Ok(end) => end.timestamp_millis(),
+            Err(_) => end_ts.parse().ok()?,
+        };
+        Some(Self {
+            end_ms,
+            duration_ms: duration_ms.parse().ok()?,
+        })
+    }
+}
+
+/// Percentile of the response times which ended within a window before the
+/// latest one.
+struct RollingPercentile {
+    responses: VecDeque<Response>,
+    window_ms: i64,
+    latest_ms: i64,
+}
+
+impl RollingPercentile {
+    /// Adds a response, and drops those which left the window. The rows of
+    /// the application are mostly ordered by their end, so that a response
+    /// ending before a later one is dropped along with it.
+    fn push(&mut self, response: Response) {
+        self.latest_ms = self.latest_ms.max(response.end_ms);
+        self.responses.push_back(response);
+        while self
+            .responses
+            .front()
+            .is_some_and(|response| response.end_ms <= self.latest_ms - self.window_ms)
+        {
+            self.responses.pop_front();
+        }
+    }
+
+    /// Nearest-rank `percentile` of the window, unset if it is empty.
+    fn value(&self, percentile: f64) -> Option<f64> {
+        let mut durations: Vec<f64> = self
+            .responses
+            .iter()
+            .map(|response| response.duration_ms)
+            .collect();
+        durations.sort_by(f64::total_cmp);
+        let rank = (percentile / 100.0 * durations.len() as f64).ceil() as usize;
+        durations.get(rank.max(1) - 1).copied()
+    }
+}
+
+struct EscalationSample {
+    epoch_ms: u128,
+    action: &'static str,
+    percentile_ms: Option<f64>,
+    threshold_ms: f64,
+    responses: usize,
+}
+
+impl Row for EscalationSample {
+    fn schema(&self) -> &'static [Field] {
+        const FIELDS: &[Field] = &[
+            Field::new("epoch_ms", Kind::Unsigned),
+            Field::new("action", Kind::Text),
+            Field::new("percentile_ms", Kind::Float),
+            Field::new("threshold_ms", Kind::Float),
+            Field::new("responses", Kind::Unsigned),
+        ];
+        FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Text(self.action.to_string()),
+            self.percentile_ms.map_or(Value::Missing, Value::Float),
+            Value::Float(self.threshold_ms),
+            Value::Unsigned(self.responses as u64),
+        ]
+    }
+}
+
+/// Tails the response times of the application, and escalates from the
+/// scheduler statistics to every subsystem while their rolling percentile
+/// breaches the SLO, and for `escalation_s` after the last breach. Each
+/// escalation and its end are recorded in `escalations.csv`, and applied by
+/// the caller, which starts or stops the bpftrace programs.
+pub struct Escalation {
+    slo: Slo,
+    tx: Sender<Response>,
+    rx: Receiver<Response>,
+    rolling: RollingPercentile,
+    escalated: bool,
+    /// End of the current escalation.
+    escalated_until: Option<Instant>,
+    series: Series,
+}
+
+impl Escalation {
+    pub fn new(slo: &Slo) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            slo: slo.clone(),
+            tx,
+            rx,
+            rolling: RollingPercentile {
+                responses: VecDeque::new(),
+                window_ms: slo.rolling_window_s as i64 * 1000,
+                latest_ms: i64::MIN,
+            },
+            escalated: false,
+            escalated_until: None,
+            series: Series::new(Scope::Session, Subsystem::Escalations, None),
+        }
+    }
+
+    /// Whether every subsystem is collected, rather than the scheduler
+    /// statistics only.
+    pub fn escalated(&self) -> bool {
+        self.escalated
+    }
+
+    /// Starts tailing the application metric file, or stdin.
+    pub fn start(&self, terminate_flag: Arc<Mutex<bool>>) {
+        let source = match &self.slo.source {
+            Some(source) => source.clone(),
+            None => return,
+        };
+        let tx = self.tx.clone();
+        thread::Builder::new()
+            .name("slo-tail".to_string())
+            .spawn(move || {
+                if let Err(e) = tail(&source, &tx, &terminate_flag) {
+                    println!("Failed to read response times from {}. {e}", source);
+                }
+            })
+            .expect("Failed to create slo-tail thread");
+    }
+
+    /// Updates the percentile with the responses read since the previous
+    /// call, and escalates or ends the escalation. Returns whether either
+    /// happened.
+    pub fn poll(&mut self, sink: &SharedSink) -> Result<bool> {
+        for response in self.rx.try_iter() {
+            self.rolling.push(response);
+        }
+        let percentile_ms = self.rolling.value(self.slo.percentile);
+        let breached = percentile_ms.is_some_and(|percentile| percentile > self.slo.threshold_ms);
+        let escalated = self.escalated;
+
+        let action = if breached {
+            self.escalated_until = Some(Instant::now() + self.slo.escalation());
+            (!escalated).then_some("escalated")
+        } else if escalated
+            && self
+                .escalated_until
+                .is_some_and(|until| Instant::now() >= until)
+        {
+            self.escalated_until = None;
+            Some("deescalated")
+        } else {
+            None
+        };
+        let Some(action) = action else {
+            return Ok(false);
+        };
+
+        self.escalated = action == "escalated";
+        println!(
+            "Collection {}, with a p{} response time of {}ms",
+            action,
+            self.slo.percentile,
+            percentile_ms.unwrap_or_default()
+        );
+        let sample = EscalationSample {
+            epoch_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis(),
+            action,
+            percentile_ms,
+            threshold_ms: self.slo.threshold_ms,
+            responses: self.rolling.responses.len(),
+        };
+        sink.lock()
+            .unwrap()
+            .write(&self.series, sample.epoch_ms, &sample)?;
+        Ok(true)
+    }
+}
+
+/// Sends the responses of the rows appended to `source`, waiting for the
+/// file to be created, until the session ends.
+fn tail(source: &str, tx: &Sender<Response>, terminate_flag: &Mutex<bool>) -> Result<()> {
+    let terminated = || *terminate_flag.lock().unwrap();
+    let mut reader: Box<dyn BufRead> = if source == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        loop {
+            match File::open(source) {
+                Ok(file) => break Box::new(BufReader::new(file)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !terminated() => {
+                    thread::sleep(TAIL_INTERVAL)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    };
+
+    let mut line = String::new();
+    while !terminated() {
+        if reader.read_line(&mut line)? == 0 {
+            if source == "-" {
+                return Ok(());
+            }
+            thread::sleep(TAIL_INTERVAL);
+            continue;
+        }
+        // The row is completed by a later read
+        if !line.ends_with('\n') {
+            continue;
+        }
+        // e.g. the header
+        if let Some(response) = Response::parse(&line) {
+            if tx.send(response).is_err() {
+                return Ok(());
+            }
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Escalation, Response};
+    use crate::{configure::{Buffers, Slo}, metrics::sink::csv::CsvSink};
+    use eyre::Result;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn parse_response() {
+        assert_eq!(
+            Response::parse("2024-08-04T18:07:00.250+00:00,12\n"),
+            Some(Response {
+                end_ms: 1722794820250,
+                duration_ms: 12.0
+            })
+        );
+        assert_eq!(
+            Response::parse("1722794820250,12.5"),
+            Some(Response {
+                end_ms: 1722794820250,
+                duration_ms: 12.5
+            })
+        );
+        assert_eq!(Response::parse("end_ts,duration_ms"), None);
+    }
+
+    #[test]
+    fn escalate_on_breach() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let data_directory = dir.path().to_str().unwrap();
+        let mut escalation = Escalation::new(&Slo {
+            source: Some("-".to_string()),
+            percentile: 90.0,
+            threshold_ms: 100.0,
+            rolling_window_s: 10,
+            escalation_s: 0,
+        });
+        let sink = CsvSink::shared(data_directory, &Buffers::default());
+        let tx = escalation.tx.clone();
+        let send = |end_ms: i64, durations: &[f64]| {
+            for duration_ms in durations {
+                let response = Response {
+                    end_ms,
+                    duration_ms: *duration_ms,
+                };
+                tx.send(response).unwrap();
+            }
+        };
+
+        // The p90 of 10 responses is the 9th slowest
+        send(1722794820000, &[10.0; 8]);
+        send(1722794820000, &[150.0, 150.0]);
+        assert!(escalation.poll(&sink)?);
+        assert!(escalation.escalated());
+        // Still breached
+        assert!(!escalation.poll(&sink)?);
+
+        // Once the slow responses left the window, the escalation ends
+        send(1722794831000, &[10.0; 10]);
+        assert!(escalation.poll(&sink)?);
+        assert!(!escalation.escalated());
+        sink.lock().unwrap().close()?;
+
+        let rows: Vec<String> = fs::read_to_string(dir.path().join("escalations.csv"))?
+            .lines()
+            .map(|line| line.split_once(',').unwrap().1.to_string())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                "action,percentile_ms,threshold_ms,responses",
+                "escalated,150,100,10",
+                "deescalated,10,100,10",
+            ]
+        );
+        Ok(())
+    }
+}
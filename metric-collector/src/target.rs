@@ -1,19 +1,19 @@
 use crate::{
-    execute::{
-        programs::{
-            futex::FutexProgram,
-            ipc::{Connection, IpcProgram},
-        },
-        Executor,
+    execute::programs::{
+        futex::FutexProgram,
+        ipc::{Connection, IpcProgram},
+        plugin::PluginProgram,
     },
     metrics::{
         futex::Futex,
         ipc::{Ipc, KFile},
+        plugin::Plugin,
         scheduler::{Sched, SchedStat},
-        Collect,
+        sink::{Scope, Series, SharedSink, Subsystem},
+        Collect, Field, Kind, Row, Value,
     },
 };
-use eyre::Result;
+use eyre::{eyre, Result};
 use regex::Regex;
 use std::{
     cell::RefCell,
@@ -21,13 +21,14 @@ use std::{
     error::Error,
     fmt::{self, Display},
     fs,
+    path::Path,
     rc::Rc,
     sync::{
         mpsc::{self, Receiver, Sender},
         Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug)]
@@ -41,65 +42,108 @@ impl Display for NotFound {
 
 impl Error for NotFound {}
 
+/// State shared by all targets, which their collectors are created from.
+#[derive(Clone)]
+pub struct TargetContext {
+    pub sched: bool,
+    /// Mount point of procfs.
+    pub procfs: Rc<str>,
+    pub futex_program: Option<Rc<RefCell<FutexProgram>>>,
+    pub ipc_program: Option<Rc<RefCell<IpcProgram>>>,
+    pub plugin_programs: Vec<Rc<RefCell<PluginProgram>>>,
+    pub sink: SharedSink,
+    pub kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
+    pub time_sensitive_collector_tx: Sender<Box<dyn Collect + Send>>,
+}
+
 pub struct Target {
+    pub pid: usize,
     pub tid: usize,
-    collectors: [Box<dyn Collect>; 2],
+    pub comm: Rc<str>,
+    procfs: Rc<str>,
+    collectors: Vec<Box<dyn Collect>>,
 }
 
 impl Target {
-    pub fn new(
-        tid: usize,
-        futex_program: Rc<RefCell<FutexProgram>>,
-        ipc_program: Rc<RefCell<IpcProgram>>,
-        root_directory: Rc<str>,
-        target_subdirectory: &str,
-        kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
-        time_sensitive_collector_tx: Sender<Box<dyn Collect + Send>>,
-    ) -> Self {
+    pub fn new(pid: usize, tid: usize, context: &TargetContext) -> Self {
         println!("Register new target {}", tid);
-        time_sensitive_collector_tx
-            .send(Box::new(SchedStat::new(
+        if context.sched {
+            context
+                .time_sensitive_collector_tx
+                .send(Box::new(SchedStat::new(
+                    pid,
+                    tid,
+                    &context.procfs,
+                    context.sink.clone(),
+                )))
+                .expect("Failed to send time sensitive collector");
+            context
+                .time_sensitive_collector_tx
+                .send(Box::new(Sched::new(
+                    pid,
+                    tid,
+                    &context.procfs,
+                    context.sink.clone(),
+                )))
+                .expect("Failed to send time sensitive collector");
+        }
+        Self::generation(pid, tid, context)
+    }
+
+    /// The thread `pid` once it called exec, whose command name and collectors
+    /// start over with the new program. The scheduler statistics of the
+    /// thread carry on across the exec, so its time sensitive collectors are
+    /// kept.
+    pub fn exec(pid: usize, context: &TargetContext) -> Self {
+        println!("Register new generation of target {}", pid);
+        Self::generation(pid, pid, context)
+    }
+
+    fn generation(pid: usize, tid: usize, context: &TargetContext) -> Self {
+        let comm = fs::read_to_string(format!("{}/{}/task/{}/comm", context.procfs, pid, tid))
+            .map(|comm| Rc::from(comm.trim()))
+            .unwrap_or_else(|_| Rc::from(""));
+        let mut collectors: Vec<Box<dyn Collect>> = Vec::new();
+        if let Some(futex_program) = &context.futex_program {
+            collectors.push(Box::new(Futex::new(
+                futex_program.clone(),
+                pid,
+                tid,
+                context.sink.clone(),
+            )));
+        }
+        if let Some(ipc_program) = &context.ipc_program {
+            collectors.push(Box::new(Ipc::new(
+                ipc_program.clone(),
+                pid,
                 tid,
-                &format!("{}/{}", root_directory, target_subdirectory),
-            )))
-            .expect("Failed to send time sensitive collector");
-        time_sensitive_collector_tx
-            .send(Box::new(Sched::new(
+                context.sink.clone(),
+                context.kfile_socket_map.clone(),
+            )));
+        }
+        for plugin_program in &context.plugin_programs {
+            collectors.push(Box::new(Plugin::new(
+                plugin_program.clone(),
+                pid,
                 tid,
-                &format!("{}/{}", root_directory, target_subdirectory),
-            )))
-            .expect("Failed to send time sensitive collector");
+                context.sink.clone(),
+            )));
+        }
+
         Self {
+            pid,
             tid,
-            collectors: [
-                Box::new(Futex::new(
-                    futex_program,
-                    tid,
-                    root_directory.clone(),
-                    target_subdirectory,
-                )),
-                Box::new(Ipc::new(
-                    ipc_program,
-                    tid,
-                    root_directory,
-                    target_subdirectory,
-                    kfile_socket_map,
-                )),
-            ],
+            comm,
+            procfs: context.procfs.clone(),
+            collectors,
         }
     }
 
-    pub fn search_targets_regex(
-        name: &str,
-        kthread: bool,
-        data_directory: Rc<str>,
-        executor: &mut Executor,
-        kfile_socket_map: Rc<RefCell<HashMap<KFile, Connection>>>,
-        time_sensitive_collector_tx: Sender<Box<dyn Collect + Send>>,
-    ) -> Result<Vec<Self>> {
-        let mut targets = Vec::new();
+    /// Returns the processes whose command name matches `name`.
+    pub fn search_pids_regex(procfs: &str, name: &str, kthread: bool) -> Result<Vec<usize>> {
+        let mut pids = Vec::new();
 
-        let tasks = fs::read_dir(format!("/proc"))?;
+        let tasks = fs::read_dir(procfs)?;
         for task in tasks {
             let file_path = task?.path();
             let stem = file_path.file_stem().unwrap().to_str().unwrap();
@@ -130,34 +174,14 @@ impl Target {
             if let None = re_match {
                 continue;
             }
-            let pid: usize = stem.parse()?;
-            executor.monitor(pid);
-
-            let futex_program = executor.futex.clone();
-            let ipc_program = executor.ipc.clone();
-            targets.extend(
-                Self::get_threads(pid)?
-                    .into_iter()
-                    .map(|tid| {
-                        Ok(Target::new(
-                            tid,
-                            futex_program.clone(),
-                            ipc_program.clone(),
-                            data_directory.clone(),
-                            &format!("thread/{}/{}", pid, tid),
-                            kfile_socket_map.clone(),
-                            time_sensitive_collector_tx.clone(),
-                        ))
-                    })
-                    .collect::<Result<Vec<Target>>>()?,
-            );
+            pids.push(stem.parse()?);
         }
 
-        return Ok(targets);
+        return Ok(pids);
     }
 
-    pub fn get_threads(pid: usize) -> Result<Vec<usize>> {
-        let tasks = fs::read_dir(format!("/proc/{}/task", pid))?;
+    pub fn get_threads(procfs: &str, pid: usize) -> Result<Vec<usize>> {
+        let tasks = fs::read_dir(format!("{}/{}/task", procfs, pid))?;
 
         tasks
             .map(|task| {
@@ -168,7 +192,16 @@ impl Target {
             .collect()
     }
 
+    /// Samples the collectors of the target, and fails once the thread
+    /// exited. Without collectors, e.g. with the scheduler statistics only,
+    /// the thread is looked up in procfs instead.
     pub fn sample(&mut self) -> Result<()> {
+        if self.collectors.is_empty() {
+            let task = format!("{}/{}/task/{}", self.procfs, self.pid, self.tid);
+            if !Path::new(&task).exists() {
+                return Err(NotFound.into());
+            }
+        }
         for (_i, collector) in self.collectors.iter_mut().enumerate() {
             collector.sample()?;
             collector.store()?;
@@ -177,6 +210,131 @@ impl Target {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetAction {
+    Added,
+    Removed,
+    Exec,
+}
+
+impl Display for TargetAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Added => write!(f, "added"),
+            Self::Removed => write!(f, "removed"),
+            Self::Exec => write!(f, "exec"),
+        }
+    }
+}
+
+/// A change in the set of targets. The cause names the mechanism which
+/// discovered the target, and the resource linking it to the other targets.
+pub struct TargetLogEntry {
+    pub epoch_ms: u128,
+    pub pid: usize,
+    pub tid: usize,
+    pub comm: Rc<str>,
+    pub action: TargetAction,
+    pub cause: String,
+}
+
+impl TargetLogEntry {
+    pub const FIELDS: &'static [Field] = &[
+        Field::new("epoch_ms", Kind::Unsigned),
+        Field::new("pid", Kind::Unsigned),
+        Field::new("tid", Kind::Unsigned),
+        Field::new("comm", Kind::Text),
+        Field::new("action", Kind::Text),
+        Field::new("cause", Kind::Text),
+    ];
+
+    pub fn now(target: &Target, action: TargetAction, cause: String) -> Self {
+        Self {
+            epoch_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis(),
+            pid: target.pid,
+            tid: target.tid,
+            comm: target.comm.clone(),
+            action,
+            cause,
+        }
+    }
+
+    /// Reads an entry back from the values of its fields, e.g. of a recorded
+    /// targets log.
+    pub fn from_fields(fields: Vec<Value>) -> Result<Self> {
+        let [epoch_ms, pid, tid, comm, action, cause] = &fields[..] else {
+            return Err(eyre!("Invalid target log entry {:?}", fields));
+        };
+        let unsigned = |value: &Value| {
+            value
+                .as_u64()
+                .ok_or(eyre!("Invalid target log field {:?}", value))
+        };
+        let text = |value: &Value| {
+            value
+                .as_str()
+                .map(str::to_string)
+                .ok_or(eyre!("Invalid target log field {:?}", value))
+        };
+        let action = match text(action)?.as_str() {
+            "added" => TargetAction::Added,
+            "removed" => TargetAction::Removed,
+            "exec" => TargetAction::Exec,
+            action => return Err(eyre!("Invalid target action {}", action)),
+        };
+        Ok(Self {
+            epoch_ms: unsigned(epoch_ms)? as u128,
+            pid: unsigned(pid)? as usize,
+            tid: unsigned(tid)? as usize,
+            comm: Rc::from(text(comm)?),
+            action,
+            cause: text(cause)?,
+        })
+    }
+}
+
+impl Row for TargetLogEntry {
+    fn schema(&self) -> &'static [Field] {
+        Self::FIELDS
+    }
+
+    fn fields(&self) -> Vec<Value> {
+        vec![
+            Value::Unsigned(self.epoch_ms as u64),
+            Value::Unsigned(self.pid as u64),
+            Value::Unsigned(self.tid as u64),
+            Value::Text(self.comm.to_string()),
+            Value::Text(self.action.to_string()),
+            Value::Text(self.cause.clone()),
+        ]
+    }
+}
+
+/// Records why every target was added or removed, e.g. in `targets.csv`.
+pub struct TargetLog {
+    series: Series,
+    sink: SharedSink,
+}
+
+impl TargetLog {
+    pub fn new(sink: SharedSink) -> Self {
+        Self {
+            series: Series::new(Scope::Session, Subsystem::Targets, None),
+            sink,
+        }
+    }
+
+    pub fn record(&mut self, entry: &TargetLogEntry) -> Result<()> {
+        self.sink
+            .lock()
+            .unwrap()
+            .write(&self.series, entry.epoch_ms, entry)
+    }
+}
+
 pub struct TimeSensitive;
 
 impl TimeSensitive {
@@ -202,10 +360,16 @@ impl TimeSensitive {
                         "{:?} - Start time sensitive collect",
                         chrono::offset::Utc::now()
                     );
-                    for collector in collectors.iter_mut() {
-                        collector.sample();
-                        collector.store();
-                    }
+                    // A collector fails to sample once its thread exited
+                    collectors.retain_mut(|collector| {
+                        if collector.sample().is_err() {
+                            return false;
+                        }
+                        if let Err(e) = collector.store() {
+                            println!("Failed to store time sensitive sample. {e}");
+                        }
+                        true
+                    });
                     println!(
                         "{:?} - End time sensitive collect",
                         chrono::offset::Utc::now()
@@ -235,3 +399,95 @@ impl TimeSensitive {
         sample_rx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Target, TargetAction, TargetContext, TargetLog, TargetLogEntry};
+    use crate::{
+        configure::Buffers,
+        metrics::{
+            sink::csv::{self, CsvSink},
+            ToCsv,
+        },
+    };
+    use eyre::Result;
+    use indoc::indoc;
+    use std::{cell::RefCell, collections::HashMap, fs, rc::Rc, sync::mpsc};
+    use tempdir::TempDir;
+
+    #[test]
+    fn exited_without_collectors() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let task = dir.path().join("proc/8877/task/8955");
+        fs::create_dir_all(&task)?;
+        let context = TargetContext {
+            sched: false,
+            procfs: Rc::from(dir.path().join("proc").to_str().unwrap()),
+            futex_program: None,
+            ipc_program: None,
+            plugin_programs: Vec::new(),
+            sink: CsvSink::shared(dir.path().to_str().unwrap(), &Buffers::default()),
+            kfile_socket_map: Rc::new(RefCell::new(HashMap::new())),
+            time_sensitive_collector_tx: mpsc::channel().0,
+        };
+        let mut target = Target::new(8877, 8955, &context);
+
+        target.sample()?;
+        fs::remove_dir(&task)?;
+        assert!(target.sample().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn target_log() -> Result<()> {
+        let dir = TempDir::new("")?;
+        let mut log = TargetLog::new(CsvSink::shared(
+            dir.path().to_str().unwrap(),
+            &Buffers::default(),
+        ));
+        let mut entry = TargetLogEntry {
+            epoch_ms: 1722794820000,
+            pid: 3021,
+            tid: 3025,
+            comm: Rc::from("redis-server"),
+            action: TargetAction::Added,
+            cause: "futex 0x7c3dd4f85fb0 of 8877".to_string(),
+        };
+        log.record(&entry)?;
+        entry.action = TargetAction::Removed;
+        entry.cause = "sample failed: No such file, or directory".to_string();
+        log.record(&entry)?;
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("targets.csv"))?,
+            indoc! {"
+                epoch_ms,pid,tid,comm,action,cause
+                1722794820000,3021,3025,redis-server,added,futex 0x7c3dd4f85fb0 of 8877
+                1722794820000,3021,3025,redis-server,removed,sample failed: No such file  or directory
+            "}
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_log_entry() -> Result<()> {
+        let entry = TargetLogEntry {
+            epoch_ms: 1722794820000,
+            pid: 8877,
+            tid: 8955,
+            comm: Rc::from("redis,server"),
+            action: TargetAction::Exec,
+            cause: "execve".to_string(),
+        };
+        let row = entry.to_csv_row();
+        let read =
+            TargetLogEntry::from_fields(csv::parse_row(TargetLogEntry::FIELDS, row.trim_end())?)?;
+        assert_eq!(read.epoch_ms, entry.epoch_ms);
+        assert_eq!((read.pid, read.tid), (8877, 8955));
+        assert_eq!(&*read.comm, "redis server");
+        assert_eq!(read.action, TargetAction::Exec);
+        assert_eq!(read.cause, entry.cause);
+        assert!(TargetLogEntry::from_fields(Vec::new()).is_err());
+        Ok(())
+    }
+}
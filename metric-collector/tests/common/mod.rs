@@ -0,0 +1,180 @@
+//! Harness running the whole extractor without root, against a synthetic
+//! procfs tree and a fake bpftrace printing the scripted output of each
+//! program.
+
+use collector::{cmdline, configure::Config, extract::Extractor};
+use eyre::{eyre, Result};
+use indoc::formatdoc;
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use tempdir::TempDir;
+
+/// Header of a bpftrace program, printed once its probes are attached.
+const HEADER: &str = r#"{"type": "attached_probes", "data": {"probes": 4}}"#;
+
+pub struct Harness {
+    dir: TempDir,
+}
+
+impl Harness {
+    pub fn new() -> Result<Self> {
+        let harness = Self {
+            dir: TempDir::new("prism")?,
+        };
+        fs::create_dir_all(harness.procfs())?;
+        fs::create_dir_all(harness.outputs())?;
+
+        // Called with -f json -k <script> <parameters>. The rest of the output
+        // follows the header once every program attached, as the collector
+        // drops the clone events read meanwhile.
+        let bpftrace = harness.bpftrace();
+        fs::write(
+            &bpftrace,
+            formatdoc! {r#"
+                #!/bin/sh
+                if [ "$1" = --version ]; then
+                    echo "bpftrace v0.20.0"
+                    exit 0
+                fi
+                echo "$4" >> "{outputs}/launches"
+                output="{outputs}/$(basename "$4" .bt).ndjson"
+                if [ ! -f "$output" ]; then
+                    echo '{header}'
+                    exec sleep 60
+                fi
+                head -n 1 "$output"
+                sleep 1
+                tail -n +2 "$output"
+                exec sleep 60
+            "#,
+                outputs = harness.outputs().display(),
+                header = HEADER,
+            },
+        )?;
+        fs::set_permissions(&bpftrace, fs::Permissions::from_mode(0o755))?;
+        Ok(harness)
+    }
+
+    fn procfs(&self) -> PathBuf {
+        self.dir.path().join("proc")
+    }
+
+    fn outputs(&self) -> PathBuf {
+        self.dir.path().join("outputs")
+    }
+
+    fn bpftrace(&self) -> PathBuf {
+        self.dir.path().join("bpftrace")
+    }
+
+    /// Number of times the program of a bundled script was launched.
+    pub fn launches(&self, script: &str) -> Result<usize> {
+        Ok(fs::read_to_string(self.outputs().join("launches"))?
+            .lines()
+            .filter(|line| line.ends_with(&format!("/{}", script)))
+            .count())
+    }
+
+    /// Adds a thread to the procfs tree, with its scheduler statistics.
+    pub fn add_thread(&self, pid: usize, tid: usize, comm: &str) -> Result<()> {
+        let process = self.procfs().join(pid.to_string());
+        if pid == tid {
+            fs::create_dir_all(&process)?;
+            fs::write(
+                process.join("stat"),
+                format!("{pid} ({comm}) S 1 {pid} {pid} 0 -1 4194560 1213 0 0 0\n"),
+            )?;
+        }
+        let task = process.join(format!("task/{}", tid));
+        fs::create_dir_all(&task)?;
+        fs::write(task.join("comm"), format!("{}\n", comm))?;
+
+        let thread = self.procfs().join(tid.to_string());
+        fs::create_dir_all(&thread)?;
+        fs::write(
+            thread.join("sched"),
+            formatdoc! {"
+                {comm} ({tid}, #threads: 1)
+                -------------------------------------------------------------------
+                se.exec_start                                :      65384570.945103
+                se.sum_exec_runtime                          :          1204.370128
+                se.nr_migrations                             :                   12
+                sum_sleep_runtime                            :         52131.080457
+                sum_block_runtime                            :             3.416702
+                wait_start                                   :             0.000000
+                sleep_start                                  :             0.000000
+                block_start                                  :             0.000000
+                wait_max                                     :             2.081513
+                wait_sum                                     :            31.582290
+                wait_count                                   :                   57
+                iowait_sum                                   :             1.250000
+                iowait_count                                 :                    3
+            "},
+        )?;
+        fs::write(thread.join("schedstat"), "1204370128 31582290 57\n")?;
+        Ok(())
+    }
+
+    /// Scripts the output of the program of a bundled script, e.g.
+    /// `futex_wait`, starting with its header.
+    pub fn script(&self, program: &str, output: &str) -> Result<()> {
+        fs::write(
+            self.outputs().join(format!("{}.ndjson", program)),
+            format!("{}\n{}", HEADER, output),
+        )?;
+        Ok(())
+    }
+
+    /// Records a session with `args` for `duration`, and returns its
+    /// directory.
+    pub fn run(&self, args: &[&str], duration: Duration) -> Result<PathBuf> {
+        let root_directory = self.dir.path().join("data");
+        let (procfs, bpftrace) = (self.procfs(), self.bpftrace());
+        let mut argv = vec![
+            "metric-collector",
+            "--skip-doctor",
+            "--period",
+            "200",
+            "--procfs",
+            procfs.to_str().unwrap(),
+            "--bpftrace",
+            bpftrace.to_str().unwrap(),
+            "--data-directory",
+            root_directory.to_str().unwrap(),
+        ];
+        argv.extend_from_slice(args);
+        let matches = cmdline::register_args().get_matches_from(argv);
+        let config = Config::try_from(matches).map_err(|e| eyre!("{}", e))?;
+
+        let extractor = Extractor::new(config)?;
+        let terminate_flag = extractor.terminate_flag();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            *terminate_flag.lock().unwrap() = true;
+        });
+        extractor.run()?;
+
+        let session = fs::read_dir(&root_directory)?
+            .next()
+            .ok_or(eyre!("No session directory"))??;
+        Ok(session.path().join("system-metrics"))
+    }
+}
+
+/// Rows of a CSV file after its header, without their epoch.
+pub fn rows(path: &Path) -> Result<Vec<String>> {
+    let content =
+        fs::read_to_string(path).map_err(|e| eyre!("Could not read {}. {}", path.display(), e))?;
+    let mut rows: Vec<String> = content
+        .lines()
+        .skip(1)
+        .map(|line| line.split_once(',').unwrap().1.to_string())
+        .collect();
+    rows.sort();
+    Ok(rows)
+}
@@ -0,0 +1,103 @@
+//! Runs the whole extractor without root, against a synthetic procfs tree and
+//! a fake bpftrace printing the scripted output of each program.
+
+mod common;
+
+use collector::execute::programs::BOOT_EPOCH_NS;
+use common::{rows, Harness};
+use eyre::{eyre, Result};
+use indoc::indoc;
+use std::{fs, time::Duration};
+
+#[test]
+fn end_to_end() -> Result<()> {
+    // The instants since boot below are then within 2024-08-04T18:07:00Z
+    *BOOT_EPOCH_NS.write().unwrap() = 1_722_794_760_000_000_000;
+
+    let harness = Harness::new()?;
+    harness.add_thread(8877, 8877, "redis-server")?;
+    harness.add_thread(8877, 8955, "redis-server")?;
+    harness.add_thread(9001, 9001, "redis-check-aof")?;
+    harness.script(
+        "clone",
+        indoc! {r#"
+            {"type": "printf", "data": "NewProcess\t9001\t8877\tredis-check-aof\n"}
+        "#},
+    )?;
+    harness.script(
+        "futex_wait",
+        indoc! {r#"
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wait_elapsed": {"8955, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant  \t60500000000\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#},
+    )?;
+    harness.script(
+        "ipc",
+        indoc! {r#"
+            {"type": "printf", "data": "NewSocketMap\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\n"}
+            {"type": "printf", "data": "AcceptEnd\t8955\tsockfs\t8\t90098\tAF_INET\t127.0.0.1\t7878\t127.0.0.1\t50058\t60000000000\tredis-server\n"}
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@inode_map": {"8955, sockfs, 8, 90098": [2848, 1, "redis-server"]}}}
+            {"type": "printf", "data": "SampleInstant\t61000000000\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#},
+    )?;
+    harness.script(
+        "io_wait",
+        indoc! {r#"
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@completed": {"271581184, 271581187, 8955, 8877": 22616}}}
+            {"type": "printf", "data": "SampleInstant   62000000000\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#},
+    )?;
+
+    let session = harness.run(&["--pids", "8877"], Duration::from_secs(3))?;
+    assert_eq!(harness.launches("clone.bt")?, 1);
+
+    for file in ["version.txt", "config.toml", "manifest.json"] {
+        assert!(session.join(file).exists(), "{} is missing", file);
+    }
+    assert!(fs::read_to_string(session.join("manifest.json"))?
+        .contains(r#""exit_reason": "Interrupted""#));
+    assert_eq!(
+        rows(&session.join("targets.csv"))?,
+        vec![
+            "8877,8877,redis-server,added,pids argument",
+            "8877,8955,redis-server,added,pids argument",
+            "9001,9001,redis-check-aof,added,fork of process 8877",
+        ]
+    );
+
+    let thread = session.join("thread/8877/8955");
+    assert_eq!(
+        fs::read_to_string(thread.join("futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv"))?,
+        indoc! {"
+            epoch_ms,futex_wait_ns,futex_count
+            1722794820500,847638877,4
+        "}
+    );
+    assert_eq!(
+        rows(&thread.join("ipc/sockets/1722794820/ipv4_127.0.0.1:7878_127.0.0.1:50058.csv"))?,
+        vec!["2848,1"]
+    );
+    assert_eq!(
+        rows(&session.join("global/iowait/8877/8955/1722794820/271581187.csv"))?,
+        vec!["22616"]
+    );
+
+    // The scheduler statistics are sampled every period, at the current time
+    let day = fs::read_dir(thread.join("sched"))?
+        .next()
+        .ok_or(eyre!("No sched file"))??
+        .path();
+    assert!(rows(&day)?.contains(&"1204.370128,31.58229,52131.080457,3.416702,1.25".to_string()));
+    let day = fs::read_dir(thread.join("schedstat"))?
+        .next()
+        .ok_or(eyre!("No schedstat file"))??
+        .path();
+    assert!(rows(&day)?.contains(&"1204370128,31582290,57".to_string()));
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Runs a session escalating on the response times of the application, in a
+//! process of its own as the extractor handles Ctrl-C.
+
+mod common;
+
+use collector::execute::programs::BOOT_EPOCH_NS;
+use common::{rows, Harness};
+use eyre::Result;
+use indoc::indoc;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    thread,
+    time::Duration,
+};
+use tempdir::TempDir;
+
+#[test]
+fn escalate_on_slo_breach() -> Result<()> {
+    *BOOT_EPOCH_NS.write().unwrap() = 1_722_794_760_000_000_000;
+
+    let harness = Harness::new()?;
+    harness.add_thread(8877, 8877, "redis-server")?;
+    harness.script(
+        "futex_wait",
+        indoc! {r#"
+            {"type": "printf", "data": "=> start map statistics\n"}
+            {"type": "map", "data": {"@wait_elapsed": {"8877, 8877, 0x7c3dd4f85fb0": [847638877, 4]}}}
+            {"type": "printf", "data": "SampleInstant  \t60500000000\n"}
+            {"type": "printf", "data": "=> end map statistics\n"}
+        "#},
+    )?;
+    let dir = TempDir::new("slo")?;
+    let responses = dir.path().join("response_time.csv");
+    fs::write(&responses, "end_ts,duration_ms\n1722794820000,10\n")?;
+
+    // The futex program is stopped once attached, before printing its map,
+    // and relaunched once the SLO is breached
+    let breach = responses.clone();
+    thread::spawn(move || -> Result<()> {
+        thread::sleep(Duration::from_millis(1500));
+        let mut file = OpenOptions::new().append(true).open(breach)?;
+        file.write_all(b"1722794821000,250\n")?;
+        Ok(())
+    });
+    let session = harness.run(
+        &[
+            "--pids",
+            "8877",
+            "--collectors",
+            "sched,futex",
+            "--slo-source",
+            responses.to_str().unwrap(),
+            "--slo-threshold-ms",
+            "100",
+        ],
+        Duration::from_secs(4),
+    )?;
+
+    assert_eq!(harness.launches("futex_wait.bt")?, 2);
+    assert_eq!(
+        rows(&session.join("escalations.csv"))?,
+        vec!["escalated,250,100,2"]
+    );
+    assert_eq!(
+        rows(&session.join("thread/8877/8877/futex/wait/1722794820/8877-0x7c3dd4f85fb0.csv"))?,
+        vec!["847638877,4"]
+    );
+    Ok(())
+}
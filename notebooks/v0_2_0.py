@@ -33,6 +33,8 @@ def recursive_dfs(path: str) -> list[str]:
     return files
 
 def apply_naming_convention(metric_df: pd.DataFrame, file_path: str):
+    # Partitions compressed once closed are named as their CSV file
+    file_path = re.sub(r'\.(gz|zst)$', '', file_path)
     m = re.search(r'system-metrics/(.+?)/', file_path)
     if m == None: 
         return